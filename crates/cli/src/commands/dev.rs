@@ -79,10 +79,13 @@ async fn handle_request(
             style("(asset)").black().bright()
         );
 
-        let run_result = match handle_asset(public_dir.unwrap(), asset) {
-            Ok(response) => RunResult::Response(response, None),
-            Err(error) => RunResult::Error(format!("Could not retrieve asset ({asset}): {error}")),
-        };
+        let run_result =
+            match handle_asset(public_dir.unwrap(), asset, req.headers(), &HashMap::new()) {
+                Ok(response) => RunResult::Response(response, None),
+                Err(error) => {
+                    RunResult::Error(format!("Could not retrieve asset ({asset}): {error}"))
+                }
+            };
 
         tx.send_async(run_result).await.unwrap_or(());
     } else if is_favicon {
@@ -111,13 +114,15 @@ async fn handle_request(
         isolate_tx
             .send_async(IsolateEvent::Request(IsolateRequest {
                 request,
+                body_stream: None,
                 sender: tx,
+                request_id: None,
             }))
             .await
             .unwrap_or(());
     }
 
-    handle_response(rx, |event| async move {
+    handle_response(rx, None, |event| async move {
         match event {
             ResponseEvent::StreamDoneNoDataError => {
                 println!(
@@ -135,6 +140,11 @@ async fn handle_request(
             ResponseEvent::LimitsReached(result) => {
                 if result.is_timeout() {
                     println!("{} Function execution timed out", style("✕").red());
+                } else if result.is_stack_overflow() {
+                    println!(
+                        "{} Function execution exceeded maximum call stack size",
+                        style("✕").red()
+                    );
                 } else {
                     println!(
                         "{} Function execution reached memory limit",
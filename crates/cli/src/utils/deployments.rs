@@ -8,6 +8,7 @@ use dialoguer::{Confirm, Input};
 use hyper::{Body, Method, Request};
 use pathdiff::diff_paths;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::sync::Arc;
 use std::{
     collections::HashMap,
@@ -365,6 +366,7 @@ struct Asset {
 struct CreateDeploymentRequest {
     function_id: String,
     function_size: usize,
+    function_hash: String,
     assets: Vec<Asset>,
 }
 
@@ -407,6 +409,7 @@ pub async fn create_deployment(
             CreateDeploymentRequest {
                 function_id: function_config.function_id.clone(),
                 function_size: index.len(),
+                function_hash: format!("{:x}", Sha256::digest(&index)),
                 assets: assets
                     .iter()
                     .map(|(key, value)| Asset {
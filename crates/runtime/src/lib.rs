@@ -31,6 +31,12 @@ impl Runtime {
             flags += " --expose-gc";
         }
 
+        // Limit the call stack, so runaway recursions throw a RangeError.
+        // The threads running the isolates need a larger native stack
+        if let Some(stack_size) = options.stack_size {
+            flags += &format!(" --stack-size={stack_size}");
+        }
+
         V8::set_flags_from_string(&flags);
 
         let platform = v8::new_default_platform(0, false).make_shared();
@@ -2,6 +2,9 @@
 pub struct RuntimeOptions {
     pub allow_code_generation: bool,
     pub expose_gc: bool,
+    // Maximum size in KB of the call stack of the isolates, V8
+    // uses 984KB when not set. Applies to all the isolates
+    pub stack_size: Option<usize>,
 }
 
 impl RuntimeOptions {
@@ -14,4 +17,9 @@ impl RuntimeOptions {
         self.expose_gc = expose_gc;
         self
     }
+
+    pub fn stack_size(mut self, stack_size: Option<usize>) -> Self {
+        self.stack_size = stack_size;
+        self
+    }
 }
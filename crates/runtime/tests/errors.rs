@@ -1,4 +1,4 @@
-use hyper::Request;
+use hyper::{header::CONTENT_TYPE, Request, Response};
 use lagon_runtime_http::RunResult;
 use lagon_runtime_isolate::options::IsolateOptions;
 use std::time::Duration;
@@ -55,6 +55,64 @@ async fn handler_reject() {
     .await;
 }
 
+#[tokio::test]
+async fn statistics_on_error() {
+    utils::setup();
+    let (statistics_tx, statistics_rx) = flume::unbounded();
+    let (send, receiver) = utils::create_isolate(
+        IsolateOptions::new(
+            "export function handler() {
+    throw new Error('Rejected');
+}"
+            .into(),
+        )
+        .on_statistics_callback(Box::new(move |_, statistics| {
+            statistics_tx.send(statistics).unwrap();
+        })),
+    );
+    send(Request::default());
+
+    utils::assert_run_result(
+        &receiver,
+        RunResult::Error("Uncaught Error: Rejected\n  at handler (2:11)".into()),
+    )
+    .await;
+
+    let statistics = statistics_rx.recv_async().await.unwrap();
+    assert_eq!(statistics.result, "error");
+    assert!(statistics.memory_usage > 0);
+}
+
+#[tokio::test]
+async fn heap_statistics() {
+    utils::setup();
+    let (statistics_tx, statistics_rx) = flume::unbounded();
+    let (send, receiver) = utils::create_isolate(
+        IsolateOptions::new(
+            "export function handler() {
+    throw new Error('Rejected');
+}"
+            .into(),
+        )
+        .heap_statistics(true)
+        .on_statistics_callback(Box::new(move |_, statistics| {
+            statistics_tx.send(statistics).unwrap();
+        })),
+    );
+    send(Request::default());
+
+    utils::assert_run_result(
+        &receiver,
+        RunResult::Error("Uncaught Error: Rejected\n  at handler (2:11)".into()),
+    )
+    .await;
+
+    let statistics = statistics_rx.recv_async().await.unwrap();
+    let heap = statistics.heap.unwrap();
+    assert_eq!(heap.used_heap_size, statistics.memory_usage);
+    assert!(heap.total_heap_size >= heap.used_heap_size);
+}
+
 #[tokio::test]
 async fn compilation_error() {
     utils::setup();
@@ -68,7 +126,7 @@ async fn compilation_error() {
 
     utils::assert_run_result(
         &receiver,
-        RunResult::Error("Uncaught SyntaxError: Unexpected identifier 'syntax'".into()),
+        RunResult::CompileError("Uncaught SyntaxError: Unexpected identifier 'syntax'".into()),
     )
     .await;
 }
@@ -88,7 +146,7 @@ export function handler() {
 
     utils::assert_run_result(
         &receiver,
-        RunResult::Error(
+        RunResult::CompileError(
             "Uncaught Error: Can't import modules, everything should be bundled in a single file"
                 .into(),
         ),
@@ -96,6 +154,26 @@ export function handler() {
     .await;
 }
 
+#[tokio::test]
+async fn evaluation_error() {
+    utils::setup();
+    let (send, receiver) = utils::create_isolate(IsolateOptions::new(
+        "throw new Error('Top-level');
+
+export function handler() {
+    return new Response('hello world');
+}"
+        .into(),
+    ));
+    send(Request::default());
+
+    utils::assert_run_result(
+        &receiver,
+        RunResult::EvaluationError("Uncaught Error: Top-level\n  at 1:7".into()),
+    )
+    .await;
+}
+
 #[tokio::test]
 async fn execution_tick_timeout_reached() {
     utils::setup();
@@ -169,6 +247,72 @@ async fn memory_reached() {
     utils::assert_run_result(&receiver, RunResult::MemoryLimit).await;
 }
 
+#[tokio::test]
+async fn stack_overflow() {
+    utils::setup();
+    let (send, receiver) = utils::create_isolate(IsolateOptions::new(
+        "function recurse(n) {
+    return recurse(n + 1) + 1;
+}
+export function handler() {
+    return new Response(recurse(0));
+}"
+        .into(),
+    ));
+    send(Request::default());
+
+    utils::assert_run_result(&receiver, RunResult::StackOverflow(String::new())).await;
+}
+
+#[tokio::test]
+async fn stack_overflow_caught() {
+    utils::setup();
+    let (send, receiver) = utils::create_isolate(IsolateOptions::new(
+        "function recurse(n) {
+    return recurse(n + 1) + 1;
+}
+export function handler() {
+    try {
+        recurse(0);
+    } catch (error) {
+        return new Response(error.message);
+    }
+}"
+        .into(),
+    ));
+    send(Request::default());
+
+    utils::assert_response(
+        &receiver,
+        Response::builder()
+            .header(CONTENT_TYPE, "text/plain;charset=UTF-8")
+            .body("Maximum call stack size exceeded".into())
+            .unwrap(),
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn stack_overflow_in_message() {
+    utils::setup();
+    let (send, receiver) = utils::create_isolate(IsolateOptions::new(
+        "export function handler() {
+    throw new Error('RangeError: Maximum call stack size exceeded');
+}"
+        .into(),
+    ));
+    send(Request::default());
+
+    utils::assert_run_result(
+        &receiver,
+        RunResult::Error(
+            "Uncaught Error: RangeError: Maximum call stack size exceeded\n  at handler (2:11)"
+                .into(),
+        ),
+    )
+    .await;
+}
+
 #[tokio::test]
 async fn stacktrace() {
     utils::setup();
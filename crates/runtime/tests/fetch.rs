@@ -1,7 +1,11 @@
 use httptest::{matchers::*, responders::*, Expectation, Server};
-use hyper::{header::CONTENT_TYPE, Request, Response};
+use hyper::{header::CONTENT_TYPE, Body, Request, Response};
 use lagon_runtime_http::RunResult;
-use lagon_runtime_isolate::options::IsolateOptions;
+use lagon_runtime_isolate::options::{FetchPolicy, IsolateOptions};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
 
 mod utils;
 
@@ -131,6 +135,49 @@ async fn request_headers() {
     .await;
 }
 
+#[tokio::test]
+async fn request_traceparent() {
+    utils::setup();
+    let server = Server::run();
+    server.expect(
+        Expectation::matching(all_of![
+            request::method_path("GET", "/"),
+            request::headers(contains((
+                "traceparent",
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+            )))
+        ])
+        .respond_with(status_code(200).body("Hello, World")),
+    );
+    let url = server.url("/");
+
+    let (send, receiver) = utils::create_isolate(IsolateOptions::new(format!(
+        "export async function handler() {{
+    const body = await fetch('{url}').then(res => res.text());
+
+    return new Response(body);
+}}"
+    )));
+    send(
+        Request::builder()
+            .header(
+                "traceparent",
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            )
+            .body(Body::empty())
+            .unwrap(),
+    );
+
+    utils::assert_response(
+        &receiver,
+        Response::builder()
+            .header(CONTENT_TYPE, "text/plain;charset=UTF-8")
+            .body("Hello, World".into())
+            .unwrap(),
+    )
+    .await;
+}
+
 #[tokio::test]
 async fn request_headers_class() {
     utils::setup();
@@ -649,3 +696,112 @@ async fn fetch_set_content_length() {
     )
     .await;
 }
+
+#[tokio::test]
+async fn block_private_ips() {
+    utils::setup();
+    let server = Server::run();
+    let url = server.url("/");
+    let port = server.addr().port();
+    let blocked = Arc::new(AtomicUsize::new(0));
+    let blocked_handle = Arc::clone(&blocked);
+
+    let (send, receiver) = utils::create_isolate(
+        IsolateOptions::new(
+            "export async function handler(request) {
+    const url = request.headers.get('x-url');
+    await fetch(url);
+    return new Response('Ok');
+}"
+            .into(),
+        )
+        .fetch_policy(FetchPolicy {
+            block_private_ips: true,
+            ..Default::default()
+        })
+        .on_fetch_blocked_callback(Box::new(move |_, _| {
+            blocked_handle.fetch_add(1, Ordering::SeqCst);
+        })),
+    );
+
+    send(
+        Request::builder()
+            .header("x-url", url.to_string())
+            .body(Body::empty())
+            .unwrap(),
+    );
+    utils::assert_run_result(
+        &receiver,
+        RunResult::Error("Uncaught Error: fetch() to 127.0.0.1 is not allowed".into()),
+    )
+    .await;
+
+    // Hostnames resolving to a private IP are blocked too
+    send(
+        Request::builder()
+            .header("x-url", format!("http://localhost:{port}/"))
+            .body(Body::empty())
+            .unwrap(),
+    );
+    utils::assert_run_result(
+        &receiver,
+        RunResult::Error("Uncaught Error: fetch() to localhost is not allowed".into()),
+    )
+    .await;
+
+    assert_eq!(blocked.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn allowed_hosts() {
+    utils::setup();
+    let server = Server::run();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/"))
+            .respond_with(status_code(200).body("Hello, World")),
+    );
+    let url = server.url("/");
+    let port = server.addr().port();
+
+    let (send, receiver) = utils::create_isolate(
+        IsolateOptions::new(
+            "export async function handler(request) {
+    const url = request.headers.get('x-url');
+    const body = await fetch(url).then(res => res.text());
+    return new Response(body);
+}"
+            .into(),
+        )
+        .fetch_policy(FetchPolicy {
+            allowed_hosts: Some(vec!["*.lagon.app".into(), "127.0.0.1".into()]),
+            ..Default::default()
+        }),
+    );
+
+    send(
+        Request::builder()
+            .header("x-url", url.to_string())
+            .body(Body::empty())
+            .unwrap(),
+    );
+    utils::assert_response(
+        &receiver,
+        Response::builder()
+            .header(CONTENT_TYPE, "text/plain;charset=UTF-8")
+            .body("Hello, World".into())
+            .unwrap(),
+    )
+    .await;
+
+    send(
+        Request::builder()
+            .header("x-url", format!("http://localhost:{port}/"))
+            .body(Body::empty())
+            .unwrap(),
+    );
+    utils::assert_run_result(
+        &receiver,
+        RunResult::Error("Uncaught Error: fetch() to localhost is not allowed".into()),
+    )
+    .await;
+}
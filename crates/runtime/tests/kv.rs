@@ -0,0 +1,102 @@
+use hyper::{header::CONTENT_TYPE, Request, Response};
+use lagon_runtime_http::RunResult;
+use lagon_runtime_isolate::options::{IsolateOptions, KvRequest, KvResponse};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+mod utils;
+
+#[tokio::test]
+async fn kv_unavailable() {
+    utils::setup();
+    let (send, receiver) = utils::create_isolate(IsolateOptions::new(
+        "export async function handler() {
+    await kv.get('key');
+    return new Response('Ok');
+}"
+        .into(),
+    ));
+    send(Request::default());
+
+    utils::assert_run_result(
+        &receiver,
+        RunResult::Error("Uncaught Error: KV storage is not available".into()),
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn kv_get_set_delete() {
+    utils::setup();
+    let store = Arc::new(Mutex::new(HashMap::new()));
+    let store_handle = Arc::clone(&store);
+
+    let (send, receiver) = utils::create_isolate(
+        IsolateOptions::new(
+            "export async function handler() {
+    const missing = await kv.get('key');
+    await kv.set('key', { hello: 'world' }, { ttl: 60 });
+    const value = await kv.get('key');
+    const deleted = await kv.delete('key');
+    const deletedAgain = await kv.delete('key');
+    return new Response(JSON.stringify({ missing, value, deleted, deletedAgain }));
+}"
+            .into(),
+        )
+        .on_kv_callback(Box::new(move |_, request| {
+            let mut store = store_handle.lock().unwrap();
+
+            let response = match request {
+                KvRequest::Get(key) => KvResponse::Value(store.get(&key).cloned()),
+                KvRequest::Set { key, value, ttl } => {
+                    assert_eq!(ttl, Some(60));
+                    store.insert(key, value);
+                    KvResponse::Set
+                }
+                KvRequest::Delete(key) => KvResponse::Deleted(store.remove(&key).is_some()),
+            };
+
+            Box::pin(async move { Ok(response) })
+        })),
+    );
+    send(Request::default());
+
+    utils::assert_response(
+        &receiver,
+        Response::builder()
+            .header(CONTENT_TYPE, "text/plain;charset=UTF-8")
+            .body(
+                r#"{"missing":null,"value":{"hello":"world"},"deleted":true,"deletedAgain":false}"#
+                    .into(),
+            )
+            .unwrap(),
+    )
+    .await;
+
+    assert!(store.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn kv_max_value_size() {
+    utils::setup();
+    let (send, receiver) = utils::create_isolate(
+        IsolateOptions::new(
+            "export async function handler() {
+    await kv.set('key', 'a'.repeat(10));
+    return new Response('Ok');
+}"
+            .into(),
+        )
+        .on_kv_callback(Box::new(|_, _| Box::pin(async { Ok(KvResponse::Set) })))
+        .kv_max_value_size(8),
+    );
+    send(Request::default());
+
+    utils::assert_run_result(
+        &receiver,
+        RunResult::Error("Uncaught Error: KV values can't be larger than 8 bytes".into()),
+    )
+    .await;
+}
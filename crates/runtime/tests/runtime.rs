@@ -3,7 +3,9 @@ use hyper::{
     Body, Method, Request, Response,
 };
 use lagon_runtime_http::{RunResult, StreamResult};
-use lagon_runtime_isolate::options::IsolateOptions;
+use lagon_runtime_isolate::{
+    options::IsolateOptions, Isolate, IsolateEvent, IsolateRequest, WarmIsolate,
+};
 
 mod utils;
 
@@ -83,6 +85,46 @@ async fn execute_function_twice() {
     .await;
 }
 
+#[tokio::test]
+async fn code_cache() {
+    utils::setup();
+    let code = "export function handler() {
+    return new Response('Hello world');
+}";
+    let (code_cache_tx, code_cache_rx) = flume::unbounded();
+    let (send, receiver) = utils::create_isolate(
+        IsolateOptions::new(code.into()).on_code_cache_callback(Box::new(move |code_cache| {
+            code_cache_tx.send(code_cache).unwrap();
+        })),
+    );
+    send(Request::default());
+
+    utils::assert_response(
+        &receiver,
+        Response::builder()
+            .header(CONTENT_TYPE, "text/plain;charset=UTF-8")
+            .body("Hello world".into())
+            .unwrap(),
+    )
+    .await;
+
+    let code_cache = code_cache_rx.recv_async().await.unwrap();
+    assert!(!code_cache.is_empty());
+
+    let (send, receiver) =
+        utils::create_isolate(IsolateOptions::new(code.into()).code_cache(Some(code_cache)));
+    send(Request::default());
+
+    utils::assert_response(
+        &receiver,
+        Response::builder()
+            .header(CONTENT_TYPE, "text/plain;charset=UTF-8")
+            .body("Hello world".into())
+            .unwrap(),
+    )
+    .await;
+}
+
 #[tokio::test]
 async fn environment_variables() {
     utils::setup();
@@ -417,3 +459,89 @@ async fn btoa() {
     )
     .await;
 }
+
+#[tokio::test]
+async fn log_level() {
+    let (logs_sender, logs_receiver) = flume::unbounded();
+    utils::setup();
+    let (send, receiver) = utils::create_isolate(
+        IsolateOptions::new(
+            "export function handler() {
+    console.debug('debug');
+    console.log('log');
+    console.warn('warn');
+    console.error('error');
+    return new Response('Hello world');
+}"
+            .into(),
+        )
+        .log_sender(logs_sender)
+        .log_level(log::LevelFilter::Warn),
+    );
+    send(Request::default());
+
+    utils::assert_response(
+        &receiver,
+        Response::builder()
+            .header(CONTENT_TYPE, "text/plain;charset=UTF-8")
+            .body("Hello world".into())
+            .unwrap(),
+    )
+    .await;
+    assert_eq!(
+        logs_receiver.recv_async().await.unwrap(),
+        ("warn".into(), "warn".into(), None, None)
+    );
+    assert_eq!(
+        logs_receiver.recv_async().await.unwrap(),
+        ("error".into(), "error".into(), None, None)
+    );
+    assert!(logs_receiver.is_empty());
+}
+
+#[tokio::test]
+async fn warm_isolate() {
+    utils::setup();
+    let (request_tx, request_rx) = flume::unbounded();
+    let (sender, receiver) = flume::unbounded();
+    let snapshot_blob = include_bytes!("../../serverless/snapshot.bin");
+
+    let handle = tokio::runtime::Handle::current();
+    std::thread::spawn(move || {
+        let warm = WarmIsolate::new(128, Some(snapshot_blob));
+        let options = IsolateOptions::new(
+            "export function handler() {
+    return new Response('Hello world');
+}"
+            .into(),
+        )
+        .snapshot_blob(snapshot_blob);
+
+        assert!(warm.is_compatible(&options));
+        assert!(!warm.is_compatible(&IsolateOptions::new(String::new()).memory(64)));
+
+        handle.block_on(async move {
+            let mut isolate = Isolate::from_warm(warm, options, request_rx);
+            isolate.evaluate();
+            isolate.run_event_loop().await;
+        })
+    });
+
+    request_tx
+        .send(IsolateEvent::Request(IsolateRequest {
+            request: Request::default().into_parts(),
+            body_stream: None,
+            sender,
+            request_id: None,
+        }))
+        .unwrap();
+
+    utils::assert_response(
+        &receiver,
+        Response::builder()
+            .header(CONTENT_TYPE, "text/plain;charset=UTF-8")
+            .body("Hello world".into())
+            .unwrap(),
+    )
+    .await;
+}
@@ -54,7 +54,7 @@ async fn set_timeout_not_blocking_response() {
 
     assert_eq!(
         logs_receiver.recv_async().await.unwrap(),
-        ("log".into(), "before".into(), None)
+        ("log".into(), "before".into(), None, None)
     );
     utils::assert_response(
         &receiver,
@@ -66,7 +66,7 @@ async fn set_timeout_not_blocking_response() {
     .await;
     assert_eq!(
         logs_receiver.recv_async().await.unwrap(),
-        ("log".into(), "after".into(), None)
+        ("log".into(), "after".into(), None, None)
     );
 }
 
@@ -163,19 +163,19 @@ async fn set_interval() {
 
     assert_eq!(
         logs_receiver.recv_async().await.unwrap(),
-        ("log".into(), "interval 1".into(), None)
+        ("log".into(), "interval 1".into(), None, None)
     );
     assert_eq!(
         logs_receiver.recv_async().await.unwrap(),
-        ("log".into(), "interval 2".into(), None)
+        ("log".into(), "interval 2".into(), None, None)
     );
     assert_eq!(
         logs_receiver.recv_async().await.unwrap(),
-        ("log".into(), "interval 3".into(), None)
+        ("log".into(), "interval 3".into(), None, None)
     );
     assert_eq!(
         logs_receiver.recv_async().await.unwrap(),
-        ("log".into(), "res".into(), None)
+        ("log".into(), "res".into(), None, None)
     );
 
     utils::assert_response(
@@ -212,11 +212,11 @@ async fn queue_microtask() {
 
     assert_eq!(
         logs_receiver.recv_async().await.unwrap(),
-        ("log".into(), "before".into(), None)
+        ("log".into(), "before".into(), None, None)
     );
     assert_eq!(
         logs_receiver.recv_async().await.unwrap(),
-        ("log".into(), "microtask".into(), None)
+        ("log".into(), "microtask".into(), None, None)
     );
 
     utils::assert_response(
@@ -264,23 +264,23 @@ async fn timers_order() {
 
     assert_eq!(
         logs_receiver.recv_async().await.unwrap(),
-        ("log".into(), "main".into(), None)
+        ("log".into(), "main".into(), None, None)
     );
     assert_eq!(
         logs_receiver.recv_async().await.unwrap(),
-        ("log".into(), "microtask".into(), None)
+        ("log".into(), "microtask".into(), None, None)
     );
     assert_eq!(
         logs_receiver.recv_async().await.unwrap(),
-        ("log".into(), "promise".into(), None)
+        ("log".into(), "promise".into(), None, None)
     );
     assert_eq!(
         logs_receiver.recv_async().await.unwrap(),
-        ("log".into(), "timeout".into(), None)
+        ("log".into(), "timeout".into(), None, None)
     );
     assert_eq!(
         logs_receiver.recv_async().await.unwrap(),
-        ("log".into(), "main 2".into(), None)
+        ("log".into(), "main 2".into(), None, None)
     );
     utils::assert_response(
         &receiver,
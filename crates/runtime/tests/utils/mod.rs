@@ -52,7 +52,12 @@ pub fn create_isolate(options: IsolateOptions) -> (SendRequest, flume::Receiver<
             let request = (parts, body);
 
             request_tx
-                .send(IsolateEvent::Request(IsolateRequest { request, sender }))
+                .send(IsolateEvent::Request(IsolateRequest {
+                    request,
+                    body_stream: None,
+                    sender,
+                    request_id: None,
+                }))
                 .unwrap();
         });
     });
@@ -86,7 +91,12 @@ pub fn create_isolate_without_snapshot(
             let request = (parts, body);
 
             request_tx
-                .send(IsolateEvent::Request(IsolateRequest { request, sender }))
+                .send(IsolateEvent::Request(IsolateRequest {
+                    request,
+                    body_stream: None,
+                    sender,
+                    request_id: None,
+                }))
                 .unwrap();
         });
     });
@@ -105,6 +115,12 @@ pub async fn assert_run_result(receiver: &flume::Receiver<RunResult>, run_result
         RunResult::Error(error) => {
             assert_eq!(error, result.as_error());
         }
+        RunResult::CompileError(error) => {
+            assert_eq!(error, result.as_compile_error());
+        }
+        RunResult::EvaluationError(error) => {
+            assert_eq!(error, result.as_evaluation_error());
+        }
         RunResult::MemoryLimit => {
             assert!(
                 result.is_memory_limit(),
@@ -115,6 +131,14 @@ pub async fn assert_run_result(receiver: &flume::Receiver<RunResult>, run_result
         RunResult::Timeout => {
             assert!(result.is_timeout(), "Expected Timeout, got {:?}", result);
         }
+        // The message contains the frames of the recursion
+        RunResult::StackOverflow(_) => {
+            assert!(
+                result.is_stack_overflow(),
+                "Expected StackOverflow, got {:?}",
+                result
+            );
+        }
         RunResult::Stream(stream_result) => match stream_result {
             StreamResult::Done(_) => {
                 assert!(
@@ -1,5 +1,19 @@
 pub const X_FORWARDED_FOR: &str = "x-forwarded-for";
 pub const X_REAL_IP: &str = "x-real-ip";
+pub const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+pub const X_FORWARDED_HOST: &str = "x-forwarded-host";
 
 pub const X_LAGON_REGION: &str = "x-lagon-region";
 pub const X_LAGON_ID: &str = "x-lagon-id";
+pub const X_LAGON_DEPLOYMENT: &str = "x-lagon-deployment";
+pub const X_LAGON_ADMIN_SECRET: &str = "x-lagon-admin-secret";
+pub const X_LAGON_TIMEOUT: &str = "x-lagon-timeout";
+pub const X_LAGON_MEMORY: &str = "x-lagon-memory";
+pub const X_REQUEST_ID: &str = "x-request-id";
+pub const X_LAGON_WEBSOCKET_ID: &str = "x-lagon-websocket-id";
+pub const X_LAGON_WEBSOCKET_EVENT: &str = "x-lagon-websocket-event";
+
+pub const TRACEPARENT: &str = "traceparent";
+pub const SERVER_TIMING: &str = "server-timing";
+
+pub const LAGON_RUN_RESULT: &str = "lagon-run-result";
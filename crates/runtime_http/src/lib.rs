@@ -26,7 +26,17 @@ pub enum RunResult {
     Stream(StreamResult),
     Timeout,
     MemoryLimit,
+    // Thrown while running the Function, often specific to a request
     Error(String),
+    // Thrown while compiling the code or importing modules, before any
+    // request can run. The deployment itself is broken
+    CompileError(String),
+    // Thrown while evaluating the top-level code, e.g by a failed fetch()
+    // in a top-level await. Can be transient, unlike compile errors
+    EvaluationError(String),
+    // The call stack reached its limit while running the Function,
+    // e.g because of a runaway recursion
+    StackOverflow(String),
 }
 
 impl RunResult {
@@ -38,6 +48,10 @@ impl RunResult {
         matches!(self, RunResult::MemoryLimit)
     }
 
+    pub fn is_stack_overflow(&self) -> bool {
+        matches!(self, RunResult::StackOverflow(_))
+    }
+
     pub fn as_error(self) -> String {
         if let RunResult::Error(error) = self {
             return error;
@@ -46,6 +60,22 @@ impl RunResult {
         panic!("RunResult is not an Error: {:?}", self);
     }
 
+    pub fn as_compile_error(self) -> String {
+        if let RunResult::CompileError(error) = self {
+            return error;
+        }
+
+        panic!("RunResult is not a CompileError: {:?}", self);
+    }
+
+    pub fn as_evaluation_error(self) -> String {
+        if let RunResult::EvaluationError(error) = self {
+            return error;
+        }
+
+        panic!("RunResult is not an EvaluationError: {:?}", self);
+    }
+
     pub fn as_response(self) -> Response<Body> {
         if let RunResult::Response(response, _) = self {
             return response;
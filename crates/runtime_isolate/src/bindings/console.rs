@@ -1,6 +1,6 @@
 use log::error;
 
-use crate::Isolate;
+use crate::{options::get_log_level, Isolate};
 
 pub fn console_binding(
     scope: &mut v8::HandleScope,
@@ -9,11 +9,29 @@ pub fn console_binding(
 ) {
     let level = args.get(0).to_rust_string_lossy(scope);
     let message = args.get(1).to_rust_string_lossy(scope);
+    let id = scope
+        .get_continuation_preserved_embedder_data()
+        .to_uint32(scope)
+        .map_or(0, |value| value.value());
+
     let state = Isolate::state(scope);
     let state = state.borrow();
 
+    if get_log_level(&level) > state.log_level {
+        return;
+    }
+
+    // Logs made outside of a request (e.g when initializing the isolate)
+    // don't have a request id
+    let request_id = state
+        .handler_results
+        .get(&id)
+        .and_then(|handler_result| handler_result.context.request_id.clone());
+
     if let Some(log_sender) = state.log_sender.as_ref() {
-        if let Err(error) = log_sender.send((level, message, state.metadata.as_ref().clone())) {
+        let metadata = state.metadata.as_ref().clone();
+
+        if let Err(error) = log_sender.send((level, message, metadata, request_id)) {
             error!("Failed to send log message: {}", error)
         }
     }
@@ -1,18 +1,223 @@
 use anyhow::{anyhow, Result};
 use async_recursion::async_recursion;
-use hyper::{client::HttpConnector, header::LOCATION, http::Uri, Body, Client, Request, Response};
+use futures::Future;
+use hyper::{
+    client::{
+        connect::dns::{GaiResolver, Name},
+        HttpConnector,
+    },
+    header::LOCATION,
+    http::Uri,
+    service::Service,
+    Body, Client, Request, Response,
+};
 use hyper_tls::HttpsConnector;
-use lagon_runtime_http::request_from_v8;
+use lagon_runtime_http::{request_from_v8, TRACEPARENT};
 use once_cell::sync::Lazy;
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    rc::Rc,
+    task::{Context, Poll},
+    vec,
+};
 
-use crate::{bindings::PromiseResult, Isolate};
+use crate::{
+    bindings::PromiseResult,
+    options::{FetchPolicy, Metadata, OnIsolateFetchBlockedCallback},
+    Isolate,
+};
 
 use super::BindingResult;
 
 static CLIENT: Lazy<Client<HttpsConnector<HttpConnector>>> =
     Lazy::new(|| Client::builder().build::<_, Body>(HttpsConnector::new()));
 
-type Arg = Request<Body>;
+// Used when private IPs are blocked. The addresses are filtered after
+// being resolved, so a hostname can't resolve to a private IP between
+// the check and the connection
+static PUBLIC_CLIENT: Lazy<Client<HttpsConnector<HttpConnector<PublicResolver>>>> =
+    Lazy::new(|| {
+        let mut http = HttpConnector::new_with_resolver(PublicResolver(GaiResolver::new()));
+        http.enforce_http(false);
+
+        Client::builder().build::<_, Body>(HttpsConnector::new_with_connector(http))
+    });
+
+type Arg = (Request<Body>, Rc<FetchGuard>);
+
+fn is_private_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                // Shared address space (100.64.0.0/10)
+                || (octets[0] == 100 && (octets[1] & 0xc0) == 64)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_private_ip(IpAddr::V4(ip)),
+            None => {
+                let segment = ip.segments()[0];
+
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    // Unique local (fc00::/7) and link-local (fe80::/10)
+                    || (segment & 0xfe00) == 0xfc00
+                    || (segment & 0xffc0) == 0xfe80
+            }
+        },
+    }
+}
+
+#[derive(Debug)]
+struct BlockedAddress;
+
+impl fmt::Display for BlockedAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Resolved to a blocked address")
+    }
+}
+
+impl std::error::Error for BlockedAddress {}
+
+#[derive(Clone)]
+struct PublicResolver(GaiResolver);
+
+type ResolverError = Box<dyn std::error::Error + Send + Sync>;
+
+impl Service<Name> for PublicResolver {
+    type Response = vec::IntoIter<SocketAddr>;
+    type Error = ResolverError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let resolving = self.0.call(name);
+
+        Box::pin(async move {
+            let addrs = resolving
+                .await?
+                .filter(|addr| !is_private_ip(addr.ip()))
+                .collect::<Vec<SocketAddr>>();
+
+            if addrs.is_empty() {
+                return Err(BlockedAddress.into());
+            }
+
+            Ok(addrs.into_iter())
+        })
+    }
+}
+
+fn is_blocked_address(error: &hyper::Error) -> bool {
+    let mut source = std::error::Error::source(error);
+
+    while let Some(error) = source {
+        if error.is::<BlockedAddress>() {
+            return true;
+        }
+
+        source = error.source();
+    }
+
+    false
+}
+
+// Enforce the fetch policy of an isolate
+pub struct FetchGuard {
+    policy: FetchPolicy,
+    metadata: Rc<Metadata>,
+    on_blocked: Option<OnIsolateFetchBlockedCallback>,
+}
+
+impl FetchGuard {
+    pub fn new(
+        policy: FetchPolicy,
+        metadata: Rc<Metadata>,
+        on_blocked: Option<OnIsolateFetchBlockedCallback>,
+    ) -> Self {
+        Self {
+            policy,
+            metadata,
+            on_blocked,
+        }
+    }
+
+    fn is_host_allowed(&self, host: &str) -> bool {
+        let allowed_hosts = match &self.policy.allowed_hosts {
+            Some(allowed_hosts) => allowed_hosts,
+            None => return true,
+        };
+
+        let host = host.to_lowercase();
+
+        allowed_hosts.iter().any(|allowed_host| {
+            let allowed_host = allowed_host.to_lowercase();
+
+            match allowed_host.strip_prefix("*.") {
+                Some(domain) => host
+                    .strip_suffix(domain)
+                    .map_or(false, |subdomain| subdomain.ends_with('.')),
+                None => host == allowed_host,
+            }
+        })
+    }
+
+    fn blocked(&self, host: &str) -> anyhow::Error {
+        if let Some(on_blocked) = &self.on_blocked {
+            on_blocked(Rc::clone(&self.metadata), host.to_string());
+        }
+
+        anyhow!("fetch() to {} is not allowed", host)
+    }
+
+    // Called before each request, including redirects
+    fn check(&self, uri: &Uri) -> Result<()> {
+        // Let the client fail with relative URLs
+        let host = match uri.host() {
+            Some(host) => host.trim_start_matches('[').trim_end_matches(']'),
+            None => return Ok(()),
+        };
+
+        if !self.is_host_allowed(host) {
+            return Err(self.blocked(host));
+        }
+
+        // Hostnames are checked once resolved, see PUBLIC_CLIENT
+        if self.policy.block_private_ips {
+            if let Ok(ip) = host.parse::<IpAddr>() {
+                if is_private_ip(ip) {
+                    return Err(self.blocked(host));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn request(&self, request: Request<Body>) -> Result<Response<Body>> {
+        if !self.policy.block_private_ips {
+            return Ok(CLIENT.request(request).await?);
+        }
+
+        let host = request.uri().host().unwrap_or_default().to_string();
+
+        match PUBLIC_CLIENT.request(request).await {
+            Ok(response) => Ok(response),
+            Err(error) if is_blocked_address(&error) => Err(self.blocked(&host)),
+            Err(error) => Err(error.into()),
+        }
+    }
+}
 
 pub fn fetch_init(scope: &mut v8::HandleScope, args: v8::FunctionCallbackArguments) -> Result<Arg> {
     let id = scope
@@ -21,14 +226,19 @@ pub fn fetch_init(scope: &mut v8::HandleScope, args: v8::FunctionCallbackArgumen
         .map_or(0, |value| value.value());
 
     let state = Isolate::state(scope);
-    let fetch_calls = {
+    let (fetch_calls, traceparent, fetch_guard) = {
         let mut state = state.borrow_mut();
+        let fetch_guard = Rc::clone(&state.fetch_guard);
 
         if let Some(mut handler_result) = state.handler_results.get_mut(&id) {
             handler_result.context.fetch_calls += 1;
-            handler_result.context.fetch_calls
+            (
+                handler_result.context.fetch_calls,
+                handler_result.context.traceparent.clone(),
+                fetch_guard,
+            )
         } else {
-            0
+            (0, None, fetch_guard)
         }
     };
 
@@ -41,7 +251,19 @@ pub fn fetch_init(scope: &mut v8::HandleScope, args: v8::FunctionCallbackArgumen
         None => return Err(anyhow!("Invalid request")),
     };
 
-    request_from_v8(scope, request.into())
+    let mut request = request_from_v8(scope, request.into())?;
+
+    // Propagate the trace of the current request, unless
+    // the Function already set its own trace context
+    if let Some(traceparent) = traceparent {
+        if !request.headers().contains_key(TRACEPARENT) {
+            request
+                .headers_mut()
+                .insert(TRACEPARENT, traceparent.parse()?);
+        }
+    }
+
+    Ok((request, fetch_guard))
 }
 
 async fn clone_response(request: Request<Body>) -> Result<(Request<Body>, Request<Body>)> {
@@ -67,11 +289,12 @@ async fn clone_response(request: Request<Body>) -> Result<(Request<Body>, Reques
     Ok((request_a, request_b))
 }
 
-#[async_recursion]
+#[async_recursion(?Send)]
 async fn make_request(
     mut request: Request<Body>,
     url: Option<String>,
     mut count: u8,
+    fetch_guard: &FetchGuard,
 ) -> Result<Response<Body>> {
     if count >= 5 {
         return Err(anyhow!("Too many redirects"));
@@ -82,9 +305,10 @@ async fn make_request(
     }
 
     let uri = request.uri().clone();
+    fetch_guard.check(&uri)?;
 
     let (request_a, request_b) = clone_response(request).await?;
-    let response = CLIENT.request(request_a).await?;
+    let response = fetch_guard.request(request_a).await?;
 
     if response.status().is_redirection() {
         let mut redirect_url = match response.headers().get(LOCATION) {
@@ -101,14 +325,14 @@ async fn make_request(
         }
 
         count += 1;
-        return make_request(request_b, Some(redirect_url), count).await;
+        return make_request(request_b, Some(redirect_url), count, fetch_guard).await;
     }
 
     Ok(response)
 }
 
-pub async fn fetch_binding(id: usize, arg: Arg) -> BindingResult {
-    let hyper_response = match make_request(arg, None, 0).await {
+pub async fn fetch_binding(id: usize, (request, fetch_guard): Arg) -> BindingResult {
+    let hyper_response = match make_request(request, None, 0, &fetch_guard).await {
         Ok(hyper_response) => hyper_response,
         Err(error) => {
             return BindingResult {
@@ -0,0 +1,98 @@
+use anyhow::{anyhow, Result};
+use lagon_runtime_v8_utils::{extract_v8_integer, extract_v8_string};
+use std::rc::Rc;
+
+use crate::{
+    bindings::PromiseResult,
+    options::{KvRequest, KvResponse, Metadata, OnIsolateKvCallback},
+    Isolate,
+};
+
+use super::BindingResult;
+
+const MAX_KEY_SIZE: usize = 512;
+
+type Arg = (KvRequest, Rc<KvStore>);
+
+// The KV storage of an isolate, that forwards each
+// operation to the backend set by the embedder
+pub struct KvStore {
+    on_kv: Option<OnIsolateKvCallback>,
+    metadata: Rc<Metadata>,
+    max_value_size: usize,
+}
+
+impl KvStore {
+    pub fn new(
+        on_kv: Option<OnIsolateKvCallback>,
+        metadata: Rc<Metadata>,
+        max_value_size: usize,
+    ) -> Self {
+        Self {
+            on_kv,
+            metadata,
+            max_value_size,
+        }
+    }
+}
+
+pub fn kv_init(scope: &mut v8::HandleScope, args: v8::FunctionCallbackArguments) -> Result<Arg> {
+    let kv_store = Rc::clone(&Isolate::state(scope).borrow().kv_store);
+
+    if kv_store.on_kv.is_none() {
+        return Err(anyhow!("KV storage is not available"));
+    }
+
+    let operation = extract_v8_string(args.get(0), scope)?;
+    let key = extract_v8_string(args.get(1), scope)?;
+
+    if key.is_empty() || key.len() > MAX_KEY_SIZE {
+        return Err(anyhow!(
+            "KV keys must be between 1 and {} bytes",
+            MAX_KEY_SIZE
+        ));
+    }
+
+    let request = match operation.as_str() {
+        "get" => KvRequest::Get(key),
+        "delete" => KvRequest::Delete(key),
+        "set" => {
+            let value = extract_v8_string(args.get(2), scope)?;
+
+            if value.len() > kv_store.max_value_size {
+                return Err(anyhow!(
+                    "KV values can't be larger than {} bytes",
+                    kv_store.max_value_size
+                ));
+            }
+
+            let ttl = args.get(3);
+            let ttl = match ttl.is_null_or_undefined() {
+                true => None,
+                false => match extract_v8_integer(ttl, scope)? {
+                    ttl if ttl > 0 => Some(ttl as u64),
+                    _ => return Err(anyhow!("KV TTL must be a positive number of seconds")),
+                },
+            };
+
+            KvRequest::Set { key, value, ttl }
+        }
+        _ => return Err(anyhow!("Unknown KV operation: {}", operation)),
+    };
+
+    Ok((request, kv_store))
+}
+
+pub async fn kv_binding(id: usize, (request, kv_store): Arg) -> BindingResult {
+    // Checked in kv_init()
+    let on_kv = kv_store.on_kv.as_ref().unwrap();
+
+    let result = match on_kv(Rc::clone(&kv_store.metadata), request).await {
+        Ok(KvResponse::Value(Some(value))) => PromiseResult::String(value),
+        Ok(KvResponse::Value(None)) | Ok(KvResponse::Set) => PromiseResult::Undefined,
+        Ok(KvResponse::Deleted(deleted)) => PromiseResult::Boolean(deleted),
+        Err(error) => PromiseResult::Error(error.to_string()),
+    };
+
+    BindingResult { id, result }
+}
@@ -5,11 +5,13 @@ use crypto::{
     verify_binding, verify_init,
 };
 use fetch::{fetch_binding, fetch_init};
+use kv::{kv_binding, kv_init};
 use hyper::{body::Bytes, http::response::Parts};
 use lagon_runtime_http::response_to_v8;
 use lagon_runtime_v8_utils::{v8_boolean, v8_string, v8_uint8array};
 use pull_stream::pull_stream_binding;
 use queue_microtask::queue_microtask_binding;
+use read_body::{read_body_binding, read_body_init};
 use sleep::{sleep_binding, sleep_init};
 
 use crate::{
@@ -22,8 +24,10 @@ use crate::{
 pub mod console;
 pub mod crypto;
 pub mod fetch;
+pub mod kv;
 pub mod pull_stream;
 pub mod queue_microtask;
+pub mod read_body;
 pub mod sleep;
 
 pub struct BindingResult {
@@ -35,6 +39,7 @@ pub enum PromiseResult {
     Response((Parts, Bytes)),
     ArrayBuffer(Vec<u8>),
     Boolean(bool),
+    String(String),
     Error(String),
     Undefined,
 }
@@ -45,6 +50,7 @@ impl PromiseResult {
             PromiseResult::Response(response) => response_to_v8(response, scope).into(),
             PromiseResult::ArrayBuffer(bytes) => v8_uint8array(scope, bytes).into(),
             PromiseResult::Boolean(boolean) => v8_boolean(scope, boolean).into(),
+            PromiseResult::String(string) => v8_string(scope, &string).into(),
             PromiseResult::Error(error) => v8_string(scope, &error).into(),
             PromiseResult::Undefined => v8::undefined(scope).into(),
         }
@@ -159,6 +165,14 @@ pub fn bind<'a>(
             decrypt_binding
         );
         async_binding!(scope, lagon_object, "sleep", sleep_init, sleep_binding);
+        async_binding!(scope, lagon_object, "kv", kv_init, kv_binding);
+        async_binding!(
+            scope,
+            lagon_object,
+            "readBody",
+            read_body_init,
+            read_body_binding
+        );
         async_binding!(
             scope,
             lagon_object,
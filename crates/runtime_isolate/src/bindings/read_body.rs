@@ -0,0 +1,39 @@
+use anyhow::{anyhow, Result};
+
+use crate::{bindings::PromiseResult, Isolate, RequestBodyStream};
+
+use super::BindingResult;
+
+type Arg = Option<RequestBodyStream>;
+
+pub fn read_body_init(
+    scope: &mut v8::HandleScope,
+    args: v8::FunctionCallbackArguments,
+) -> Result<Arg> {
+    let id = match args.get(0).to_uint32(scope) {
+        Some(id) => id.value(),
+        None => return Err(anyhow!("Invalid request id")),
+    };
+
+    let state = Isolate::state(scope);
+    let state = state.borrow();
+
+    Ok(state
+        .handler_results
+        .get(&id)
+        .and_then(|handler_result| handler_result.body_stream.clone()))
+}
+
+// Resolves with the next chunk, or undefined once the whole body was read
+pub async fn read_body_binding(id: usize, arg: Arg) -> BindingResult {
+    let result = match arg {
+        Some(body_stream) => match body_stream.recv_async().await {
+            Ok(Ok(chunk)) => PromiseResult::ArrayBuffer(chunk.to_vec()),
+            Ok(Err(error)) => PromiseResult::Error(error),
+            Err(_) => PromiseResult::Undefined,
+        },
+        None => PromiseResult::Undefined,
+    };
+
+    BindingResult { id, result }
+}
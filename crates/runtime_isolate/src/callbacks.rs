@@ -1,4 +1,5 @@
 use lagon_runtime_v8_utils::v8_string;
+use std::cell::Cell;
 
 use crate::get_exception_message;
 
@@ -16,6 +17,24 @@ where
     callback(current_heap_limit)
 }
 
+// Garbage collections since the statistics were last sent, stored
+// in a slot of the isolate when heap statistics are enabled
+#[derive(Default)]
+pub struct GcCount(pub Cell<usize>);
+
+pub extern "C" fn gc_prologue_callback(
+    isolate: *mut v8::Isolate,
+    _gc_type: v8::GCType,
+    _flags: v8::GCCallbackFlags,
+    _data: *mut std::ffi::c_void,
+) {
+    let isolate = unsafe { &mut *isolate };
+
+    if let Some(gc_count) = isolate.get_slot::<GcCount>() {
+        gc_count.0.set(gc_count.0.get() + 1);
+    }
+}
+
 pub extern "C" fn promise_reject_callback(message: v8::PromiseRejectMessage) {
     let scope = &mut unsafe { v8::CallbackScope::new(&message) };
     let promise = message.get_promise();
@@ -3,8 +3,8 @@ use hyper::{
     body::Bytes,
     http::{request::Parts, response::Builder},
 };
-use lagon_runtime_http::{request_to_v8, response_from_v8, RunResult, StreamResult};
-use lagon_runtime_v8_utils::v8_string;
+use lagon_runtime_http::{request_to_v8, response_from_v8, RunResult, StreamResult, TRACEPARENT};
+use lagon_runtime_v8_utils::{v8_boolean, v8_string};
 use linked_hash_map::LinkedHashMap;
 use std::{
     cell::{RefCell, RefMut},
@@ -13,14 +13,17 @@ use std::{
     rc::Rc,
     sync::{Arc, RwLock},
     task::{Context, Poll},
-    time::Instant,
+    time::{Duration, Instant},
 };
 use v8::MapFnTo;
 
 use self::{
-    bindings::{BindingResult, PromiseResult},
-    callbacks::{heap_limit_callback, promise_reject_callback, resolve_module_callback},
-    options::{IsolateOptions, Metadata},
+    bindings::{fetch::FetchGuard, kv::KvStore, BindingResult, PromiseResult},
+    callbacks::{
+        gc_prologue_callback, heap_limit_callback, promise_reject_callback,
+        resolve_module_callback, GcCount,
+    },
+    options::{HeapStatistics, IsolateOptions, IsolateStatistics, LogMessage, Metadata},
 };
 
 mod bindings;
@@ -30,15 +33,26 @@ pub mod options;
 const RUNTIME_ONLY_SCRIPT_NAME: &str = "runtime.js";
 const CODE_ONLY_SCRIPT_NAME: &str = "code.js";
 const ISOLATE_SCRIPT_NAME: &str = "isolate.js";
+const STACK_OVERFLOW_ERROR: &str = "Uncaught RangeError: Maximum call stack size exceeded";
 
 #[derive(Debug, Default)]
 pub struct RequestContext {
     fetch_calls: usize,
+    request_id: Option<String>,
+    traceparent: Option<String>,
 }
 
+// Chunks of a request body, sent while it's received instead of
+// buffering it. Errors when the body couldn't be read entirely
+pub type RequestBodyStream = flume::Receiver<Result<Bytes, String>>;
+
 pub struct IsolateRequest {
     pub request: (Parts, Bytes),
+    // The body of the request is read from this stream when
+    // set, and the body of `request` is ignored
+    pub body_stream: Option<RequestBodyStream>,
     pub sender: flume::Sender<RunResult>,
+    pub request_id: Option<String>,
 }
 
 pub enum IsolateEvent {
@@ -53,6 +67,7 @@ pub struct HandlerResult {
     start_time: Instant,
     stream_response_sent: RefCell<bool>,
     stream_status: RefCell<StreamStatus>,
+    body_stream: Option<RequestBodyStream>,
     context: RequestContext,
 }
 
@@ -69,7 +84,10 @@ pub struct IsolateState {
     rejected_promises: LinkedHashMap<v8::Global<v8::Promise>, String>,
     lines: usize,
     requests_count: u32,
-    log_sender: Option<flume::Sender<(String, String, Metadata)>>,
+    log_sender: Option<flume::Sender<LogMessage>>,
+    log_level: log::LevelFilter,
+    fetch_guard: Rc<FetchGuard>,
+    kv_store: Rc<KvStore>,
 }
 
 #[derive(Debug)]
@@ -102,12 +120,116 @@ impl Heartbeat {
     }
 }
 
+// Create a V8 isolate and its context with the runtime bindings,
+// but without the code of the Function
+fn create_v8_isolate(
+    memory: usize,
+    snapshot: bool,
+    snapshot_blob: Option<&'static [u8]>,
+) -> (v8::OwnedIsolate, v8::Global<v8::Context>) {
+    let memory_mb = memory * 1024 * 1024;
+    let mut params = v8::CreateParams::default().heap_limits(0, memory_mb);
+
+    let references = vec![
+        v8::ExternalReference {
+            function: bindings::console::console_binding.map_fn_to(),
+        },
+        v8::ExternalReference {
+            function: bindings::pull_stream::pull_stream_binding.map_fn_to(),
+        },
+        v8::ExternalReference {
+            function: bindings::crypto::uuid_binding.map_fn_to(),
+        },
+        v8::ExternalReference {
+            function: bindings::crypto::random_values_binding.map_fn_to(),
+        },
+        v8::ExternalReference {
+            function: bindings::crypto::get_key_value_binding.map_fn_to(),
+        },
+        v8::ExternalReference {
+            function: bindings::queue_microtask::queue_microtask_binding.map_fn_to(),
+        },
+    ];
+
+    let refs = v8::ExternalReferences::new(&references);
+    std::mem::forget(references);
+    let refs: &'static v8::ExternalReferences = Box::leak(Box::new(refs));
+
+    let mut isolate = match snapshot {
+        true => v8::Isolate::snapshot_creator(Some(refs)),
+        false => {
+            if let Some(snapshot_blob) = snapshot_blob {
+                params = params
+                    .external_references(&**refs)
+                    .snapshot_blob(snapshot_blob);
+            }
+
+            v8::Isolate::new(params)
+        }
+    };
+
+    isolate.set_capture_stack_trace_for_uncaught_exceptions(true, 4);
+    isolate.set_promise_reject_callback(promise_reject_callback);
+
+    let global = {
+        let isolate_scope = &mut v8::HandleScope::new(&mut isolate);
+
+        if snapshot {
+            let context = bindings::bind(isolate_scope, bindings::BindStrategy::Sync);
+            let global = v8::Global::new(isolate_scope, context);
+            isolate_scope.set_default_context(context);
+            global
+        } else if snapshot_blob.is_some() {
+            let context = bindings::bind(isolate_scope, bindings::BindStrategy::Async);
+            v8::Global::new(isolate_scope, context)
+        } else {
+            let context = bindings::bind(isolate_scope, bindings::BindStrategy::All);
+            v8::Global::new(isolate_scope, context)
+        }
+    };
+
+    (isolate, global)
+}
+
+// A V8 isolate and its context created in advance, before knowing which
+// Function will run in it, to make the creation of an `Isolate` faster.
+// It must be used on the thread that created it
+pub struct WarmIsolate {
+    isolate: v8::OwnedIsolate,
+    global: v8::Global<v8::Context>,
+    memory: usize,
+    snapshot_blob: bool,
+}
+
+impl WarmIsolate {
+    pub fn new(memory: usize, snapshot_blob: Option<&'static [u8]>) -> Self {
+        let (isolate, global) = create_v8_isolate(memory, false, snapshot_blob);
+
+        Self {
+            isolate,
+            global,
+            memory,
+            snapshot_blob: snapshot_blob.is_some(),
+        }
+    }
+
+    // The memory limit can't be changed once the isolate is created
+    pub fn is_compatible(&self, options: &IsolateOptions) -> bool {
+        !options.snapshot
+            && options.memory == self.memory
+            && options.snapshot_blob.is_some() == self.snapshot_blob
+    }
+}
+
 pub struct Isolate {
     options: IsolateOptions,
     isolate: Option<v8::OwnedIsolate>,
     master_handler: Option<v8::Global<v8::Function>>,
     handler: Option<v8::Global<v8::Value>>,
     compilation_error: Option<String>,
+    // Whether the compilation error was thrown while evaluating the code,
+    // instead of while compiling it or importing modules
+    evaluation_error: bool,
     stream_receiver: flume::Receiver<(u32, StreamResult)>,
     termination_result: Arc<RwLock<Option<RunResult>>>,
     heartbeat: Arc<RwLock<Heartbeat>>,
@@ -125,79 +247,63 @@ unsafe impl Sync for Isolate {}
 // That's why we use .unwrap_or(()) to silently discard any error.
 impl Isolate {
     pub fn new(options: IsolateOptions, rx: flume::Receiver<IsolateEvent>) -> Self {
-        let memory_mb = options.memory * 1024 * 1024;
-        let mut params = v8::CreateParams::default().heap_limits(0, memory_mb);
+        let (isolate, global) =
+            create_v8_isolate(options.memory, options.snapshot, options.snapshot_blob);
 
-        let references = vec![
-            v8::ExternalReference {
-                function: bindings::console::console_binding.map_fn_to(),
-            },
-            v8::ExternalReference {
-                function: bindings::pull_stream::pull_stream_binding.map_fn_to(),
-            },
-            v8::ExternalReference {
-                function: bindings::crypto::uuid_binding.map_fn_to(),
-            },
-            v8::ExternalReference {
-                function: bindings::crypto::random_values_binding.map_fn_to(),
-            },
-            v8::ExternalReference {
-                function: bindings::crypto::get_key_value_binding.map_fn_to(),
-            },
-            v8::ExternalReference {
-                function: bindings::queue_microtask::queue_microtask_binding.map_fn_to(),
-            },
-        ];
-
-        let refs = v8::ExternalReferences::new(&references);
-        std::mem::forget(references);
-        let refs: &'static v8::ExternalReferences = Box::leak(Box::new(refs));
-
-        let mut isolate = match options.snapshot {
-            true => v8::Isolate::snapshot_creator(Some(refs)),
-            false => {
-                if let Some(snapshot_blob) = options.snapshot_blob {
-                    params = params
-                        .external_references(&**refs)
-                        .snapshot_blob(snapshot_blob);
-                }
+        Self::with_v8_isolate(isolate, global, options, rx)
+    }
 
-                v8::Isolate::new(params)
-            }
-        };
+    // Skip the creation of the V8 isolate and its context, which must
+    // be compatible with the options
+    pub fn from_warm(
+        warm: WarmIsolate,
+        options: IsolateOptions,
+        rx: flume::Receiver<IsolateEvent>,
+    ) -> Self {
+        Self::with_v8_isolate(warm.isolate, warm.global, options, rx)
+    }
 
-        isolate.set_capture_stack_trace_for_uncaught_exceptions(true, 4);
-        isolate.set_promise_reject_callback(promise_reject_callback);
+    fn with_v8_isolate(
+        mut isolate: v8::OwnedIsolate,
+        global: v8::Global<v8::Context>,
+        mut options: IsolateOptions,
+        rx: flume::Receiver<IsolateEvent>,
+    ) -> Self {
+        if options.heap_statistics {
+            isolate.set_slot(GcCount::default());
+            isolate.add_gc_prologue_callback(
+                gc_prologue_callback,
+                std::ptr::null_mut(),
+                v8::GCType::SCAVENGE
+                    | v8::GCType::MINOR_MARK_COMPACT
+                    | v8::GCType::MARK_SWEEP_COMPACT,
+            );
+        }
 
         let (stream_sender, stream_receiver) = flume::unbounded();
 
-        let state: IsolateState = {
-            let isolate_scope = &mut v8::HandleScope::new(&mut isolate);
-            let global = if options.snapshot {
-                let context = bindings::bind(isolate_scope, bindings::BindStrategy::Sync);
-                let global = v8::Global::new(isolate_scope, context);
-                isolate_scope.set_default_context(context);
-                global
-            } else if options.snapshot_blob.is_some() {
-                let context = bindings::bind(isolate_scope, bindings::BindStrategy::Async);
-                v8::Global::new(isolate_scope, context)
-            } else {
-                let context = bindings::bind(isolate_scope, bindings::BindStrategy::All);
-                v8::Global::new(isolate_scope, context)
-            };
-
-            IsolateState {
-                global: Some(Global(global)),
-                promises: FuturesUnordered::new(),
-                js_promises: HashMap::new(),
-                handler_results: HashMap::new(),
-                stream_sender,
-                metadata: Rc::clone(&options.metadata),
-                rejected_promises: LinkedHashMap::new(),
-                lines: 0,
-                requests_count: 0,
-                log_sender: options.log_sender.clone(),
-            }
+        let state = IsolateState {
+            global: Some(Global(global)),
+            promises: FuturesUnordered::new(),
+            js_promises: HashMap::new(),
+            handler_results: HashMap::new(),
+            stream_sender,
+            metadata: Rc::clone(&options.metadata),
+            rejected_promises: LinkedHashMap::new(),
+            lines: 0,
+            requests_count: 0,
+            log_sender: options.log_sender.clone(),
+            log_level: options.log_level,
+            fetch_guard: Rc::new(FetchGuard::new(
+                options.fetch_policy.clone(),
+                Rc::clone(&options.metadata),
+                options.on_fetch_blocked.take(),
+            )),
+            kv_store: Rc::new(KvStore::new(
+                options.on_kv.take(),
+                Rc::clone(&options.metadata),
+                options.kv_max_value_size,
+            )),
         };
 
         isolate.set_slot(Rc::new(RefCell::new(state)));
@@ -208,6 +314,7 @@ impl Isolate {
             master_handler: None,
             handler: None,
             compilation_error: None,
+            evaluation_error: false,
             stream_receiver,
             termination_result: Arc::new(RwLock::new(None)),
             heartbeat: Arc::new(RwLock::new(Heartbeat::None)),
@@ -255,6 +362,11 @@ impl Isolate {
         Rc::clone(&self.options.metadata)
     }
 
+    // Error thrown while compiling or evaluating the code, set by `evaluate`
+    pub fn get_compilation_error(&self) -> Option<&str> {
+        self.compilation_error.as_deref()
+    }
+
     fn terminate(&mut self, run_result: RunResult) {
         self.termination_result.write().unwrap().replace(run_result);
 
@@ -295,22 +407,37 @@ impl Isolate {
         let source_map_url = v8_string(try_catch, "");
         isolate_state.borrow_mut().lines = lines;
 
-        let source = v8::script_compiler::Source::new(
-            code,
-            Some(&v8::ScriptOrigin::new(
-                try_catch,
-                resource_name.into(),
-                0,
-                0,
-                false,
-                i32::from(self.options.snapshot_blob.is_some()),
-                source_map_url.into(),
-                false,
-                false,
-                true,
-            )),
+        let origin = v8::ScriptOrigin::new(
+            try_catch,
+            resource_name.into(),
+            0,
+            0,
+            false,
+            i32::from(self.options.snapshot_blob.is_some()),
+            source_map_url.into(),
+            false,
+            false,
+            true,
         );
 
+        // V8 checks that the code cache matches the source and silently
+        // falls back to a full compilation if it doesn't
+        let code_cache = self.options.code_cache.take();
+        let (source, compile_options) = match &code_cache {
+            Some(code_cache) => (
+                v8::script_compiler::Source::new_with_cached_data(
+                    code,
+                    Some(&origin),
+                    v8::CachedData::new(code_cache),
+                ),
+                v8::script_compiler::CompileOptions::ConsumeCodeCache,
+            ),
+            None => (
+                v8::script_compiler::Source::new(code, Some(&origin)),
+                v8::script_compiler::CompileOptions::NoCompileOptions,
+            ),
+        };
+
         let thread_safe_handle = try_catch.thread_safe_handle();
         let termination_result = Arc::clone(&self.termination_result);
         let tick_timeout = self.options.tick_timeout;
@@ -357,7 +484,12 @@ impl Isolate {
             }
         });
 
-        match v8::script_compiler::compile_module(try_catch, source) {
+        match v8::script_compiler::compile_module2(
+            try_catch,
+            source,
+            compile_options,
+            v8::script_compiler::NoCacheReason::NoReason,
+        ) {
             Some(module) => {
                 if module
                     .instantiate_module(try_catch, resolve_module_callback)
@@ -369,9 +501,23 @@ impl Isolate {
 
                 if module.evaluate(try_catch).is_none() {
                     self.compilation_error = Some(handle_error(try_catch, lines).as_error());
+                    self.evaluation_error = true;
                     return;
                 }
 
+                // Create the code cache after the evaluation, so it also
+                // includes the functions that have been lazily compiled
+                if code_cache.is_none() {
+                    if let Some(on_code_cache) = &self.options.on_code_cache {
+                        if let Some(code_cache) = module
+                            .get_unbound_module_script(try_catch)
+                            .create_code_cache()
+                        {
+                            on_code_cache(code_cache.to_vec());
+                        }
+                    }
+                }
+
                 if !self.options.snapshot {
                     let namespace = module.get_module_namespace().to_object(try_catch).unwrap();
                     let handler_key = v8_string(try_catch, "handler");
@@ -398,7 +544,18 @@ impl Isolate {
 
     pub fn handle_event(&mut self, event: IsolateEvent, state: &Rc<RefCell<IsolateState>>) {
         match event {
-            IsolateEvent::Request(IsolateRequest { request, sender }) => {
+            IsolateEvent::Request(IsolateRequest {
+                request,
+                body_stream,
+                sender,
+                request_id,
+            }) => {
+                // The request was aborted while waiting to be picked up,
+                // e.g because the client disconnected
+                if sender.is_disconnected() {
+                    return;
+                }
+
                 let (global, requests_count) = {
                     let mut isolate_state = state.borrow_mut();
                     let global = isolate_state.global.as_ref().unwrap().0.clone();
@@ -422,7 +579,22 @@ impl Isolate {
                 let global = global.open(try_catch);
                 let global = global.global(try_catch);
 
+                let traceparent = request
+                    .0
+                    .headers
+                    .get(TRACEPARENT)
+                    .and_then(|value| value.to_str().ok())
+                    .map(String::from);
+
                 let request = request_to_v8(request, try_catch);
+
+                // The body is then read using LagonAsync.readBody()
+                if body_stream.is_some() {
+                    let key = v8_string(try_catch, "s");
+                    let value = v8_boolean(try_catch, true);
+                    request.set(try_catch, key.into(), value.into());
+                }
+
                 let id = v8::Integer::new(try_catch, requests_count as i32);
                 try_catch.set_continuation_preserved_embedder_data(id.into());
 
@@ -434,7 +606,12 @@ impl Isolate {
                         start_time: Instant::now(),
                         stream_response_sent: RefCell::new(false),
                         stream_status: RefCell::new(StreamStatus::None),
-                        context: RequestContext::default(),
+                        body_stream,
+                        context: RequestContext {
+                            request_id,
+                            traceparent,
+                            ..Default::default()
+                        },
                     },
                 );
 
@@ -460,7 +637,9 @@ impl Isolate {
                         self.termination_result
                             .write()
                             .unwrap()
-                            .get_or_insert_with(|| handle_error(try_catch, 0));
+                            .get_or_insert_with(|| {
+                                into_request_error(handle_error(try_catch, 0).as_error())
+                            });
                     }
                 };
             }
@@ -555,7 +734,10 @@ impl Isolate {
             if let Ok(IsolateEvent::Request(IsolateRequest { sender, .. })) = self.rx.try_recv() {
                 let termination_result = match self.termination_result.write().unwrap().take() {
                     Some(termination_result) => termination_result,
-                    None => RunResult::Error(compilation_error.to_string()),
+                    None if self.evaluation_error => {
+                        RunResult::EvaluationError(compilation_error.to_string())
+                    }
+                    None => RunResult::CompileError(compilation_error.to_string()),
                 };
 
                 sender.send(termination_result).unwrap_or(());
@@ -598,6 +780,13 @@ impl Isolate {
 
         if let Some(termination_result) = self.termination_result.write().unwrap().take() {
             if let Some(handler_result) = state.handler_results.values().next() {
+                send_statistics(
+                    &self.options,
+                    self.isolate.as_mut().unwrap(),
+                    handler_result.start_time.elapsed(),
+                    get_statistics_result(&termination_result),
+                );
+
                 handler_result.sender.send(termination_result).unwrap_or(());
             }
 
@@ -632,16 +821,33 @@ impl Isolate {
             };
 
         state.handler_results.retain(|_, handler_result| {
+            // The request was aborted, e.g because it timed out
+            if handler_result.sender.is_disconnected() {
+                return false;
+            }
+
             if *handler_result.stream_response_sent.borrow() {
                 if handler_result.stream_status.borrow().is_done() {
                     if should_send_statistics {
-                        send_statistics(options, try_catch);
+                        send_statistics(
+                            options,
+                            try_catch,
+                            handler_result.start_time.elapsed(),
+                            "success",
+                        );
                     }
 
                     return false;
                 }
 
                 if handler_result.start_time.elapsed() >= options.total_timeout {
+                    send_statistics(
+                        options,
+                        try_catch,
+                        handler_result.start_time.elapsed(),
+                        "timeout",
+                    );
+
                     handler_result.sender.send(RunResult::Timeout).unwrap_or(());
                     return false;
                 }
@@ -685,32 +891,46 @@ impl Isolate {
                         return true;
                     }
 
-                    handler_result.sender.send(run_result).unwrap_or(());
+                    let result = get_statistics_result(&run_result);
 
-                    if should_send_statistics {
-                        send_statistics(options, try_catch);
+                    if should_send_statistics || result != "success" {
+                        send_statistics(
+                            options,
+                            try_catch,
+                            handler_result.start_time.elapsed(),
+                            result,
+                        );
                     }
 
+                    handler_result.sender.send(run_result).unwrap_or(());
+
                     false
                 }
                 v8::PromiseState::Rejected => {
                     let exception = promise.result(try_catch);
+                    let run_result =
+                        into_request_error(get_exception_message(try_catch, exception, lines));
 
-                    handler_result
-                        .sender
-                        .send(RunResult::Error(get_exception_message(
-                            try_catch, exception, lines,
-                        )))
-                        .unwrap_or(());
+                    send_statistics(
+                        options,
+                        try_catch,
+                        handler_result.start_time.elapsed(),
+                        get_statistics_result(&run_result),
+                    );
 
-                    if should_send_statistics {
-                        send_statistics(options, try_catch);
-                    }
+                    handler_result.sender.send(run_result).unwrap_or(());
 
                     false
                 }
                 v8::PromiseState::Pending => {
                     if handler_result.start_time.elapsed() >= options.total_timeout {
+                        send_statistics(
+                            options,
+                            try_catch,
+                            handler_result.start_time.elapsed(),
+                            "timeout",
+                        );
+
                         handler_result.sender.send(RunResult::Timeout).unwrap_or(());
                         return false;
                     }
@@ -750,18 +970,61 @@ impl Drop for Isolate {
     fn drop(&mut self) {
         self.terminate(RunResult::Error(String::from("Dropped")));
 
+        // Collections can happen while disposing the isolate, after its
+        // slots have been dropped
+        if self.options.heap_statistics {
+            if let Some(isolate) = self.isolate.as_mut() {
+                isolate.remove_gc_prologue_callback(gc_prologue_callback, std::ptr::null_mut());
+            }
+        }
+
         if let Some(on_drop) = &self.options.on_drop {
             on_drop(Rc::clone(&self.options.metadata));
         }
     }
 }
 
-pub fn send_statistics(options: &IsolateOptions, isolate: &mut v8::Isolate) {
+fn get_statistics_result(run_result: &RunResult) -> &'static str {
+    match run_result {
+        RunResult::Response(..) | RunResult::Stream(_) => "success",
+        RunResult::Timeout => "timeout",
+        RunResult::MemoryLimit => "memory-limit",
+        RunResult::Error(_) => "error",
+        RunResult::CompileError(_) | RunResult::EvaluationError(_) => "compile-error",
+        RunResult::StackOverflow(_) => "stack-overflow",
+    }
+}
+
+// Successful requests only send statistics once per `statistics_interval`,
+// but failed ones always do to see the resources they used
+pub fn send_statistics(
+    options: &IsolateOptions,
+    isolate: &mut v8::Isolate,
+    cpu_time: Duration,
+    result: &'static str,
+) {
     if let Some(on_statistics) = &options.on_statistics {
         let mut statistics = v8::HeapStatistics::default();
         isolate.get_heap_statistics(&mut statistics);
 
-        on_statistics(Rc::clone(&options.metadata), statistics.used_heap_size())
+        let heap = options.heap_statistics.then(|| HeapStatistics {
+            used_heap_size: statistics.used_heap_size(),
+            total_heap_size: statistics.total_heap_size(),
+            external_memory: statistics.external_memory(),
+            gc_count: isolate
+                .get_slot::<GcCount>()
+                .map_or(0, |gc_count| gc_count.0.replace(0)),
+        });
+
+        on_statistics(
+            Rc::clone(&options.metadata),
+            IsolateStatistics {
+                memory_usage: statistics.used_heap_size(),
+                cpu_time,
+                result,
+                heap,
+            },
+        )
     }
 }
 
@@ -816,6 +1079,18 @@ pub fn get_exception_message(
     message
 }
 
+// V8 throws a RangeError when the call stack reaches its limit. It can
+// be caught like any error, so only uncaught ones are reported this way.
+// The start of the message is matched, so other errors mentioning it
+// (e.g in their own message) are still regular errors
+fn into_request_error(error: String) -> RunResult {
+    if error.starts_with(STACK_OVERFLOW_ERROR) {
+        return RunResult::StackOverflow(error);
+    }
+
+    RunResult::Error(error)
+}
+
 fn handle_error(scope: &mut v8::TryCatch<v8::HandleScope>, lines: usize) -> RunResult {
     if let Some(exception) = scope.exception() {
         return RunResult::Error(get_exception_message(scope, exception, lines));
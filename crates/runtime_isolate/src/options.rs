@@ -1,11 +1,104 @@
+use anyhow::Result;
+use futures::Future;
 use lagon_runtime_v8_utils::v8_string;
-use std::{collections::HashMap, rc::Rc, time::Duration};
+use std::{collections::HashMap, pin::Pin, rc::Rc, time::Duration};
 
 const JS_RUNTIME: &str = include_str!("../runtime.js");
+const DEFAULT_KV_MAX_VALUE_SIZE: usize = 64 * 1024;
 
 pub type Metadata = Option<(String, String)>;
+// Level, message, metadata of the isolate and id of the request that logged it
+pub type LogMessage = (String, String, Metadata, Option<String>);
+
+// Level of a log made with `console.*` or by the serverless
+// itself, where `console.log` is the same as `console.info`
+pub fn get_log_level(level: &str) -> log::Level {
+    match level {
+        "error" => log::Level::Error,
+        "warn" => log::Level::Warn,
+        "debug" => log::Level::Debug,
+        _ => log::Level::Info,
+    }
+}
 type OnIsolateDropCallback = Box<dyn Fn(Rc<Metadata>)>;
-type OnIsolateStatisticsCallback = Box<dyn Fn(Rc<Metadata>, usize)>;
+type OnIsolateStatisticsCallback = Box<dyn Fn(Rc<Metadata>, IsolateStatistics)>;
+type OnIsolateCodeCacheCallback = Box<dyn Fn(Vec<u8>)>;
+pub type OnIsolateFetchBlockedCallback = Box<dyn Fn(Rc<Metadata>, String)>;
+pub type KvFuture = Pin<Box<dyn Future<Output = Result<KvResponse>>>>;
+pub type OnIsolateKvCallback = Box<dyn Fn(Rc<Metadata>, KvRequest) -> KvFuture>;
+
+// Resources used by a request, sent once it has completed, failed or timed out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IsolateStatistics {
+    // Used heap size of the isolate, in bytes
+    pub memory_usage: usize,
+    pub cpu_time: Duration,
+    // `success`, `error`, `timeout` or `memory-limit`
+    pub result: &'static str,
+    // Only set when `heap_statistics` is enabled
+    pub heap: Option<HeapStatistics>,
+}
+
+// Sizes are in bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStatistics {
+    pub used_heap_size: usize,
+    pub total_heap_size: usize,
+    // Memory of objects allocated outside of the heap, e.g ArrayBuffers
+    pub external_memory: usize,
+    // Garbage collections since the statistics were last sent
+    pub gc_count: usize,
+}
+
+// Operations on the KV storage of an isolate. Keys are the ones used by the
+// Function, and values are already serialized by the JS runtime
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KvRequest {
+    Get(String),
+    // The TTL is in seconds, the key never expires when not set
+    Set {
+        key: String,
+        value: String,
+        ttl: Option<u64>,
+    },
+    Delete(String),
+}
+
+impl KvRequest {
+    pub fn key(&self) -> &str {
+        match self {
+            KvRequest::Get(key) | KvRequest::Delete(key) => key,
+            KvRequest::Set { key, .. } => key,
+        }
+    }
+
+    pub fn operation(&self) -> &'static str {
+        match self {
+            KvRequest::Get(_) => "get",
+            KvRequest::Set { .. } => "set",
+            KvRequest::Delete(_) => "delete",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KvResponse {
+    Value(Option<String>),
+    Set,
+    Deleted(bool),
+}
+
+// Restricts the requests made with fetch(), e.g to prevent
+// reaching internal services. Everything is allowed by default
+#[derive(Debug, Clone, Default)]
+pub struct FetchPolicy {
+    // Block requests to private, loopback and link-local IPs,
+    // including hostnames resolving to them
+    pub block_private_ips: bool,
+    // Only allow requests to these hostnames when set, where
+    // `*.domain.com` allows all the subdomains of `domain.com`
+    pub allowed_hosts: Option<Vec<String>>,
+}
 
 pub struct IsolateOptions {
     pub code: String,
@@ -17,7 +110,20 @@ pub struct IsolateOptions {
     pub metadata: Rc<Metadata>,
     pub on_drop: Option<OnIsolateDropCallback>,
     pub on_statistics: Option<OnIsolateStatisticsCallback>,
-    pub log_sender: Option<flume::Sender<(String, String, Metadata)>>,
+    // Counting the garbage collections and reading the whole heap
+    // statistics has a cost, so they are only sent when enabled
+    pub heap_statistics: bool,
+    // V8 code cache of a previous compilation of the same code
+    pub code_cache: Option<Vec<u8>>,
+    pub on_code_cache: Option<OnIsolateCodeCacheCallback>,
+    pub fetch_policy: FetchPolicy,
+    pub on_fetch_blocked: Option<OnIsolateFetchBlockedCallback>,
+    // Backend of the KV storage, which isn't available when not set
+    pub on_kv: Option<OnIsolateKvCallback>,
+    pub kv_max_value_size: usize,
+    pub log_sender: Option<flume::Sender<LogMessage>>,
+    // Logs above this level aren't sent to `log_sender`
+    pub log_level: log::LevelFilter,
     pub snapshot: bool,
     pub snapshot_blob: Option<&'static [u8]>,
 }
@@ -36,9 +142,17 @@ impl IsolateOptions {
             metadata: Rc::new(None),
             on_drop: None,
             on_statistics: None,
+            heap_statistics: false,
+            code_cache: None,
+            on_code_cache: None,
+            fetch_policy: FetchPolicy::default(),
+            on_fetch_blocked: None,
+            on_kv: None,
+            kv_max_value_size: DEFAULT_KV_MAX_VALUE_SIZE,
             snapshot: false,
             snapshot_blob: None,
             log_sender: None,
+            log_level: log::LevelFilter::Trace,
         }
     }
 
@@ -77,11 +191,59 @@ impl IsolateOptions {
         self
     }
 
-    pub fn log_sender(mut self, log_sender: flume::Sender<(String, String, Metadata)>) -> Self {
+    pub fn heap_statistics(mut self, heap_statistics: bool) -> Self {
+        self.heap_statistics = heap_statistics;
+        self
+    }
+
+    pub fn code_cache(mut self, code_cache: Option<Vec<u8>>) -> Self {
+        self.code_cache = code_cache;
+        self
+    }
+
+    // Called with the code cache after the code has been compiled
+    // without one, so it can be stored and reused
+    pub fn on_code_cache_callback(mut self, on_code_cache: OnIsolateCodeCacheCallback) -> Self {
+        self.on_code_cache = Some(on_code_cache);
+        self
+    }
+
+    pub fn fetch_policy(mut self, fetch_policy: FetchPolicy) -> Self {
+        self.fetch_policy = fetch_policy;
+        self
+    }
+
+    // Called with the hostname of each request blocked by the fetch policy
+    pub fn on_fetch_blocked_callback(
+        mut self,
+        on_fetch_blocked: OnIsolateFetchBlockedCallback,
+    ) -> Self {
+        self.on_fetch_blocked = Some(on_fetch_blocked);
+        self
+    }
+
+    // Called for each KV operation, the backend has to namespace
+    // the keys to isolate the storage of each isolate
+    pub fn on_kv_callback(mut self, on_kv: OnIsolateKvCallback) -> Self {
+        self.on_kv = Some(on_kv);
+        self
+    }
+
+    pub fn kv_max_value_size(mut self, kv_max_value_size: usize) -> Self {
+        self.kv_max_value_size = kv_max_value_size;
+        self
+    }
+
+    pub fn log_sender(mut self, log_sender: flume::Sender<LogMessage>) -> Self {
         self.log_sender = Some(log_sender);
         self
     }
 
+    pub fn log_level(mut self, log_level: log::LevelFilter) -> Self {
+        self.log_level = log_level;
+        self
+    }
+
     pub fn snapshot(mut self, snapshot: bool) -> Self {
         self.snapshot = snapshot;
         self
@@ -111,11 +273,16 @@ impl IsolateOptions {
         } = self;
 
         let environment_variables = match environment_variables {
-            Some(environment_variables) => environment_variables
-                .iter()
-                .map(|(k, v)| format!("globalThis.process.env.{k} = '{v}'"))
-                .collect::<Vec<String>>()
-                .join("\n"),
+            Some(environment_variables) => {
+                // Sort the environment variables so the code is always the same,
+                // otherwise the code cache can't be reused
+                let mut environment_variables = environment_variables
+                    .iter()
+                    .map(|(k, v)| format!("globalThis.process.env.{k} = '{v}'"))
+                    .collect::<Vec<String>>();
+                environment_variables.sort_unstable();
+                environment_variables.join("\n")
+            }
             None => "".to_string(),
         };
 
@@ -1,11 +1,27 @@
 use anyhow::Result;
-use hyper::{body::Bytes, header::CONTENT_TYPE, Body, Response};
+use hyper::{
+    body::Bytes,
+    header::{
+        ACCEPT_RANGES, CACHE_CONTROL, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_NONE_MATCH, IF_RANGE,
+        LAST_MODIFIED, RANGE,
+    },
+    Body, HeaderMap, Response,
+};
 use std::{
-    collections::HashSet,
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
     fs,
+    hash::{Hash, Hasher},
     path::{Path, PathBuf},
 };
 
+// Assets can change with each deployment, so browsers need to
+// revalidate them using the ETag before reusing their cache
+const ASSETS_CACHE_CONTROL: &str = "public, max-age=0, must-revalidate";
+
+// Range headers with more ranges are ignored, instead of
+// building a huge multipart response
+const MAX_RANGES: usize = 16;
+
 pub fn find_asset<'a>(url: &'a str, assets: &'a HashSet<String>) -> Option<&'a String> {
     // Remove the leading '/' from the url
     let url = &url[1..];
@@ -18,29 +34,226 @@ pub fn find_asset<'a>(url: &'a str, assets: &'a HashSet<String>) -> Option<&'a S
     })
 }
 
-pub fn handle_asset(root: PathBuf, asset: &String) -> Result<Response<Body>> {
+fn get_etag(body: &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    body.hash(&mut hasher);
+
+    format!("\"{:x}\"", hasher.finish())
+}
+
+fn matches_etag(if_none_match: &str, etag: &str) -> bool {
+    if_none_match.split(',').any(|value| {
+        let value = value.trim();
+
+        // If-None-Match uses a weak comparison
+        value == "*" || value.strip_prefix("W/").unwrap_or(value) == etag
+    })
+}
+
+// Inclusive byte ranges of a Range header that are within the asset. Returns
+// None when the header is invalid or has too many ranges, since it should
+// then be ignored, and an empty list when no range can be satisfied
+fn parse_range(range: &str, size: u64) -> Option<Vec<(u64, u64)>> {
+    let ranges = range.trim().strip_prefix("bytes=")?.split(',');
+    let mut satisfiable = Vec::new();
+
+    for (index, range) in ranges.enumerate() {
+        if index >= MAX_RANGES {
+            return None;
+        }
+
+        let (start, end) = range.trim().split_once('-')?;
+        let (start, end) = (start.trim(), end.trim());
+
+        let (start, end) = if start.is_empty() {
+            // Suffix range, e.g `-500` for the last 500 bytes
+            let suffix = end.parse::<u64>().ok()?;
+
+            if suffix == 0 {
+                continue;
+            }
+
+            (size.saturating_sub(suffix), size.saturating_sub(1))
+        } else {
+            let start = start.parse::<u64>().ok()?;
+            let end = match end {
+                "" => u64::MAX,
+                end => end.parse::<u64>().ok()?,
+            };
+
+            if end < start {
+                return None;
+            }
+
+            (start, end.min(size.saturating_sub(1)))
+        };
+
+        if start < size {
+            satisfiable.push((start, end));
+        }
+    }
+
+    Some(satisfiable)
+}
+
+// Multiple ranges are sent as a multipart/byteranges body, each
+// part having its own Content-Type and Content-Range headers
+fn multipart_body(
+    body: &[u8],
+    ranges: &[(u64, u64)],
+    content_type: &str,
+    boundary: &str,
+) -> Vec<u8> {
+    let mut multipart = Vec::new();
+
+    for (start, end) in ranges {
+        multipart.extend_from_slice(
+            format!(
+                "\r\n--{boundary}\r\nContent-Type: {content_type}\r\nContent-Range: bytes {start}-{end}/{}\r\n\r\n",
+                body.len()
+            )
+            .as_bytes(),
+        );
+        multipart.extend_from_slice(&body[*start as usize..=*end as usize]);
+    }
+
+    multipart.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+    multipart
+}
+
+// Content types configured for an extension (without the leading
+// dot) take precedence over the built-in ones. Unknown extensions
+// are sent as application/octet-stream
+fn get_content_type<'a>(asset: &str, content_types: &'a HashMap<String, String>) -> &'a str {
+    let extension = match Path::new(asset)
+        .extension()
+        .and_then(|extension| extension.to_str())
+    {
+        Some(extension) => extension.to_ascii_lowercase(),
+        None => return "application/octet-stream",
+    };
+
+    if let Some((_, content_type)) = content_types.iter().find(|(configured, _)| {
+        configured
+            .trim_start_matches('.')
+            .eq_ignore_ascii_case(&extension)
+    }) {
+        return content_type;
+    }
+
+    match extension.as_str() {
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "js" | "mjs" | "cjs" => "application/javascript",
+        "json" | "map" => "application/json",
+        "webmanifest" => "application/manifest+json",
+        "xml" => "application/xml",
+        "txt" => "text/plain",
+        "csv" => "text/csv",
+        "md" => "text/markdown",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "svg" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "mp3" => "audio/mpeg",
+        "wav" => "audio/wav",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
+
+pub fn handle_asset(
+    root: PathBuf,
+    asset: &String,
+    headers: &HeaderMap,
+    content_types: &HashMap<String, String>,
+) -> Result<Response<Body>> {
     let path = root.join(asset);
-    let body = fs::read(path)?;
-
-    let content_type = Path::new(asset).extension().map_or(
-        "application/octet-stream",
-        |extension| match extension.to_str().unwrap_or("") {
-            "js" => "application/javascript",
-            "css" => "text/css",
-            "html" => "text/html",
-            "png" => "image/png",
-            "jpg" => "image/jpeg",
-            "jpeg" => "image/jpeg",
-            "svg" => "image/svg+xml",
-            "json" => "application/json",
-            "txt" => "text/plain",
-            _ => "application/octet-stream",
-        },
-    );
-
-    Ok(Response::builder()
-        .header(CONTENT_TYPE, content_type)
-        .body(Body::from(Bytes::from(body)))?)
+    let body = fs::read(&path)?;
+    let etag = get_etag(&body);
+
+    let mut response = Response::builder()
+        .header(ETAG, &etag)
+        .header(CACHE_CONTROL, ASSETS_CACHE_CONTROL)
+        .header(ACCEPT_RANGES, "bytes");
+
+    if let Ok(modified) = fs::metadata(&path).and_then(|metadata| metadata.modified()) {
+        response = response.header(LAST_MODIFIED, httpdate::fmt_http_date(modified));
+    }
+
+    let if_none_match = headers
+        .get(IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok());
+
+    if if_none_match.map_or(false, |if_none_match| matches_etag(if_none_match, &etag)) {
+        return Ok(response.status(304).body(Body::empty())?);
+    }
+
+    let content_type = get_content_type(asset, content_types);
+
+    // A client resuming a download with If-Range gets the whole
+    // asset if it changed since, instead of mixing both versions.
+    // Dates are not compared, so they always get the whole asset
+    let ranges = headers
+        .get(RANGE)
+        .and_then(|value| value.to_str().ok())
+        .filter(|_| {
+            headers
+                .get(IF_RANGE)
+                .map_or(true, |if_range| if_range.as_bytes() == etag.as_bytes())
+        })
+        .and_then(|range| parse_range(range, body.len() as u64));
+
+    let ranges = match ranges {
+        Some(ranges) => ranges,
+        None => {
+            return Ok(response
+                .header(CONTENT_TYPE, content_type)
+                .body(Body::from(Bytes::from(body)))?)
+        }
+    };
+
+    match ranges.as_slice() {
+        [] => Ok(response
+            .status(416)
+            .header(CONTENT_RANGE, format!("bytes */{}", body.len()))
+            .body(Body::empty())?),
+        [(start, end)] => Ok(response
+            .status(206)
+            .header(CONTENT_TYPE, content_type)
+            .header(
+                CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end, body.len()),
+            )
+            .body(Body::from(body[*start as usize..=*end as usize].to_vec()))?),
+        ranges => {
+            let boundary = etag.trim_matches('"');
+
+            Ok(response
+                .status(206)
+                .header(
+                    CONTENT_TYPE,
+                    format!("multipart/byteranges; boundary={boundary}"),
+                )
+                .body(Body::from(multipart_body(
+                    &body,
+                    ranges,
+                    content_type,
+                    boundary,
+                )))?)
+        }
+    }
 }
 
 #[cfg(test)]
@@ -116,4 +329,91 @@ mod tests {
         assert_eq!(find_asset("/hello/none", &assets), None);
         assert_eq!(find_asset("/hello/world/none", &assets), None);
     }
+
+    #[test]
+    fn matches_etag_values() {
+        assert!(matches_etag("\"abc\"", "\"abc\""));
+        assert!(matches_etag("W/\"abc\"", "\"abc\""));
+        assert!(matches_etag("\"def\", \"abc\"", "\"abc\""));
+        assert!(matches_etag("*", "\"abc\""));
+        assert!(!matches_etag("\"def\"", "\"abc\""));
+    }
+
+    #[test]
+    fn parse_range_values() {
+        assert_eq!(parse_range("bytes=0-499", 1000), Some(vec![(0, 499)]));
+        assert_eq!(parse_range("bytes=500-", 1000), Some(vec![(500, 999)]));
+        assert_eq!(parse_range("bytes=-200", 1000), Some(vec![(800, 999)]));
+        assert_eq!(parse_range("bytes=-2000", 1000), Some(vec![(0, 999)]));
+        assert_eq!(parse_range("bytes=900-1500", 1000), Some(vec![(900, 999)]));
+        assert_eq!(
+            parse_range("bytes=0-1, 5-6", 1000),
+            Some(vec![(0, 1), (5, 6)])
+        );
+    }
+
+    #[test]
+    fn parse_range_unsatisfiable() {
+        assert_eq!(parse_range("bytes=1000-", 1000), Some(vec![]));
+        assert_eq!(parse_range("bytes=-0", 1000), Some(vec![]));
+        assert_eq!(parse_range("bytes=0-", 0), Some(vec![]));
+        assert_eq!(
+            parse_range("bytes=2000-3000, 0-0", 1000),
+            Some(vec![(0, 0)])
+        );
+    }
+
+    #[test]
+    fn parse_range_invalid() {
+        assert_eq!(parse_range("items=0-1", 1000), None);
+        assert_eq!(parse_range("bytes=5-1", 1000), None);
+        assert_eq!(parse_range("bytes=a-b", 1000), None);
+        assert_eq!(parse_range("bytes=-", 1000), None);
+        assert_eq!(parse_range("bytes=0", 1000), None);
+        assert_eq!(
+            parse_range(&format!("bytes={}", vec!["0-0"; 17].join(",")), 1000),
+            None
+        );
+    }
+
+    #[test]
+    fn content_types() {
+        let content_types = HashMap::from([
+            ("wgsl".to_string(), "text/wgsl".to_string()),
+            (".JS".to_string(), "text/javascript".to_string()),
+        ]);
+
+        assert_eq!(get_content_type("index.css", &HashMap::new()), "text/css");
+        assert_eq!(
+            get_content_type("style/INDEX.CSS", &HashMap::new()),
+            "text/css"
+        );
+        assert_eq!(
+            get_content_type("font.woff2", &HashMap::new()),
+            "font/woff2"
+        );
+        assert_eq!(
+            get_content_type("shader.wgsl", &HashMap::new()),
+            "application/octet-stream"
+        );
+        assert_eq!(
+            get_content_type("README", &HashMap::new()),
+            "application/octet-stream"
+        );
+        assert_eq!(get_content_type("shader.wgsl", &content_types), "text/wgsl");
+        assert_eq!(
+            get_content_type("app.js", &content_types),
+            "text/javascript"
+        );
+    }
+
+    #[test]
+    fn multipart_ranges() {
+        let body = multipart_body(b"hello world", &[(0, 1), (6, 10)], "text/plain", "b");
+
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            "\r\n--b\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/11\r\n\r\nhe\r\n--b\r\nContent-Type: text/plain\r\nContent-Range: bytes 6-10/11\r\n\r\nworld\r\n--b--\r\n"
+        );
+    }
 }
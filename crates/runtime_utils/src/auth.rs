@@ -0,0 +1,104 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hyper::{header::AUTHORIZATION, HeaderMap};
+use serde::Deserialize;
+
+// Credentials required before running the Function, e.g to keep
+// preview deployments private. Either `{ "username", "password" }`
+// for basic auth or `{ "token" }` for a bearer token
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum Auth {
+    Basic { username: String, password: String },
+    Bearer { token: String },
+}
+
+// Compare all the bytes even when they differ early, so the
+// time taken doesn't leak how much of the credentials matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+impl Auth {
+    pub fn is_authorized(&self, headers: &HeaderMap) -> bool {
+        let authorization = match headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+        {
+            Some(authorization) => authorization.trim(),
+            None => return false,
+        };
+
+        let (scheme, credentials) = match authorization.split_once(' ') {
+            Some((scheme, credentials)) => (scheme, credentials.trim()),
+            None => return false,
+        };
+
+        match self {
+            Auth::Basic { username, password } => {
+                if !scheme.eq_ignore_ascii_case("basic") {
+                    return false;
+                }
+
+                let expected = format!("{}:{}", username, password);
+
+                STANDARD.decode(credentials).map_or(false, |decoded| {
+                    constant_time_eq(&decoded, expected.as_bytes())
+                })
+            }
+            Auth::Bearer { token } => {
+                scheme.eq_ignore_ascii_case("bearer")
+                    && constant_time_eq(credentials.as_bytes(), token.as_bytes())
+            }
+        }
+    }
+
+    // Value of the WWW-Authenticate header sent with 401 responses,
+    // making browsers prompt for the credentials with basic auth
+    pub fn www_authenticate(&self) -> &'static str {
+        match self {
+            Auth::Basic { .. } => r#"Basic realm="Lagon", charset="UTF-8""#,
+            Auth::Bearer { .. } => r#"Bearer realm="Lagon""#,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(authorization: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, authorization.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn basic() {
+        let auth = Auth::Basic {
+            username: "user".into(),
+            password: "pass".into(),
+        };
+
+        // user:pass
+        assert!(auth.is_authorized(&headers("Basic dXNlcjpwYXNz")));
+        assert!(auth.is_authorized(&headers("basic dXNlcjpwYXNz")));
+        // user:wrong
+        assert!(!auth.is_authorized(&headers("Basic dXNlcjp3cm9uZw==")));
+        assert!(!auth.is_authorized(&headers("Basic not base64!")));
+        assert!(!auth.is_authorized(&headers("Bearer pass")));
+        assert!(!auth.is_authorized(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn bearer() {
+        let auth = Auth::Bearer {
+            token: "secret".into(),
+        };
+
+        assert!(auth.is_authorized(&headers("Bearer secret")));
+        assert!(!auth.is_authorized(&headers("Bearer secre")));
+        assert!(!auth.is_authorized(&headers("Bearer wrong!")));
+        assert!(!auth.is_authorized(&headers("secret")));
+        assert!(!auth.is_authorized(&HeaderMap::new()));
+    }
+}
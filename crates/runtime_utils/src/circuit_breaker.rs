@@ -0,0 +1,145 @@
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Closed,
+    // Requests are rejected until the cooldown ends
+    Open(Instant),
+    // A single trial request is running since the given instant
+    HalfOpen(Instant),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CircuitBreakerOptions {
+    // Number of consecutive failures before opening the circuit
+    pub threshold: u32,
+    pub cooldown: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+    options: CircuitBreakerOptions,
+    failures: u32,
+    state: State,
+}
+
+impl CircuitBreaker {
+    pub fn new(options: CircuitBreakerOptions) -> Self {
+        Self {
+            options,
+            failures: 0,
+            state: State::Closed,
+        }
+    }
+
+    // Allow a request, or return how long to wait before the
+    // next trial request is allowed
+    pub fn try_acquire(&mut self, now: Instant) -> Result<(), Duration> {
+        match self.state {
+            State::Closed => Ok(()),
+            State::Open(until) | State::HalfOpen(until) if now < until => {
+                Err(until.saturating_duration_since(now))
+            }
+            // The trial request might never be reported (e.g the client
+            // disconnected), so allow a new one after another cooldown
+            State::Open(_) | State::HalfOpen(_) => {
+                self.state = State::HalfOpen(now + self.options.cooldown);
+                Ok(())
+            }
+        }
+    }
+
+    // Returns true when this failure opened the circuit
+    pub fn on_failure(&mut self, now: Instant) -> bool {
+        self.failures = self.failures.saturating_add(1);
+
+        let should_open = match self.state {
+            State::Closed => self.failures >= self.options.threshold,
+            State::HalfOpen(_) => true,
+            State::Open(_) => false,
+        };
+
+        if should_open {
+            self.state = State::Open(now + self.options.cooldown);
+        }
+
+        should_open
+    }
+
+    pub fn on_success(&mut self) {
+        self.failures = 0;
+        self.state = State::Closed;
+    }
+
+    pub fn state(&self) -> &'static str {
+        match self.state {
+            State::Closed => "closed",
+            State::Open(_) => "open",
+            State::HalfOpen(_) => "half-open",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPTIONS: CircuitBreakerOptions = CircuitBreakerOptions {
+        threshold: 3,
+        cooldown: Duration::from_secs(10),
+    };
+
+    #[test]
+    fn open_after_threshold() {
+        let now = Instant::now();
+        let mut breaker = CircuitBreaker::new(OPTIONS);
+
+        assert!(!breaker.on_failure(now));
+        assert!(!breaker.on_failure(now));
+        assert!(breaker.try_acquire(now).is_ok());
+        assert!(breaker.on_failure(now));
+
+        assert_eq!(breaker.state(), "open");
+        assert_eq!(breaker.try_acquire(now), Err(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn reset_on_success() {
+        let now = Instant::now();
+        let mut breaker = CircuitBreaker::new(OPTIONS);
+
+        breaker.on_failure(now);
+        breaker.on_failure(now);
+        breaker.on_success();
+
+        assert!(!breaker.on_failure(now));
+        assert_eq!(breaker.state(), "closed");
+    }
+
+    #[test]
+    fn half_open_trial() {
+        let now = Instant::now();
+        let mut breaker = CircuitBreaker::new(OPTIONS);
+
+        for _ in 0..3 {
+            breaker.on_failure(now);
+        }
+
+        let now = now + Duration::from_secs(10);
+        assert!(breaker.try_acquire(now).is_ok());
+        assert_eq!(breaker.state(), "half-open");
+
+        // Only one trial request at a time
+        assert!(breaker.try_acquire(now).is_err());
+
+        // A failed trial opens the circuit again
+        assert!(breaker.on_failure(now));
+        assert_eq!(breaker.state(), "open");
+
+        let now = now + Duration::from_secs(10);
+        assert!(breaker.try_acquire(now).is_ok());
+        breaker.on_success();
+        assert_eq!(breaker.state(), "closed");
+        assert!(breaker.try_acquire(now).is_ok());
+    }
+}
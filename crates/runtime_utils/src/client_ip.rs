@@ -0,0 +1,309 @@
+use anyhow::{anyhow, Error, Result};
+use hyper::{
+    header::{HeaderValue, FORWARDED},
+    HeaderMap,
+};
+use ipnet::IpNet;
+use lagon_runtime_http::{X_FORWARDED_FOR, X_FORWARDED_HOST, X_FORWARDED_PROTO, X_REAL_IP};
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+// Peers (e.g load balancers) allowed to set the client IP with the
+// X-Real-Ip, Forwarded and X-Forwarded-For headers
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies(Vec<IpNet>);
+
+impl TrustedProxies {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        self.0.iter().any(|net| net.contains(ip))
+    }
+}
+
+// Comma-separated list of CIDRs, e.g `10.0.0.0/8,::1/128`. IPs
+// without a prefix length only match themselves
+impl FromStr for TrustedProxies {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|net| !net.is_empty())
+            .map(|net| {
+                net.parse::<IpNet>()
+                    .or_else(|_| net.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| anyhow!("Invalid CIDR {}", net))
+            })
+            .collect::<Result<_>>()
+            .map(Self)
+    }
+}
+
+// Nodes can be quoted, have a port, and IPv6 can be in brackets
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+
+    node.parse::<IpAddr>()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| {
+            node.strip_prefix('[')
+                .and_then(|node| node.strip_suffix(']'))
+                .and_then(|node| node.parse().ok())
+        })
+}
+
+// IPs of the Forwarded header `for` parameters, or of the X-Forwarded-For
+// header, from the client to the closest proxy. Obfuscated or invalid
+// nodes are kept as `None`
+fn get_forwarded_chain(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let forwarded = headers
+        .get_all(FORWARDED)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|element| {
+            element
+                .split(';')
+                .find_map(|pair| {
+                    let (key, value) = pair.split_once('=')?;
+                    key.trim().eq_ignore_ascii_case("for").then_some(value)
+                })
+                .and_then(parse_node)
+        })
+        .collect::<Vec<_>>();
+
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+
+    headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(parse_node)
+        .collect()
+}
+
+// The headers are only read when the peer is a trusted proxy, since
+// anyone could set them otherwise. The chain is walked from the closest
+// proxy and stops at the first untrusted IP, which is the client
+pub fn get_client_ip(
+    headers: &HeaderMap,
+    remote_ip: IpAddr,
+    trusted_proxies: &TrustedProxies,
+) -> IpAddr {
+    if !trusted_proxies.contains(&remote_ip) {
+        return remote_ip;
+    }
+
+    if let Some(ip) = headers
+        .get(X_REAL_IP)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_node)
+    {
+        return ip;
+    }
+
+    let mut client_ip = remote_ip;
+
+    for node in get_forwarded_chain(headers).into_iter().rev() {
+        match node {
+            Some(ip) => {
+                client_ip = ip;
+
+                if !trusted_proxies.contains(&ip) {
+                    break;
+                }
+            }
+            None => break,
+        }
+    }
+
+    client_ip
+}
+
+// Standard proxy headers sent to the isolates, so Functions don't have to
+// trust the values sent by clients. The protocol and host set by a trusted
+// proxy are kept, since they differ from the ones of the connection to the
+// node when the proxy terminates TLS
+pub fn apply_proxy_headers(
+    headers: &mut HeaderMap,
+    hostname: &str,
+    client_ip: IpAddr,
+    is_tls: bool,
+    remote_ip: IpAddr,
+    trusted_proxies: &TrustedProxies,
+) -> Result<()> {
+    let is_trusted_proxy = trusted_proxies.contains(&remote_ip);
+
+    if !is_trusted_proxy || !headers.contains_key(X_FORWARDED_PROTO) {
+        headers.insert(
+            X_FORWARDED_PROTO,
+            HeaderValue::from_static(if is_tls { "https" } else { "http" }),
+        );
+    }
+
+    if !is_trusted_proxy || !headers.contains_key(X_FORWARDED_HOST) {
+        headers.insert(X_FORWARDED_HOST, hostname.parse()?);
+    }
+
+    headers.insert(X_REAL_IP, client_ip.to_string().parse()?);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header_map(headers: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+
+        for (key, value) in headers {
+            map.append(*key, value.parse().unwrap());
+        }
+
+        map
+    }
+
+    #[test]
+    fn parse_trusted_proxies() {
+        let trusted_proxies = "10.0.0.0/8, 127.0.0.1,::1/128"
+            .parse::<TrustedProxies>()
+            .unwrap();
+
+        assert!(trusted_proxies.contains(&"10.1.2.3".parse().unwrap()));
+        assert!(trusted_proxies.contains(&"127.0.0.1".parse().unwrap()));
+        assert!(trusted_proxies.contains(&"::1".parse().unwrap()));
+        assert!(!trusted_proxies.contains(&"127.0.0.2".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<TrustedProxies>().is_err());
+        assert!("".parse::<TrustedProxies>().unwrap().0.is_empty());
+    }
+
+    #[test]
+    fn untrusted_peer() {
+        let trusted_proxies = "10.0.0.0/8".parse().unwrap();
+        let headers = header_map(&[("x-forwarded-for", "1.2.3.4"), ("x-real-ip", "1.2.3.4")]);
+
+        assert_eq!(
+            get_client_ip(&headers, "5.6.7.8".parse().unwrap(), &trusted_proxies),
+            "5.6.7.8".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            get_client_ip(
+                &headers,
+                "10.0.0.1".parse().unwrap(),
+                &TrustedProxies::default()
+            ),
+            "10.0.0.1".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn x_forwarded_for() {
+        let trusted_proxies = "10.0.0.0/8".parse().unwrap();
+        let remote_ip = "10.0.0.1".parse().unwrap();
+
+        // The first IP is spoofed by the client
+        let headers = header_map(&[
+            ("x-forwarded-for", "9.9.9.9, 1.2.3.4"),
+            ("x-forwarded-for", "10.0.0.2"),
+        ]);
+        assert_eq!(
+            get_client_ip(&headers, remote_ip, &trusted_proxies),
+            "1.2.3.4".parse::<IpAddr>().unwrap()
+        );
+
+        // Only trusted proxies
+        let headers = header_map(&[("x-forwarded-for", "10.0.0.3, 10.0.0.2")]);
+        assert_eq!(
+            get_client_ip(&headers, remote_ip, &trusted_proxies),
+            "10.0.0.3".parse::<IpAddr>().unwrap()
+        );
+
+        // Invalid nodes stop the chain
+        let headers = header_map(&[("x-forwarded-for", "1.2.3.4, unknown, 10.0.0.2")]);
+        assert_eq!(
+            get_client_ip(&headers, remote_ip, &trusted_proxies),
+            "10.0.0.2".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn forwarded() {
+        let trusted_proxies = "10.0.0.0/8".parse().unwrap();
+        let remote_ip = "10.0.0.1".parse().unwrap();
+
+        let headers = header_map(&[
+            (
+                "forwarded",
+                "for=9.9.9.9, for=\"[2001:db8:cafe::17]:4711\";proto=https",
+            ),
+            ("forwarded", "For=10.0.0.2:8080;by=10.0.0.1"),
+            ("x-forwarded-for", "1.2.3.4"),
+        ]);
+        assert_eq!(
+            get_client_ip(&headers, remote_ip, &trusted_proxies),
+            "2001:db8:cafe::17".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn x_real_ip() {
+        let trusted_proxies = "10.0.0.0/8".parse().unwrap();
+        let headers = header_map(&[("x-real-ip", "1.2.3.4"), ("x-forwarded-for", "5.6.7.8")]);
+
+        assert_eq!(
+            get_client_ip(&headers, "10.0.0.1".parse().unwrap(), &trusted_proxies),
+            "1.2.3.4".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn proxy_headers() {
+        let trusted_proxies = "10.0.0.0/8".parse().unwrap();
+        let client_ip = "1.2.3.4".parse().unwrap();
+
+        let mut headers = header_map(&[
+            ("x-forwarded-proto", "https"),
+            ("x-forwarded-host", "spoofed.com"),
+            ("x-real-ip", "9.9.9.9"),
+        ]);
+        apply_proxy_headers(
+            &mut headers,
+            "lagon.app",
+            client_ip,
+            false,
+            client_ip,
+            &trusted_proxies,
+        )
+        .unwrap();
+
+        assert_eq!(headers.get(X_FORWARDED_PROTO).unwrap(), "http");
+        assert_eq!(headers.get(X_FORWARDED_HOST).unwrap(), "lagon.app");
+        assert_eq!(headers.get(X_REAL_IP).unwrap(), "1.2.3.4");
+
+        let mut headers = header_map(&[
+            ("x-forwarded-proto", "https"),
+            ("x-forwarded-host", "lagon.app"),
+        ]);
+        apply_proxy_headers(
+            &mut headers,
+            "internal.lagon.app",
+            client_ip,
+            false,
+            "10.0.0.1".parse().unwrap(),
+            &trusted_proxies,
+        )
+        .unwrap();
+
+        assert_eq!(headers.get(X_FORWARDED_PROTO).unwrap(), "https");
+        assert_eq!(headers.get(X_FORWARDED_HOST).unwrap(), "lagon.app");
+        assert_eq!(headers.get(X_REAL_IP).unwrap(), "1.2.3.4");
+    }
+}
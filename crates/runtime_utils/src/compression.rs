@@ -0,0 +1,373 @@
+use anyhow::{anyhow, Result};
+use flate2::{
+    read::GzDecoder,
+    write::{DeflateEncoder, GzEncoder},
+    Compression,
+};
+use hyper::{
+    body::{to_bytes, HttpBody},
+    header::{
+        HeaderMap, HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE,
+        ETAG, VARY,
+    },
+    Body, Response,
+};
+use std::{
+    io::{Read, Write},
+    str::FromStr,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    fn encode(&self, body: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(body)?;
+                Ok(encoder.finish()?)
+            }
+            Encoding::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(body)?;
+                Ok(encoder.finish()?)
+            }
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "gzip" => Ok(Encoding::Gzip),
+            "deflate" => Ok(Encoding::Deflate),
+            _ => Err(anyhow!("Unknown encoding {}", value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CompressionOptions {
+    // Encodings to use, in order of preference
+    pub encodings: Vec<Encoding>,
+    // Bodies smaller than this size (in bytes) are sent as-is
+    pub min_size: usize,
+}
+
+impl Default for CompressionOptions {
+    fn default() -> Self {
+        Self {
+            encodings: vec![Encoding::Gzip, Encoding::Deflate],
+            min_size: 1024,
+        }
+    }
+}
+
+fn is_compressible(content_type: &str) -> bool {
+    let content_type = content_type.split(';').next().unwrap_or("").trim();
+
+    content_type.starts_with("text/")
+        || matches!(
+            content_type,
+            "application/javascript"
+                | "application/json"
+                | "application/xml"
+                | "application/manifest+json"
+                | "image/svg+xml"
+        )
+}
+
+fn find_encoding(accept_encoding: &str, encodings: &[Encoding]) -> Option<Encoding> {
+    let accepted = accept_encoding
+        .split(',')
+        .filter_map(|value| {
+            let mut parts = value.split(';');
+            let name = parts.next()?.trim();
+
+            // Encodings with a quality of 0 are explicitly refused
+            let refused = parts.any(|part| {
+                part.trim()
+                    .strip_prefix("q=")
+                    .and_then(|quality| quality.parse::<f32>().ok())
+                    .map_or(false, |quality| quality == 0.0)
+            });
+
+            (!refused).then_some(name)
+        })
+        .collect::<Vec<_>>();
+
+    encodings.iter().copied().find(|encoding| {
+        accepted
+            .iter()
+            .any(|name| *name == encoding.as_str() || *name == "*")
+    })
+}
+
+// Encoding to compress a response of `size` bytes with, when the client
+// accepts one of the configured encodings. Already encoded responses and
+// non-compressible content types (e.g. images) aren't compressed
+fn get_response_encoding(
+    headers: &HeaderMap,
+    size: usize,
+    accept_encoding: Option<&str>,
+    options: &CompressionOptions,
+) -> Option<Encoding> {
+    let encoding = find_encoding(accept_encoding?, &options.encodings)?;
+
+    if size < options.min_size
+        || headers.contains_key(CONTENT_ENCODING)
+        || headers.contains_key(CONTENT_RANGE)
+        || !headers
+            .get(CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .map_or(false, is_compressible)
+    {
+        return None;
+    }
+
+    Some(encoding)
+}
+
+fn set_encoding_headers(headers: &mut HeaderMap, encoding: Encoding) -> Result<()> {
+    // The compressed body is a different representation, so a strong
+    // ETag (e.g from assets) can only be kept as a weak one
+    if let Some(etag) = headers.get(ETAG).and_then(|etag| etag.to_str().ok()) {
+        if !etag.starts_with("W/") {
+            let etag = HeaderValue::from_str(&format!("W/{}", etag))?;
+            headers.insert(ETAG, etag);
+        }
+    }
+
+    headers.remove(CONTENT_LENGTH);
+    headers.insert(
+        CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.as_str()),
+    );
+    headers.append(VARY, HeaderValue::from_static("accept-encoding"));
+
+    Ok(())
+}
+
+// Compress the body of a non-stream response if the client accepts one of
+// the configured encodings. Stream responses, already encoded responses and
+// non-compressible content types (e.g. images) are returned untouched
+pub async fn compress_response(
+    response: Response<Body>,
+    accept_encoding: Option<&str>,
+    options: &CompressionOptions,
+) -> Result<Response<Body>> {
+    let size = match response.body().size_hint().exact() {
+        Some(size) => size as usize,
+        None => return Ok(response),
+    };
+
+    let encoding = match get_response_encoding(response.headers(), size, accept_encoding, options) {
+        Some(encoding) => encoding,
+        None => return Ok(response),
+    };
+
+    let (mut parts, body) = response.into_parts();
+    let body = encoding.encode(&to_bytes(body).await?)?;
+    set_encoding_headers(&mut parts.headers, encoding)?;
+
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
+
+// Set the headers a GET response would be compressed with on a response to
+// a HEAD request, using its Content-Length, without compressing the body
+// that isn't sent. The compressed length isn't known, so it's removed
+pub fn compress_head_response(
+    mut response: Response<Body>,
+    accept_encoding: Option<&str>,
+    options: &CompressionOptions,
+) -> Result<Response<Body>> {
+    let size = match response
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|size| size.to_str().ok())
+        .and_then(|size| size.parse::<usize>().ok())
+    {
+        Some(size) => size,
+        None => return Ok(response),
+    };
+
+    if let Some(encoding) =
+        get_response_encoding(response.headers(), size, accept_encoding, options)
+    {
+        set_encoding_headers(response.headers_mut(), encoding)?;
+    }
+
+    Ok(response)
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+// The code of a deployment can be stored gzip-compressed to make it
+// faster to download. It's detected from its magic bytes, which a
+// JavaScript file can't start with since they aren't valid UTF-8
+pub fn decompress_code(code: Vec<u8>) -> Result<Vec<u8>> {
+    if code.starts_with(&GZIP_MAGIC) {
+        let mut decompressed = Vec::new();
+        GzDecoder::new(&code[..]).read_to_end(&mut decompressed)?;
+
+        return Ok(decompressed);
+    }
+
+    if code.starts_with(&ZSTD_MAGIC) {
+        return Err(anyhow!(
+            "zstd-compressed code isn't supported, use gzip instead"
+        ));
+    }
+
+    Ok(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(content_type: &str, body: &str) -> Response<Body> {
+        Response::builder()
+            .header(CONTENT_TYPE, content_type)
+            .body(body.to_string().into())
+            .unwrap()
+    }
+
+    #[test]
+    fn find_encoding_preference() {
+        let encodings = vec![Encoding::Gzip, Encoding::Deflate];
+
+        assert_eq!(
+            find_encoding("deflate, gzip", &encodings),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(
+            find_encoding("deflate", &encodings),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(find_encoding("*", &encodings), Some(Encoding::Gzip));
+        assert_eq!(
+            find_encoding("gzip;q=0, deflate;q=0.5", &encodings),
+            Some(Encoding::Deflate)
+        );
+        assert_eq!(find_encoding("br", &encodings), None);
+        assert_eq!(find_encoding("gzip", &[]), None);
+    }
+
+    #[tokio::test]
+    async fn compress_gzip() {
+        let body = "a".repeat(2048);
+        let mut response = compress_response(
+            response("text/html; charset=utf-8", &body),
+            Some("gzip, deflate"),
+            &CompressionOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(response.headers().get(CONTENT_ENCODING).unwrap(), "gzip");
+        assert_eq!(response.headers().get(VARY).unwrap(), "accept-encoding");
+
+        let bytes = to_bytes(response.body_mut()).await.unwrap();
+        let mut decoded = String::new();
+        GzDecoder::new(&bytes[..])
+            .read_to_string(&mut decoded)
+            .unwrap();
+
+        assert_eq!(decoded, body);
+    }
+
+    #[tokio::test]
+    async fn skip_small_body() {
+        let response = compress_response(
+            response("text/html", "Hello world"),
+            Some("gzip"),
+            &CompressionOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        assert!(response.headers().get(CONTENT_ENCODING).is_none());
+    }
+
+    #[tokio::test]
+    async fn skip_not_compressible() {
+        let response = compress_response(
+            response("image/png", &"a".repeat(2048)),
+            Some("gzip"),
+            &CompressionOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        assert!(response.headers().get(CONTENT_ENCODING).is_none());
+    }
+
+    #[tokio::test]
+    async fn skip_without_accept_encoding() {
+        let response = compress_response(
+            response("text/html", &"a".repeat(2048)),
+            None,
+            &CompressionOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        assert!(response.headers().get(CONTENT_ENCODING).is_none());
+    }
+
+    #[test]
+    fn compress_head() {
+        let response = Response::builder()
+            .header(CONTENT_TYPE, "text/html")
+            .header(CONTENT_LENGTH, 2048)
+            .header(ETAG, "\"hash\"")
+            .body(Body::empty())
+            .unwrap();
+        let response =
+            compress_head_response(response, Some("gzip"), &CompressionOptions::default()).unwrap();
+
+        assert_eq!(response.headers().get(CONTENT_ENCODING).unwrap(), "gzip");
+        assert_eq!(response.headers().get(VARY).unwrap(), "accept-encoding");
+        assert_eq!(response.headers().get(ETAG).unwrap(), "W/\"hash\"");
+        assert!(response.headers().get(CONTENT_LENGTH).is_none());
+
+        let response = Response::builder()
+            .header(CONTENT_TYPE, "text/html")
+            .header(CONTENT_LENGTH, 11)
+            .body(Body::empty())
+            .unwrap();
+        let response =
+            compress_head_response(response, Some("gzip"), &CompressionOptions::default()).unwrap();
+
+        assert!(response.headers().get(CONTENT_ENCODING).is_none());
+        assert_eq!(response.headers().get(CONTENT_LENGTH).unwrap(), "11");
+    }
+
+    #[test]
+    fn decompress_gzip_code() {
+        let code = b"export function handler() {}".to_vec();
+
+        assert_eq!(
+            decompress_code(Encoding::Gzip.encode(&code).unwrap()).unwrap(),
+            code
+        );
+        assert_eq!(decompress_code(code.clone()).unwrap(), code);
+        assert!(decompress_code(vec![0x28, 0xb5, 0x2f, 0xfd, 0]).is_err());
+        assert!(decompress_code(vec![0x1f, 0x8b, 0]).is_err());
+    }
+}
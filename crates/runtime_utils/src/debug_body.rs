@@ -0,0 +1,75 @@
+use hyper::HeaderMap;
+
+// Headers that can contain credentials, never logged
+const REDACTED_HEADERS: [&str; 5] = [
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+];
+
+pub const REDACTED: &str = "[redacted]";
+
+// `name: value` pairs separated by commas, with the values of
+// sensitive headers replaced by `[redacted]`
+pub fn format_headers(headers: &HeaderMap) -> String {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if REDACTED_HEADERS.contains(&name.as_str()) {
+                REDACTED
+            } else {
+                value.to_str().unwrap_or("[binary]")
+            };
+
+            format!("{name}: {value}")
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Bodies above `max_size` bytes are truncated, invalid
+// UTF-8 is replaced instead of failing
+pub fn format_body(body: &[u8], max_size: usize) -> String {
+    if body.len() <= max_size {
+        return String::from_utf8_lossy(body).into_owned();
+    }
+
+    format!(
+        "{}... ({} bytes truncated)",
+        String::from_utf8_lossy(&body[..max_size]),
+        body.len() - max_size
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redact_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", "text/plain".parse().unwrap());
+        headers.insert("authorization", "Bearer secret".parse().unwrap());
+        headers.insert("cookie", "session=secret".parse().unwrap());
+        headers.insert("x-api-key", "secret".parse().unwrap());
+
+        let formatted = format_headers(&headers);
+
+        assert!(!formatted.contains("secret"));
+        assert!(formatted.contains("content-type: text/plain"));
+        assert!(formatted.contains("authorization: [redacted]"));
+        assert!(formatted.contains("cookie: [redacted]"));
+    }
+
+    #[test]
+    fn truncate_body() {
+        assert_eq!(format_body(b"Hello", 5), "Hello");
+        assert_eq!(
+            format_body(b"Hello world", 5),
+            "Hello... (6 bytes truncated)"
+        );
+        assert_eq!(format_body(b"", 5), "");
+    }
+}
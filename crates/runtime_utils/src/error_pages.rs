@@ -0,0 +1,153 @@
+use anyhow::Result;
+use hyper::{body::HttpBody, header::CONTENT_TYPE, Body, Response};
+use lagon_runtime_http::LAGON_RUN_RESULT;
+use serde::Deserialize;
+
+const DEFAULT_CONTENT_TYPE: &str = "text/html; charset=utf-8";
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorPage {
+    pub body: String,
+    // Defaults to HTML
+    pub content_type: Option<String>,
+    // Defaults to the status of the replaced response
+    pub status: Option<u16>,
+}
+
+// Custom responses replacing the default ones of the runtime
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ErrorPages {
+    pub error: Option<ErrorPage>,
+    pub timeout: Option<ErrorPage>,
+    pub memory_limit: Option<ErrorPage>,
+    // Used for 404 responses without a body, e.g when
+    // the function returns `new Response(null, { status: 404 })`
+    pub not_found: Option<ErrorPage>,
+    // Used when the deployment is in maintenance
+    pub maintenance: Option<ErrorPage>,
+}
+
+impl ErrorPages {
+    fn find(&self, response: &Response<Body>) -> Option<&ErrorPage> {
+        let run_result = response
+            .headers()
+            .get(LAGON_RUN_RESULT)
+            .and_then(|value| value.to_str().ok());
+
+        match run_result {
+            Some("error") | Some("stack-overflow") => self.error.as_ref(),
+            Some("timeout") => self.timeout.as_ref(),
+            Some("memory-limit") => self.memory_limit.as_ref(),
+            Some("maintenance") => self.maintenance.as_ref(),
+            _ if response.status() == 404 && response.body().size_hint().exact() == Some(0) => {
+                self.not_found.as_ref()
+            }
+            _ => None,
+        }
+    }
+
+    pub fn apply(&self, response: Response<Body>) -> Result<Response<Body>> {
+        let page = match self.find(&response) {
+            Some(page) => page,
+            None => return Ok(response),
+        };
+
+        let mut builder = Response::builder()
+            .status(page.status.unwrap_or_else(|| response.status().as_u16()))
+            .header(
+                CONTENT_TYPE,
+                page.content_type.as_deref().unwrap_or(DEFAULT_CONTENT_TYPE),
+            );
+
+        if let Some(run_result) = response.headers().get(LAGON_RUN_RESULT) {
+            builder = builder.header(LAGON_RUN_RESULT, run_result);
+        }
+
+        Ok(builder.body(page.body.clone().into())?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::body::to_bytes;
+
+    fn error_pages() -> ErrorPages {
+        ErrorPages {
+            timeout: Some(ErrorPage {
+                body: r#"{"error":"timeout"}"#.into(),
+                content_type: Some("application/json".into()),
+                status: Some(503),
+            }),
+            not_found: Some(ErrorPage {
+                body: "<h1>Not found</h1>".into(),
+                content_type: None,
+                status: None,
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn replace_timeout() {
+        let response = Response::builder()
+            .status(504)
+            .header(LAGON_RUN_RESULT, "timeout")
+            .body("Timeouted".into())
+            .unwrap();
+
+        let response = error_pages().apply(response).unwrap();
+
+        assert_eq!(response.status(), 503);
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE).unwrap(),
+            "application/json"
+        );
+        assert_eq!(response.headers().get(LAGON_RUN_RESULT).unwrap(), "timeout");
+        assert_eq!(
+            to_bytes(response.into_body()).await.unwrap(),
+            r#"{"error":"timeout"}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn replace_empty_not_found() {
+        let response = Response::builder().status(404).body(Body::empty()).unwrap();
+
+        let response = error_pages().apply(response).unwrap();
+
+        assert_eq!(response.status(), 404);
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE).unwrap(),
+            DEFAULT_CONTENT_TYPE
+        );
+        assert_eq!(
+            to_bytes(response.into_body()).await.unwrap(),
+            "<h1>Not found</h1>"
+        );
+    }
+
+    #[tokio::test]
+    async fn keep_other_responses() {
+        let response = Response::builder()
+            .status(404)
+            .body("Custom not found".into())
+            .unwrap();
+        let response = error_pages().apply(response).unwrap();
+        assert_eq!(
+            to_bytes(response.into_body()).await.unwrap(),
+            "Custom not found"
+        );
+
+        let response = Response::builder()
+            .status(500)
+            .header(LAGON_RUN_RESULT, "error")
+            .body("Error".into())
+            .unwrap();
+        let response = error_pages().apply(response).unwrap();
+        assert_eq!(response.status(), 500);
+        assert_eq!(to_bytes(response.into_body()).await.unwrap(), "Error");
+    }
+}
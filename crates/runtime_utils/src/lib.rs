@@ -1,15 +1,31 @@
 use anyhow::{anyhow, Result};
-
+use auth::Auth;
+use error_pages::ErrorPages;
+use rate_limit::RateLimit;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
     env,
     fs::{self, File},
+    hash::{Hash, Hasher},
     io::Write,
     path::Path,
 };
 
 pub mod assets;
+pub mod auth;
+pub mod circuit_breaker;
+pub mod client_ip;
+pub mod compression;
+pub mod debug_body;
+pub mod error_pages;
+pub mod rate_limit;
 pub mod response;
+pub mod response_cache;
+pub mod secrets;
+pub mod trace_context;
+pub mod websocket;
 
 #[cfg(not(feature = "test"))]
 pub const DEPLOYMENTS_DIR: &str = "deployments";
@@ -17,6 +33,185 @@ pub const DEPLOYMENTS_DIR: &str = "deployments";
 #[cfg(feature = "test")]
 pub const DEPLOYMENTS_DIR: &str = "deployments_test";
 
+// Optional per-Function settings, stored as a JSON object
+// in the `config` column. Missing keys use their default value
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct DeploymentConfig {
+    // Create the isolate when the deployment is loaded instead
+    // of on the first request
+    pub warm: bool,
+    // Maximum number of requests handled at the same time, requests
+    // above this limit get a 429. Unlimited when not set
+    pub max_concurrency: Option<usize>,
+    // Number of isolates running the requests of the deployment, to run
+    // more of them in parallel for high-traffic deployments. Defaults to 1
+    pub isolate_pool_size: Option<usize>,
+    // Maximum size of request bodies in bytes, requests above
+    // get a 413. Uses the serverless default when not set
+    pub max_body_size: Option<usize>,
+    // Maximum size of response bodies in bytes, responses above get
+    // a 502 (or are aborted when streaming). Uses the serverless
+    // default when not set
+    pub max_response_size: Option<usize>,
+    // Rate limit of requests per client IP, overriding the
+    // global one set by the serverless
+    pub rate_limit: Option<RateLimit>,
+    // Send a part of the production traffic to another deployment
+    pub canary: Option<CanaryConfig>,
+    // Also send a part of the production traffic to another deployment,
+    // whose responses are discarded. Mirrored requests of all methods
+    // run twice, including the ones with side effects
+    pub shadow: Option<ShadowConfig>,
+    // Variants of the deployment with different environment variables,
+    // e.g for A/B tests. Clients are pinned to a variant with a cookie
+    pub variants: Vec<VariantConfig>,
+    // Maximum wall-clock time in milliseconds to wait for a response,
+    // e.g when awaiting a slow fetch(). Independent from the CPU
+    // timeouts of the isolate, requests above get a 504
+    pub request_timeout: Option<u64>,
+    // Send GET and HEAD requests once more to a new isolate when the one
+    // created for them fails to evaluate its code, e.g because of a failed
+    // fetch() at the top level. Compile errors, other methods and handler
+    // errors are never retried
+    pub retry_transient_errors: bool,
+    // Hostnames that fetch() can reach, e.g `api.domain.com` or
+    // `*.domain.com`, overriding the global ones set by the serverless
+    pub fetch_allowed_hosts: Option<Vec<String>>,
+    // Accept WebSocket upgrade requests, forwarding each message
+    // to the isolate as a request
+    pub websocket: bool,
+    // Custom responses for errors, timeouts, memory limits, empty
+    // 404s and maintenance, instead of the default pages
+    pub error_pages: ErrorPages,
+    // Answer all the requests with a 503 maintenance page without running
+    // the isolate, to take the deployment offline temporarily
+    pub maintenance: bool,
+    // Add a Server-Timing header to responses with the total and CPU
+    // time, visible in the browser devtools
+    pub server_timing: bool,
+    // Only serve the deployment from this region (e.g `eu-west-1`), other
+    // regions answer with a 421 so misrouted traffic is noticed
+    pub region: Option<String>,
+    // Log the headers and bodies of this many requests and responses, to
+    // debug a Function. Sensitive headers are redacted and bodies truncated
+    pub debug_bodies: Option<usize>,
+    // HTTP methods accepted by the Function (e.g `["POST"]` for webhooks),
+    // other requests get a 405 without running the isolate. All methods
+    // are allowed when not set
+    pub allowed_methods: Option<Vec<String>>,
+    // Content types of request bodies accepted by the Function (e.g
+    // `["application/json"]`, or `["text/*"]` for a whole type), other
+    // requests with a body get a 415 without running the isolate. All
+    // content types are allowed when not set
+    pub allowed_content_types: Option<Vec<String>>,
+    // Credentials required to call the Function, requests without
+    // them get a 401 without running the isolate
+    pub auth: Option<Auth>,
+    // Content types of assets by extension, e.g `{ "wgsl": "text/wgsl" }`,
+    // for extensions unknown to the serverless or to override its own
+    pub content_types: HashMap<String, String>,
+    // Give the request body to the Function as a stream while it's
+    // received, e.g for large uploads, instead of reading it entirely
+    // before running the isolate. `maxBodySize` still applies
+    pub stream_request_body: bool,
+    // Headers removed from the responses of the Function, e.g `["server"]`.
+    // Hop-by-hop headers, like Connection, are always removed
+    pub strip_response_headers: Vec<String>,
+    // Link headers preloading the critical assets of HTML responses, e.g
+    // `</style.css>; rel=preload; as=style`. Nothing is added when empty
+    pub early_hints: Vec<String>,
+    // Drop the logs of the Function less severe than this level, both
+    // from `console.*` and from its errors. All logs are kept when not set
+    pub log_level: Option<LogLevel>,
+}
+
+impl DeploymentConfig {
+    // Uppercased, with HEAD added when GET is allowed since HEAD
+    // requests are answered like GET requests without a body
+    pub fn get_allowed_methods(&self) -> Option<Vec<String>> {
+        let mut methods = self
+            .allowed_methods
+            .as_ref()?
+            .iter()
+            .map(|method| method.to_ascii_uppercase())
+            .collect::<Vec<_>>();
+
+        if methods.iter().any(|method| method == "GET")
+            && !methods.iter().any(|method| method == "HEAD")
+        {
+            methods.push(String::from("HEAD"));
+        }
+
+        Some(methods)
+    }
+
+    // Parameters like `charset` are ignored, and a missing content type
+    // is only allowed when no content types are set
+    pub fn is_content_type_allowed(&self, content_type: Option<&str>) -> bool {
+        let allowed_content_types = match &self.allowed_content_types {
+            Some(allowed_content_types) => allowed_content_types,
+            None => return true,
+        };
+
+        let content_type = match content_type
+            .and_then(|content_type| content_type.split(';').next())
+            .map(|content_type| content_type.trim().to_ascii_lowercase())
+        {
+            Some(content_type) if !content_type.is_empty() => content_type,
+            _ => return false,
+        };
+
+        allowed_content_types.iter().any(|allowed_content_type| {
+            let allowed_content_type = allowed_content_type.trim().to_ascii_lowercase();
+
+            match allowed_content_type.strip_suffix("/*") {
+                Some(allowed_type) => content_type
+                    .split_once('/')
+                    .map_or(false, |(content_type, _)| content_type == allowed_type),
+                None => content_type == allowed_content_type,
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CanaryConfig {
+    pub deployment_id: String,
+    // Percentage (0-100) of the requests handled by the canary deployment
+    pub weight: f64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShadowConfig {
+    pub deployment_id: String,
+    // Percentage (0-100) of the requests mirrored to the shadow deployment
+    pub weight: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VariantConfig {
+    pub name: String,
+    // Percentage (0-100) of the new clients pinned to the variant
+    pub weight: f64,
+    // Merged over the environment variables of the deployment
+    #[serde(default)]
+    pub environment_variables: HashMap<String, String>,
+}
+
 #[derive(Debug, Clone)]
 pub struct Deployment {
     pub id: String,
@@ -30,6 +225,12 @@ pub struct Deployment {
     pub total_timeout: usize, // in ms (MilliSeconds)
     pub is_production: bool,
     pub cron: Option<String>,
+    // SHA-256 of the code, in hex. Deployments created
+    // before the hash was stored don't have one
+    pub code_hash: Option<String>,
+    pub config: DeploymentConfig,
+    // Name of the variant run by this copy of the deployment, see `with_variant`
+    pub variant: Option<String>,
 }
 
 impl Deployment {
@@ -56,6 +257,33 @@ impl Deployment {
         domains
     }
 
+    // Copy of the deployment running one of its variants, with the
+    // environment variables of the variant. None if it doesn't exist
+    pub fn with_variant(&self, name: &str) -> Option<Deployment> {
+        let variant = self
+            .config
+            .variants
+            .iter()
+            .find(|variant| variant.name == name)?;
+
+        let mut deployment = self.clone();
+        deployment
+            .environment_variables
+            .extend(variant.environment_variables.clone());
+        deployment.variant = Some(variant.name.clone());
+
+        Some(deployment)
+    }
+
+    // Key of the isolates of the deployment in the workers, each
+    // variant having its own since their environment variables differ
+    pub fn worker_key(&self) -> String {
+        match &self.variant {
+            Some(variant) => format!("{}#{}", self.id, variant),
+            None => self.id.clone(),
+        }
+    }
+
     pub fn should_run_cron(&self) -> bool {
         self.is_production && self.cron.is_some()
     }
@@ -69,6 +297,28 @@ impl Deployment {
         Ok(code)
     }
 
+    // Detect code corrupted or truncated by the storage, which would
+    // otherwise fail with confusing errors when running the isolate
+    pub fn verify_code(&self, code: &[u8]) -> Result<()> {
+        let expected_hash = match &self.code_hash {
+            Some(code_hash) => code_hash,
+            None => return Ok(()),
+        };
+
+        let hash = format!("{:x}", Sha256::digest(code));
+
+        if !hash.eq_ignore_ascii_case(expected_hash) {
+            return Err(anyhow!(
+                "Code hash mismatch, expected {} but got {} ({} bytes)",
+                expected_hash,
+                hash,
+                code.len()
+            ));
+        }
+
+        Ok(())
+    }
+
     pub fn has_code(&self) -> bool {
         let path = Path::new(DEPLOYMENTS_DIR).join(self.id.clone() + ".js");
 
@@ -83,6 +333,40 @@ impl Deployment {
         Ok(())
     }
 
+    // Key of the code cache, which changes when the code or the
+    // environment variables (that are part of the compiled code) change
+    pub fn code_cache_key(&self, code: &str) -> u64 {
+        let mut environment_variables = self.environment_variables.iter().collect::<Vec<_>>();
+        environment_variables.sort_unstable();
+
+        let mut hasher = DefaultHasher::new();
+        code.hash(&mut hasher);
+        environment_variables.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    // The code cache is stored next to the code, prefixed by its key. Each
+    // variant has its own, since their environment variables differ
+    pub fn get_code_cache(&self, key: u64) -> Option<Vec<u8>> {
+        let content =
+            fs::read(Path::new(DEPLOYMENTS_DIR).join(self.worker_key() + ".cache")).ok()?;
+
+        if content.len() < 8 || content[..8] != key.to_le_bytes() {
+            return None;
+        }
+
+        Some(content[8..].to_vec())
+    }
+
+    pub fn write_code_cache(&self, key: u64, code_cache: &[u8]) -> Result<()> {
+        let mut file = File::create(Path::new(DEPLOYMENTS_DIR).join(self.worker_key() + ".cache"))?;
+
+        file.write_all(&key.to_le_bytes())?;
+        file.write_all(code_cache)?;
+
+        Ok(())
+    }
+
     pub fn write_asset(&self, asset: &str, content: &[u8]) -> Result<()> {
         let asset = asset.replace("public/", "");
         let asset = asset.as_str();
@@ -122,6 +406,9 @@ mod tests {
             total_timeout: 1000,
             is_production: false,
             cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig::default(),
         };
 
         assert_eq!(deployment.get_domains(), vec!["123.lagon.test".to_owned()]);
@@ -143,6 +430,9 @@ mod tests {
             total_timeout: 1000,
             is_production: false,
             cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig::default(),
         };
 
         assert_eq!(deployment.get_domains(), vec!["123.lagon.test".to_owned(),]);
@@ -164,6 +454,9 @@ mod tests {
             total_timeout: 1000,
             is_production: true,
             cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig::default(),
         };
 
         assert_eq!(
@@ -175,4 +468,158 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn deployment_code_cache_key() {
+        let mut deployment = Deployment {
+            id: "123".into(),
+            function_id: "456".into(),
+            function_name: "hello".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::from([
+                ("A".to_owned(), "1".to_owned()),
+                ("B".to_owned(), "2".to_owned()),
+            ]),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig::default(),
+        };
+
+        let key = deployment.code_cache_key("export function handler() {}");
+        assert_eq!(
+            key,
+            deployment.code_cache_key("export function handler() {}")
+        );
+        assert_ne!(
+            key,
+            deployment.code_cache_key("export function handler() { }")
+        );
+
+        deployment
+            .environment_variables
+            .insert("B".to_owned(), "3".to_owned());
+        assert_ne!(
+            key,
+            deployment.code_cache_key("export function handler() {}")
+        );
+    }
+
+    #[test]
+    fn deployment_verify_code() {
+        let mut deployment = Deployment {
+            id: "123".into(),
+            function_id: "456".into(),
+            function_name: "hello".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig::default(),
+        };
+
+        assert!(deployment.verify_code(b"hello").is_ok());
+
+        deployment.code_hash =
+            Some("2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824".into());
+        assert!(deployment.verify_code(b"hello").is_ok());
+        assert!(deployment.verify_code(b"hell").is_err());
+    }
+
+    #[test]
+    fn allowed_methods() {
+        let config = DeploymentConfig::default();
+
+        assert_eq!(config.get_allowed_methods(), None);
+
+        let config = DeploymentConfig {
+            allowed_methods: Some(vec!["POST".into()]),
+            ..Default::default()
+        };
+
+        assert_eq!(config.get_allowed_methods(), Some(vec!["POST".into()]));
+
+        let config = DeploymentConfig {
+            allowed_methods: Some(vec!["GET".into(), "post".into()]),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            config.get_allowed_methods(),
+            Some(vec!["GET".into(), "POST".into(), "HEAD".into()])
+        );
+    }
+
+    #[test]
+    fn allowed_content_types() {
+        let config = DeploymentConfig::default();
+
+        assert!(config.is_content_type_allowed(None));
+        assert!(config.is_content_type_allowed(Some("text/plain")));
+
+        let config = DeploymentConfig {
+            allowed_content_types: Some(vec!["application/json".into(), "text/*".into()]),
+            ..Default::default()
+        };
+
+        assert!(config.is_content_type_allowed(Some("application/json")));
+        assert!(config.is_content_type_allowed(Some("Application/JSON; charset=utf-8")));
+        assert!(config.is_content_type_allowed(Some("text/csv")));
+        assert!(!config.is_content_type_allowed(Some("application/xml")));
+        assert!(!config.is_content_type_allowed(Some("textual/plain")));
+        assert!(!config.is_content_type_allowed(Some("")));
+        assert!(!config.is_content_type_allowed(None));
+    }
+
+    #[test]
+    fn deployment_with_variant() {
+        let deployment = Deployment {
+            id: "123".into(),
+            function_id: "456".into(),
+            function_name: "hello".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::from([
+                ("A".into(), "1".into()),
+                ("B".into(), "2".into()),
+            ]),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig {
+                variants: vec![VariantConfig {
+                    name: "blue".into(),
+                    weight: 50.0,
+                    environment_variables: HashMap::from([("B".into(), "3".into())]),
+                }],
+                ..Default::default()
+            },
+        };
+
+        assert_eq!(deployment.worker_key(), "123");
+        assert!(deployment.with_variant("green").is_none());
+
+        let variant = deployment.with_variant("blue").unwrap();
+
+        assert_eq!(variant.worker_key(), "123#blue");
+        assert_eq!(
+            variant.environment_variables,
+            HashMap::from([("A".into(), "1".into()), ("B".into(), "3".into())])
+        );
+    }
 }
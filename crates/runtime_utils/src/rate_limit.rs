@@ -0,0 +1,128 @@
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RateLimit {
+    // Number of requests allowed per second, on average
+    pub requests_per_second: f64,
+    // Number of requests allowed at once. Defaults to `requests_per_second`
+    pub burst: Option<f64>,
+}
+
+impl RateLimit {
+    pub fn capacity(&self) -> f64 {
+        self.burst.unwrap_or(self.requests_per_second).max(1.0)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            limit,
+            tokens: limit.capacity(),
+            refilled_at: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.limit.requests_per_second)
+            .min(self.limit.capacity());
+        self.refilled_at = now;
+    }
+
+    // Take a token from the bucket, or return how long to wait
+    // before a token is available again
+    pub fn try_acquire(&mut self, limit: RateLimit, now: Instant) -> Result<(), Duration> {
+        // The limit can change when the deployment is updated
+        self.limit = limit;
+
+        self.refill(now);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+
+        if self.limit.requests_per_second <= 0.0 {
+            return Err(Duration::MAX);
+        }
+
+        Err(Duration::from_secs_f64(
+            (1.0 - self.tokens) / self.limit.requests_per_second,
+        ))
+    }
+
+    // A full bucket behaves like a new one, so it can be
+    // removed to free memory
+    pub fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+
+        self.tokens >= self.limit.capacity()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: RateLimit = RateLimit {
+        requests_per_second: 2.0,
+        burst: Some(3.0),
+    };
+
+    #[test]
+    fn allow_burst() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(LIMIT, now);
+
+        assert!(bucket.try_acquire(LIMIT, now).is_ok());
+        assert!(bucket.try_acquire(LIMIT, now).is_ok());
+        assert!(bucket.try_acquire(LIMIT, now).is_ok());
+        assert_eq!(
+            bucket.try_acquire(LIMIT, now),
+            Err(Duration::from_millis(500))
+        );
+    }
+
+    #[test]
+    fn refill_over_time() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(LIMIT, now);
+
+        for _ in 0..3 {
+            bucket.try_acquire(LIMIT, now).unwrap();
+        }
+
+        assert!(!bucket.is_full(now));
+        assert!(bucket
+            .try_acquire(LIMIT, now + Duration::from_millis(500))
+            .is_ok());
+        assert!(bucket
+            .try_acquire(LIMIT, now + Duration::from_millis(500))
+            .is_err());
+        assert!(bucket.is_full(now + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn default_burst() {
+        let limit = RateLimit {
+            requests_per_second: 1.0,
+            burst: None,
+        };
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(limit, now);
+
+        assert!(bucket.try_acquire(limit, now).is_ok());
+        assert_eq!(bucket.try_acquire(limit, now), Err(Duration::from_secs(1)));
+    }
+}
@@ -1,26 +1,170 @@
 use anyhow::Result;
 use flume::Receiver;
-use hyper::{body::Bytes, Body, Response};
-use lagon_runtime_http::{RunResult, StreamResult};
+use hyper::{
+    body::{Bytes, HttpBody},
+    header::{
+        HeaderName, HeaderValue, CONNECTION, CONTENT_LENGTH, CONTENT_TYPE, LINK,
+        STRICT_TRANSPORT_SECURITY, TE, TRAILER, TRANSFER_ENCODING, UPGRADE,
+    },
+    Body, HeaderMap, Response,
+};
+use lagon_runtime_http::{RunResult, StreamResult, LAGON_RUN_RESULT};
 use std::future::Future;
 
+pub const PAGE_400: &str = include_str!("../public/400.html");
+pub const PAGE_401: &str = include_str!("../public/401.html");
 pub const PAGE_404: &str = include_str!("../public/404.html");
+pub const PAGE_405: &str = include_str!("../public/405.html");
+pub const PAGE_408: &str = include_str!("../public/408.html");
 pub const PAGE_403: &str = include_str!("../public/403.html");
+pub const PAGE_413: &str = include_str!("../public/413.html");
+pub const PAGE_415: &str = include_str!("../public/415.html");
+pub const PAGE_417: &str = include_str!("../public/417.html");
+pub const PAGE_421: &str = include_str!("../public/421.html");
+pub const PAGE_429: &str = include_str!("../public/429.html");
+pub const PAGE_431: &str = include_str!("../public/431.html");
 pub const PAGE_502: &str = include_str!("../public/502.html");
+pub const PAGE_503: &str = include_str!("../public/503.html");
 pub const PAGE_500: &str = include_str!("../public/500.html");
+pub const PAGE_504: &str = include_str!("../public/504.html");
+pub const PAGE_MAINTENANCE: &str = include_str!("../public/maintenance.html");
+
+pub const BODY_MEMORY_LIMIT: &str =
+    r#"{"error":"memory_limit","message":"Function exceeded its memory limit"}"#;
+
+pub const BODY_STACK_OVERFLOW: &str =
+    r#"{"error":"stack_overflow","message":"Function exceeded its maximum call stack size"}"#;
 
 pub const FAVICON_URL: &str = "/favicon.ico";
 
+// Headers only meaningful for a single connection, which a proxy must
+// not forward. A Transfer-Encoding set by a Function could otherwise
+// conflict with the framing of hyper and allow response smuggling
+const HOP_BY_HOP_HEADERS: [HeaderName; 5] = [CONNECTION, TE, TRAILER, TRANSFER_ENCODING, UPGRADE];
+const KEEP_ALIVE: &str = "keep-alive";
+const PROXY_CONNECTION: &str = "proxy-connection";
+
 pub enum ResponseEvent {
+    // Sent for each chunk of a stream response, and once
+    // for the whole body of a non-stream response
+    Data(usize),
     Bytes(usize, Option<u128>),
     StreamDoneNoDataError,
     UnexpectedStreamResult(RunResult),
     LimitsReached(RunResult),
     Error(RunResult),
+    // The body is larger than the maximum response size
+    SizeLimitReached(usize),
+}
+
+fn size_limit_reached(total_bytes: usize, max_size: Option<usize>) -> bool {
+    max_size.map_or(false, |max_size| total_bytes > max_size)
+}
+
+fn size_limit_response() -> Result<Response<Body>> {
+    Ok(Response::builder()
+        .status(502)
+        .header(LAGON_RUN_RESULT, "response-size-limit")
+        .body(PAGE_502.into())?)
+}
+
+// Responses to HEAD requests keep their headers, with the length of the
+// body when it's known, but their body is dropped without being read
+pub fn into_head_response(response: Response<Body>) -> Response<Body> {
+    let (mut parts, body) = response.into_parts();
+
+    if let Some(size) = body.size_hint().exact() {
+        parts.headers.entry(CONTENT_LENGTH).or_insert(size.into());
+    }
+
+    Response::from_parts(parts, Body::empty())
+}
+
+// Add the headers that the response doesn't already set, so functions can
+// override them. HSTS is ignored by browsers over plain HTTP, and would
+// break the domains that aren't served over TLS, so it's only sent with TLS
+pub fn apply_default_headers(response: &mut Response<Body>, headers: &HeaderMap, is_tls: bool) {
+    for (name, value) in headers {
+        if name == STRICT_TRANSPORT_SECURITY && !is_tls {
+            continue;
+        }
+
+        response
+            .headers_mut()
+            .entry(name)
+            .or_insert_with(|| value.clone());
+    }
+}
+
+// Add the preload Link headers of `links` (e.g `</style.css>; rel=preload;
+// as=style`) to HTML responses. hyper can't send 103 Early Hints interim
+// responses, but browsers also start preloading from the final response
+// headers, before parsing its body. Invalid values are ignored
+pub fn apply_early_hints(response: &mut Response<Body>, links: &[String]) {
+    let is_html = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map_or(false, |value| {
+            value
+                .trim_start()
+                .to_ascii_lowercase()
+                .starts_with("text/html")
+        });
+
+    if !is_html {
+        return;
+    }
+
+    for link in links {
+        if let Ok(value) = HeaderValue::from_str(link) {
+            response.headers_mut().append(LINK, value);
+        }
+    }
+}
+
+// Remove the hop-by-hop headers set by the Function, including the ones
+// listed in its Connection header, and the headers of `denied` (e.g
+// `server` or internal debug headers), which are case-insensitive
+pub fn strip_response_headers(response: &mut Response<Body>, denied: &[String]) {
+    let headers = response.headers_mut();
+
+    for name in denied {
+        headers.remove(name.as_str());
+    }
+
+    strip_hop_by_hop_headers(headers);
 }
 
+// Also used for the requests sent to the isolates, which
+// shouldn't see the headers of the connection to the node
+pub fn strip_hop_by_hop_headers(headers: &mut HeaderMap) {
+    let connection_headers = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect::<Vec<_>>();
+
+    for name in &connection_headers {
+        headers.remove(name.as_str());
+    }
+
+    for name in HOP_BY_HOP_HEADERS {
+        headers.remove(name);
+    }
+
+    headers.remove(KEEP_ALIVE);
+    headers.remove(PROXY_CONNECTION);
+}
+
+// Responses larger than `max_size` are replaced by a 502, or aborted
+// if the response has already been sent for stream responses
 pub async fn handle_response<F>(
     rx: Receiver<RunResult>,
+    max_size: Option<usize>,
     on_event: impl Fn(ResponseEvent) -> F + Send + Sync + 'static,
 ) -> Result<Response<Body>>
 where
@@ -43,6 +187,17 @@ where
                 StreamResult::Data(bytes) => {
                     total_bytes += bytes.len();
 
+                    if size_limit_reached(total_bytes, max_size) {
+                        on_event(ResponseEvent::SizeLimitReached(
+                            max_size.unwrap_or_default(),
+                        ))
+                        .await?;
+
+                        return size_limit_response();
+                    }
+
+                    on_event(ResponseEvent::Data(bytes.len())).await?;
+
                     let bytes = Bytes::from(bytes);
                     stream_tx.send_async(Ok(bytes)).await.unwrap_or(());
                 }
@@ -63,6 +218,29 @@ where
                         RunResult::Stream(StreamResult::Data(bytes)) => {
                             total_bytes += bytes.len();
 
+                            if size_limit_reached(total_bytes, max_size) {
+                                on_event(ResponseEvent::SizeLimitReached(
+                                    max_size.unwrap_or_default(),
+                                ))
+                                .await
+                                .unwrap_or(());
+
+                                // Abort the body instead of closing it, so the
+                                // client doesn't see a truncated response as complete
+                                stream_tx
+                                    .send_async(Err(std::io::Error::new(
+                                        std::io::ErrorKind::Other,
+                                        "Response size limit reached",
+                                    )))
+                                    .await
+                                    .unwrap_or(());
+                                break;
+                            }
+
+                            on_event(ResponseEvent::Data(bytes.len()))
+                                .await
+                                .unwrap_or(());
+
                             let bytes = Bytes::from(bytes);
                             stream_tx.send_async(Ok(bytes)).await.unwrap_or(());
                         }
@@ -79,36 +257,87 @@ where
                                 .await
                                 .unwrap_or(());
 
-                            // Close the stream by sending empty bytes
-                            stream_tx.send_async(Ok(Bytes::new())).await.unwrap_or(());
+                            // Abort the body, the stream failed midway
+                            stream_tx
+                                .send_async(Err(std::io::Error::new(
+                                    std::io::ErrorKind::Other,
+                                    "Stream errored",
+                                )))
+                                .await
+                                .unwrap_or(());
                             break;
                         }
                     }
                 }
             });
 
-            let response_builder = response_builder_rx.recv_async().await?;
-            let response = response_builder.body(body)?;
+            // The response builder can be missing if the stream errored
+            // before the response was sent, in which case the task above
+            // already reported the error
+            let response = match response_builder_rx.recv_async().await {
+                Ok(response_builder) => response_builder.body(body)?,
+                Err(_) => Response::builder().status(500).body(PAGE_500.into())?,
+            };
 
             Ok(response)
         }
         RunResult::Response(response, elapsed) => {
+            let bytes = response.body().size_hint().exact().unwrap_or(0) as usize;
+
+            if size_limit_reached(bytes, max_size) {
+                on_event(ResponseEvent::SizeLimitReached(
+                    max_size.unwrap_or_default(),
+                ))
+                .await?;
+
+                return size_limit_response();
+            }
+
+            on_event(ResponseEvent::Data(bytes)).await?;
+
             let event = ResponseEvent::Bytes(0, elapsed.map(|duration| duration.as_micros()));
             on_event(event).await?;
 
             Ok(response)
         }
-        RunResult::Timeout | RunResult::MemoryLimit => {
+        RunResult::Timeout => {
+            let event = ResponseEvent::LimitsReached(result);
+            on_event(event).await?;
+
+            Ok(Response::builder()
+                .status(504)
+                .header(LAGON_RUN_RESULT, "timeout")
+                .body(PAGE_504.into())?)
+        }
+        RunResult::MemoryLimit => {
             let event = ResponseEvent::LimitsReached(result);
             on_event(event).await?;
 
-            Ok(Response::builder().status(502).body(PAGE_502.into())?)
+            Ok(Response::builder()
+                .status(502)
+                .header(LAGON_RUN_RESULT, "memory-limit")
+                .header("content-type", "application/json")
+                .body(BODY_MEMORY_LIMIT.into())?)
         }
-        RunResult::Error(_) => {
+        // 508 Loop Detected, as a runaway recursion is the usual cause
+        RunResult::StackOverflow(_) => {
+            let event = ResponseEvent::LimitsReached(result);
+            on_event(event).await?;
+
+            Ok(Response::builder()
+                .status(508)
+                .header(LAGON_RUN_RESULT, "stack-overflow")
+                .header("content-type", "application/json")
+                .body(BODY_STACK_OVERFLOW.into())?)
+        }
+        RunResult::Error(_) | RunResult::CompileError(_) | RunResult::EvaluationError(_) => {
             let event = ResponseEvent::Error(result);
             on_event(event).await?;
 
-            Ok(Response::builder().status(500).body(PAGE_500.into())?)
+            Ok(Response::builder()
+                .status(500)
+                .header(LAGON_RUN_RESULT, "error")
+                .body(PAGE_500.into())?)
         }
     }
 }
@@ -117,6 +346,10 @@ where
 mod tests {
     use super::*;
     use hyper::{body::to_bytes, Response};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
     use std::time::Duration;
 
     #[tokio::test]
@@ -124,7 +357,9 @@ mod tests {
         let (tx, rx) = flume::unbounded::<RunResult>();
 
         let handle = tokio::spawn(async move {
-            let mut response = handle_response(rx, |_| async { Ok(()) }).await.unwrap();
+            let mut response = handle_response(rx, None, |_| async { Ok(()) })
+                .await
+                .unwrap();
 
             assert_eq!(response.status(), 200);
             assert_eq!(
@@ -148,7 +383,9 @@ mod tests {
         let (tx, rx) = flume::unbounded::<RunResult>();
 
         let handle = tokio::spawn(async move {
-            let mut response = handle_response(rx, |_| async { Ok(()) }).await.unwrap();
+            let mut response = handle_response(rx, None, |_| async { Ok(()) })
+                .await
+                .unwrap();
 
             assert_eq!(response.status(), 200);
             assert_eq!(
@@ -185,7 +422,9 @@ mod tests {
         let (tx, rx) = flume::unbounded::<RunResult>();
 
         let handle = tokio::spawn(async move {
-            let mut response = handle_response(rx, |_| async { Ok(()) }).await.unwrap();
+            let mut response = handle_response(rx, None, |_| async { Ok(()) })
+                .await
+                .unwrap();
 
             assert_eq!(response.status(), 200);
             assert_eq!(
@@ -216,4 +455,323 @@ mod tests {
 
         handle.await.unwrap();
     }
+
+    #[tokio::test]
+    async fn stream_error_before_response() {
+        let (tx, rx) = flume::unbounded::<RunResult>();
+
+        let handle = tokio::spawn(async move {
+            let mut response = handle_response(rx, None, |_| async { Ok(()) })
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), 500);
+            assert_eq!(
+                to_bytes(response.body_mut()).await.unwrap(),
+                Bytes::from(PAGE_500)
+            );
+        });
+
+        tx.send_async(RunResult::Stream(StreamResult::Data(b"Hello".to_vec())))
+            .await
+            .unwrap();
+
+        tx.send_async(RunResult::Error("Stream errored".into()))
+            .await
+            .unwrap();
+
+        drop(tx);
+
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn stream_error_after_response() {
+        let (tx, rx) = flume::unbounded::<RunResult>();
+
+        let handle = tokio::spawn(async move {
+            let mut response = handle_response(rx, None, |_| async { Ok(()) })
+                .await
+                .unwrap();
+
+            // The body is aborted instead of ending like a complete one
+            assert_eq!(response.status(), 200);
+            assert!(to_bytes(response.body_mut()).await.is_err());
+        });
+
+        tx.send_async(RunResult::Stream(StreamResult::Start(Response::builder())))
+            .await
+            .unwrap();
+
+        tx.send_async(RunResult::Stream(StreamResult::Data(b"Hello".to_vec())))
+            .await
+            .unwrap();
+
+        tx.send_async(RunResult::Error("Stream errored".into()))
+            .await
+            .unwrap();
+
+        drop(tx);
+
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn timeout() {
+        let (tx, rx) = flume::unbounded::<RunResult>();
+
+        let handle = tokio::spawn(async move {
+            let mut response = handle_response(rx, None, |_| async { Ok(()) })
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), 504);
+            assert_eq!(response.headers().get(LAGON_RUN_RESULT).unwrap(), "timeout");
+            assert_eq!(
+                to_bytes(response.body_mut()).await.unwrap(),
+                Bytes::from(PAGE_504)
+            );
+        });
+
+        tx.send_async(RunResult::Timeout).await.unwrap();
+
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn memory_limit() {
+        let (tx, rx) = flume::unbounded::<RunResult>();
+
+        let handle = tokio::spawn(async move {
+            let mut response = handle_response(rx, None, |_| async { Ok(()) })
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), 502);
+            assert_eq!(
+                response.headers().get(LAGON_RUN_RESULT).unwrap(),
+                "memory-limit"
+            );
+            assert_eq!(
+                to_bytes(response.body_mut()).await.unwrap(),
+                Bytes::from(BODY_MEMORY_LIMIT)
+            );
+        });
+
+        tx.send_async(RunResult::MemoryLimit).await.unwrap();
+
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn stack_overflow() {
+        let (tx, rx) = flume::unbounded::<RunResult>();
+
+        let handle = tokio::spawn(async move {
+            let mut response = handle_response(rx, None, |_| async { Ok(()) })
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), 508);
+            assert_eq!(
+                response.headers().get(LAGON_RUN_RESULT).unwrap(),
+                "stack-overflow"
+            );
+            assert_eq!(
+                to_bytes(response.body_mut()).await.unwrap(),
+                Bytes::from(BODY_STACK_OVERFLOW)
+            );
+        });
+
+        tx.send_async(RunResult::StackOverflow(
+            "Uncaught RangeError: Maximum call stack size exceeded".into(),
+        ))
+        .await
+        .unwrap();
+
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn size_limit() {
+        let (tx, rx) = flume::unbounded::<RunResult>();
+
+        let handle = tokio::spawn(async move {
+            let limit = Arc::new(AtomicUsize::new(0));
+            let limit_handle = Arc::clone(&limit);
+
+            let mut response = handle_response(rx, Some(5), move |event| {
+                let limit = Arc::clone(&limit_handle);
+
+                async move {
+                    if let ResponseEvent::SizeLimitReached(max_size) = event {
+                        limit.store(max_size, Ordering::SeqCst);
+                    }
+
+                    Ok(())
+                }
+            })
+            .await
+            .unwrap();
+
+            assert_eq!(response.status(), 502);
+            assert_eq!(
+                response.headers().get(LAGON_RUN_RESULT).unwrap(),
+                "response-size-limit"
+            );
+            assert_eq!(
+                to_bytes(response.body_mut()).await.unwrap(),
+                Bytes::from(PAGE_502)
+            );
+            assert_eq!(limit.load(Ordering::SeqCst), 5);
+        });
+
+        tx.send_async(RunResult::Response(
+            Response::new("Hello World".into()),
+            None,
+        ))
+        .await
+        .unwrap();
+
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn stream_size_limit() {
+        let (tx, rx) = flume::unbounded::<RunResult>();
+
+        let handle = tokio::spawn(async move {
+            let mut response = handle_response(rx, Some(8), |_| async { Ok(()) })
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), 200);
+            assert!(to_bytes(response.body_mut()).await.is_err());
+        });
+
+        tx.send_async(RunResult::Stream(StreamResult::Start(Response::builder())))
+            .await
+            .unwrap();
+
+        tx.send_async(RunResult::Stream(StreamResult::Data(b"Hello".to_vec())))
+            .await
+            .unwrap();
+
+        tx.send_async(RunResult::Stream(StreamResult::Data(b" world".to_vec())))
+            .await
+            .unwrap();
+
+        tx.send_async(RunResult::Stream(StreamResult::Done(Duration::from_secs(
+            0,
+        ))))
+        .await
+        .unwrap();
+
+        drop(tx);
+
+        handle.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn head_response() {
+        let response = Response::builder()
+            .header("x-custom", "value")
+            .body("Hello World".into())
+            .unwrap();
+        let mut response = into_head_response(response);
+
+        assert_eq!(response.headers().get(CONTENT_LENGTH).unwrap(), "11");
+        assert_eq!(response.headers().get("x-custom").unwrap(), "value");
+        assert_eq!(to_bytes(response.body_mut()).await.unwrap(), Bytes::new());
+    }
+
+    #[test]
+    fn default_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-frame-options", "DENY".parse().unwrap());
+        headers.insert(
+            "content-security-policy",
+            "default-src 'self'".parse().unwrap(),
+        );
+        headers.insert(
+            STRICT_TRANSPORT_SECURITY,
+            "max-age=63072000".parse().unwrap(),
+        );
+
+        let mut response = Response::builder()
+            .header("x-frame-options", "SAMEORIGIN")
+            .body(Body::empty())
+            .unwrap();
+        apply_default_headers(&mut response, &headers, false);
+
+        assert_eq!(
+            response.headers().get("x-frame-options").unwrap(),
+            "SAMEORIGIN"
+        );
+        assert_eq!(
+            response.headers().get("content-security-policy").unwrap(),
+            "default-src 'self'"
+        );
+        assert!(response.headers().get(STRICT_TRANSPORT_SECURITY).is_none());
+
+        let mut response = Response::new(Body::empty());
+        apply_default_headers(&mut response, &headers, true);
+
+        assert_eq!(response.headers().get("x-frame-options").unwrap(), "DENY");
+        assert_eq!(
+            response.headers().get(STRICT_TRANSPORT_SECURITY).unwrap(),
+            "max-age=63072000"
+        );
+    }
+
+    #[test]
+    fn strip_headers() {
+        let mut response = Response::builder()
+            .header("server", "internal")
+            .header("x-debug", "1")
+            .header(CONNECTION, "keep-alive, X-Internal")
+            .header(KEEP_ALIVE, "timeout=5")
+            .header(TRANSFER_ENCODING, "chunked")
+            .header("x-internal", "1")
+            .header("x-custom", "custom")
+            .body(Body::empty())
+            .unwrap();
+        strip_response_headers(&mut response, &["Server".into(), "x-debug".into()]);
+
+        assert_eq!(response.headers().len(), 1);
+        assert_eq!(response.headers().get("x-custom").unwrap(), "custom");
+    }
+
+    #[test]
+    fn early_hints() {
+        let links = vec![
+            "</style.css>; rel=preload; as=style".into(),
+            "</font.woff2>; rel=preload; as=font; crossorigin".into(),
+            "invalid\nlink".into(),
+        ];
+
+        let mut response = Response::builder()
+            .header(CONTENT_TYPE, "text/html; charset=utf-8")
+            .header(LINK, "</app.js>; rel=preload; as=script")
+            .body(Body::empty())
+            .unwrap();
+        apply_early_hints(&mut response, &links);
+
+        assert_eq!(
+            response.headers().get_all(LINK).iter().collect::<Vec<_>>(),
+            vec![
+                "</app.js>; rel=preload; as=script",
+                "</style.css>; rel=preload; as=style",
+                "</font.woff2>; rel=preload; as=font; crossorigin",
+            ]
+        );
+
+        let mut response = Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::empty())
+            .unwrap();
+        apply_early_hints(&mut response, &links);
+
+        assert!(response.headers().get(LINK).is_none());
+    }
 }
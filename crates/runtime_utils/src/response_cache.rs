@@ -0,0 +1,468 @@
+use anyhow::Result;
+use hyper::{
+    body::{to_bytes, Bytes, HttpBody},
+    header::{
+        HeaderName, HeaderValue, AGE, AUTHORIZATION, CACHE_CONTROL, HOST, SET_COOKIE, UPGRADE, VARY,
+    },
+    http::response::Parts,
+    Body, HeaderMap, Method, Request, Response, StatusCode,
+};
+use lru::LruCache;
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+struct CachedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    stored_at: Instant,
+    expires_at: Instant,
+    // Request headers listed in the Vary header of the response,
+    // with their value in the request that was cached
+    vary: Vec<(HeaderName, Option<HeaderValue>)>,
+}
+
+impl CachedResponse {
+    fn size(&self) -> usize {
+        self.body.len()
+            + self
+                .headers
+                .iter()
+                .map(|(name, value)| name.as_str().len() + value.len())
+                .sum::<usize>()
+    }
+
+    fn matches(&self, request_headers: &HeaderMap) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| request_headers.get(name) == value.as_ref())
+    }
+}
+
+struct Entries {
+    cache: LruCache<String, CachedResponse>,
+    size: usize,
+}
+
+// LRU cache of Function responses, limited by the total size of the
+// cached bodies and headers. Only one variant is kept per URL, a request
+// with different Vary headers replaces it
+pub struct ResponseCache {
+    entries: Mutex<Entries>,
+    max_size: usize,
+}
+
+// Freshness lifetime of a response from its Cache-Control header, or None
+// when a shared cache must not store it. s-maxage takes precedence over
+// max-age since this cache is shared between all the clients
+fn get_ttl(headers: &HeaderMap) -> Option<Duration> {
+    if headers.contains_key(SET_COOKIE) {
+        return None;
+    }
+
+    if headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.split(',').any(|name| name.trim() == "*"))
+    {
+        return None;
+    }
+
+    let mut max_age = None;
+    let mut s_maxage = None;
+
+    for directive in headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+    {
+        let (name, value) = directive
+            .split_once('=')
+            .map_or((directive, None), |(name, value)| (name, Some(value)));
+        let value = value.and_then(|value| value.trim().trim_matches('"').parse::<u64>().ok());
+
+        match name.trim().to_ascii_lowercase().as_str() {
+            "private" | "no-store" | "no-cache" => return None,
+            "max-age" => max_age = value,
+            "s-maxage" => s_maxage = value,
+            _ => {}
+        }
+    }
+
+    s_maxage
+        .or(max_age)
+        .filter(|ttl| *ttl > 0)
+        .map(Duration::from_secs)
+}
+
+fn get_vary(
+    response_headers: &HeaderMap,
+    request_headers: &HeaderMap,
+) -> Vec<(HeaderName, Option<HeaderValue>)> {
+    response_headers
+        .get_all(VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .map(|name| {
+            let value = request_headers.get(&name).cloned();
+            (name, value)
+        })
+        .collect()
+}
+
+impl ResponseCache {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            entries: Mutex::new(Entries {
+                cache: LruCache::unbounded(),
+                size: 0,
+            }),
+            max_size,
+        }
+    }
+
+    // Only GET and HEAD requests without credentials can be answered from
+    // the cache, HEAD requests using the cached GET response. The host is
+    // part of the key since a deployment can be served on multiple domains
+    pub fn get_key(deployment_id: &str, req: &Request<Body>) -> Option<String> {
+        if (req.method() != Method::GET && req.method() != Method::HEAD)
+            || req.headers().contains_key(AUTHORIZATION)
+            || req.headers().contains_key(UPGRADE)
+        {
+            return None;
+        }
+
+        let host = req
+            .headers()
+            .get(HOST)
+            .and_then(|host| host.to_str().ok())
+            .or_else(|| req.uri().authority().map(|authority| authority.as_str()))
+            .unwrap_or_default();
+        let path = req
+            .uri()
+            .path_and_query()
+            .map_or("/", |path_and_query| path_and_query.as_str());
+
+        Some(format!("{} {}{}", deployment_id, host, path))
+    }
+
+    pub fn get(
+        &self,
+        key: &str,
+        request_headers: &HeaderMap,
+        now: Instant,
+    ) -> Option<Response<Body>> {
+        let mut entries = self.entries.lock().unwrap();
+
+        let is_fresh = entries.cache.get(key)?.expires_at > now;
+
+        if !is_fresh {
+            if let Some(entry) = entries.cache.pop(key) {
+                entries.size -= entry.size();
+            }
+
+            return None;
+        }
+
+        let entry = entries.cache.get(key)?;
+
+        if !entry.matches(request_headers) {
+            return None;
+        }
+
+        let mut response = Response::new(Body::from(entry.body.clone()));
+        *response.status_mut() = entry.status;
+        *response.headers_mut() = entry.headers.clone();
+        response
+            .headers_mut()
+            .insert(AGE, now.duration_since(entry.stored_at).as_secs().into());
+
+        Some(response)
+    }
+
+    // Buffer and store cacheable responses, returning the same response
+    // to send. Stream responses are never cached
+    pub async fn store(
+        &self,
+        key: String,
+        request_headers: &HeaderMap,
+        response: Response<Body>,
+        now: Instant,
+    ) -> Result<Response<Body>> {
+        if response.status() != StatusCode::OK {
+            return Ok(response);
+        }
+
+        let ttl = match get_ttl(response.headers()) {
+            Some(ttl) => ttl,
+            None => return Ok(response),
+        };
+
+        match response.body().size_hint().exact() {
+            Some(size) if size as usize <= self.max_size => {}
+            _ => return Ok(response),
+        };
+
+        let (parts, body) = response.into_parts();
+        let body = to_bytes(body).await?;
+
+        self.insert(key, request_headers, &parts, body.clone(), now, ttl);
+
+        Ok(Response::from_parts(parts, Body::from(body)))
+    }
+
+    fn insert(
+        &self,
+        key: String,
+        request_headers: &HeaderMap,
+        parts: &Parts,
+        body: Bytes,
+        now: Instant,
+        ttl: Duration,
+    ) {
+        let entry = CachedResponse {
+            status: parts.status,
+            headers: parts.headers.clone(),
+            body,
+            stored_at: now,
+            expires_at: now + ttl,
+            vary: get_vary(&parts.headers, request_headers),
+        };
+        let size = entry.size();
+
+        if size > self.max_size {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+
+        if let Some(previous) = entries.cache.put(key, entry) {
+            entries.size -= previous.size();
+        }
+
+        entries.size += size;
+
+        while entries.size > self.max_size {
+            match entries.cache.pop_lru() {
+                Some((_, evicted)) => entries.size -= evicted.size(),
+                None => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header_map(headers: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+
+        for (key, value) in headers {
+            map.append(*key, value.parse().unwrap());
+        }
+
+        map
+    }
+
+    fn response(headers: &[(&'static str, &'static str)], body: &'static str) -> Response<Body> {
+        let mut response = Response::new(Body::from(body));
+        *response.headers_mut() = header_map(headers);
+        response
+    }
+
+    #[test]
+    fn ttl() {
+        assert_eq!(
+            get_ttl(&header_map(&[("cache-control", "public, max-age=60")])),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            get_ttl(&header_map(&[(
+                "cache-control",
+                "max-age=60, s-maxage=300"
+            )])),
+            Some(Duration::from_secs(300))
+        );
+        assert_eq!(get_ttl(&header_map(&[])), None);
+        assert_eq!(
+            get_ttl(&header_map(&[("cache-control", "max-age=0")])),
+            None
+        );
+        assert_eq!(
+            get_ttl(&header_map(&[("cache-control", "private, max-age=60")])),
+            None
+        );
+        assert_eq!(get_ttl(&header_map(&[("cache-control", "no-store")])), None);
+        assert_eq!(
+            get_ttl(&header_map(&[
+                ("cache-control", "max-age=60"),
+                ("set-cookie", "session=1")
+            ])),
+            None
+        );
+        assert_eq!(
+            get_ttl(&header_map(&[
+                ("cache-control", "max-age=60"),
+                ("vary", "*")
+            ])),
+            None
+        );
+    }
+
+    #[test]
+    fn key() {
+        let request = Request::builder()
+            .uri("https://hello.lagon.app/path?query=1")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            ResponseCache::get_key("123", &request),
+            Some(String::from("123 hello.lagon.app/path?query=1"))
+        );
+
+        let request = Request::builder()
+            .uri("/path?query=1")
+            .header(HOST, "hello.lagon.app")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            ResponseCache::get_key("123", &request),
+            Some(String::from("123 hello.lagon.app/path?query=1"))
+        );
+
+        let request = Request::builder()
+            .uri("/path?query=1")
+            .header(HOST, "world.lagon.app")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            ResponseCache::get_key("123", &request),
+            Some(String::from("123 world.lagon.app/path?query=1"))
+        );
+
+        let request = Request::builder()
+            .method(Method::POST)
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(ResponseCache::get_key("123", &request), None);
+
+        let request = Request::builder()
+            .header(AUTHORIZATION, "Bearer token")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(ResponseCache::get_key("123", &request), None);
+    }
+
+    #[tokio::test]
+    async fn store_and_get() {
+        let cache = ResponseCache::new(1024);
+        let now = Instant::now();
+        let request_headers = HeaderMap::new();
+
+        let response = cache
+            .store(
+                "key".into(),
+                &request_headers,
+                response(&[("cache-control", "max-age=60")], "hello"),
+                now,
+            )
+            .await
+            .unwrap();
+        assert_eq!(to_bytes(response.into_body()).await.unwrap(), "hello");
+
+        let response = cache
+            .get("key", &request_headers, now + Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(response.headers()[AGE], "10");
+        assert_eq!(to_bytes(response.into_body()).await.unwrap(), "hello");
+
+        assert!(cache
+            .get("key", &request_headers, now + Duration::from_secs(60))
+            .is_none());
+        assert!(cache.get("other", &request_headers, now).is_none());
+    }
+
+    #[tokio::test]
+    async fn store_uncacheable() {
+        let cache = ResponseCache::new(1024);
+        let now = Instant::now();
+        let request_headers = HeaderMap::new();
+
+        cache
+            .store(
+                "key".into(),
+                &request_headers,
+                response(&[("cache-control", "private, max-age=60")], "hello"),
+                now,
+            )
+            .await
+            .unwrap();
+        assert!(cache.get("key", &request_headers, now).is_none());
+
+        let mut not_found = response(&[("cache-control", "max-age=60")], "hello");
+        *not_found.status_mut() = StatusCode::NOT_FOUND;
+
+        cache
+            .store("key".into(), &request_headers, not_found, now)
+            .await
+            .unwrap();
+        assert!(cache.get("key", &request_headers, now).is_none());
+    }
+
+    #[tokio::test]
+    async fn vary() {
+        let cache = ResponseCache::new(1024);
+        let now = Instant::now();
+
+        cache
+            .store(
+                "key".into(),
+                &header_map(&[("accept-language", "en")]),
+                response(
+                    &[("cache-control", "max-age=60"), ("vary", "Accept-Language")],
+                    "hello",
+                ),
+                now,
+            )
+            .await
+            .unwrap();
+
+        assert!(cache
+            .get("key", &header_map(&[("accept-language", "en")]), now)
+            .is_some());
+        assert!(cache
+            .get("key", &header_map(&[("accept-language", "fr")]), now)
+            .is_none());
+        assert!(cache.get("key", &HeaderMap::new(), now).is_none());
+    }
+
+    #[tokio::test]
+    async fn evict_least_recently_used() {
+        let cache = ResponseCache::new(64);
+        let now = Instant::now();
+        let request_headers = HeaderMap::new();
+        let body = "a".repeat(30);
+
+        for key in ["first", "second", "third"] {
+            cache.insert(
+                key.into(),
+                &request_headers,
+                &Response::new(()).into_parts().0,
+                Bytes::from(body.clone()),
+                now,
+                Duration::from_secs(60),
+            );
+        }
+
+        assert!(cache.get("first", &request_headers, now).is_none());
+        assert!(cache.get("second", &request_headers, now).is_some());
+        assert!(cache.get("third", &request_headers, now).is_some());
+    }
+}
@@ -0,0 +1,149 @@
+use aes_gcm::{
+    aead::{Aead, AeadCore, OsRng},
+    Aes256Gcm, Key, KeyInit, Nonce,
+};
+use anyhow::{anyhow, Error, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::{collections::HashMap, str::FromStr};
+
+// Encrypted values are `enc:v1:` followed by the base64 of the
+// 12 bytes nonce and the ciphertext. Other values are in plaintext
+const ENCRYPTED_PREFIX: &str = "enc:v1:";
+const NONCE_SIZE: usize = 12;
+
+// AES-256-GCM key, set as the base64 of 32 random bytes
+pub struct SecretKey(Aes256Gcm);
+
+impl FromStr for SecretKey {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let key = STANDARD
+            .decode(value.trim())
+            .map_err(|_| anyhow!("Secret key is not valid base64"))?;
+
+        if key.len() != 32 {
+            return Err(anyhow!("Secret key must be 32 bytes, got {}", key.len()));
+        }
+
+        Ok(Self(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))))
+    }
+}
+
+impl SecretKey {
+    pub fn encrypt(&self, value: &str) -> Result<String> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .0
+            .encrypt(&nonce, value.as_bytes())
+            .map_err(|_| anyhow!("Could not encrypt value"))?;
+
+        let mut payload = nonce.to_vec();
+        payload.extend(ciphertext);
+
+        Ok(format!("{}{}", ENCRYPTED_PREFIX, STANDARD.encode(payload)))
+    }
+
+    fn decrypt(&self, value: &str) -> Result<String> {
+        let payload = STANDARD
+            .decode(value)
+            .map_err(|_| anyhow!("Encrypted value is not valid base64"))?;
+
+        if payload.len() < NONCE_SIZE {
+            return Err(anyhow!("Encrypted value is too short"));
+        }
+
+        let (nonce, ciphertext) = payload.split_at(NONCE_SIZE);
+        let plaintext = self
+            .0
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| anyhow!("Could not decrypt value, the key may be wrong"))?;
+
+        String::from_utf8(plaintext).map_err(|_| anyhow!("Decrypted value is not valid UTF-8"))
+    }
+}
+
+pub fn is_encrypted(value: &str) -> bool {
+    value.starts_with(ENCRYPTED_PREFIX)
+}
+
+// Errors only contain the name of the variable, never its value
+pub fn decrypt_environment_variables(
+    environment_variables: &HashMap<String, String>,
+    key: Option<&SecretKey>,
+) -> Result<HashMap<String, String>> {
+    environment_variables
+        .iter()
+        .map(|(name, value)| {
+            let value = match value.strip_prefix(ENCRYPTED_PREFIX) {
+                Some(encrypted) => key
+                    .ok_or_else(|| anyhow!("{} is encrypted but no secret key is set", name))?
+                    .decrypt(encrypted)
+                    .map_err(|error| anyhow!("Could not decrypt {}: {}", name, error))?,
+                None => value.clone(),
+            };
+
+            Ok((name.clone(), value))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=";
+
+    #[test]
+    fn parse_key() {
+        assert!(KEY.parse::<SecretKey>().is_ok());
+        assert!("c2hvcnQ=".parse::<SecretKey>().is_err());
+        assert!("not base64!".parse::<SecretKey>().is_err());
+    }
+
+    #[test]
+    fn encrypt_decrypt() {
+        let key = KEY.parse::<SecretKey>().unwrap();
+        let encrypted = key.encrypt("secret").unwrap();
+
+        assert!(is_encrypted(&encrypted));
+        assert!(!encrypted.contains("secret"));
+
+        let environment_variables = HashMap::from([
+            ("SECRET".to_string(), encrypted),
+            ("PLAIN".to_string(), "value".to_string()),
+        ]);
+        let decrypted = decrypt_environment_variables(&environment_variables, Some(&key)).unwrap();
+
+        assert_eq!(decrypted["SECRET"], "secret");
+        assert_eq!(decrypted["PLAIN"], "value");
+    }
+
+    #[test]
+    fn decrypt_errors() {
+        let key = KEY.parse::<SecretKey>().unwrap();
+        let other_key = "ZmVkY2JhOTg3NjU0MzIxMGZlZGNiYTk4NzY1NDMyMTA="
+            .parse::<SecretKey>()
+            .unwrap();
+        let environment_variables =
+            HashMap::from([("SECRET".to_string(), key.encrypt("secret").unwrap())]);
+
+        let error = decrypt_environment_variables(&environment_variables, None).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "SECRET is encrypted but no secret key is set"
+        );
+
+        let error =
+            decrypt_environment_variables(&environment_variables, Some(&other_key)).unwrap_err();
+        assert!(error.to_string().starts_with("Could not decrypt SECRET"));
+
+        let environment_variables =
+            HashMap::from([("SECRET".to_string(), "enc:v1:AAAA".to_string())]);
+        assert!(decrypt_environment_variables(&environment_variables, Some(&key)).is_err());
+
+        // Plaintext values don't need a key
+        let environment_variables = HashMap::from([("PLAIN".to_string(), "value".to_string())]);
+        assert!(decrypt_environment_variables(&environment_variables, None).is_ok());
+    }
+}
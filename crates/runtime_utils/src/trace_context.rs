@@ -0,0 +1,94 @@
+// Context of a trace, as defined by the W3C Trace Context specification
+// https://www.w3.org/TR/trace-context/#traceparent-header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: String,
+    pub span_id: String,
+}
+
+impl TraceContext {
+    pub fn from_traceparent(traceparent: &str) -> Option<Self> {
+        let mut parts = traceparent.trim().split('-');
+
+        let version = parts.next()?;
+        let trace_id = parts.next()?;
+        let span_id = parts.next()?;
+        let flags = parts.next()?;
+
+        let is_hex = |value: &str, len: usize| {
+            value.len() == len && value.chars().all(|char| char.is_ascii_hexdigit())
+        };
+
+        if !is_hex(version, 2)
+            || version == "ff"
+            || !is_hex(trace_id, 32)
+            || !is_hex(span_id, 16)
+            || !is_hex(flags, 2)
+            || trace_id.chars().all(|char| char == '0')
+            || span_id.chars().all(|char| char == '0')
+        {
+            return None;
+        }
+
+        Some(Self {
+            trace_id: trace_id.to_lowercase(),
+            span_id: span_id.to_lowercase(),
+        })
+    }
+
+    pub fn to_traceparent(&self) -> String {
+        format!("00-{}-{}-01", self.trace_id, self.span_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_traceparent() {
+        assert_eq!(
+            TraceContext::from_traceparent(
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+            ),
+            Some(TraceContext {
+                trace_id: "4bf92f3577b34da6a3ce929d0e0e4736".into(),
+                span_id: "00f067aa0ba902b7".into(),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_invalid_traceparent() {
+        assert_eq!(TraceContext::from_traceparent(""), None);
+        assert_eq!(
+            TraceContext::from_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-01"),
+            None
+        );
+        assert_eq!(
+            TraceContext::from_traceparent(
+                "00-00000000000000000000000000000000-00f067aa0ba902b7-01"
+            ),
+            None
+        );
+        assert_eq!(
+            TraceContext::from_traceparent(
+                "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn format_traceparent() {
+        let context = TraceContext {
+            trace_id: "4bf92f3577b34da6a3ce929d0e0e4736".into(),
+            span_id: "00f067aa0ba902b7".into(),
+        };
+
+        assert_eq!(
+            context.to_traceparent(),
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+        );
+    }
+}
@@ -0,0 +1,430 @@
+use anyhow::{anyhow, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hyper::{
+    header::{
+        HeaderName, CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_VERSION,
+        UPGRADE,
+    },
+    Body, Method, Request, Response, StatusCode, Version,
+};
+use sha1::{Digest, Sha1};
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// https://datatracker.ietf.org/doc/html/rfc6455#section-1.3
+const ACCEPT_GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_INVALID_PAYLOAD: u16 = 1007;
+pub const CLOSE_MESSAGE_TOO_BIG: u16 = 1009;
+pub const CLOSE_INTERNAL_ERROR: u16 = 1011;
+
+fn has_token<B>(req: &Request<B>, name: HeaderName, token: &str) -> bool {
+    req.headers()
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(token))
+}
+
+// WebSocket connections can only be opened from HTTP/1.1 GET requests
+// https://datatracker.ietf.org/doc/html/rfc6455#section-4.1
+pub fn is_upgrade_request<B>(req: &Request<B>) -> bool {
+    req.method() == Method::GET
+        && req.version() == Version::HTTP_11
+        && has_token(req, CONNECTION, "upgrade")
+        && has_token(req, UPGRADE, "websocket")
+        && req.headers().contains_key(SEC_WEBSOCKET_KEY)
+        && req
+            .headers()
+            .get(SEC_WEBSOCKET_VERSION)
+            .map_or(false, |version| version == "13")
+}
+
+pub fn accept_key(key: &[u8]) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key);
+    hasher.update(ACCEPT_GUID);
+
+    STANDARD.encode(hasher.finalize())
+}
+
+pub fn upgrade_response<B>(req: &Request<B>) -> Result<Response<Body>> {
+    let key = req
+        .headers()
+        .get(SEC_WEBSOCKET_KEY)
+        .ok_or_else(|| anyhow!("Missing Sec-WebSocket-Key header"))?;
+
+    Ok(Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(CONNECTION, "upgrade")
+        .header(UPGRADE, "websocket")
+        .header(SEC_WEBSOCKET_ACCEPT, accept_key(key.as_bytes()))
+        .body(Body::empty())?)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+// A violation of the protocol, closing the connection with the given code
+struct ProtocolError(u16, &'static str);
+
+// Server side of a WebSocket connection, after the handshake. Ping frames
+// are answered automatically and fragmented messages are reassembled
+pub struct WebSocket<S> {
+    stream: S,
+    max_message_size: usize,
+    closed: bool,
+}
+
+impl<S> WebSocket<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(stream: S, max_message_size: usize) -> Self {
+        Self {
+            stream,
+            max_message_size,
+            closed: false,
+        }
+    }
+
+    async fn read_frame(&mut self) -> io::Result<Result<Frame, ProtocolError>> {
+        let mut header = [0; 2];
+        self.stream.read_exact(&mut header).await?;
+
+        let fin = header[0] & 0x80 != 0;
+        let opcode = header[0] & 0x0f;
+        let masked = header[1] & 0x80 != 0;
+
+        // No extension is negotiated, so the reserved bits must be unset
+        if header[0] & 0x70 != 0 {
+            return Ok(Err(ProtocolError(
+                CLOSE_PROTOCOL_ERROR,
+                "Reserved bits are set",
+            )));
+        }
+
+        // Clients must mask all the frames they send
+        if !masked {
+            return Ok(Err(ProtocolError(
+                CLOSE_PROTOCOL_ERROR,
+                "Frame is not masked",
+            )));
+        }
+
+        let len = match header[1] & 0x7f {
+            126 => self.stream.read_u16().await? as u64,
+            127 => self.stream.read_u64().await?,
+            len => len as u64,
+        };
+
+        if opcode >= OPCODE_CLOSE && (!fin || len > 125) {
+            return Ok(Err(ProtocolError(
+                CLOSE_PROTOCOL_ERROR,
+                "Invalid control frame",
+            )));
+        }
+
+        if len > self.max_message_size as u64 {
+            return Ok(Err(ProtocolError(
+                CLOSE_MESSAGE_TOO_BIG,
+                "Message is too big",
+            )));
+        }
+
+        let mut mask = [0; 4];
+        self.stream.read_exact(&mut mask).await?;
+
+        let mut payload = vec![0; len as usize];
+        self.stream.read_exact(&mut payload).await?;
+
+        for (index, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[index % 4];
+        }
+
+        Ok(Ok(Frame {
+            fin,
+            opcode,
+            payload,
+        }))
+    }
+
+    async fn next_message(&mut self) -> io::Result<Result<Option<Message>, ProtocolError>> {
+        let mut message: Option<(u8, Vec<u8>)> = None;
+
+        loop {
+            let Frame {
+                fin,
+                opcode,
+                payload,
+            } = match self.read_frame().await? {
+                Ok(frame) => frame,
+                Err(error) => return Ok(Err(error)),
+            };
+
+            let (opcode, payload) = match opcode {
+                OPCODE_PING => {
+                    self.write_frame(OPCODE_PONG, &payload).await?;
+                    continue;
+                }
+                OPCODE_PONG => continue,
+                OPCODE_CLOSE => return Ok(Ok(None)),
+                OPCODE_TEXT | OPCODE_BINARY if message.is_none() => (opcode, payload),
+                OPCODE_CONTINUATION => match message.take() {
+                    Some((opcode, mut data)) => {
+                        if data.len() + payload.len() > self.max_message_size {
+                            return Ok(Err(ProtocolError(
+                                CLOSE_MESSAGE_TOO_BIG,
+                                "Message is too big",
+                            )));
+                        }
+
+                        data.extend(payload);
+                        (opcode, data)
+                    }
+                    None => {
+                        return Ok(Err(ProtocolError(
+                            CLOSE_PROTOCOL_ERROR,
+                            "Unexpected continuation frame",
+                        )))
+                    }
+                },
+                _ => return Ok(Err(ProtocolError(CLOSE_PROTOCOL_ERROR, "Unexpected frame"))),
+            };
+
+            if !fin {
+                message = Some((opcode, payload));
+                continue;
+            }
+
+            return Ok(match opcode {
+                OPCODE_TEXT => match String::from_utf8(payload) {
+                    Ok(text) => Ok(Some(Message::Text(text))),
+                    Err(_) => Err(ProtocolError(CLOSE_INVALID_PAYLOAD, "Text is not UTF-8")),
+                },
+                _ => Ok(Some(Message::Binary(payload))),
+            });
+        }
+    }
+
+    // Read the next message, or None when the connection is closed. The
+    // connection is closed with the appropriate code on protocol errors
+    pub async fn read_message(&mut self) -> Result<Option<Message>> {
+        match self.next_message().await? {
+            Ok(Some(message)) => Ok(Some(message)),
+            Ok(None) => {
+                self.close(CLOSE_NORMAL).await?;
+                Ok(None)
+            }
+            Err(ProtocolError(code, reason)) => {
+                self.close(code).await?;
+                Err(anyhow!(reason))
+            }
+        }
+    }
+
+    async fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        frame.push(0x80 | opcode);
+
+        match payload.len() {
+            len if len < 126 => frame.push(len as u8),
+            len if len <= u16::MAX as usize => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+
+        frame.extend_from_slice(payload);
+
+        self.stream.write_all(&frame).await?;
+        self.stream.flush().await
+    }
+
+    pub async fn send(&mut self, message: Message) -> io::Result<()> {
+        match message {
+            Message::Text(text) => self.write_frame(OPCODE_TEXT, text.as_bytes()).await,
+            Message::Binary(bytes) => self.write_frame(OPCODE_BINARY, &bytes).await,
+        }
+    }
+
+    pub async fn close(&mut self, code: u16) -> io::Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+
+        self.closed = true;
+        self.write_frame(OPCODE_CLOSE, &code.to_be_bytes()).await?;
+        self.stream.shutdown().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, DuplexStream};
+
+    const MASK: [u8; 4] = [1, 2, 3, 4];
+
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![(u8::from(fin) << 7) | opcode, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&MASK);
+        frame.extend(
+            payload
+                .iter()
+                .enumerate()
+                .map(|(index, byte)| byte ^ MASK[index % 4]),
+        );
+        frame
+    }
+
+    async fn setup(max_message_size: usize) -> (WebSocket<DuplexStream>, DuplexStream) {
+        let (server, client) = duplex(1024);
+
+        (WebSocket::new(server, max_message_size), client)
+    }
+
+    #[test]
+    fn handshake_accept_key() {
+        // Example from https://datatracker.ietf.org/doc/html/rfc6455#section-1.3
+        assert_eq!(
+            accept_key(b"dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn detect_upgrade_request() {
+        let request = Request::builder()
+            .header(CONNECTION, "keep-alive, Upgrade")
+            .header(UPGRADE, "websocket")
+            .header(SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+            .header(SEC_WEBSOCKET_VERSION, "13")
+            .body(())
+            .unwrap();
+        assert!(is_upgrade_request(&request));
+
+        let request = Request::builder()
+            .header(CONNECTION, "keep-alive")
+            .body(())
+            .unwrap();
+        assert!(!is_upgrade_request(&request));
+    }
+
+    #[tokio::test]
+    async fn read_fragmented_message() {
+        let (mut websocket, mut client) = setup(1024).await;
+
+        client
+            .write_all(&client_frame(false, OPCODE_TEXT, b"Hello "))
+            .await
+            .unwrap();
+        client
+            .write_all(&client_frame(true, OPCODE_PING, b"ping"))
+            .await
+            .unwrap();
+        client
+            .write_all(&client_frame(true, OPCODE_CONTINUATION, b"world"))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            websocket.read_message().await.unwrap(),
+            Some(Message::Text("Hello world".into()))
+        );
+
+        // The ping is answered with a pong containing the same payload
+        let mut pong = [0; 6];
+        client.read_exact(&mut pong).await.unwrap();
+        assert_eq!(pong, [0x80 | OPCODE_PONG, 4, b'p', b'i', b'n', b'g']);
+    }
+
+    #[tokio::test]
+    async fn send_message() {
+        let (mut websocket, mut client) = setup(1024).await;
+
+        websocket
+            .send(Message::Binary(vec![1, 2, 3]))
+            .await
+            .unwrap();
+
+        let mut frame = [0; 5];
+        client.read_exact(&mut frame).await.unwrap();
+        assert_eq!(frame, [0x80 | OPCODE_BINARY, 3, 1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn close_message_too_big() {
+        let (mut websocket, mut client) = setup(4).await;
+
+        client
+            .write_all(&client_frame(true, OPCODE_BINARY, b"Hello"))
+            .await
+            .unwrap();
+
+        assert!(websocket.read_message().await.is_err());
+
+        let mut close = Vec::new();
+        client.read_to_end(&mut close).await.unwrap();
+        assert_eq!(close, [0x80 | OPCODE_CLOSE, 2, 0x03, 0xf1]);
+    }
+
+    #[tokio::test]
+    async fn close_unmasked_frame() {
+        let (mut websocket, mut client) = setup(1024).await;
+
+        client
+            .write_all(&[0x80 | OPCODE_TEXT, 2, b'h', b'i'])
+            .await
+            .unwrap();
+
+        assert!(websocket.read_message().await.is_err());
+
+        let mut close = Vec::new();
+        client.read_to_end(&mut close).await.unwrap();
+        assert_eq!(close, [0x80 | OPCODE_CLOSE, 2, 0x03, 0xea]);
+    }
+
+    #[tokio::test]
+    async fn close_connection() {
+        let (mut websocket, mut client) = setup(1024).await;
+
+        client
+            .write_all(&client_frame(
+                true,
+                OPCODE_CLOSE,
+                &CLOSE_NORMAL.to_be_bytes(),
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(websocket.read_message().await.unwrap(), None);
+
+        let mut close = Vec::new();
+        client.read_to_end(&mut close).await.unwrap();
+        assert_eq!(close, [0x80 | OPCODE_CLOSE, 2, 0x03, 0xe8]);
+    }
+}
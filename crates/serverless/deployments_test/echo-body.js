@@ -0,0 +1,3 @@
+export async function handler(request) {
+  return new Response(await request.text());
+}
@@ -0,0 +1,7 @@
+export async function handler(request) {
+  if (request.headers.get('x-lagon-websocket-event') === 'message') {
+    return new Response(`Echo: ${await request.text()}`);
+  }
+
+  return new Response('');
+}
@@ -12,6 +12,7 @@ pub struct LogRow {
     pub message: String,
     pub region: String,
     pub timestamp: u32,
+    pub request_id: String,
 }
 
 #[derive(Row, Serialize, Deserialize)]
@@ -57,6 +58,7 @@ pub async fn run_migrations(client: &Client) -> Result<()> {
     message String,
     region String,
     timestamp DateTime,
+    request_id String,
 )
 ENGINE = MergeTree()
 PRIMARY KEY (level, function_id, timestamp)
@@ -65,6 +67,12 @@ TTL timestamp + INTERVAL 1 WEEK",
         .execute()
         .await?;
 
+    // Tables created before request ids were added
+    client
+        .query("ALTER TABLE serverless.logs ADD COLUMN IF NOT EXISTS request_id String")
+        .execute()
+        .await?;
+
     client
         .query(
             "CREATE TABLE IF NOT EXISTS serverless.requests
@@ -5,16 +5,16 @@ use futures::lock::Mutex;
 use hyper::{body, Request};
 use lagon_runtime_http::RunResult;
 use lagon_runtime_isolate::{
-    options::{IsolateOptions, Metadata},
+    options::{get_log_level, IsolateOptions, LogMessage},
     Isolate, IsolateEvent, IsolateRequest,
 };
 use lagon_runtime_utils::Deployment;
 use log::{error, info, warn};
-use metrics::{decrement_gauge, histogram, increment_gauge};
+use metrics::{decrement_gauge, histogram, increment_counter, increment_gauge};
 use std::{
     collections::HashMap,
     sync::Arc,
-    time::{Duration, UNIX_EPOCH},
+    time::{Duration, Instant, UNIX_EPOCH},
 };
 use tokio::runtime::Handle;
 use tokio_cron_scheduler::{Job, JobScheduler};
@@ -22,19 +22,298 @@ use uuid::Uuid;
 
 use crate::{
     clickhouse::{LogRow, RequestRow},
+    deployments::get_environment_variables,
+    serverless::{check_deployment_limits, get_log_filter, isolate_thread, with_deployment_limits},
     REGION, SNAPSHOT_BLOB,
 };
 
+const CRON_ATTEMPTS: u32 = 3;
+const CRON_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+// Metrics of crons are distinguished from the ones of HTTP requests
+fn get_labels(deployment_id: &str, function_id: &str) -> [(&'static str, String); 4] {
+    [
+        ("deployment", deployment_id.to_string()),
+        ("function", function_id.to_string()),
+        ("region", REGION.clone()),
+        ("trigger", String::from("cron")),
+    ]
+}
+
+// Each execution runs in a new isolate, terminated once it answered
+async fn run_cron(
+    deployment: Arc<Deployment>,
+    code: String,
+    log_sender: flume::Sender<LogMessage>,
+) -> RunResult {
+    if let Err(error) = check_deployment_limits(&deployment) {
+        return RunResult::Error(format!("Deployment is misconfigured: {}", error));
+    }
+
+    let environment_variables = match get_environment_variables(&deployment) {
+        Ok(environment_variables) => environment_variables,
+        Err(error) => return RunResult::Error(error.to_string()),
+    };
+
+    let handle = Handle::current();
+    let (isolate_sender, isolate_receiver) = flume::unbounded();
+    let deployment_handle = Arc::clone(&deployment);
+
+    isolate_thread(String::from("cron-") + deployment.id.as_str()).spawn(move || {
+        handle.block_on(async move {
+            let deployment = deployment_handle;
+            let labels = get_labels(&deployment.id, &deployment.function_id);
+
+            increment_gauge!("lagon_isolates", 1.0, &labels);
+            info!(deployment = deployment.id.clone(), function = deployment.function_id.clone(); "Creating new cron isolate");
+
+            let options = IsolateOptions::new(code)
+                .environment_variables(environment_variables);
+            let options = with_deployment_limits(options, &deployment)
+                .metadata(Some((
+                    deployment.id.clone(),
+                    deployment.function_id.clone(),
+                )))
+                .on_drop_callback(Box::new(|metadata| {
+                    if let Some(metadata) = metadata.as_ref().as_ref() {
+                        let labels = get_labels(&metadata.0, &metadata.1);
+
+                        decrement_gauge!("lagon_isolates", 1.0, &labels);
+                        info!(deployment = metadata.0, function = metadata.1; "Dropping cron isolate");
+                    }
+                }))
+                .on_statistics_callback(Box::new(|metadata, statistics| {
+                    if let Some(metadata) = metadata.as_ref().as_ref() {
+                        let [deployment, function, region, trigger] =
+                            get_labels(&metadata.0, &metadata.1);
+                        let labels = [
+                            deployment,
+                            function,
+                            region,
+                            trigger,
+                            ("result", statistics.result.to_string()),
+                        ];
+
+                        histogram!(
+                            "lagon_isolate_memory_usage",
+                            statistics.memory_usage as f64,
+                            &labels
+                        );
+                        histogram!(
+                            "lagon_isolate_cpu_time",
+                            statistics.cpu_time,
+                            &labels
+                        );
+                    }
+                }))
+                .log_sender(log_sender)
+                .log_level(get_log_filter(&deployment))
+                .snapshot_blob(SNAPSHOT_BLOB);
+
+            let mut isolate = Isolate::new(options, isolate_receiver);
+            isolate.evaluate();
+            isolate.run_event_loop().await;
+        });
+    }).unwrap();
+
+    let (sender, receiver) = flume::unbounded();
+    let request = Request::new(Bytes::new()).into_parts();
+
+    isolate_sender
+        .send_async(IsolateEvent::Request(IsolateRequest {
+            sender,
+            request,
+            body_stream: None,
+            request_id: None,
+        }))
+        .await
+        .unwrap_or(());
+
+    let run_result = receiver
+        .recv_async()
+        .await
+        .unwrap_or_else(|_| RunResult::Error("Isolate didn't send a response".into()));
+
+    isolate_sender
+        .send_async(IsolateEvent::Terminate(String::from("Cron completed")))
+        .await
+        .unwrap_or(());
+
+    run_result
+}
+
+// Returns the level and message to log, and whether the execution succeeded
+async fn handle_cron_result(
+    run_result: RunResult,
+    deployment: &Deployment,
+    inserters: &Mutex<(Inserter<RequestRow>, Inserter<LogRow>)>,
+) -> (String, String, bool) {
+    match run_result {
+        RunResult::Stream(_) => {
+            warn!(
+                deployment = deployment.id,
+                function = deployment.function_id;
+                "Cron Functions can't return a stream",
+            );
+
+            (
+                String::from("warn"),
+                String::from("Cron Functions can't return a stream"),
+                false,
+            )
+        }
+        RunResult::Response(response, elapsed) => {
+            let status = response.status();
+            let body = body::to_bytes(response.into_body())
+                .await
+                .unwrap_or_else(|error| {
+                    error!(
+                        deployment = deployment.id,
+                        function = deployment.function_id;
+                        "Error while reading response body: {}", error,
+                    );
+
+                    Bytes::new()
+                });
+
+            let timestamp = UNIX_EPOCH.elapsed().unwrap().as_secs() as u32;
+
+            inserters
+                .lock()
+                .await
+                .0
+                .write(&RequestRow {
+                    function_id: deployment.function_id.clone(),
+                    deployment_id: deployment.id.clone(),
+                    region: REGION.clone(),
+                    bytes_in: 0,
+                    bytes_out: 0,
+                    cpu_time_micros: elapsed.map(|duration| duration.as_micros()),
+                    timestamp,
+                })
+                .await
+                .unwrap_or(());
+
+            let body = String::from_utf8_lossy(&body);
+            let maybe_body = if body == "" {
+                String::from("")
+            } else {
+                format!(": {body}")
+            };
+
+            if status == 200 {
+                info!(
+                    deployment = deployment.id,
+                    function = deployment.function_id;
+                    "Cron execution successful{}",
+                    maybe_body,
+                );
+
+                (
+                    String::from("info"),
+                    format!("Cron execution successful{}", maybe_body),
+                    true,
+                )
+            } else {
+                error!(
+                    deployment = deployment.id,
+                    function = deployment.function_id;
+                    "Cron execution failed with status {}{}",
+                    status,
+                    maybe_body,
+                );
+
+                (
+                    String::from("error"),
+                    format!("Cron execution failed with status {}{}", status, maybe_body),
+                    false,
+                )
+            }
+        }
+        RunResult::Timeout => {
+            warn!(
+                deployment = deployment.id,
+                function = deployment.function_id;
+                "Cron execution timed out",
+            );
+
+            (
+                String::from("warn"),
+                String::from("Cron execution timed out"),
+                false,
+            )
+        }
+        RunResult::MemoryLimit => {
+            warn!(
+                deployment = deployment.id,
+                function = deployment.function_id;
+                "Cron execution memory limit reached",
+            );
+
+            (
+                String::from("warn"),
+                String::from("Cron execution memory limit reached"),
+                false,
+            )
+        }
+        RunResult::StackOverflow(error) => {
+            warn!(
+                deployment = deployment.id,
+                function = deployment.function_id;
+                "Cron execution exceeded its maximum call stack size: {}",
+                error,
+            );
+
+            (
+                String::from("warn"),
+                format!(
+                    "Cron execution exceeded its maximum call stack size: {}",
+                    error
+                ),
+                false,
+            )
+        }
+        RunResult::Error(error) => {
+            error!(
+                deployment = deployment.id,
+                function = deployment.function_id;
+                "Cron execution error: {}",
+                error,
+            );
+
+            (
+                String::from("error"),
+                format!("Cron execution error: {}", error),
+                false,
+            )
+        }
+        RunResult::CompileError(error) | RunResult::EvaluationError(error) => {
+            error!(
+                deployment = deployment.id,
+                function = deployment.function_id;
+                "Cron compilation error: {}",
+                error,
+            );
+
+            (
+                String::from("error"),
+                format!("Cron compilation error: {}", error),
+                false,
+            )
+        }
+    }
+}
+
 pub struct Cronjob {
     jobs: HashMap<String, Uuid>,
     scheduler: JobScheduler,
-    log_sender: flume::Sender<(String, String, Metadata)>,
+    log_sender: flume::Sender<LogMessage>,
     inserters: Arc<Mutex<(Inserter<RequestRow>, Inserter<LogRow>)>>,
 }
 
 impl Cronjob {
     pub async fn new(
-        log_sender: flume::Sender<(String, String, Metadata)>,
+        log_sender: flume::Sender<LogMessage>,
         inserters: Arc<Mutex<(Inserter<RequestRow>, Inserter<LogRow>)>>,
     ) -> Self {
         let scheduler = JobScheduler::new().await.unwrap();
@@ -63,12 +342,6 @@ impl Cronjob {
             let uuid = self
                 .scheduler
                 .add(Job::new_async(cron.as_str(), move |_, _| {
-                    let labels = [
-                        ("deployment", deployment.id.clone()),
-                        ("function", deployment.function_id.clone()),
-                        ("region", REGION.clone()),
-                    ];
-
                     let deployment = Arc::clone(&deployment);
                     let inserters = Arc::clone(&inserters);
                     let log_sender = log_sender.clone();
@@ -79,176 +352,38 @@ impl Cronjob {
                     });
 
                     Box::pin(async move {
-                        let handle = Handle::current();
-                        let (isolate_sender, isolate_receiver) = flume::unbounded();
-                        let log_sender_handle = log_sender.clone();
-                        let deployment_handle = Arc::clone(&deployment);
-
-                        std::thread::Builder::new().name(String::from("cron-") + deployment.id.as_str()).spawn(move || {
-                            handle.block_on(async move {
-                                let deployment  = deployment_handle;
-
-                                increment_gauge!("lagon_isolates", 1.0, &labels);
-                                info!(deployment = deployment.id.clone(), function = deployment.function_id.clone(); "Creating new cron isolate");
-
-                                let options = IsolateOptions::new(code)
-                                    .environment_variables(deployment.environment_variables.clone())
-                                    .memory(deployment.memory)
-                                    .tick_timeout(Duration::from_millis(deployment.tick_timeout as u64))
-                                    .total_timeout(Duration::from_millis(
-                                        deployment.total_timeout as u64,
-                                    ))
-                                    .metadata(Some((
-                                        deployment.id.clone(),
-                                        deployment.function_id.clone(),
-                                    )))
-                                    .on_drop_callback(Box::new(|metadata| {
-                                        if let Some(metadata) = metadata.as_ref().as_ref() {
-                                            let labels = [
-                                                ("deployment", metadata.0.clone()),
-                                                ("function", metadata.1.clone()),
-                                                ("region", REGION.clone()),
-                                            ];
-
-                                            decrement_gauge!("lagon_isolates", 1.0, &labels);
-                                            info!(deployment = metadata.0, function = metadata.1; "Dropping cron isolate");
-                                        }
-                                    }))
-                                    .on_statistics_callback(Box::new(|metadata, statistics| {
-                                        if let Some(metadata) = metadata.as_ref().as_ref() {
-                                            let labels = [
-                                                ("deployment", metadata.0.clone()),
-                                                ("function", metadata.1.clone()),
-                                                ("region", REGION.clone()),
-                                            ];
-
-                                            histogram!(
-                                                "lagon_isolate_memory_usage",
-                                                statistics as f64,
-                                                &labels
-                                            );
-                                        }
-                                    }))
-                                    .log_sender(log_sender_handle)
-                                    .snapshot_blob(SNAPSHOT_BLOB);
-
-                                let mut isolate = Isolate::new(options, isolate_receiver);
-                                isolate.evaluate();
-                                isolate.run_event_loop().await;
-                            });
-                        }).unwrap();
-
-                        let (sender, receiver) = flume::unbounded();
-                        let request = Request::new(Bytes::new()).into_parts();
-
-                        isolate_sender.send_async(IsolateEvent::Request(IsolateRequest {
-                            sender,
-                            request,
-                        })).await.unwrap_or(());
-
-                        let run_result = receiver.recv_async().await.expect("Isolate didn't send a response");
-
-                        isolate_sender.send_async(IsolateEvent::Terminate(String::from("Cron completed"))).await.unwrap_or(());
-
-                        let (level, message) = match run_result {
-                            RunResult::Stream(_) => {
-                                warn!(
-                                    deployment = deployment.id,
-                                    function = deployment.function_id;
-                                    "Cron Functions can't return a stream",
-                                );
-
-                                (String::from("warn"), String::from("Cron Functions can't return a stream"))
-                            }
-                            RunResult::Response(response, elapsed) => {
-                                let status = response.status();
-                                let body = body::to_bytes(response.into_body()).await.unwrap_or_else(|error| {
-                                    error!(
-                                        deployment = deployment.id,
-                                        function = deployment.function_id;
-                                        "Error while reading response body: {}", error,
-                                    );
-
-                                    Bytes::new()
-                                });
-
-                                let timestamp = UNIX_EPOCH.elapsed().unwrap().as_secs() as u32;
-
-                                inserters
-                                    .lock()
-                                    .await
-                                    .0
-                                    .write(&RequestRow {
-                                        function_id: deployment.function_id.clone(),
-                                        deployment_id: deployment.id.clone(),
-                                        region: REGION.clone(),
-                                        bytes_in: 0,
-                                        bytes_out: 0,
-                                        cpu_time_micros: elapsed.map(|duration| duration.as_micros()),
-                                        timestamp,
-                                    })
-                                    .await
-                                    .unwrap_or(());
-
-                                let body = String::from_utf8_lossy(&body);
-                                let maybe_body = if body == "" { String::from("") } else { format!(": {body}") };
-
-                                if status == 200 {
-                                    info!(
-                                        deployment = deployment.id,
-                                        function = deployment.function_id;
-                                        "Cron execution successful{}",
-                                        maybe_body,
-                                    );
-
-                                    (String::from("info"), format!("Cron execution successful{}", maybe_body))
-                                } else {
-
-                                    error!(
-                                        deployment = deployment.id,
-                                        function = deployment.function_id;
-                                        "Cron execution failed with status {}{}",
-                                        status,
-                                        maybe_body,
-                                    );
-
-                                    (String::from("error"), format!("Cron execution failed with status {}{}", status, maybe_body))
-                                }
-                            }
-                            RunResult::Timeout => {
-                                warn!(
-                                    deployment = deployment.id,
-                                    function = deployment.function_id;
-                                    "Cron execution timed out",
-                                );
-
-                                (String::from("warn"), String::from("Cron execution timed out"))
-                            }
-                            RunResult::MemoryLimit => {
-                                warn!(
-                                    deployment = deployment.id,
-                                    function = deployment.function_id;
-                                    "Cron execution memory limit reached",
-                                );
-
-                                (String::from("warn"), String::from("Cron execution memory limit reached"))
+                        let labels = get_labels(&deployment.id, &deployment.function_id);
+                        let mut backoff = CRON_INITIAL_BACKOFF;
+
+                        for attempt in 1..=CRON_ATTEMPTS {
+                            let start = Instant::now();
+                            let run_result = run_cron(Arc::clone(&deployment), code.clone(), log_sender.clone()).await;
+
+                            histogram!("lagon_cron_duration", start.elapsed().as_secs_f64(), &labels);
+                            increment_counter!("lagon_cron_executions", &labels);
+
+                            let (level, message, success) = handle_cron_result(run_result, &deployment, &inserters).await;
+
+                            if get_log_level(&level) <= get_log_filter(&deployment) {
+                                log_sender.send_async((level, message, Some((
+                                    deployment.id.clone(),
+                                    deployment.function_id.clone(),
+                                )), None)).await.unwrap_or(());
                             }
-                            RunResult::Error(error) => {
-                                error!(
-                                    deployment = deployment.id,
-                                    function = deployment.function_id;
-                                    "Cron execution error: {}",
-                                    error,
-                                );
-
-                                (String::from("error"), format!("Cron execution error: {}", error))
+
+                            if success {
+                                break;
                             }
-                        };
 
-                        log_sender.send_async((level, message, Some((
-                            deployment.id.clone(),
-                            deployment.function_id.clone(),
-                        )))).await.unwrap_or(());
+                            increment_counter!("lagon_cron_failures", &labels);
+
+                            if attempt < CRON_ATTEMPTS {
+                                warn!(deployment = deployment.id, function = deployment.function_id; "Retrying cron execution in {:?} ({}/{})", backoff, attempt, CRON_ATTEMPTS);
+
+                                tokio::time::sleep(backoff).await;
+                                backoff *= 2;
+                            }
+                        }
                     })
                 })?)
                 .await?;
@@ -1,6 +1,10 @@
-use super::pubsub::clear_deployment_cache;
-use crate::serverless::Workers;
+use crate::{
+    serverless::{record_isolates_count, IsolateRequests, Workers, ISOLATES_MEMORY_USAGE},
+    REGION,
+};
 use dashmap::DashMap;
+use log::warn;
+use metrics::{gauge, increment_counter};
 use std::{
     env,
     sync::Arc,
@@ -9,7 +13,21 @@ use std::{
 
 const CACHE_TASK_INTERVAL: Duration = Duration::from_secs(5);
 
-pub fn run_cache_clear_task(last_requests: Arc<DashMap<String, Instant>>, workers: Workers) {
+// The last requests are tracked by worker key, so only the pool of this
+// key is terminated, not the other variants of the deployment
+fn evict_worker(worker_key: &str, workers: &Workers, reason: &str) {
+    if let Some((_, pool)) = workers.remove(worker_key) {
+        record_isolates_count(workers);
+
+        pool.terminate(reason);
+    }
+}
+
+pub fn run_cache_clear_task(
+    last_requests: Arc<DashMap<String, Instant>>,
+    workers: Workers,
+    isolate_requests: IsolateRequests,
+) {
     let isolates_cache_seconds = Duration::from_secs(
         env::var("LAGON_ISOLATES_CACHE_SECONDS")
             .expect("LAGON_ISOLATES_CACHE_SECONDS is not set")
@@ -17,19 +35,120 @@ pub fn run_cache_clear_task(last_requests: Arc<DashMap<String, Instant>>, worker
             .expect("LAGON_ISOLATES_CACHE_SECONDS is not a valid number"),
     );
 
+    // The maximum number of isolates is optional, only the
+    // idle TTL is used when it's not set
+    let isolates_cache_max = env::var("LAGON_ISOLATES_CACHE_MAX")
+        .ok()
+        .filter(|value| !value.is_empty())
+        .map(|value| {
+            value
+                .parse::<usize>()
+                .expect("LAGON_ISOLATES_CACHE_MAX is not a valid number")
+        });
+
+    // Total heap the cached isolates can use on this node, in MB. The
+    // least recently used isolates are evicted when it's exceeded
+    let isolates_memory_budget = env::var("LAGON_ISOLATES_MEMORY_BUDGET")
+        .ok()
+        .filter(|value| !value.is_empty())
+        .map(|value| {
+            value
+                .parse::<usize>()
+                .expect("LAGON_ISOLATES_MEMORY_BUDGET is not a valid number")
+                * 1024
+                * 1024
+        });
+
     tokio::spawn(async move {
         let mut deployments_to_clear = Vec::new();
 
         loop {
             tokio::time::sleep(CACHE_TASK_INTERVAL).await;
 
+            record_isolates_count(&workers);
+
             let now = Instant::now();
+            let mut cached_deployments = Vec::new();
 
             for last_request in last_requests.iter() {
                 let (deployment_id, last_request) = last_request.pair();
 
                 if now.duration_since(*last_request) > isolates_cache_seconds {
-                    deployments_to_clear.push(deployment_id.clone());
+                    deployments_to_clear.push((deployment_id.clone(), "expiration"));
+                } else {
+                    cached_deployments.push((deployment_id.clone(), *last_request));
+                }
+            }
+
+            // Evict the least recently used isolates first
+            cached_deployments.sort_by_key(|(_, last_request)| *last_request);
+
+            if let Some(isolates_cache_max) = isolates_cache_max {
+                if cached_deployments.len() > isolates_cache_max {
+                    let mut overflow = cached_deployments.len() - isolates_cache_max;
+
+                    // Isolates handling requests are kept, the next least
+                    // recently used idle ones are evicted instead
+                    cached_deployments.retain(|(deployment_id, _)| {
+                        if overflow == 0 || isolate_requests.contains_key(deployment_id) {
+                            return true;
+                        }
+
+                        overflow -= 1;
+                        deployments_to_clear.push((deployment_id.clone(), "capacity"));
+                        false
+                    });
+                }
+            }
+
+            // Summed over all the isolates of the pool of the deployment
+            let memory_of = |deployment_id: &String| {
+                ISOLATES_MEMORY_USAGE
+                    .iter()
+                    .filter(|memory_usage| &memory_usage.key().0 == deployment_id)
+                    .map(|memory_usage| *memory_usage.value())
+                    .sum::<usize>()
+            };
+            let memory_usage = ISOLATES_MEMORY_USAGE
+                .iter()
+                .map(|memory_usage| *memory_usage)
+                .sum::<usize>();
+
+            gauge!(
+                "lagon_isolates_memory_usage",
+                memory_usage as f64,
+                "region" => REGION.clone(),
+            );
+
+            if let Some(isolates_memory_budget) = isolates_memory_budget {
+                // Isolates already being evicted free their memory too
+                let mut memory_usage = deployments_to_clear.iter().fold(
+                    memory_usage,
+                    |memory_usage, (deployment_id, _)| {
+                        memory_usage.saturating_sub(memory_of(deployment_id))
+                    },
+                );
+
+                if memory_usage > isolates_memory_budget {
+                    warn!(
+                        "Isolates use {} MB, above the budget of {} MB, evicting the least recently used ones",
+                        memory_usage / 1024 / 1024,
+                        isolates_memory_budget / 1024 / 1024,
+                    );
+
+                    // Isolates handling requests are kept, even if it
+                    // means staying above the budget until they're idle
+                    for (deployment_id, _) in cached_deployments
+                        .drain(..)
+                        .filter(|(deployment_id, _)| !isolate_requests.contains_key(deployment_id))
+                    {
+                        if memory_usage <= isolates_memory_budget {
+                            break;
+                        }
+
+                        memory_usage = memory_usage.saturating_sub(memory_of(&deployment_id));
+                        deployments_to_clear.push((deployment_id, "memory"));
+                    }
                 }
             }
 
@@ -37,15 +156,16 @@ pub fn run_cache_clear_task(last_requests: Arc<DashMap<String, Instant>>, worker
                 continue;
             }
 
-            for deployment_id in &deployments_to_clear {
+            for (deployment_id, reason) in &deployments_to_clear {
                 last_requests.remove(deployment_id);
 
-                clear_deployment_cache(
-                    deployment_id.clone(),
-                    Arc::clone(&workers),
-                    String::from("expiration"),
-                )
-                .await;
+                increment_counter!(
+                    "lagon_isolates_evicted",
+                    "reason" => *reason,
+                    "region" => REGION.clone(),
+                );
+
+                evict_worker(deployment_id, &workers, reason);
             }
 
             deployments_to_clear.clear();
@@ -15,10 +15,29 @@ pub fn create_deployments_folder() -> Result<()> {
     Ok(())
 }
 
+// Each variant has its own code cache, named after its worker key
+#[cfg(not(feature = "test"))]
+fn rm_variants_code_cache(deployment_id: &str) {
+    let prefix = deployment_id.to_owned() + "#";
+
+    if let Ok(entries) = fs::read_dir(DEPLOYMENTS_DIR) {
+        for entry in entries.flatten() {
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+
+            if file_name.starts_with(&prefix) && file_name.ends_with(".cache") {
+                fs::remove_file(entry.path()).unwrap_or(());
+            }
+        }
+    }
+}
+
 pub fn rm_deployment(deployment_id: &str) -> Result<()> {
     #[cfg(not(feature = "test"))]
     {
         fs::remove_file(Path::new(DEPLOYMENTS_DIR).join(deployment_id.to_owned() + ".js"))?;
+        fs::remove_file(Path::new(DEPLOYMENTS_DIR).join(deployment_id.to_owned() + ".cache"))
+            .unwrap_or(());
+        rm_variants_code_cache(deployment_id);
         // It's possible that the folder doesn't exists if the deployment has no assets
         fs::remove_dir_all(Path::new(DEPLOYMENTS_DIR).join(deployment_id)).unwrap_or(());
     }
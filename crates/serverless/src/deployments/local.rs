@@ -0,0 +1,321 @@
+use super::{
+    download_deployment, filesystem::create_deployments_folder, parse_config, Deployments,
+};
+use crate::REGION;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use dashmap::DashMap;
+use futures::{Stream, StreamExt};
+use lagon_runtime_utils::{Deployment, DEPLOYMENTS_DIR};
+use lagon_serverless_downloader::Downloader;
+use lagon_serverless_pubsub::{PubSubListener, PubSubMessage, PubSubMessageKind};
+use log::{error, info, warn};
+use notify::{Config, Event, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+// Editors usually emit multiple events when saving a file
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(100);
+
+// Metadata of a local deployment, read from `<deployment>.json`. The code
+// is read from `<deployment>.js` and the assets from `<deployment>/<asset>`
+#[derive(Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+struct LocalDeployment {
+    function_id: Option<String>,
+    function_name: Option<String>,
+    domains: HashSet<String>,
+    assets: HashSet<String>,
+    env: HashMap<String, String>,
+    memory: usize,
+    tick_timeout: usize,
+    total_timeout: usize,
+    is_production: bool,
+    cron: Option<String>,
+    config: Value,
+}
+
+impl Default for LocalDeployment {
+    fn default() -> Self {
+        Self {
+            function_id: None,
+            function_name: None,
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            env: HashMap::new(),
+            memory: 128,
+            tick_timeout: 500,
+            total_timeout: 5000,
+            is_production: true,
+            cron: None,
+            config: Value::Null,
+        }
+    }
+}
+
+impl LocalDeployment {
+    fn read(dir: &Path, id: &str) -> Result<Self> {
+        let metadata = fs::read(dir.join(id.to_owned() + ".json"))?;
+
+        Ok(serde_json::from_slice(&metadata)?)
+    }
+
+    fn into_deployment(self, id: String) -> Result<Deployment> {
+        Ok(Deployment {
+            function_id: self.function_id.unwrap_or_else(|| id.clone()),
+            function_name: self.function_name.unwrap_or_else(|| id.clone()),
+            domains: self.domains,
+            assets: self.assets,
+            environment_variables: self.env,
+            memory: self.memory,
+            tick_timeout: self.tick_timeout,
+            total_timeout: self.total_timeout,
+            is_production: self.is_production,
+            cron: self.cron,
+            code_hash: None,
+            variant: None,
+            config: parse_config(self.config)?,
+            id,
+        })
+    }
+
+    // Same payload as the messages published by the dashboard
+    fn into_payload(self, id: &str) -> Value {
+        json!({
+            "deploymentId": id,
+            "functionId": self.function_id.as_deref().unwrap_or(id),
+            "functionName": self.function_name.as_deref().unwrap_or(id),
+            "domains": self.domains,
+            "assets": self.assets,
+            "env": self.env,
+            "memory": self.memory,
+            "tickTimeout": self.tick_timeout,
+            "totalTimeout": self.total_timeout,
+            "isProduction": self.is_production,
+            "cron": self.cron,
+            "cronRegion": REGION.as_str(),
+            "config": self.config,
+        })
+    }
+}
+
+fn list_local_deployments(dir: &Path) -> Result<Vec<String>> {
+    let mut ids = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        if path
+            .extension()
+            .map_or(false, |extension| extension == "json")
+        {
+            if let Some(id) = path.file_stem().and_then(|stem| stem.to_str()) {
+                ids.push(id.to_string());
+            }
+        }
+    }
+
+    Ok(ids)
+}
+
+// Load deployments from a local directory instead of the database and the
+// storage, so no external service is needed (e.g for local development)
+pub async fn get_local_deployments<D>(dir: &Path, downloader: Arc<D>) -> Result<Deployments>
+where
+    D: Downloader + ?Sized,
+{
+    create_deployments_folder()?;
+
+    // Deployments are copied to the deployments folder, which
+    // would overwrite the local deployments
+    if dir.canonicalize()? == Path::new(DEPLOYMENTS_DIR).canonicalize()? {
+        return Err(anyhow!(
+            "LAGON_LOCAL_DIR can't be the deployments folder ({})",
+            DEPLOYMENTS_DIR
+        ));
+    }
+
+    let deployments = Arc::new(DashMap::new());
+    let ids = list_local_deployments(dir)?;
+
+    info!("Found {} local deployment(s) to deploy", ids.len());
+
+    for id in ids {
+        let deployment = match LocalDeployment::read(dir, &id)
+            .and_then(|local_deployment| local_deployment.into_deployment(id.clone()))
+        {
+            Ok(deployment) => deployment,
+            Err(error) => {
+                error!(deployment = id; "Failed to read local deployment: {}", error);
+                continue;
+            }
+        };
+
+        // Always copy the code, since it may have changed since the last start
+        if let Err(error) = download_deployment(&deployment, Arc::clone(&downloader)).await {
+            error!(deployment = deployment.id; "Failed to copy local deployment: {}", error);
+            continue;
+        }
+
+        let deployment = Arc::new(deployment);
+
+        for domain in deployment.get_domains() {
+            deployments.insert(domain, Arc::clone(&deployment));
+        }
+    }
+
+    Ok(deployments)
+}
+
+// Find the deployments changed by an event, and whether
+// their code or assets changed (instead of only the metadata)
+fn collect_changes(dir: &Path, event: Event, changes: &mut HashMap<String, bool>) {
+    if event.kind.is_access() {
+        return;
+    }
+
+    for path in event.paths {
+        let mut components = match path.strip_prefix(dir) {
+            Ok(path) => path.components(),
+            Err(_) => continue,
+        };
+
+        let name = match components.next() {
+            Some(Component::Normal(name)) => name.to_string_lossy().to_string(),
+            _ => continue,
+        };
+
+        let (id, code_changed) = match components.next() {
+            // An asset inside `<deployment>/`
+            Some(_) => (name, true),
+            None => match name.rsplit_once('.') {
+                Some((id, "json")) => (id.to_string(), false),
+                Some((id, "js")) => (id.to_string(), true),
+                _ => continue,
+            },
+        };
+
+        *changes.entry(id).or_default() |= code_changed;
+    }
+}
+
+fn publish(tx: &flume::Sender<PubSubMessage>, kind: PubSubMessageKind, payload: &Value) {
+    tx.send(PubSubMessage::new(kind, payload.to_string()))
+        .unwrap_or(());
+}
+
+async fn watch(
+    dir: PathBuf,
+    events: flume::Receiver<Event>,
+    tx: flume::Sender<PubSubMessage>,
+    mut payloads: HashMap<String, Value>,
+) {
+    while let Ok(event) = events.recv_async().await {
+        let mut changes = HashMap::new();
+        collect_changes(&dir, event, &mut changes);
+
+        while let Ok(Ok(event)) = tokio::time::timeout(WATCH_DEBOUNCE, events.recv_async()).await {
+            collect_changes(&dir, event, &mut changes);
+        }
+
+        for (id, code_changed) in changes {
+            let previous_payload = payloads.remove(&id);
+
+            match LocalDeployment::read(&dir, &id) {
+                Ok(local_deployment) => {
+                    let payload = local_deployment.into_payload(&id);
+
+                    // Undeploy first so the new code is copied and the isolate recreated
+                    if code_changed {
+                        if let Some(previous_payload) = &previous_payload {
+                            publish(&tx, PubSubMessageKind::Undeploy, previous_payload);
+                        }
+                    }
+
+                    info!(deployment = id; "Reloading local deployment");
+                    publish(&tx, PubSubMessageKind::Deploy, &payload);
+                    payloads.insert(id, payload);
+                }
+                Err(error) => match previous_payload {
+                    Some(previous_payload) if !dir.join(id.clone() + ".json").exists() => {
+                        info!(deployment = id; "Removing local deployment");
+                        publish(&tx, PubSubMessageKind::Undeploy, &previous_payload);
+                    }
+                    // The metadata might be invalid while being edited,
+                    // so keep the current version
+                    Some(previous_payload) => {
+                        warn!(deployment = id; "Failed to read local deployment: {}", error);
+                        payloads.insert(id, previous_payload);
+                    }
+                    None => {}
+                },
+            }
+        }
+    }
+}
+
+// Watch the local deployments directory, publishing the same messages
+// as the dashboard when a deployment is added, updated or removed
+pub struct LocalPubSub {
+    dir: PathBuf,
+    watcher: Option<RecommendedWatcher>,
+    tx: flume::Sender<PubSubMessage>,
+    rx: flume::Receiver<PubSubMessage>,
+}
+
+impl LocalPubSub {
+    pub fn new(dir: PathBuf) -> Self {
+        let (tx, rx) = flume::unbounded();
+
+        Self {
+            dir,
+            watcher: None,
+            tx,
+            rx,
+        }
+    }
+}
+
+#[async_trait]
+impl PubSubListener for LocalPubSub {
+    async fn connect(&mut self) -> Result<()> {
+        // Events contain the path given to the watcher, which needs
+        // to be the same as the one used to find the deployments
+        let dir = self.dir.canonicalize()?;
+        let (events_tx, events_rx) = flume::unbounded();
+
+        let mut watcher = RecommendedWatcher::new(
+            move |event: notify::Result<Event>| match event {
+                Ok(event) => events_tx.send(event).unwrap_or(()),
+                Err(error) => error!("Error while watching local deployments: {}", error),
+            },
+            Config::default(),
+        )?;
+        watcher.watch(&dir, RecursiveMode::Recursive)?;
+        self.watcher = Some(watcher);
+
+        let payloads = list_local_deployments(&dir)?
+            .into_iter()
+            .filter_map(|id| {
+                LocalDeployment::read(&dir, &id)
+                    .ok()
+                    .map(|local_deployment| (id.clone(), local_deployment.into_payload(&id)))
+            })
+            .collect();
+
+        tokio::spawn(watch(dir, events_rx, self.tx.clone(), payloads));
+
+        Ok(())
+    }
+
+    fn get_stream(&mut self) -> Box<dyn Stream<Item = PubSubMessage> + Unpin + Send + '_> {
+        Box::new(self.rx.clone().into_stream().boxed())
+    }
+}
@@ -1,33 +1,101 @@
-use crate::REGION;
+use crate::{
+    serverless::{check_deployment_limits, parse_env},
+    REGION,
+};
 use anyhow::{anyhow, Result};
 use dashmap::DashMap;
-use futures::{stream::FuturesUnordered, StreamExt};
-use lagon_runtime_utils::{Deployment, DEPLOYMENTS_DIR};
+use futures::{
+    stream::{self, FuturesUnordered},
+    StreamExt,
+};
+use lagon_runtime_utils::{
+    compression::decompress_code,
+    secrets::{decrypt_environment_variables, SecretKey},
+    Deployment, DeploymentConfig, DEPLOYMENTS_DIR,
+};
 use lagon_serverless_downloader::Downloader;
 use log::{error, info, warn};
-use mysql::{prelude::Queryable, PooledConn};
+use metrics::{gauge, increment_counter};
+use mysql::{
+    prelude::{FromRow, Queryable},
+    Pool, PooledConn,
+};
+use once_cell::sync::{Lazy, OnceCell};
 use serde::Deserialize;
+use serde_json::Value;
 use std::{
     collections::{HashMap, HashSet},
     fs,
     path::Path,
     sync::Arc,
+    time::Instant,
 };
 
 use self::filesystem::{create_deployments_folder, rm_deployment};
 
 pub mod cache;
 pub mod filesystem;
+pub mod local;
 pub mod pubsub;
 
 pub type Deployments = Arc<DashMap<String, Arc<Deployment>>>;
 
+const DEFAULT_LOAD_CONCURRENCY: usize = 32;
+
+// Pool of the database the deployments have been loaded from, used to
+// re-sync them. Not set when loading deployments from a local directory
+pub static DATABASE_POOL: OnceCell<Pool> = OnceCell::new();
+
+// Version of each deployment when it was last loaded from the database, to
+// only query the ones that changed when re-syncing. The pub/sub removes the
+// version of the deployments it changes, so they are queried again next time
+static DEPLOYMENT_VERSIONS: Lazy<DashMap<String, String>> = Lazy::new(DashMap::new);
+
+// Key of the encrypted environment variables, which stay encrypted
+// in memory and are only decrypted when creating an isolate
+static SECRET_KEY: Lazy<Option<SecretKey>> = Lazy::new(|| parse_env("LAGON_SECRET_KEY"));
+
+pub fn get_environment_variables(deployment: &Deployment) -> Result<HashMap<String, String>> {
+    decrypt_environment_variables(&deployment.environment_variables, SECRET_KEY.as_ref())
+}
+
+// The map has an entry for each hostname, so count the unique deployments
+pub fn record_deployments_count(deployments: &Deployments) {
+    let count = deployments
+        .iter()
+        .map(|entry| entry.id.clone())
+        .collect::<HashSet<_>>()
+        .len();
+
+    gauge!("lagon_deployments_loaded", count as f64, "region" => REGION.clone());
+}
+
+// A deployment is registered once per hostname. Remove all of them, including
+// aliases that were since removed from the domains of the deployment
+pub fn remove_deployment_domains(deployments: &Deployments, deployment_id: &str) {
+    deployments.retain(|_, deployment| deployment.id != deployment_id);
+}
+
 pub async fn download_deployment<D>(deployment: &Deployment, downloader: Arc<D>) -> Result<()>
 where
-    D: Downloader,
+    D: Downloader + ?Sized,
 {
     match downloader.download(deployment.id.clone() + ".js").await {
         Ok(object) => {
+            // The hash is the one of the uncompressed code
+            let object = decompress_code(object)?;
+
+            if let Err(error) = deployment.verify_code(&object) {
+                increment_counter!(
+                    "lagon_code_integrity_failures",
+                    "deployment" => deployment.id.clone(),
+                    "function" => deployment.function_id.clone(),
+                    "region" => REGION.clone(),
+                );
+
+                return Err(error);
+            }
+
             deployment.write_code(&object)?;
             info!(deployment = deployment.id; "Wrote deployment");
 
@@ -63,44 +131,119 @@ where
 #[derive(Deserialize)]
 struct AssetObj(Vec<String>);
 
-type QueryResult = (
-    String,
-    bool,
-    String,
-    String,
-    String,
-    usize,
-    usize,
-    usize,
-    Option<String>,
-    Option<String>,
-    Option<String>,
-    Option<String>,
-);
+#[derive(FromRow)]
+#[mysql(rename_all = "camelCase")]
+struct DeploymentRow {
+    id: String,
+    is_production: bool,
+    assets: String,
+    code_hash: Option<String>,
+    function_id: String,
+    function_name: String,
+    memory: usize,
+    tick_timeout: usize,
+    total_timeout: usize,
+    cron: Option<String>,
+    config: Option<String>,
+    domain: Option<String>,
+    env_key: Option<String>,
+    env_value: Option<String>,
+}
 
-pub async fn get_deployments<D>(mut conn: PooledConn, downloader: Arc<D>) -> Result<Deployments>
-where
-    D: Downloader,
-{
-    let deployments = Arc::new(DashMap::new());
+// A missing config uses the defaults, but an invalid one is an error:
+// ignoring it would drop settings like `auth` and `maintenance`, and
+// serve the Function unprotected
+pub fn parse_config(config: Value) -> serde_json::Result<DeploymentConfig> {
+    if config.is_null() {
+        return Ok(DeploymentConfig::default());
+    }
+
+    serde_json::from_value(config)
+}
+
+fn record_database_query(query: &'static str) {
+    increment_counter!("lagon_database_queries", "query" => query, "region" => REGION.clone());
+}
+
+// Version of each deployment, changing with its row or the rows of its
+// Function, domains and environment variables. Counting the domains and
+// environment variables also changes it when one of them is deleted
+pub fn query_versions(conn: &mut PooledConn) -> Result<HashMap<String, String>> {
+    record_database_query("versions");
+
+    let versions = conn.query_map(
+        format!(
+            "
+SELECT
+    Deployment.id AS id,
+    CONCAT_WS(
+        ':',
+        Deployment.updatedAt,
+        Function.updatedAt,
+        (SELECT CONCAT_WS(':', COUNT(*), MAX(Domain.updatedAt)) FROM Domain WHERE Domain.functionId = Function.id),
+        (SELECT CONCAT_WS(':', COUNT(*), MAX(EnvVariable.updatedAt)) FROM EnvVariable WHERE EnvVariable.functionId = Function.id)
+    ) AS version
+FROM
+    Deployment
+INNER JOIN Function
+    ON Deployment.functionId = Function.id
+WHERE
+    Function.cron IS NULL
+OR
+    Function.cronRegion = '{}'
+",
+            REGION.as_str()
+        ),
+        |(id, version): (String, String)| (id, version),
+    )?;
+
+    Ok(versions.into_iter().collect())
+}
+
+// Deployments that should be served by this node, i.e
+// without a cron or with a cron in this region
+pub fn query_deployments(conn: &mut PooledConn) -> Result<Vec<Deployment>> {
+    query_deployments_rows(conn, Vec::new())
+}
+
+// Only the given deployments, e.g the ones that changed since the last re-sync
+pub fn query_deployments_by_id(conn: &mut PooledConn, ids: Vec<String>) -> Result<Vec<Deployment>> {
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    query_deployments_rows(conn, ids)
+}
+
+// All the deployments when `ids` is empty
+fn query_deployments_rows(conn: &mut PooledConn, ids: Vec<String>) -> Result<Vec<Deployment>> {
     let mut deployments_list: HashMap<String, Deployment> = HashMap::new();
+    let mut invalid_configs = HashSet::new();
+    let ids_condition = match ids.is_empty() {
+        true => String::new(),
+        false => format!("AND Deployment.id IN ({})", vec!["?"; ids.len()].join(", ")),
+    };
 
-    conn.query_map(
+    record_database_query("deployments");
+
+    conn.exec_map(
         format!(
             "
 SELECT
-    Deployment.id,
-    Deployment.isProduction,
-    Deployment.assets,
-    Function.id,
-    Function.name,
-    Function.memory,
-    Function.tickTimeout,
-    Function.totalTimeout,
-    Function.cron,
-    Domain.domain,
-    EnvVariable.key,
-    EnvVariable.value
+    Deployment.id AS id,
+    Deployment.isProduction AS isProduction,
+    Deployment.assets AS assets,
+    Deployment.codeHash AS codeHash,
+    Function.id AS functionId,
+    Function.name AS functionName,
+    Function.memory AS memory,
+    Function.tickTimeout AS tickTimeout,
+    Function.totalTimeout AS totalTimeout,
+    Function.cron AS cron,
+    Function.config AS config,
+    Domain.domain AS domain,
+    EnvVariable.key AS envKey,
+    EnvVariable.value AS envValue
 FROM
     Deployment
 INNER JOIN Function
@@ -110,26 +253,54 @@ LEFT JOIN Domain
 LEFT JOIN EnvVariable 
     ON Function.id = EnvVariable.functionId
 WHERE
-    Function.cron IS NULL
-OR
-    Function.cronRegion = '{}'
+    (
+        Function.cron IS NULL
+    OR
+        Function.cronRegion = '{}'
+    )
+    {}
 ",
-            REGION.as_str()
+            REGION.as_str(),
+            ids_condition,
         ),
-        |(
-            id,
-            is_production,
-            assets,
-            function_id,
-            function_name,
-            memory,
-            tick_timeout,
-            total_timeout,
-            cron,
-            domain,
-            env_key,
-            env_value,
-        ): QueryResult| {
+        ids,
+        |DeploymentRow {
+             id,
+             is_production,
+             assets,
+             code_hash,
+             function_id,
+             function_name,
+             memory,
+             tick_timeout,
+             total_timeout,
+             cron,
+             config,
+             domain,
+             env_key,
+             env_value,
+         }: DeploymentRow| {
+            if invalid_configs.contains(&id) {
+                return;
+            }
+
+            // Only parsed once, the other rows of a deployment
+            // are for its other domains and environment variables
+            let config = match deployments_list.contains_key(&id) {
+                true => DeploymentConfig::default(),
+                false => match config
+                    .map_or(Ok(Value::Null), |config| serde_json::from_str(&config))
+                    .and_then(parse_config)
+                {
+                    Ok(config) => config,
+                    Err(error) => {
+                        error!(deployment = id; "Not loading deployment with an invalid config: {}", error);
+                        invalid_configs.insert(id);
+                        return;
+                    }
+                },
+            };
+
             let assets = serde_json::from_str::<AssetObj>(&assets)
                 .map(|asset_obj| asset_obj.0)
                 .unwrap_or_default();
@@ -173,11 +344,24 @@ OR
                     total_timeout,
                     is_production,
                     cron,
+                    code_hash,
+                    variant: None,
+                    config,
                 });
         },
     )?;
 
-    let deployments_list: Vec<Deployment> = deployments_list.values().cloned().collect();
+    Ok(deployments_list.into_values().collect())
+}
+
+pub async fn get_deployments<D>(mut conn: PooledConn, downloader: Arc<D>) -> Result<Deployments>
+where
+    D: Downloader + ?Sized,
+{
+    let deployments = Arc::new(DashMap::new());
+    // Queried first, so a deployment changed in between is queried again
+    let versions = query_versions(&mut conn)?;
+    let deployments_list = query_deployments(&mut conn)?;
 
     info!("Found {} deployment(s) to deploy", deployments_list.len());
 
@@ -189,21 +373,66 @@ OR
         error!("Failed to delete old deployments: {:?}", error);
     }
 
-    futures::future::join_all(deployments_list.into_iter().map(|deployment| async {
-        if !deployment.has_code() {
-            if let Err(error) = download_deployment(&deployment, Arc::clone(&downloader)).await {
-                error!("Failed to download deployment {}: {}", deployment.id, error);
-                return;
+    let start = Instant::now();
+    let concurrency =
+        parse_env("LAGON_DEPLOYMENTS_LOAD_CONCURRENCY").unwrap_or(DEFAULT_LOAD_CONCURRENCY);
+
+    // A failed download only skips its deployment, which can
+    // still be deployed later by the pub/sub
+    let failed = stream::iter(deployments_list)
+        .map(|deployment| {
+            let downloader = Arc::clone(&downloader);
+
+            async move {
+                if let Err(error) = get_environment_variables(&deployment) {
+                    error!("Failed to load deployment {}: {}", deployment.id, error);
+                    return Err(());
+                }
+
+                // Still loaded to answer its requests with a 503
+                if let Err(error) = check_deployment_limits(&deployment) {
+                    warn!("Deployment {} is misconfigured: {}", deployment.id, error);
+                }
+
+                if !deployment.has_code() {
+                    if let Err(error) = download_deployment(&deployment, downloader).await {
+                        error!("Failed to download deployment {}: {}", deployment.id, error);
+                        return Err(());
+                    }
+                }
+
+                Ok(Arc::new(deployment))
             }
-        }
+        })
+        .buffer_unordered(concurrency.max(1))
+        .fold(0, |failed, result| {
+            let failed = match result {
+                Ok(deployment) => {
+                    for domain in deployment.get_domains() {
+                        deployments.insert(domain, Arc::clone(&deployment));
+                    }
+
+                    failed
+                }
+                Err(_) => failed + 1,
+            };
 
-        let deployment = Arc::new(deployment);
+            async move { failed }
+        })
+        .await;
 
-        for domain in deployment.get_domains() {
-            deployments.insert(domain, Arc::clone(&deployment));
+    // Failed deployments don't have a version, so they are retried when re-syncing
+    for entry in deployments.iter() {
+        if let Some(version) = versions.get(&entry.id) {
+            DEPLOYMENT_VERSIONS.insert(entry.id.clone(), version.clone());
         }
-    }))
-    .await;
+    }
+
+    info!(
+        "Loaded deployments in {:?} ({} failed)",
+        start.elapsed(),
+        failed
+    );
 
     Ok(deployments)
 }
@@ -1,37 +1,506 @@
-use super::{download_deployment, filesystem::rm_deployment, Deployment, Deployments};
-use crate::{cronjob::Cronjob, serverless::Workers, REGION};
+use super::{
+    download_deployment, filesystem::rm_deployment, get_environment_variables, parse_config,
+    query_deployments, query_deployments_by_id, query_versions, record_deployments_count,
+    remove_deployment_domains, Deployment, Deployments, DATABASE_POOL, DEPLOYMENT_VERSIONS,
+};
+use crate::{
+    cronjob::Cronjob,
+    serverless::{
+        check_deployment_limits, parse_env, record_isolates_count, warmup_deployment, Readiness,
+        Workers,
+    },
+    REGION,
+};
 use anyhow::Result;
 use futures::StreamExt;
-use lagon_runtime_isolate::IsolateEvent;
+use lagon_runtime_isolate::options::LogMessage;
 use lagon_serverless_downloader::Downloader;
 use lagon_serverless_pubsub::{PubSubListener, PubSubMessage, PubSubMessageKind};
-use log::{error, warn};
-use metrics::increment_counter;
+use log::{error, info, warn};
+use metrics::{counter, increment_counter};
 use serde_json::Value;
-use std::{collections::HashMap, sync::Arc};
-use tokio::{runtime::Handle, sync::Mutex};
-
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::{
+    runtime::Handle,
+    signal::unix::{signal, SignalKind},
+    sync::Mutex,
+    time::MissedTickBehavior,
+};
+
+const PUBSUB_INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const PUBSUB_MAX_BACKOFF: Duration = Duration::from_secs(30);
+const DEFAULT_RESYNC_INTERVAL_SECONDS: u64 = 300;
+
+// The isolates of the variants of the deployment are also terminated,
+// e.g when it is deployed again, undeployed or promoted
 pub async fn clear_deployment_cache(deployment_id: String, workers: Workers, reason: String) {
-    if let Some((_, tx)) = workers.remove(&deployment_id) {
-        tx.send_async(IsolateEvent::Terminate(reason))
-            .await
-            .unwrap_or(());
+    let variant_prefix = format!("{}#", deployment_id);
+    let keys = workers
+        .iter()
+        .map(|entry| entry.key().clone())
+        .filter(|key| key == &deployment_id || key.starts_with(&variant_prefix))
+        .collect::<Vec<_>>();
+
+    for key in keys {
+        if let Some((_, pool)) = workers.remove(&key) {
+            record_isolates_count(&workers);
+
+            pool.terminate(&reason);
+        }
+    }
+}
+
+// Messages can be delivered more than once or out of order, e.g when the
+// dashboard retries a publish. Each message has a sequence number that
+// increases with every change, and only messages newer than the last one
+// applied to a deployment are handled. Messages without a sequence number
+// (sent by older dashboards) are always handled
+fn is_stale_message(
+    sequences: &mut HashMap<String, u64>,
+    deployment_id: &str,
+    sequence: Option<u64>,
+) -> bool {
+    let sequence = match sequence {
+        Some(sequence) => sequence,
+        None => return false,
+    };
+
+    match sequences.get(deployment_id) {
+        Some(last_sequence) if *last_sequence >= sequence => true,
+        _ => {
+            sequences.insert(deployment_id.to_string(), sequence);
+            false
+        }
     }
 }
 
+// What a deploy changed, to report the result of a re-sync
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum DeployOutcome {
+    Added,
+    // The code or the settings of the isolate changed
+    Changed,
+    Unchanged,
+    Failed,
+}
+
+async fn deploy<D>(
+    deployment: Deployment,
+    downloader: Arc<D>,
+    deployments: &Deployments,
+    workers: &Workers,
+    cronjob: &Mutex<Cronjob>,
+    log_sender: &flume::Sender<LogMessage>,
+) -> DeployOutcome
+where
+    D: Downloader + ?Sized + Send + 'static,
+{
+    // A deploy message for an already loaded deployment means its Function
+    // settings (e.g environment variables or domains) have been updated
+    let previous_deployment = deployments
+        .iter()
+        .find(|entry| entry.id == deployment.id)
+        .map(|entry| Arc::clone(entry.value()));
+
+    // The code of an already loaded deployment can also be replaced, which
+    // is only known from its hash. Messages without a hash (sent by older
+    // dashboards) never replace the code
+    let code_changed = deployment.code_hash.is_some()
+        && previous_deployment
+            .as_ref()
+            .map_or(false, |previous| previous.code_hash != deployment.code_hash);
+
+    // Secrets that can't be decrypted fail the deploy, instead
+    // of failing each request when creating the isolate
+    let result = match (get_environment_variables(&deployment), &previous_deployment) {
+        (Err(error), _) => Err(error),
+        (Ok(_), Some(_)) if deployment.has_code() && !code_changed => Ok(()),
+        (Ok(_), _) => download_deployment(&deployment, Arc::clone(&downloader)).await,
+    };
+
+    match result {
+        Ok(_) => {
+            increment_counter!(
+                "lagon_deployments",
+                "status" => "success",
+                "deployment" => deployment.id.clone(),
+                "function" => deployment.function_id.clone(),
+                "region" => REGION.clone(),
+            );
+
+            // Still deployed to answer its requests with a 503
+            if let Err(error) = check_deployment_limits(&deployment) {
+                warn!(deployment = deployment.id, function = deployment.function_id; "Deployment is misconfigured: {}", error);
+            }
+
+            let mut outcome = DeployOutcome::Added;
+
+            if let Some(previous_deployment) = &previous_deployment {
+                // Environment variables (including the ones of the variants),
+                // limits, the fetch policy and the log level are part of the
+                // isolate, so it has to be recreated for them to be applied.
+                // The size of the pool is also only read when it's created
+                let settings_changed = previous_deployment.environment_variables
+                    != deployment.environment_variables
+                    || previous_deployment.memory != deployment.memory
+                    || previous_deployment.tick_timeout != deployment.tick_timeout
+                    || previous_deployment.total_timeout != deployment.total_timeout
+                    || previous_deployment.config.fetch_allowed_hosts
+                        != deployment.config.fetch_allowed_hosts
+                    || previous_deployment.config.variants != deployment.config.variants
+                    || previous_deployment.config.log_level != deployment.config.log_level
+                    || previous_deployment.config.isolate_pool_size
+                        != deployment.config.isolate_pool_size;
+
+                outcome = if code_changed || settings_changed {
+                    DeployOutcome::Changed
+                } else {
+                    DeployOutcome::Unchanged
+                };
+
+                // Requests already sent to the isolate are still answered with
+                // the previous code, the next ones create a new isolate
+                if code_changed {
+                    increment_counter!(
+                        "lagon_isolate_reloads",
+                        "deployment" => deployment.id.clone(),
+                        "function" => deployment.function_id.clone(),
+                        "region" => REGION.clone(),
+                    );
+                    info!(deployment = deployment.id; "Code changed, reloading isolate");
+                }
+
+                if code_changed || settings_changed {
+                    clear_deployment_cache(
+                        deployment.id.clone(),
+                        Arc::clone(workers),
+                        String::from(if code_changed { "reload" } else { "update" }),
+                    )
+                    .await;
+                }
+
+                if previous_deployment.should_run_cron() {
+                    let mut cronjob = cronjob.lock().await;
+
+                    if let Err(error) = cronjob.remove(&deployment.id).await {
+                        error!(deployment = deployment.id; "Failed to remove cron: {}", error);
+                    }
+                }
+            }
+
+            let domains = deployment.get_domains();
+            let deployment = Arc::new(deployment);
+
+            for domain in &domains {
+                deployments.insert(domain.clone(), Arc::clone(&deployment));
+            }
+
+            // Domains are replaced before removing the ones that aren't used
+            // anymore, so requests to the deployment never miss it
+            if previous_deployment.is_some() {
+                deployments
+                    .retain(|domain, entry| entry.id != deployment.id || domains.contains(domain));
+            }
+
+            warmup_deployment(Arc::clone(&deployment), workers, log_sender.clone());
+
+            if deployment.should_run_cron() {
+                let mut cronjob = cronjob.lock().await;
+                let id = deployment.id.clone();
+
+                if let Err(error) = cronjob.add(deployment).await {
+                    error!(deployment = id; "Failed to register cron: {}", error);
+                }
+            }
+
+            outcome
+        }
+        Err(error) => {
+            increment_counter!(
+                "lagon_deployments",
+                "status" => "error",
+                "deployment" => deployment.id.clone(),
+                "function" => deployment.function_id.clone(),
+                "region" => REGION.clone(),
+            );
+            error!(
+                deployment = deployment.id;
+                "Failed to load deployment: {}", error
+            );
+
+            DeployOutcome::Failed
+        }
+    }
+}
+
+async fn undeploy(
+    deployment: Deployment,
+    deployments: &Deployments,
+    workers: &Workers,
+    cronjob: &Mutex<Cronjob>,
+) {
+    match rm_deployment(&deployment.id) {
+        Ok(_) => {
+            increment_counter!(
+                "lagon_undeployments",
+                "status" => "success",
+                "deployment" => deployment.id.clone(),
+                "function" => deployment.function_id.clone(),
+                "region" => REGION.clone(),
+            );
+
+            remove_deployment_domains(deployments, &deployment.id);
+
+            clear_deployment_cache(
+                deployment.id.clone(),
+                Arc::clone(workers),
+                String::from("undeployment"),
+            )
+            .await;
+
+            if deployment.should_run_cron() {
+                let mut cronjob = cronjob.lock().await;
+
+                if let Err(error) = cronjob.remove(&deployment.id).await {
+                    error!(deployment = deployment.id; "Failed to remove cron: {}", error);
+                }
+            }
+        }
+        Err(error) => {
+            increment_counter!(
+                "lagon_undeployments",
+                "status" => "error",
+                "deployment" => deployment.id.clone(),
+                "function" => deployment.function_id.clone(),
+                "region" => REGION.clone(),
+            );
+            error!(deployment = deployment.id; "Failed to delete deployment: {}", error);
+        }
+    };
+}
+
+// Messages published while the pub/sub was disconnected are lost, so
+// the deployments are re-synced from the database after a reconnection.
+// Only the deployments whose version changed are queried, unless `full`
+async fn resync_deployments<D>(
+    downloader: Arc<D>,
+    deployments: &Deployments,
+    workers: &Workers,
+    cronjob: &Mutex<Cronjob>,
+    log_sender: &flume::Sender<LogMessage>,
+    full: bool,
+) -> Result<()>
+where
+    D: Downloader + ?Sized + Send + 'static,
+{
+    let pool = match DATABASE_POOL.get() {
+        Some(pool) => pool,
+        None => return Ok(()),
+    };
+
+    let mut conn = pool.get_conn()?;
+    let versions = query_versions(&mut conn)?;
+
+    let changed_ids = versions
+        .iter()
+        .filter(|(id, version)| {
+            full || DEPLOYMENT_VERSIONS
+                .get(*id)
+                .map_or(true, |cached_version| cached_version.value() != *version)
+        })
+        .map(|(id, _)| id.clone())
+        .collect::<Vec<_>>();
+    let unchanged = versions.len() - changed_ids.len();
+
+    let deployments_list = if full {
+        query_deployments(&mut conn)?
+    } else {
+        counter!("lagon_deployments_cache_hits", unchanged as u64, "region" => REGION.clone());
+        counter!("lagon_deployments_cache_misses", changed_ids.len() as u64, "region" => REGION.clone());
+
+        query_deployments_by_id(&mut conn, changed_ids)?
+    };
+
+    let removed_deployments = deployments
+        .iter()
+        .filter(|entry| !versions.contains_key(&entry.id))
+        .map(|entry| (entry.id.clone(), Arc::clone(entry.value())))
+        .collect::<HashMap<_, _>>();
+
+    info!(
+        "Re-syncing {} deployment(s), {} unchanged",
+        deployments_list.len(),
+        unchanged
+    );
+
+    let removed = removed_deployments.len();
+
+    for deployment in removed_deployments.into_values() {
+        undeploy(deployment.as_ref().clone(), deployments, workers, cronjob).await;
+    }
+
+    DEPLOYMENT_VERSIONS.retain(|id, _| versions.contains_key(id));
+
+    let mut outcomes = HashMap::new();
+
+    for deployment in deployments_list {
+        let id = deployment.id.clone();
+        let outcome = deploy(
+            deployment,
+            Arc::clone(&downloader),
+            deployments,
+            workers,
+            cronjob,
+            log_sender,
+        )
+        .await;
+
+        // Failed deployments are retried on the next re-sync
+        match (&outcome, versions.get(&id)) {
+            (DeployOutcome::Failed, _) | (_, None) => {
+                DEPLOYMENT_VERSIONS.remove(&id);
+            }
+            (_, Some(version)) => {
+                DEPLOYMENT_VERSIONS.insert(id, version.clone());
+            }
+        }
+
+        *outcomes.entry(outcome).or_insert(0) += 1;
+    }
+
+    let count = |outcome| outcomes.get(&outcome).copied().unwrap_or(0);
+
+    info!(
+        "Re-synced deployments: {} added, {} changed, {} removed, {} failed",
+        count(DeployOutcome::Added),
+        count(DeployOutcome::Changed),
+        removed,
+        count(DeployOutcome::Failed)
+    );
+
+    Ok(())
+}
+
+// Re-sync the deployments that changed in the database every
+// LAGON_DEPLOYMENTS_RESYNC_INTERVAL seconds, in case a pub/sub message
+// was missed. Receiving SIGHUP reloads all of them, e.g to apply changes
+// without restarting when not using the pub/sub
+pub fn run_resync_task<D>(
+    downloader: Arc<D>,
+    deployments: Deployments,
+    workers: Workers,
+    cronjob: Arc<Mutex<Cronjob>>,
+    log_sender: flume::Sender<LogMessage>,
+    readiness: Arc<Readiness>,
+) where
+    D: Downloader + ?Sized + Send + Sync + 'static,
+{
+    let handle = Handle::current();
+    std::thread::spawn(move || {
+        handle.block_on(async {
+            let mut sighup = match signal(SignalKind::hangup()) {
+                Ok(sighup) => sighup,
+                Err(error) => {
+                    error!("Failed to listen to SIGHUP: {}", error);
+                    return;
+                }
+            };
+
+            let resync_interval = parse_env("LAGON_DEPLOYMENTS_RESYNC_INTERVAL")
+                .unwrap_or(DEFAULT_RESYNC_INTERVAL_SECONDS);
+            let mut interval = tokio::time::interval(Duration::from_secs(resync_interval.max(1)));
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+            // The first tick completes immediately, when
+            // the deployments have just been loaded
+            interval.tick().await;
+
+            loop {
+                let full = tokio::select! {
+                    signal = sighup.recv() => match signal {
+                        Some(_) => {
+                            info!("Received SIGHUP, reloading deployments");
+                            true
+                        }
+                        None => return,
+                    },
+                    _ = interval.tick() => false,
+                };
+
+                if DATABASE_POOL.get().is_none() {
+                    if full {
+                        warn!("No database connection, deployments can't be reloaded");
+                    }
+
+                    continue;
+                }
+
+                match resync_deployments(
+                    Arc::clone(&downloader),
+                    &deployments,
+                    &workers,
+                    &cronjob,
+                    &log_sender,
+                    full,
+                )
+                .await
+                {
+                    Ok(_) => {
+                        readiness.set_database_connected(true);
+                        record_deployments_count(&deployments);
+                    }
+                    Err(error) => {
+                        readiness.set_database_connected(false);
+                        error!("Failed to reload deployments: {}", error);
+                    }
+                }
+            }
+        });
+    });
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn run<D, P>(
     downloader: Arc<D>,
     deployments: Deployments,
     workers: Workers,
     cronjob: Arc<Mutex<Cronjob>>,
     pubsub: Arc<Mutex<P>>,
+    log_sender: flume::Sender<LogMessage>,
+    sequences: &mut HashMap<String, u64>,
+    reconnecting: bool,
+    connected: &mut bool,
+    readiness: &Readiness,
 ) -> Result<()>
 where
-    D: Downloader + Send + 'static,
+    D: Downloader + ?Sized + Send + 'static,
     P: PubSubListener + Unpin + Send,
 {
     let mut pubsub = pubsub.lock().await;
     pubsub.connect().await?;
+    *connected = true;
+    readiness.set_pubsub_connected(true);
+
+    // Already subscribed, so the messages published while
+    // re-syncing are received once it's done
+    if reconnecting {
+        let result = resync_deployments(
+            Arc::clone(&downloader),
+            &deployments,
+            &workers,
+            &cronjob,
+            &log_sender,
+            false,
+        )
+        .await;
+        readiness.set_database_connected(result.is_ok());
+
+        if let Err(error) = result {
+            error!("Failed to re-sync deployments: {}", error);
+        }
+
+        record_deployments_count(&deployments);
+    }
 
     let mut stream = pubsub.get_stream();
 
@@ -51,10 +520,64 @@ where
             continue;
         }
 
+        let deployment_id = value["deploymentId"].as_str().unwrap();
+        let sequence = value["sequence"].as_u64();
+
+        if is_stale_message(sequences, deployment_id, sequence) {
+            increment_counter!(
+                "lagon_pubsub_stale_messages",
+                "deployment" => deployment_id.to_string(),
+                "region" => REGION.clone(),
+            );
+            warn!(deployment = deployment_id; "Ignoring stale {:?} message with sequence {:?}", kind, sequence);
+            continue;
+        }
+
+        // The deployment changed since it was loaded from the database
+        DEPLOYMENT_VERSIONS.remove(deployment_id);
+
+        // A promotion also changes the previous production deployment,
+        // so older messages for it must be ignored too
+        if kind == PubSubMessageKind::Promote {
+            if let (Some(previous_id), Some(sequence)) =
+                (value["previousDeploymentId"].as_str(), sequence)
+            {
+                sequences
+                    .entry(previous_id.to_string())
+                    .and_modify(|last_sequence| *last_sequence = sequence.max(*last_sequence))
+                    .or_insert(sequence);
+            }
+        }
+
         let cron = cron.map(|cron| cron.to_string());
 
+        // Keep the config already loaded when the new one is invalid, so
+        // the deployment isn't served without its settings. Undeploys
+        // don't need the config
+        let config = match parse_config(value["config"].clone()) {
+            Ok(config) => config,
+            Err(error) => {
+                let previous_config = deployments
+                    .iter()
+                    .find(|entry| entry.id == deployment_id)
+                    .map(|entry| entry.config.clone());
+
+                match previous_config {
+                    Some(previous_config) => {
+                        error!(deployment = deployment_id; "Invalid config, keeping the previous one: {}", error);
+                        previous_config
+                    }
+                    None if kind == PubSubMessageKind::Undeploy => Default::default(),
+                    None => {
+                        error!(deployment = deployment_id; "Ignoring {:?} message with an invalid config: {}", kind, error);
+                        continue;
+                    }
+                }
+            }
+        };
+
         let deployment = Deployment {
-            id: value["deploymentId"].as_str().unwrap().to_string(),
+            id: deployment_id.to_string(),
             function_id: value["functionId"].as_str().unwrap().to_string(),
             function_name: value["functionName"].as_str().unwrap().to_string(),
             assets: value["assets"]
@@ -80,96 +603,27 @@ where
             total_timeout: value["totalTimeout"].as_u64().unwrap() as usize,
             is_production: value["isProduction"].as_bool().unwrap(),
             cron,
+            code_hash: value["codeHash"].as_str().map(String::from),
+            variant: None,
+            config,
         };
 
         let workers = Arc::clone(&workers);
 
         match kind {
             PubSubMessageKind::Deploy => {
-                match download_deployment(&deployment, Arc::clone(&downloader)).await {
-                    Ok(_) => {
-                        increment_counter!(
-                            "lagon_deployments",
-                            "status" => "success",
-                            "deployment" => deployment.id.clone(),
-                            "function" => deployment.function_id.clone(),
-                            "region" => REGION.clone(),
-                        );
-
-                        let domains = deployment.get_domains();
-                        let deployment = Arc::new(deployment);
-
-                        for domain in &domains {
-                            deployments.insert(domain.clone(), Arc::clone(&deployment));
-                        }
-
-                        if deployment.should_run_cron() {
-                            let mut cronjob = cronjob.lock().await;
-                            let id = deployment.id.clone();
-
-                            if let Err(error) = cronjob.add(deployment).await {
-                                error!(deployment = id; "Failed to register cron: {}", error);
-                            }
-                        }
-                    }
-                    Err(error) => {
-                        increment_counter!(
-                            "lagon_deployments",
-                            "status" => "error",
-                            "deployment" => deployment.id.clone(),
-                            "function" => deployment.function_id.clone(),
-                            "region" => REGION.clone(),
-                        );
-                        error!(
-                            deployment = deployment.id;
-                            "Failed to download deployment: {}", error
-                        );
-                    }
-                };
+                deploy(
+                    deployment,
+                    Arc::clone(&downloader),
+                    &deployments,
+                    &workers,
+                    &cronjob,
+                    &log_sender,
+                )
+                .await;
             }
             PubSubMessageKind::Undeploy => {
-                match rm_deployment(&deployment.id) {
-                    Ok(_) => {
-                        increment_counter!(
-                            "lagon_undeployments",
-                            "status" => "success",
-                            "deployment" => deployment.id.clone(),
-                            "function" => deployment.function_id.clone(),
-                            "region" => REGION.clone(),
-                        );
-
-                        let domains = deployment.get_domains();
-
-                        for domain in &domains {
-                            deployments.remove(domain);
-                        }
-
-                        clear_deployment_cache(
-                            deployment.id.clone(),
-                            workers,
-                            String::from("undeployment"),
-                        )
-                        .await;
-
-                        if deployment.should_run_cron() {
-                            let mut cronjob = cronjob.lock().await;
-
-                            if let Err(error) = cronjob.remove(&deployment.id).await {
-                                error!(deployment = deployment.id; "Failed to remove cron: {}", error);
-                            }
-                        }
-                    }
-                    Err(error) => {
-                        increment_counter!(
-                            "lagon_undeployments",
-                            "status" => "error",
-                            "deployment" => deployment.id.clone(),
-                            "function" => deployment.function_id.clone(),
-                            "region" => REGION.clone(),
-                        );
-                        error!(deployment = deployment.id; "Failed to delete deployment: {}", error);
-                    }
-                };
+                undeploy(deployment, &deployments, &workers, &cronjob).await;
             }
             PubSubMessageKind::Promote => {
                 increment_counter!(
@@ -180,14 +634,20 @@ where
                 );
 
                 let previous_id = value["previousDeploymentId"].as_str().unwrap();
+                DEPLOYMENT_VERSIONS.remove(previous_id);
 
-                if let Some(deployment) = deployments.get(previous_id) {
-                    let mut unpromoted_deployment = deployment.as_ref().clone();
+                // The map is keyed by hostname, and holding a reference
+                // to an entry while removing others could deadlock
+                let previous_deployment = deployments
+                    .iter()
+                    .find(|entry| entry.id == previous_id)
+                    .map(|entry| Arc::clone(entry.value()));
+
+                if let Some(previous_deployment) = previous_deployment {
+                    let mut unpromoted_deployment = previous_deployment.as_ref().clone();
                     unpromoted_deployment.is_production = false;
 
-                    for domain in deployment.get_domains() {
-                        deployments.remove(&domain);
-                    }
+                    remove_deployment_domains(&deployments, previous_id);
 
                     let unpromoted_deployment = Arc::new(unpromoted_deployment);
 
@@ -203,8 +663,14 @@ where
                     deployments.insert(domain.clone(), Arc::clone(&deployment));
                 }
 
-                clear_deployment_cache(previous_id.to_string(), workers, String::from("promotion"))
-                    .await;
+                clear_deployment_cache(
+                    previous_id.to_string(),
+                    Arc::clone(&workers),
+                    String::from("promotion"),
+                )
+                .await;
+
+                warmup_deployment(Arc::clone(&deployment), &workers, log_sender.clone());
 
                 let mut cronjob = cronjob.lock().await;
 
@@ -222,6 +688,8 @@ where
             }
             _ => warn!("Unknown message kind: {:?}, {}", kind, payload),
         };
+
+        record_deployments_count(&deployments);
     }
 
     Ok(())
@@ -233,25 +701,53 @@ pub fn listen_pub_sub<D, P>(
     workers: Workers,
     cronjob: Arc<Mutex<Cronjob>>,
     pubsub: Arc<Mutex<P>>,
+    log_sender: flume::Sender<LogMessage>,
+    readiness: Arc<Readiness>,
 ) where
-    D: Downloader + Send + Sync + 'static,
+    D: Downloader + ?Sized + Send + Sync + 'static,
     P: PubSubListener + Unpin + Send + 'static,
 {
     let handle = Handle::current();
     std::thread::spawn(move || {
         handle.block_on(async {
+            // Last sequence number applied to each deployment, kept
+            // when reconnecting to not apply messages twice
+            let mut sequences = HashMap::new();
+            let mut has_connected = false;
+            let mut backoff = PUBSUB_INITIAL_BACKOFF;
+
             loop {
+                let mut connected = false;
+
                 if let Err(error) = run(
                     Arc::clone(&downloader),
                     Arc::clone(&deployments),
                     Arc::clone(&workers),
                     Arc::clone(&cronjob),
                     Arc::clone(&pubsub),
+                    log_sender.clone(),
+                    &mut sequences,
+                    has_connected,
+                    &mut connected,
+                    &readiness,
                 )
                 .await
                 {
                     error!("Pub/sub error: {}", error);
                 }
+
+                readiness.set_pubsub_connected(false);
+
+                if connected {
+                    has_connected = true;
+                    backoff = PUBSUB_INITIAL_BACKOFF;
+                }
+
+                increment_counter!("lagon_pubsub_reconnections", "region" => REGION.clone());
+                warn!("Pub/sub disconnected, reconnecting in {:?}", backoff);
+
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(PUBSUB_MAX_BACKOFF);
             }
         });
     });
@@ -0,0 +1,114 @@
+use lagon_runtime_utils::rate_limit::RateLimit;
+use log::warn;
+use metrics::increment_counter;
+use once_cell::sync::Lazy;
+use redis::Script;
+use std::time::Duration;
+
+use crate::{
+    redis_connection::{get_connection, handle_error},
+    serverless::parse_env,
+    REGION,
+};
+
+// Waiting longer for Redis would slow down all the requests
+// of rate limited deployments
+const REDIS_TIMEOUT: Duration = Duration::from_millis(100);
+
+// Rate limits are also enforced across all the nodes when
+// LAGON_DISTRIBUTED_RATE_LIMIT is set, using Redis
+static DISTRIBUTED_RATE_LIMIT: Lazy<bool> =
+    Lazy::new(|| parse_env("LAGON_DISTRIBUTED_RATE_LIMIT").unwrap_or(false));
+
+// The same token bucket as the local one, using the time of Redis so
+// the clocks of the nodes don't matter. Returns the number of seconds
+// to wait before a token is available, 0 when one was taken and -1
+// when there will never be one
+static TOKEN_BUCKET: Lazy<Script> = Lazy::new(|| {
+    Script::new(
+        r"
+local capacity = tonumber(ARGV[1])
+local rate = tonumber(ARGV[2])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) + tonumber(time[2]) / 1000000
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'refilled_at')
+local tokens = tonumber(bucket[1]) or capacity
+local refilled_at = tonumber(bucket[2]) or now
+
+tokens = math.min(capacity, tokens + math.max(0, now - refilled_at) * rate)
+
+local wait = 0
+
+if tokens >= 1 then
+  tokens = tokens - 1
+elseif rate > 0 then
+  wait = (1 - tokens) / rate
+else
+  wait = -1
+end
+
+redis.call('HSET', KEYS[1], 'tokens', tokens, 'refilled_at', now)
+
+if rate > 0 then
+  redis.call('EXPIRE', KEYS[1], math.ceil(capacity / rate) + 1)
+end
+
+return tostring(wait)
+",
+    )
+});
+
+// Take a token from the bucket of the client shared by all the nodes,
+// or return how long to wait before a token is available again. Returns
+// None when the distributed rate limit is disabled or Redis isn't
+// available, so only the local rate limit applies
+pub async fn try_acquire(
+    deployment_id: &str,
+    ip: &str,
+    limit: RateLimit,
+) -> Option<Result<(), Duration>> {
+    if !*DISTRIBUTED_RATE_LIMIT {
+        return None;
+    }
+
+    let key = format!("rate_limit:{}:{}", deployment_id, ip);
+    let result: Result<anyhow::Result<String>, _> = tokio::time::timeout(REDIS_TIMEOUT, async {
+        let mut connection = get_connection().await?;
+
+        match TOKEN_BUCKET
+            .key(key)
+            .arg(limit.capacity())
+            .arg(limit.requests_per_second)
+            .invoke_async(&mut connection)
+            .await
+        {
+            Ok(wait) => Ok(wait),
+            Err(error) => {
+                handle_error(&error).await;
+                Err(error.into())
+            }
+        }
+    })
+    .await;
+
+    let error = match result {
+        Ok(Ok(wait)) => {
+            let wait = wait.parse::<f64>().ok()?;
+
+            return Some(if wait < 0.0 {
+                Err(Duration::MAX)
+            } else if wait > 0.0 {
+                Err(Duration::from_secs_f64(wait))
+            } else {
+                Ok(())
+            });
+        }
+        Ok(Err(error)) => error.to_string(),
+        Err(_) => String::from("Redis timed out"),
+    };
+
+    increment_counter!("lagon_rate_limit_redis_errors", "region" => REGION.clone());
+    warn!(deployment = deployment_id; "Falling back to the local rate limit: {}", error);
+
+    None
+}
@@ -0,0 +1,26 @@
+use anyhow::{anyhow, Result};
+use log::warn;
+
+use crate::serverless::parse_env;
+
+// Backend receiving the metrics, set by METRICS_BACKEND to `prometheus`
+// (the default), `statsd` or `none`. Each backend is only compiled in with
+// the feature of the same name, `prometheus` being a default feature
+pub fn init_metrics() -> Result<()> {
+    let backend = parse_env::<String>("METRICS_BACKEND").unwrap_or_else(|| "prometheus".into());
+
+    match backend.as_str() {
+        #[cfg(feature = "prometheus")]
+        "prometheus" => crate::prometheus::init_metrics(),
+        #[cfg(feature = "statsd")]
+        "statsd" => crate::statsd::init_metrics(),
+        "none" => {
+            warn!("METRICS_BACKEND is none, metrics won't be exported");
+            Ok(())
+        }
+        backend => Err(anyhow!(
+            "Metrics backend {} is unknown or wasn't compiled in",
+            backend
+        )),
+    }
+}
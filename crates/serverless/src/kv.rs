@@ -0,0 +1,98 @@
+use crate::{
+    redis_connection::{get_connection, handle_error},
+    REGION,
+};
+use lagon_runtime_isolate::options::{KvRequest, KvResponse, OnIsolateKvCallback};
+use log::error;
+use metrics::{histogram, increment_counter};
+use once_cell::sync::Lazy;
+use redis::{aio::MultiplexedConnection, AsyncCommands, RedisResult};
+use std::{env, time::Instant};
+
+// The KV storage is only available when LAGON_KV_ENABLED is set
+static ENABLED: Lazy<bool> =
+    Lazy::new(|| env::var("LAGON_KV_ENABLED").map_or(false, |value| value == "true"));
+
+// Maximum size of the serialized values, uses the
+// isolate default when not set
+pub static KV_MAX_VALUE_SIZE: Lazy<Option<usize>> = Lazy::new(|| {
+    env::var("LAGON_KV_MAX_VALUE_SIZE")
+        .ok()
+        .filter(|value| !value.is_empty())
+        .map(|value| {
+            value
+                .parse()
+                .expect("LAGON_KV_MAX_VALUE_SIZE is not a valid number")
+        })
+});
+
+async fn run(
+    connection: &mut MultiplexedConnection,
+    key: String,
+    request: KvRequest,
+) -> RedisResult<KvResponse> {
+    match request {
+        KvRequest::Get(_) => Ok(KvResponse::Value(connection.get(key).await?)),
+        KvRequest::Set {
+            value,
+            ttl: Some(ttl),
+            ..
+        } => {
+            connection.set_ex(key, value, ttl as usize).await?;
+
+            Ok(KvResponse::Set)
+        }
+        KvRequest::Set {
+            value, ttl: None, ..
+        } => {
+            connection.set(key, value).await?;
+
+            Ok(KvResponse::Set)
+        }
+        KvRequest::Delete(_) => {
+            let deleted: usize = connection.del(key).await?;
+
+            Ok(KvResponse::Deleted(deleted > 0))
+        }
+    }
+}
+
+// Keys are prefixed by the id of the deployment running the isolate,
+// so a Function can't access the keys of another one
+pub fn create_kv_callback() -> Option<OnIsolateKvCallback> {
+    if !*ENABLED {
+        return None;
+    }
+
+    Some(Box::new(move |metadata, request| {
+        Box::pin(async move {
+            let (deployment_id, function_id) = metadata
+                .as_ref()
+                .clone()
+                .ok_or_else(|| anyhow::anyhow!("KV storage is not available"))?;
+
+            let start = Instant::now();
+            let operation = request.operation();
+            let key = format!("kv:{}:{}", deployment_id, request.key());
+            let labels = [
+                ("deployment", deployment_id),
+                ("function", function_id),
+                ("region", REGION.clone()),
+                ("operation", operation.to_string()),
+            ];
+
+            let mut connection = get_connection().await?;
+            let result = run(&mut connection, key, request).await;
+
+            histogram!("lagon_kv_duration", start.elapsed().as_secs_f64(), &labels);
+
+            if let Err(error) = &result {
+                increment_counter!("lagon_kv_errors", &labels);
+                error!(deployment = labels[0].1, function = labels[1].1; "KV {} error: {}", operation, error);
+                handle_error(error).await;
+            }
+
+            Ok(result?)
+        })
+    }))
+}
@@ -4,7 +4,20 @@ use std::env;
 pub mod clickhouse;
 pub mod cronjob;
 pub mod deployments;
+pub mod distributed_rate_limit;
+pub mod exporter;
+pub mod kv;
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+pub mod redis_connection;
 pub mod serverless;
+#[cfg(feature = "statsd")]
+pub mod statsd;
+pub mod telemetry;
+pub mod timeout;
+pub mod tls;
+pub mod validate;
+pub mod warm_pool;
 
 pub static REGION: Lazy<String> =
     Lazy::new(|| env::var("LAGON_REGION").expect("LAGON_REGION must be set"));
@@ -1,24 +1,166 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use futures::FutureExt;
 use lagon_runtime::{options::RuntimeOptions, Runtime};
 use lagon_serverless::clickhouse::{create_client, run_migrations};
-use lagon_serverless::deployments::get_deployments;
-use lagon_serverless::serverless::start;
+use lagon_serverless::deployments::{
+    get_deployments,
+    local::{get_local_deployments, LocalPubSub},
+    Deployments, DATABASE_POOL,
+};
+use lagon_serverless::exporter::init_metrics;
+use lagon_serverless::serverless::{parse_env, start, ISOLATE_STACK_SIZE};
+use lagon_serverless::telemetry::init_tracing;
 use lagon_serverless::REGION;
-use lagon_serverless_downloader::{get_bucket, S3BucketDownloader};
+use lagon_serverless_downloader::{get_downloader, Downloader, FilesystemDownloader};
 use lagon_serverless_logger::init_logger;
 use lagon_serverless_pubsub::RedisPubSub;
-use log::info;
-use metrics_exporter_prometheus::PrometheusBuilder;
-use mysql::{Opts, Pool};
+use log::{info, warn};
+use metrics::increment_counter;
 #[cfg(not(debug_assertions))]
-use mysql::{OptsBuilder, SslOpts};
+use mysql::SslOpts;
+use mysql::{Opts, OptsBuilder, Pool, PoolConstraints};
 #[cfg(not(debug_assertions))]
 use std::borrow::Cow;
 use std::env;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 #[cfg(not(debug_assertions))]
 use std::path::Path;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
+
+const DATABASE_ATTEMPTS: u32 = 5;
+const DATABASE_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const DEFAULT_DATABASE_HEALTH_CHECK_INTERVAL_SECONDS: u64 = 60;
+const DEFAULT_HOST: &str = "0.0.0.0";
+const DEFAULT_PORT: &str = "4000";
+
+// LAGON_LISTEN_ADDR takes precedence over LAGON_HOST and LAGON_PORT,
+// which default to listening on all the interfaces on port 4000
+fn get_listen_addr() -> Result<SocketAddr> {
+    let get_var = |key: &str| env::var(key).ok().filter(|value| !value.is_empty());
+
+    if let Some(addr) = get_var("LAGON_LISTEN_ADDR") {
+        return addr
+            .parse()
+            .map_err(|_| anyhow!("Invalid LAGON_LISTEN_ADDR {}", addr));
+    }
+
+    let host = get_var("LAGON_HOST").unwrap_or_else(|| DEFAULT_HOST.into());
+    let port = get_var("LAGON_PORT").unwrap_or_else(|| DEFAULT_PORT.into());
+
+    let host = host
+        .parse::<IpAddr>()
+        .map_err(|_| anyhow!("Invalid LAGON_HOST {}", host))?;
+    let port = port
+        .parse::<u16>()
+        .map_err(|_| anyhow!("Invalid LAGON_PORT {}", port))?;
+
+    Ok(SocketAddr::new(host, port))
+}
+
+// Size of the pool and timeouts, so the node doesn't hang on a slow
+// database. The pool constraints default to the ones of DATABASE_URL
+fn database_opts(opts: Opts) -> Opts {
+    let constraints = opts.get_pool_opts().constraints();
+    let constraints = PoolConstraints::new(
+        parse_env("LAGON_DATABASE_POOL_MIN").unwrap_or(constraints.min()),
+        parse_env("LAGON_DATABASE_POOL_MAX").unwrap_or(constraints.max()),
+    )
+    .expect("LAGON_DATABASE_POOL_MIN must be lower than LAGON_DATABASE_POOL_MAX");
+    let pool_opts = opts.get_pool_opts().clone().with_constraints(constraints);
+
+    let mut builder = OptsBuilder::from_opts(opts)
+        .pool_opts(pool_opts)
+        .tcp_connect_timeout(
+            parse_env("LAGON_DATABASE_CONNECT_TIMEOUT_SECONDS").map(Duration::from_secs),
+        );
+
+    // Enforced by the server for SELECT statements, and
+    // by the socket in case the server doesn't answer
+    if let Some(statement_timeout) = parse_env::<u64>("LAGON_DATABASE_STATEMENT_TIMEOUT_MS") {
+        builder = builder
+            .init(vec![format!(
+                "SET SESSION max_execution_time = {}",
+                statement_timeout
+            )])
+            .read_timeout(Some(Duration::from_millis(statement_timeout)));
+    }
+
+    Opts::from(builder)
+}
+
+// The database can be briefly unavailable when the node starts (e.g during
+// orchestrated restarts), so retry with an exponential backoff before giving up
+async fn load_deployments(
+    opts: Opts,
+    downloader: Arc<dyn Downloader + Send + Sync>,
+) -> Result<(Deployments, Pool)> {
+    let mut backoff = DATABASE_INITIAL_BACKOFF;
+    let mut attempt = 1;
+
+    loop {
+        let result = async {
+            let pool = Pool::new(opts.clone())?;
+            let conn = match parse_env("LAGON_DATABASE_ACQUIRE_TIMEOUT_SECONDS") {
+                Some(timeout) => pool.try_get_conn(Duration::from_secs(timeout))?,
+                None => pool.get_conn()?,
+            };
+
+            let deployments = get_deployments(conn, Arc::clone(&downloader)).await?;
+
+            Ok::<_, anyhow::Error>((deployments, pool))
+        }
+        .await;
+
+        match result {
+            Ok(result) => return Ok(result),
+            Err(error) if attempt < DATABASE_ATTEMPTS => {
+                warn!(
+                    "Failed to load deployments (attempt {}/{}), retrying in {:?}: {}",
+                    attempt, DATABASE_ATTEMPTS, backoff, error
+                );
+
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+// Connections checked out from the pool are pinged, and reconnected when
+// stale (e.g closed by the server after being idle), so regularly check out
+// one to keep the pool healthy and notice when the database is unreachable
+fn run_database_health_check(pool: Pool) {
+    let interval = Duration::from_secs(
+        parse_env("LAGON_DATABASE_HEALTH_CHECK_INTERVAL_SECONDS")
+            .unwrap_or(DEFAULT_DATABASE_HEALTH_CHECK_INTERVAL_SECONDS),
+    );
+
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+
+            let pool = pool.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                let mut conn = pool.get_conn()?;
+
+                match conn.as_mut().ping() {
+                    true => Ok(()),
+                    false => Err(anyhow!("Ping failed")),
+                }
+            })
+            .await;
+
+            if let Ok(Err(error)) = result {
+                increment_counter!("lagon_database_health_check_failures", "region" => REGION.clone());
+                warn!("Database health check failed: {}", error);
+            }
+        }
+    });
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -27,48 +169,57 @@ async fn main() -> Result<()> {
     dotenv::dotenv().expect("Failed to load .env file");
 
     let _flush_guard = init_logger(REGION.clone()).expect("Failed to init logger");
+    init_tracing();
 
-    let runtime = Runtime::new(RuntimeOptions::default());
-    let addr: SocketAddr = env::var("LAGON_LISTEN_ADDR")
-        .expect("LAGON_LISTEN_ADDR must be set")
-        .parse()?;
-    let prometheus_addr: SocketAddr = env::var("PROMETHEUS_LISTEN_ADDR")
-        .expect("PROMETHEUS_LISTEN_ADDR must be set")
-        .parse()?;
+    let runtime = Runtime::new(RuntimeOptions::default().stack_size(*ISOLATE_STACK_SIZE));
+    let addr = get_listen_addr()?;
+    init_metrics().expect("Failed to start metrics exporter");
 
-    let mut builder = PrometheusBuilder::new().with_http_listener(prometheus_addr);
+    let client = create_client();
 
-    if let Ok(allowed_subnet) = env::var("PROMETHEUS_ALLOWED_SUBNET") {
-        if !allowed_subnet.is_empty() {
-            info!("Allowing Prometheus exporter to be accessed from {allowed_subnet}");
+    let serverless = match env::var("LAGON_LOCAL_DIR") {
+        // Load deployments from a local directory, without the database,
+        // storage and pub/sub (ClickHouse is still used for logs)
+        Ok(local_dir) if !local_dir.is_empty() => {
+            info!("Loading deployments from {}", local_dir);
 
-            builder = builder.add_allowed_address(allowed_subnet)?;
-        }
-    }
+            let local_dir = PathBuf::from(local_dir);
+            let downloader: Arc<dyn Downloader + Send + Sync> =
+                Arc::new(FilesystemDownloader::new(local_dir.clone()));
+            let pubsub = LocalPubSub::new(local_dir.clone());
 
-    builder.install().expect("Failed to start metrics exporter");
-
-    let url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let url = url.as_str();
-    let opts = Opts::from_url(url).expect("Failed to parse DATABASE_URL");
-    #[cfg(not(debug_assertions))]
-    let opts = OptsBuilder::from_opts(opts).ssl_opts(Some(SslOpts::default().with_root_cert_path(
-        Some(Cow::from(Path::new("/etc/ssl/certs/ca-certificates.crt"))),
-    )));
-    let pool = Pool::new(opts)?;
-    let conn = pool.get_conn()?;
+            let deployments = get_local_deployments(&local_dir, Arc::clone(&downloader)).await?;
+            start(deployments, addr, downloader, pubsub, client)
+                .await?
+                .boxed()
+        }
+        _ => {
+            let url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+            let url = url.as_str();
+            let opts = Opts::from_url(url).expect("Failed to parse DATABASE_URL");
+            #[cfg(not(debug_assertions))]
+            let opts = Opts::from(OptsBuilder::from_opts(opts).ssl_opts(Some(
+                SslOpts::default().with_root_cert_path(Some(Cow::from(Path::new(
+                    "/etc/ssl/certs/ca-certificates.crt",
+                )))),
+            )));
+            let opts = database_opts(opts);
+            let downloader = get_downloader()?;
 
-    let bucket = get_bucket()?;
-    let downloader = Arc::new(S3BucketDownloader::new(bucket));
+            let url = env::var("REDIS_URL").expect("REDIS_URL must be set");
+            let pubsub = RedisPubSub::new(url);
 
-    let url = env::var("REDIS_URL").expect("REDIS_URL must be set");
-    let pubsub = RedisPubSub::new(url);
+            run_migrations(&client).await?;
 
-    let client = create_client();
-    run_migrations(&client).await?;
+            let (deployments, pool) = load_deployments(opts, Arc::clone(&downloader)).await?;
 
-    let deployments = get_deployments(conn, Arc::clone(&downloader)).await?;
-    let serverless = start(deployments, addr, downloader, pubsub, client).await?;
+            run_database_health_check(pool.clone());
+            DATABASE_POOL.set(pool).unwrap_or(());
+            start(deployments, addr, downloader, pubsub, client)
+                .await?
+                .boxed()
+        }
+    };
     tokio::spawn(serverless).await?;
 
     runtime.dispose();
@@ -0,0 +1,187 @@
+use anyhow::Result;
+use hyper::{
+    client::HttpConnector,
+    header::{CONTENT_TYPE, WWW_AUTHENTICATE},
+    server::conn::AddrStream,
+    service::{make_service_fn, service_fn},
+    Body, Client, Method, Request, Response, Server,
+};
+use hyper_tls::HttpsConnector;
+use lagon_runtime_utils::{auth::Auth, client_ip::TrustedProxies};
+use log::{error, info, warn};
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use once_cell::sync::OnceCell;
+use std::{convert::Infallible, net::SocketAddr, sync::Arc, time::Duration};
+
+use crate::serverless::parse_env;
+
+const DEFAULT_PATH: &str = "/metrics";
+const DEFAULT_PUSH_INTERVAL_SECONDS: u64 = 10;
+const CONTENT_TYPE_TEXT: &str = "text/plain; version=0.0.4";
+
+// Set when the metrics are also served on the main port of the server,
+// so they share the recorder with the exporter
+static MAIN_PORT_HANDLE: OnceCell<PrometheusHandle> = OnceCell::new();
+
+struct Exporter {
+    handle: PrometheusHandle,
+    path: String,
+    // Subnets allowed to scrape the metrics, all when not set
+    allowed_subnet: Option<TrustedProxies>,
+    auth: Option<Auth>,
+}
+
+impl Exporter {
+    fn respond(&self, req: &Request<Body>, remote_addr: &SocketAddr) -> Result<Response<Body>> {
+        let is_allowed = self.allowed_subnet.as_ref().map_or(true, |allowed_subnet| {
+            allowed_subnet.contains(&remote_addr.ip())
+        });
+
+        if !is_allowed {
+            return Ok(Response::builder().status(403).body(Body::empty())?);
+        }
+
+        if req.uri().path() != self.path {
+            return Ok(Response::builder().status(404).body(Body::empty())?);
+        }
+
+        if let Some(auth) = &self.auth {
+            if !auth.is_authorized(req.headers()) {
+                return Ok(Response::builder()
+                    .status(401)
+                    .header(WWW_AUTHENTICATE, auth.www_authenticate())
+                    .body(Body::empty())?);
+            }
+        }
+
+        Ok(Response::builder()
+            .header(CONTENT_TYPE, CONTENT_TYPE_TEXT)
+            .body(Body::from(self.handle.render()))?)
+    }
+}
+
+fn serve(exporter: Exporter, addr: SocketAddr) -> Result<()> {
+    let exporter = Arc::new(exporter);
+    let server = Server::try_bind(&addr)?.serve(make_service_fn(move |conn: &AddrStream| {
+        let exporter = Arc::clone(&exporter);
+        let remote_addr = conn.remote_addr();
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let response = exporter.respond(&req, &remote_addr);
+
+                async move { response }
+            }))
+        }
+    }));
+
+    tokio::spawn(async move {
+        if let Err(error) = server.await {
+            error!("Metrics exporter stopped: {}", error);
+        }
+    });
+
+    Ok(())
+}
+
+// The metrics to serve on the main port, or None when it's not enabled.
+// Authenticating the request is up to the caller
+pub fn main_port_metrics() -> Option<Result<Response<Body>>> {
+    let handle = MAIN_PORT_HANDLE.get()?;
+
+    Some(
+        Response::builder()
+            .header(CONTENT_TYPE, CONTENT_TYPE_TEXT)
+            .body(Body::from(handle.render()))
+            .map_err(Into::into),
+    )
+}
+
+// The whole set of metrics replaces the previous one on each push
+fn push(handle: PrometheusHandle, url: String, interval: Duration) {
+    tokio::spawn(async move {
+        let client = Client::builder().build::<_, Body>(HttpsConnector::<HttpConnector>::new());
+
+        loop {
+            tokio::time::sleep(interval).await;
+
+            let request = match Request::builder()
+                .method(Method::PUT)
+                .uri(&url)
+                .header(CONTENT_TYPE, CONTENT_TYPE_TEXT)
+                .body(Body::from(handle.render()))
+            {
+                Ok(request) => request,
+                Err(error) => {
+                    error!("Error while building metrics request: {}", error);
+                    continue;
+                }
+            };
+
+            match client.request(request).await {
+                Ok(response) if !response.status().is_success() => {
+                    error!("Error while pushing metrics: {}", response.status());
+                }
+                Err(error) => {
+                    error!("Error while pushing metrics: {}", error);
+                }
+                _ => {}
+            }
+        }
+    });
+}
+
+// Metrics are pushed to PROMETHEUS_PUSH_GATEWAY_URL when it's set, without
+// listening for scrapes. Otherwise, they are served on PROMETHEUS_LISTEN_ADDR,
+// optionally restricted to a subnet and protected by a bearer token.
+// PROMETHEUS_ON_MAIN_PORT also serves them on the main port, behind
+// the admin secret, for environments where a second port can't be opened
+pub fn init_metrics() -> Result<()> {
+    let handle = PrometheusBuilder::new().install_recorder()?;
+
+    if parse_env("PROMETHEUS_ON_MAIN_PORT").unwrap_or(false) {
+        info!("Serving metrics on the main port");
+        MAIN_PORT_HANDLE.set(handle.clone()).unwrap_or(());
+    }
+
+    if let Some(url) = parse_env::<String>("PROMETHEUS_PUSH_GATEWAY_URL") {
+        let interval = Duration::from_secs(
+            parse_env("PROMETHEUS_PUSH_INTERVAL_SECONDS").unwrap_or(DEFAULT_PUSH_INTERVAL_SECONDS),
+        );
+
+        info!("Pushing metrics to {} every {:?}", url, interval);
+        push(handle, url, interval);
+
+        return Ok(());
+    }
+
+    let addr = match parse_env::<SocketAddr>("PROMETHEUS_LISTEN_ADDR") {
+        Some(addr) => addr,
+        None => {
+            if MAIN_PORT_HANDLE.get().is_none() {
+                warn!("PROMETHEUS_LISTEN_ADDR and PROMETHEUS_PUSH_GATEWAY_URL are not set, metrics won't be exported");
+            }
+
+            return Ok(());
+        }
+    };
+
+    let allowed_subnet = parse_env::<TrustedProxies>("PROMETHEUS_ALLOWED_SUBNET");
+
+    if allowed_subnet.is_some() {
+        info!(
+            "Allowing Prometheus exporter to be accessed from {}",
+            parse_env::<String>("PROMETHEUS_ALLOWED_SUBNET").unwrap_or_default()
+        );
+    }
+
+    serve(
+        Exporter {
+            handle,
+            path: parse_env("PROMETHEUS_PATH").unwrap_or_else(|| DEFAULT_PATH.into()),
+            allowed_subnet,
+            auth: parse_env("PROMETHEUS_BEARER_TOKEN").map(|token| Auth::Bearer { token }),
+        },
+        addr,
+    )
+}
@@ -0,0 +1,37 @@
+use anyhow::Result;
+use futures::lock::Mutex;
+use once_cell::sync::Lazy;
+use redis::{aio::MultiplexedConnection, Client, RedisError};
+use std::env;
+
+// The same Redis as the pub/sub, used by the KV storage
+// and the distributed rate limits
+static CLIENT: Lazy<Client> = Lazy::new(|| {
+    let url = env::var("REDIS_URL").expect("REDIS_URL must be set");
+
+    Client::open(url).expect("Failed to open Redis Client")
+});
+
+// Shared by all the requests and isolates, and recreated
+// on the next operation when the connection is lost
+static CONNECTION: Lazy<Mutex<Option<MultiplexedConnection>>> = Lazy::new(|| Mutex::new(None));
+
+pub async fn get_connection() -> Result<MultiplexedConnection> {
+    let mut connection = CONNECTION.lock().await;
+
+    if let Some(connection) = connection.as_ref() {
+        return Ok(connection.clone());
+    }
+
+    let new_connection = CLIENT.get_multiplexed_tokio_connection().await?;
+    *connection = Some(new_connection.clone());
+
+    Ok(new_connection)
+}
+
+// Drop the connection when the error means it was lost
+pub async fn handle_error(error: &RedisError) {
+    if error.is_io_error() || error.is_connection_dropped() {
+        CONNECTION.lock().await.take();
+    }
+}
@@ -1,54 +1,822 @@
 use crate::{
     clickhouse::{LogRow, RequestRow},
     cronjob::Cronjob,
-    deployments::{cache::run_cache_clear_task, pubsub::listen_pub_sub, Deployments},
+    deployments::{
+        cache::run_cache_clear_task,
+        get_environment_variables,
+        pubsub::{clear_deployment_cache, listen_pub_sub, run_resync_task},
+        record_deployments_count, Deployments,
+    },
+    distributed_rate_limit,
+    kv::{create_kv_callback, KV_MAX_VALUE_SIZE},
+    telemetry::Span,
+    timeout::{ConnectionTimeouts, TimeoutConnection, TimeoutIncoming},
+    tls::{Incoming, TlsConfig},
+    validate::validate_deployment,
+    warm_pool::{init_warm_pool, spawn_isolate},
     REGION, SNAPSHOT_BLOB,
 };
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use bytes::{Bytes, BytesMut};
 use clickhouse::{inserter::Inserter, Client};
 use dashmap::DashMap;
 use futures::lock::Mutex;
 use hyper::{
-    header::HOST,
+    body::HttpBody,
+    header::{
+        HeaderMap, HeaderName, HeaderValue, ACCEPT_ENCODING, ALLOW, CONNECTION, CONTENT_LENGTH,
+        CONTENT_TYPE, COOKIE, EXPECT, HOST, RETRY_AFTER, SEC_WEBSOCKET_EXTENSIONS,
+        SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_PROTOCOL, SEC_WEBSOCKET_VERSION, SET_COOKIE, UPGRADE,
+        WWW_AUTHENTICATE,
+    },
     http::response::Builder,
-    server::conn::AddrStream,
+    server::{conn::AddrIncoming, Builder as ServerBuilder},
     service::{make_service_fn, service_fn},
-    Body, Request, Response, Server,
+    upgrade::OnUpgrade,
+    Body, Method, Request, Response, Server, Uri,
+};
+use lagon_runtime_http::{
+    RunResult, LAGON_RUN_RESULT, SERVER_TIMING, TRACEPARENT, X_FORWARDED_FOR, X_LAGON_ADMIN_SECRET,
+    X_LAGON_DEPLOYMENT, X_LAGON_ID, X_LAGON_MEMORY, X_LAGON_REGION, X_LAGON_TIMEOUT,
+    X_LAGON_WEBSOCKET_EVENT, X_LAGON_WEBSOCKET_ID, X_REQUEST_ID,
 };
-use lagon_runtime_http::{RunResult, X_FORWARDED_FOR, X_LAGON_ID, X_LAGON_REGION, X_REAL_IP};
 use lagon_runtime_isolate::{
-    options::{IsolateOptions, Metadata},
-    Isolate, IsolateEvent, IsolateRequest,
+    options::{get_log_level, FetchPolicy, IsolateOptions, LogMessage},
+    Isolate, IsolateEvent, IsolateRequest, RequestBodyStream,
 };
 use lagon_runtime_utils::{
     assets::{find_asset, handle_asset},
-    response::{handle_response, ResponseEvent, FAVICON_URL, PAGE_403, PAGE_404},
-    DEPLOYMENTS_DIR,
+    circuit_breaker::{CircuitBreaker, CircuitBreakerOptions},
+    client_ip::{apply_proxy_headers, get_client_ip, TrustedProxies},
+    compression::{compress_head_response, compress_response, CompressionOptions},
+    debug_body::{format_body, format_headers},
+    rate_limit::{RateLimit, TokenBucket},
+    response::{
+        apply_default_headers, apply_early_hints, handle_response, into_head_response,
+        strip_hop_by_hop_headers, strip_response_headers, ResponseEvent, FAVICON_URL, PAGE_400,
+        PAGE_401, PAGE_403, PAGE_404, PAGE_405, PAGE_408, PAGE_413, PAGE_415, PAGE_417, PAGE_421,
+        PAGE_429, PAGE_431, PAGE_500, PAGE_503, PAGE_504, PAGE_MAINTENANCE,
+    },
+    response_cache::ResponseCache,
+    trace_context::TraceContext,
+    websocket::{is_upgrade_request, upgrade_response, Message, WebSocket, CLOSE_INTERNAL_ERROR},
+    Deployment, LogLevel, DEPLOYMENTS_DIR,
 };
 use lagon_serverless_downloader::Downloader;
 use lagon_serverless_pubsub::PubSubListener;
-use log::{as_debug, error, info, warn};
-use metrics::{decrement_gauge, histogram, increment_counter, increment_gauge};
+use log::{as_debug, error, info, warn, Level, LevelFilter};
+use metrics::{counter, decrement_gauge, gauge, histogram, increment_counter, increment_gauge};
+use once_cell::sync::{Lazy, OnceCell};
+use serde_json::{json, Value};
 use std::{
+    any::Any,
     collections::HashSet,
     convert::Infallible,
-    env,
+    env, fs,
     future::Future,
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
+    os::unix::fs::FileTypeExt,
+    panic::{self, AssertUnwindSafe},
     path::Path,
-    sync::Arc,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
     time::{Duration, Instant, UNIX_EPOCH},
 };
-use tokio::{runtime::Handle, sync::Mutex as TokioMutex};
+use tokio::{
+    net::UnixListener,
+    runtime::Handle,
+    signal::unix::{signal, SignalKind},
+    sync::{oneshot, Mutex as TokioMutex, OwnedSemaphorePermit, Semaphore},
+};
+use uuid::Uuid;
+
+const DEFAULT_SHUTDOWN_GRACE_PERIOD_SECONDS: u64 = 30;
+const HEALTH_PATH: &str = "/__lagon/health";
+const READY_PATH: &str = "/__lagon/ready";
+const ADMIN_DEPLOYMENTS_PATH: &str = "/__lagon/deployments";
+const ADMIN_DRAIN_PATH: &str = "/__lagon/drain";
+const ADMIN_VALIDATE_PATH: &str = "/__lagon/validate";
+const ADMIN_METRICS_PATH: &str = "/__lagon/metrics";
+const CODE_FETCH_ATTEMPTS: u32 = 3;
+const CODE_FETCH_INITIAL_BACKOFF: Duration = Duration::from_millis(50);
+const DEFAULT_MAX_BODY_SIZE: usize = 10 * 1024 * 1024;
+const DEFAULT_MAX_HEADERS: usize = 100;
+const DEFAULT_MAX_HEADERS_SIZE: usize = 64 * 1024;
+const DEFAULT_HEADER_READ_TIMEOUT_SECONDS: u64 = 10;
+const DEFAULT_BODY_READ_TIMEOUT_SECONDS: u64 = 30;
+const DEFAULT_CONNECTION_IDLE_TIMEOUT_SECONDS: u64 = 120;
+const DEFAULT_CONNECTION_LIFETIME_SECONDS: u64 = 60 * 60;
+const ISOLATE_THREAD_STACK_MARGIN_KB: usize = 1024;
+const RATE_LIMITS_CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+const ISOLATES_EXHAUSTED_RETRY_AFTER_SECONDS: u64 = 1;
+const OVERLOADED_RETRY_AFTER_SECONDS: u64 = 1;
+// Time a queued request waits for another one to complete
+const IN_FLIGHT_QUEUE_TIMEOUT: Duration = Duration::from_secs(1);
+const DEFAULT_CIRCUIT_BREAKER_COOLDOWN_SECONDS: u64 = 30;
+const DEBUG_BODY_MAX_SIZE: usize = 1024;
+const DEFAULT_MIN_MEMORY: usize = 8;
+const DEFAULT_MIN_TIMEOUT: usize = 10;
+// Chunks of a streamed request body received ahead of the isolate,
+// the client is slowed down when the isolate reads slower
+const REQUEST_BODY_STREAM_CHUNKS: usize = 16;
+const VARIANT_COOKIE: &str = "lagon-variant";
+const VARIANT_COOKIE_MAX_AGE_SECONDS: u64 = 30 * 24 * 60 * 60;
+// Variant of the clients pinned to the deployment itself
+const DEFAULT_VARIANT: &str = "default";
+
+static COMPRESSION_OPTIONS: Lazy<CompressionOptions> = Lazy::new(|| {
+    let default_options = CompressionOptions::default();
+
+    CompressionOptions {
+        encodings: env::var("LAGON_COMPRESSION_ENCODINGS").map_or(
+            default_options.encodings,
+            |value| {
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|encoding| !encoding.is_empty())
+                    .map(|encoding| {
+                        encoding
+                            .parse()
+                            .expect("LAGON_COMPRESSION_ENCODINGS contains an invalid encoding")
+                    })
+                    .collect()
+            },
+        ),
+        min_size: env::var("LAGON_COMPRESSION_MIN_SIZE")
+            .ok()
+            .filter(|value| !value.is_empty())
+            .map_or(default_options.min_size, |value| {
+                value
+                    .parse()
+                    .expect("LAGON_COMPRESSION_MIN_SIZE is not a valid number")
+            }),
+    }
+});
+
+// Headers added to the responses of all the deployments (e.g CSP or
+// HSTS), as a JSON object. Functions can override them
+static DEFAULT_RESPONSE_HEADERS: Lazy<HeaderMap> = Lazy::new(|| {
+    parse_env::<Value>("LAGON_DEFAULT_RESPONSE_HEADERS")
+        .and_then(|headers| headers.as_object().cloned())
+        .unwrap_or_default()
+        .iter()
+        .map(|(name, value)| {
+            HeaderName::from_str(name)
+                .ok()
+                .zip(
+                    value
+                        .as_str()
+                        .and_then(|value| HeaderValue::from_str(value).ok()),
+                )
+                .unwrap_or_else(|| {
+                    panic!(
+                        "LAGON_DEFAULT_RESPONSE_HEADERS contains an invalid header {}",
+                        name
+                    )
+                })
+        })
+        .collect()
+});
+static ADMIN_SECRET: Lazy<Option<String>> = Lazy::new(|| parse_env("LAGON_ADMIN_SECRET"));
+
+// Hostname of the deployment serving requests to unknown hostnames,
+// e.g a landing page for misconfigured custom domains
+static FALLBACK_HOSTNAME: Lazy<Option<String>> = Lazy::new(|| parse_env("LAGON_FALLBACK_HOSTNAME"));
+
+// Path to an HTML page replacing the default 404 page
+// for unknown hostnames, read once
+static NOT_FOUND_PAGE: Lazy<Option<String>> = Lazy::new(|| {
+    parse_env::<String>("LAGON_NOT_FOUND_PAGE_PATH").map(|path| {
+        fs::read_to_string(&path).unwrap_or_else(|error| {
+            panic!(
+                "Could not read LAGON_NOT_FOUND_PAGE_PATH {}: {}",
+                path, error
+            )
+        })
+    })
+});
+
+// Maximum size of request bodies for deployments without a `maxBodySize`
+static MAX_BODY_SIZE: Lazy<usize> =
+    Lazy::new(|| parse_env("LAGON_MAX_BODY_SIZE").unwrap_or(DEFAULT_MAX_BODY_SIZE));
+
+// Maximum number of request headers, requests above get a 431. Their
+// total size is limited by hyper, with LAGON_MAX_HEADERS_SIZE
+static MAX_HEADERS: Lazy<usize> =
+    Lazy::new(|| parse_env("LAGON_MAX_HEADERS").unwrap_or(DEFAULT_MAX_HEADERS));
+
+// Maximum number of requests handled at the same time by the node, to
+// shed load with 503s instead of running out of memory. Unlimited when
+// not set. Up to LAGON_IN_FLIGHT_QUEUE_SIZE requests wait for a slot
+static MAX_IN_FLIGHT_REQUESTS: Lazy<Option<usize>> =
+    Lazy::new(|| parse_env("LAGON_MAX_IN_FLIGHT_REQUESTS"));
+static IN_FLIGHT_QUEUE_SIZE: Lazy<usize> =
+    Lazy::new(|| parse_env("LAGON_IN_FLIGHT_QUEUE_SIZE").unwrap_or(0));
+
+// Time to receive a whole request body when it isn't streamed to the
+// isolate, answering with a 408 otherwise. 0 disables the timeout
+static BODY_READ_TIMEOUT: Lazy<Option<Duration>> = Lazy::new(|| {
+    parse_timeout(
+        "LAGON_BODY_READ_TIMEOUT_SECONDS",
+        DEFAULT_BODY_READ_TIMEOUT_SECONDS,
+    )
+});
+
+// Maximum size of response bodies for deployments without a
+// `maxResponseSize`. Unlimited when not set
+static MAX_RESPONSE_SIZE: Lazy<Option<usize>> = Lazy::new(|| parse_env("LAGON_MAX_RESPONSE_SIZE"));
+
+// Maximum size in bytes of the cached responses, the
+// response cache is disabled when not set
+static RESPONSE_CACHE_MAX_SIZE: Lazy<Option<usize>> =
+    Lazy::new(|| parse_env("LAGON_RESPONSE_CACHE_MAX_SIZE"));
+
+// Load balancers or proxies allowed to set the client IP with headers
+static TRUSTED_PROXIES: Lazy<TrustedProxies> =
+    Lazy::new(|| parse_env("LAGON_TRUSTED_PROXIES").unwrap_or_default());
+
+// Block fetch() calls to private IPs (e.g internal services or the cloud
+// metadata endpoint) unless explicitly disabled
+static FETCH_BLOCK_PRIVATE_IPS: Lazy<bool> =
+    Lazy::new(|| parse_env("LAGON_FETCH_BLOCK_PRIVATE_IPS").unwrap_or(true));
+
+// Add X-Forwarded-Proto, X-Forwarded-Host and X-Real-IP
+// headers to the requests sent to the isolates
+static PROXY_HEADERS: Lazy<bool> = Lazy::new(|| parse_env("LAGON_PROXY_HEADERS").unwrap_or(false));
+
+// Hostnames allowed for deployments without `fetchAllowedHosts`
+static FETCH_ALLOWED_HOSTS: Lazy<Option<Vec<String>>> = Lazy::new(|| {
+    parse_env::<String>("LAGON_FETCH_ALLOWED_HOSTS").map(|value| {
+        value
+            .split(',')
+            .map(|host| host.trim().to_string())
+            .filter(|host| !host.is_empty())
+            .collect()
+    })
+});
+
+// Rate limit applied to all deployments without a `rateLimit`
+static RATE_LIMIT: Lazy<Option<RateLimit>> = Lazy::new(|| {
+    parse_env("LAGON_RATE_LIMIT_REQUESTS_PER_SECOND").map(|requests_per_second| RateLimit {
+        requests_per_second,
+        burst: parse_env("LAGON_RATE_LIMIT_BURST"),
+    })
+});
+
+// Ceilings of the memory (in MB) and timeouts (in ms) of the deployments,
+// to protect a host shared by many Functions from generous settings
+static MAX_MEMORY: Lazy<Option<usize>> = Lazy::new(|| parse_env("LAGON_MAX_MEMORY"));
+static MAX_TIMEOUT: Lazy<Option<usize>> = Lazy::new(|| parse_env("LAGON_MAX_TIMEOUT"));
+
+// Floors of the memory (in MB) and timeouts (in ms) of the deployments,
+// below which an isolate can't run them
+static MIN_MEMORY: Lazy<usize> =
+    Lazy::new(|| parse_env("LAGON_MIN_MEMORY").unwrap_or(DEFAULT_MIN_MEMORY));
+static MIN_TIMEOUT: Lazy<usize> =
+    Lazy::new(|| parse_env("LAGON_MIN_TIMEOUT").unwrap_or(DEFAULT_MIN_TIMEOUT));
+
+// Hard limit of isolates on this node, each one running in its own
+// thread. Unlimited when not set, the cache only evicts periodically
+static MAX_ISOLATES: Lazy<Option<usize>> = Lazy::new(|| parse_env("LAGON_MAX_ISOLATES"));
+
+// Record the heap statistics of the isolates beyond their memory
+// usage, e.g to tell a memory leak from a legitimate high usage
+static HEAP_STATISTICS: Lazy<bool> =
+    Lazy::new(|| parse_env("LAGON_HEAP_STATISTICS").unwrap_or(false));
+
+// Maximum size in KB of the call stack of the isolates, a deeper
+// recursion throws a RangeError. Uses the default of V8 when not set
+pub static ISOLATE_STACK_SIZE: Lazy<Option<usize>> =
+    Lazy::new(|| parse_env("LAGON_ISOLATE_STACK_SIZE"));
+
+// Stop running the isolate of deployments failing repeatedly,
+// returning a 503 until the cooldown ends
+static CIRCUIT_BREAKER: Lazy<Option<CircuitBreakerOptions>> = Lazy::new(|| {
+    parse_env("LAGON_CIRCUIT_BREAKER_THRESHOLD").map(|threshold| CircuitBreakerOptions {
+        threshold,
+        cooldown: Duration::from_secs(
+            parse_env("LAGON_CIRCUIT_BREAKER_COOLDOWN_SECONDS")
+                .unwrap_or(DEFAULT_CIRCUIT_BREAKER_COOLDOWN_SECONDS),
+        ),
+    })
+});
+
+// Log a line for each request once responded, for a
+// ratio (between 0 and 1) of the requests
+static ACCESS_LOG: Lazy<bool> = Lazy::new(|| parse_env("LAGON_ACCESS_LOG").unwrap_or(false));
+static ACCESS_LOG_SAMPLE_RATE: Lazy<f64> =
+    Lazy::new(|| parse_env("LAGON_ACCESS_LOG_SAMPLE_RATE").unwrap_or(1.0));
+
+// Log a warning for requests taking longer than this, even when the
+// access log is disabled
+static SLOW_REQUEST_THRESHOLD: Lazy<Option<Duration>> =
+    Lazy::new(|| parse_env("LAGON_SLOW_REQUEST_MS").map(Duration::from_millis));
+
+// Isolate pools by worker key, see `Deployment::worker_key`
+pub type Workers = Arc<DashMap<String, IsolatePool>>;
+
+// Isolates of a deployment, only one unless it has an `isolatePoolSize`.
+// Requests are sent to each isolate in turn, skipping the ones that stopped
+pub struct IsolatePool {
+    // Tells pools of the same worker key apart, e.g when one is replaced
+    id: usize,
+    senders: Vec<flume::Sender<IsolateEvent>>,
+    next: AtomicUsize,
+}
+
+static NEXT_POOL_ID: AtomicUsize = AtomicUsize::new(0);
+
+impl IsolatePool {
+    fn new(
+        deployment: Arc<Deployment>,
+        workers: &Workers,
+        log_sender: flume::Sender<LogMessage>,
+        request_id: String,
+    ) -> Self {
+        let size = deployment.config.isolate_pool_size.unwrap_or(1).max(1);
+        let senders = (0..size)
+            .map(|index| {
+                create_isolate_worker(
+                    Arc::clone(&deployment),
+                    Some(Arc::clone(workers)),
+                    index,
+                    log_sender.clone(),
+                    request_id.clone(),
+                )
+            })
+            .collect();
+
+        Self {
+            id: NEXT_POOL_ID.fetch_add(1, Ordering::Relaxed),
+            senders,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.senders.len()
+    }
+
+    fn sender(&self) -> flume::Sender<IsolateEvent> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let size = self.senders.len();
+
+        (0..size)
+            .map(|offset| &self.senders[(start + offset) % size])
+            .find(|sender| !sender.is_disconnected())
+            .unwrap_or(&self.senders[start % size])
+            .clone()
+    }
+
+    // All the isolates stopped, e.g because they reached their limits
+    fn is_stopped(&self) -> bool {
+        self.senders.iter().all(|sender| sender.is_disconnected())
+    }
+
+    pub fn terminate(&self, reason: &str) {
+        for sender in &self.senders {
+            sender
+                .send(IsolateEvent::Terminate(reason.to_string()))
+                .unwrap_or(());
+        }
+    }
+}
+
+// The pool is only removed once all its isolates stopped,
+// the other ones keep answering requests in the meantime
+fn remove_stopped_pool(workers: &Workers, deployment_id: &str) {
+    workers.remove_if(deployment_id, |_, pool| pool.is_stopped());
+    record_isolates_count(workers);
+}
+
+// Heap used by each cached isolate after its last request, in bytes, by
+// worker key (see `Deployment::worker_key`) and index in its pool.
+// Isolates of a single request aren't included
+pub static ISOLATES_MEMORY_USAGE: Lazy<DashMap<(String, usize), usize>> = Lazy::new(DashMap::new);
+
+pub fn isolates_count(workers: &Workers) -> usize {
+    workers.iter().map(|pool| pool.size()).sum()
+}
+
+// Updated whenever an isolate is created or removed, and regularly
+// by the cache task in case a removal was missed
+pub fn record_isolates_count(workers: &Workers) {
+    gauge!(
+        "lagon_isolates_count",
+        isolates_count(workers) as f64,
+        "region" => REGION.clone(),
+    );
+}
+
+// Semaphores of deployments with a `maxConcurrency`, by deployment id
+type ConcurrencyLimits = Arc<DashMap<String, Arc<Semaphore>>>;
+
+// Number of requests currently handled by each isolate, by worker
+// key. Isolates without requests can be evicted to create new ones
+pub type IsolateRequests = Arc<DashMap<String, usize>>;
+
+// Circuit breakers by deployment id, only used when
+// LAGON_CIRCUIT_BREAKER_THRESHOLD is set
+type CircuitBreakers = Arc<DashMap<String, CircuitBreaker>>;
+
+// Token buckets by deployment id and client IP. DashMap is sharded,
+// so requests from different clients rarely contend on the same lock
+type RateLimits = Arc<DashMap<(String, String), TokenBucket>>;
+
+// Number of requests whose bodies have been logged, by deployment
+// id, for deployments with a `debugBodies` config
+type DebugBodies = Arc<DashMap<String, usize>>;
+
+// Metrics labels of a deployment, shared by the callbacks of a request
+// (called for each chunk of a stream) instead of being cloned each time
+type Labels = Arc<[(&'static str, String); 4]>;
+
+// Reading the code can transiently fail, so retry a few times with
+// an exponential backoff before giving up
+async fn get_code_with_retry(deployment: &Deployment) -> Result<String> {
+    let mut backoff = CODE_FETCH_INITIAL_BACKOFF;
+
+    for _ in 1..CODE_FETCH_ATTEMPTS {
+        match deployment.get_code() {
+            Ok(code) => return Ok(code),
+            Err(error) => {
+                warn!(deployment = deployment.id, function = deployment.function_id; "Error while getting deployment code, retrying in {:?}: {}", backoff, error);
+
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
+    }
+
+    deployment.get_code()
+}
+
+fn get_panic_message(panic: &Box<dyn Any + Send>) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("Unknown panic")
+}
+
+fn clamp_limit(deployment: &Deployment, name: &str, value: usize, max: Option<usize>) -> usize {
+    match max {
+        Some(max) if value > max => {
+            warn!(deployment = deployment.id, function = deployment.function_id; "Deployment {} of {} is above the maximum of {}, using the maximum", name, value, max);
+
+            max
+        }
+        _ => value,
+    }
+}
+
+// Deployments with limits below the minimums of the node are rejected,
+// instead of failing to create an isolate. Limits above the maximums
+// are clamped by `with_deployment_limits`
+pub fn check_deployment_limits(deployment: &Deployment) -> Result<()> {
+    if deployment.memory < *MIN_MEMORY {
+        return Err(anyhow!(
+            "memory of {} MB is below the minimum of {} MB",
+            deployment.memory,
+            *MIN_MEMORY
+        ));
+    }
+
+    for (name, timeout) in [
+        ("tick timeout", deployment.tick_timeout),
+        ("total timeout", deployment.total_timeout),
+    ] {
+        if timeout < *MIN_TIMEOUT {
+            return Err(anyhow!(
+                "{} of {} ms is below the minimum of {} ms",
+                name,
+                timeout,
+                *MIN_TIMEOUT
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+// Memory and timeouts of the deployment, clamped to the maximums of the node
+pub fn with_deployment_limits(options: IsolateOptions, deployment: &Deployment) -> IsolateOptions {
+    let memory = clamp_limit(deployment, "memory", deployment.memory, *MAX_MEMORY);
+    let tick_timeout = clamp_limit(
+        deployment,
+        "tick timeout",
+        deployment.tick_timeout,
+        *MAX_TIMEOUT,
+    );
+    let total_timeout = clamp_limit(
+        deployment,
+        "total timeout",
+        deployment.total_timeout,
+        *MAX_TIMEOUT,
+    );
+
+    options
+        .memory(memory)
+        .tick_timeout(Duration::from_millis(tick_timeout as u64))
+        .total_timeout(Duration::from_millis(total_timeout as u64))
+}
+
+// Logs of the deployment above its `logLevel` are dropped, all
+// of them are kept when it's not set
+pub fn get_log_filter(deployment: &Deployment) -> LevelFilter {
+    match deployment.config.log_level {
+        Some(LogLevel::Off) => LevelFilter::Off,
+        Some(LogLevel::Error) => LevelFilter::Error,
+        Some(LogLevel::Warn) => LevelFilter::Warn,
+        Some(LogLevel::Info) => LevelFilter::Info,
+        Some(LogLevel::Debug) | None => LevelFilter::Trace,
+    }
+}
+
+pub fn get_fetch_policy(deployment: &Deployment) -> FetchPolicy {
+    FetchPolicy {
+        block_private_ips: *FETCH_BLOCK_PRIVATE_IPS,
+        allowed_hosts: deployment
+            .config
+            .fetch_allowed_hosts
+            .clone()
+            .or_else(|| FETCH_ALLOWED_HOSTS.clone()),
+    }
+}
+
+// Isolates created without workers aren't shared with other requests,
+// e.g when running a single request with overridden limits. `index` is
+// the index of the isolate in the pool of its deployment
+pub fn create_isolate_worker(
+    deployment: Arc<Deployment>,
+    workers: Option<Workers>,
+    index: usize,
+    log_sender: flume::Sender<LogMessage>,
+    request_id: String,
+) -> flume::Sender<IsolateEvent> {
+    let handle = Handle::current();
+    let (sender, receiver) = flume::unbounded();
+    let labels = [
+        ("deployment", deployment.id.clone()),
+        ("function", deployment.function_id.clone()),
+        ("region", REGION.clone()),
+    ];
+
+    spawn_isolate(String::from("isolate-") + deployment.id.as_str(), deployment.memory, Box::new(move |warm| {
+        let panic_deployment = Arc::clone(&deployment);
+        let panic_workers = workers.clone();
+        let panic_labels = labels.clone();
+        let cached = workers.is_some();
+
+        // A panic would otherwise leave a dead worker in the map,
+        // failing all the next requests to this deployment
+        let result = panic::catch_unwind(AssertUnwindSafe(|| handle.block_on(async move {
+            increment_gauge!("lagon_isolates", 1.0, &labels);
+            info!(deployment = deployment.id, function = deployment.function_id, request = request_id; "Creating new isolate");
+
+            let code = match get_code_with_retry(&deployment).await {
+                Ok(code) => deployment.verify_code(code.as_bytes()).map(|_| code).map_err(|error| {
+                    increment_counter!("lagon_code_integrity_failures", &labels);
+                    error
+                }),
+                Err(error) => {
+                    increment_counter!("lagon_code_fetch_errors", &labels);
+                    Err(error)
+                }
+            };
+
+            // Secrets are decrypted last, right before being given to the isolate
+            let code = code.and_then(|code| {
+                get_environment_variables(&deployment).map(|environment_variables| (code, environment_variables))
+            });
+
+            let (code, environment_variables) = match code {
+                Ok(code) => code,
+                Err(error) => {
+                    error!(deployment = deployment.id, function = deployment.function_id, request = request_id; "Error while preparing deployment: {}", error);
+
+                    // Answer the requests that were already sent to this worker, and
+                    // don't keep a broken isolate around so the next request can retry
+                    while let Ok(event) = receiver.try_recv() {
+                        if let IsolateEvent::Request(request) = event {
+                            request
+                                .sender
+                                .send_async(RunResult::Error("Could not prepare deployment.".into()))
+                                .await
+                                .unwrap_or(());
+                        }
+                    }
+
+                    drop(receiver);
+
+                    if let Some(workers) = &workers {
+                        remove_stopped_pool(workers, &deployment.worker_key());
+                    }
+
+                    decrement_gauge!("lagon_isolates", 1.0, &labels);
+                    return;
+                }
+            };
+
+            // Reuse the code cache of a previous compilation, e.g before
+            // the isolate was dropped or the node restarted
+            let code_cache_key = deployment.code_cache_key(&code);
+            let code_cache = deployment.get_code_cache(code_cache_key);
+            let code_cache_status = if code_cache.is_some() { "hit" } else { "miss" };
+            let code_cache_deployment = Arc::clone(&deployment);
+            let drop_worker_key = deployment.worker_key();
+            let statistics_worker_key = deployment.worker_key();
+
+            let options = IsolateOptions::new(code)
+                .environment_variables(environment_variables);
+            let options = with_deployment_limits(options, &deployment)
+                .metadata(Some((
+                    deployment.id.clone(),
+                    deployment.function_id.clone(),
+                )))
+                .on_drop_callback(Box::new(move |metadata| {
+                    if let Some(metadata) = metadata.as_ref().as_ref() {
+                        let labels = [
+                            ("deployment", metadata.0.clone()),
+                            ("function", metadata.1.clone()),
+                            ("region", REGION.clone()),
+                        ];
+
+                        if cached {
+                            ISOLATES_MEMORY_USAGE.remove(&(drop_worker_key.clone(), index));
+                        }
 
-pub type Workers = Arc<DashMap<String, flume::Sender<IsolateEvent>>>;
+                        decrement_gauge!("lagon_isolates", 1.0, &labels);
+                        info!(deployment = metadata.0, function = metadata.1; "Dropping isolate");
+                    }
+                }))
+                .on_statistics_callback(Box::new(move |metadata, statistics| {
+                    if let Some(metadata) = metadata.as_ref().as_ref() {
+                        let labels = [
+                            ("deployment", metadata.0.clone()),
+                            ("function", metadata.1.clone()),
+                            ("region", REGION.clone()),
+                            ("result", statistics.result.to_string()),
+                        ];
+
+                        if cached {
+                            ISOLATES_MEMORY_USAGE.insert((statistics_worker_key.clone(), index), statistics.memory_usage);
+                        }
+
+                        histogram!(
+                            "lagon_isolate_memory_usage",
+                            statistics.memory_usage as f64,
+                            &labels
+                        );
+                        histogram!(
+                            "lagon_isolate_cpu_time",
+                            statistics.cpu_time,
+                            &labels
+                        );
+
+                        if let Some(heap) = statistics.heap {
+                            histogram!(
+                                "lagon_isolate_total_heap_size",
+                                heap.total_heap_size as f64,
+                                &labels
+                            );
+                            histogram!(
+                                "lagon_isolate_external_memory",
+                                heap.external_memory as f64,
+                                &labels
+                            );
+                            counter!("lagon_isolate_gcs", heap.gc_count as u64, &labels);
+                        }
+                    }
+                }))
+                .heap_statistics(*HEAP_STATISTICS)
+                .log_level(get_log_filter(&deployment))
+                .fetch_policy(get_fetch_policy(&deployment))
+                .on_fetch_blocked_callback(Box::new(|metadata, host| {
+                    if let Some(metadata) = metadata.as_ref().as_ref() {
+                        let labels = [
+                            ("deployment", metadata.0.clone()),
+                            ("function", metadata.1.clone()),
+                            ("region", REGION.clone()),
+                        ];
+
+                        increment_counter!("lagon_fetch_blocked", &labels);
+                        warn!(deployment = metadata.0, function = metadata.1, host = host; "Blocked fetch() call");
+                    }
+                }))
+                .code_cache(code_cache)
+                .on_code_cache_callback(Box::new(move |code_cache| {
+                    if let Err(error) = code_cache_deployment.write_code_cache(code_cache_key, &code_cache) {
+                        error!(deployment = code_cache_deployment.id, function = code_cache_deployment.function_id; "Error while writing code cache: {}", error);
+                    }
+                }))
+                .log_sender(log_sender)
+                .snapshot_blob(SNAPSHOT_BLOB);
+
+            let mut options = match create_kv_callback() {
+                Some(on_kv) => options.on_kv_callback(on_kv),
+                None => options,
+            };
+
+            if let Some(max_value_size) = *KV_MAX_VALUE_SIZE {
+                options = options.kv_max_value_size(max_value_size);
+            }
+
+            let init_start = Instant::now();
+            let (mut isolate, warm_pool_status) = match warm {
+                Some(warm) if warm.is_compatible(&options) => (Isolate::from_warm(warm, options, receiver), "hit"),
+                warm => {
+                    // V8 requires isolates to be dropped in the
+                    // reverse order of their creation
+                    drop(warm);
+
+                    (Isolate::new(options, receiver), "miss")
+                }
+            };
+
+            increment_counter!("lagon_isolates_created", &labels);
+            histogram!(
+                "lagon_isolate_init_time",
+                init_start.elapsed().as_secs_f64(),
+                &[
+                    labels[0].clone(),
+                    labels[1].clone(),
+                    labels[2].clone(),
+                    ("warm_pool", String::from(warm_pool_status)),
+                ]
+            );
+
+            // Compared between code cache hits and misses, it measures the
+            // cold start gain of the cache on the real deployments, which a
+            // benchmark on a sample code wouldn't reflect
+            let evaluation_start = Instant::now();
+            isolate.evaluate();
+            histogram!(
+                "lagon_isolate_evaluation_duration",
+                evaluation_start.elapsed().as_secs_f64(),
+                "code_cache" => code_cache_status,
+                "region" => REGION.clone(),
+            );
+            isolate.run_event_loop().await;
+
+            // When the event loop is completed, that means a) the isolate was terminate due to limits
+            // or b) the isolate was dropped because of cache expiration. In the first case, the isolate
+            // isn't removed from the workers map. Dropping it first marks it as stopped in its pool
+            drop(isolate);
+
+            if let Some(workers) = &workers {
+                remove_stopped_pool(workers, &deployment.worker_key());
+            }
+        })));
+
+        if let Err(panic) = result {
+            if let Some(workers) = &panic_workers {
+                ISOLATES_MEMORY_USAGE.remove(&(panic_deployment.worker_key(), index));
+                remove_stopped_pool(workers, &panic_deployment.worker_key());
+            }
+
+            increment_counter!("lagon_isolate_panics", &panic_labels);
+            error!(deployment = panic_deployment.id, function = panic_deployment.function_id; "Isolate panicked: {}", get_panic_message(&panic));
+        }
+    })).unwrap();
+
+    sender
+}
+
+// Create the isolate of deployments that should be warm in advance,
+// so the first request doesn't have to wait for it to be created
+pub fn warmup_deployment(
+    deployment: Arc<Deployment>,
+    workers: &Workers,
+    log_sender: flume::Sender<LogMessage>,
+) {
+    if !deployment.config.warm
+        || deployment.config.maintenance
+        || check_deployment_limits(&deployment).is_err()
+    {
+        return;
+    }
+
+    let isolate_workers = Arc::clone(workers);
+
+    workers.entry(deployment.worker_key()).or_insert_with(|| {
+        info!(deployment = deployment.id, function = deployment.function_id; "Warming up isolate");
+
+        IsolatePool::new(deployment, &isolate_workers, log_sender, String::new())
+    });
+}
 
 async fn handle_error(
     result: RunResult,
     function_id: String,
     deployment_id: String,
     request_id: &String,
-    labels: &[(&'static str, String); 3],
+    labels: &[(&'static str, String); 4],
+    log_filter: LevelFilter,
     inserters: Arc<Mutex<(Inserter<RequestRow>, Inserter<LogRow>)>>,
 ) {
     let (level, message) = match result {
@@ -68,6 +836,14 @@ async fn handle_error(
 
             ("warn", message.into())
         }
+        RunResult::StackOverflow(error) => {
+            increment_counter!("lagon_isolate_stack_overflows", labels);
+
+            let message = format!("Function exceeded its maximum call stack size: {}", error);
+            warn!(deployment = deployment_id, function = function_id, request = request_id; "{}", message);
+
+            ("warn", message)
+        }
         RunResult::Error(error) => {
             increment_counter!("lagon_isolate_errors", labels);
 
@@ -76,9 +852,71 @@ async fn handle_error(
 
             ("error", message)
         }
+        // Every request fails until the deployment is fixed
+        RunResult::CompileError(error) | RunResult::EvaluationError(error) => {
+            increment_counter!("lagon_isolate_compile_errors", labels);
+
+            let message = format!("Function compilation error: {}", error);
+            error!(deployment = deployment_id, function = function_id, request = request_id; "{}", message);
+
+            ("error", message)
+        }
         _ => ("warn", "Unknown result".into()),
     };
 
+    // Still logged by the serverless, independently of the deployment
+    if get_log_level(level) > log_filter {
+        return;
+    }
+
+    write_log(
+        level,
+        message,
+        function_id,
+        deployment_id,
+        request_id,
+        inserters,
+    )
+    .await;
+}
+
+async fn handle_size_limit(
+    max_size: usize,
+    function_id: String,
+    deployment_id: String,
+    request_id: &String,
+    labels: &[(&'static str, String); 4],
+    log_filter: LevelFilter,
+    inserters: Arc<Mutex<(Inserter<RequestRow>, Inserter<LogRow>)>>,
+) {
+    increment_counter!("lagon_response_size_limits", labels);
+
+    let message = format!("Function response is larger than {} bytes", max_size);
+    warn!(deployment = deployment_id, function = function_id, request = request_id; "{}", message);
+
+    if Level::Warn > log_filter {
+        return;
+    }
+
+    write_log(
+        "warn",
+        message,
+        function_id,
+        deployment_id,
+        request_id,
+        inserters,
+    )
+    .await;
+}
+
+async fn write_log(
+    level: &str,
+    message: String,
+    function_id: String,
+    deployment_id: String,
+    request_id: &str,
+    inserters: Arc<Mutex<(Inserter<RequestRow>, Inserter<LogRow>)>>,
+) {
     if let Err(error) = inserters
         .lock()
         .await
@@ -90,6 +928,7 @@ async fn handle_error(
             message,
             region: REGION.clone(),
             timestamp: UNIX_EPOCH.elapsed().unwrap().as_secs() as u32,
+            request_id: request_id.to_string(),
         })
         .await
     {
@@ -97,206 +936,2330 @@ async fn handle_error(
     }
 }
 
-async fn handle_request(
-    req: Request<Body>,
-    ip: String,
-    deployments: Deployments,
-    last_requests: Arc<DashMap<String, Instant>>,
-    workers: Workers,
-    inserters: Arc<Mutex<(Inserter<RequestRow>, Inserter<LogRow>)>>,
-    log_sender: flume::Sender<(String, String, Metadata)>,
-) -> Result<Response<Body>> {
-    let request_id = match req.headers().get(X_LAGON_ID) {
-        Some(x_lagon_id) => x_lagon_id.to_str().unwrap_or("").to_string(),
-        None => String::new(),
+// Clients sending `Expect: 100-continue` wait before sending the body,
+// which hyper accepts with a 100 Continue once the body is first read.
+// Refusing instead avoids uploading a body larger than the limit, and
+// other expectations can't be met
+fn meets_expectation(headers: &HeaderMap, body: &Body, limit: usize) -> bool {
+    match headers.get(EXPECT) {
+        Some(expect) => {
+            expect.as_bytes().eq_ignore_ascii_case(b"100-continue")
+                && body.size_hint().lower() <= limit as u64
+        }
+        None => true,
+    }
+}
+
+enum BodyError {
+    // Answered with a 413
+    TooLarge,
+    // The body doesn't match its Content-Length, e.g because the client
+    // stopped sending it early. Answered with a 400
+    LengthMismatch,
+    // The body wasn't received before `LAGON_BODY_READ_TIMEOUT_SECONDS`.
+    // Answered with a 408
+    Timeout,
+    Read(hyper::Error),
+}
+
+// None for chunked bodies, whose end is found by hyper from their framing
+fn get_content_length(headers: &HeaderMap) -> Option<usize> {
+    headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+}
+
+// Read the whole body, or stop as soon as it's larger than the
+// limit to avoid buffering abusive uploads in memory
+async fn read_body(
+    mut body: Body,
+    limit: usize,
+    content_length: Option<usize>,
+) -> Result<Bytes, BodyError> {
+    if body.size_hint().lower() > limit as u64 {
+        return Err(BodyError::TooLarge);
+    }
+
+    let mut bytes = BytesMut::new();
+
+    while let Some(chunk) = body.data().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(_) if content_length.is_some() => return Err(BodyError::LengthMismatch),
+            Err(error) => return Err(BodyError::Read(error)),
+        };
+
+        if bytes.len() + chunk.len() > limit {
+            return Err(BodyError::TooLarge);
+        }
+
+        bytes.extend_from_slice(&chunk);
+    }
+
+    if content_length.map_or(false, |content_length| content_length != bytes.len()) {
+        return Err(BodyError::LengthMismatch);
+    }
+
+    Ok(bytes.freeze())
+}
+
+// Send the body to the isolate while it's received, stopping with an
+// error once it's larger than the limit or when it doesn't match its
+// Content-Length. `bytes_in` is updated with the size received so far
+fn stream_body(
+    mut body: Body,
+    limit: usize,
+    content_length: Option<usize>,
+    bytes_in: Arc<AtomicUsize>,
+    labels: Labels,
+) -> RequestBodyStream {
+    let (sender, receiver) = flume::bounded(REQUEST_BODY_STREAM_CHUNKS);
+
+    tokio::spawn(async move {
+        let mut received = 0;
+
+        while let Some(chunk) = body.data().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(error) => {
+                    sender
+                        .send_async(Err(format!("Could not read request body: {}", error)))
+                        .await
+                        .unwrap_or(());
+                    return;
+                }
+            };
+
+            received += chunk.len();
+            bytes_in.store(received, Ordering::Relaxed);
+
+            if received > limit {
+                increment_counter!("lagon_requests_body_too_large", &*labels);
+
+                sender
+                    .send_async(Err(format!("Request body is larger than {} bytes", limit)))
+                    .await
+                    .unwrap_or(());
+                return;
+            }
+
+            // The isolate doesn't read the body anymore, e.g
+            // because it already answered
+            if sender.send_async(Ok(chunk)).await.is_err() {
+                return;
+            }
+        }
+
+        if content_length.map_or(false, |content_length| content_length != received) {
+            increment_counter!("lagon_requests_length_mismatch", &*labels);
+
+            sender
+                .send_async(Err(String::from(
+                    "Request body doesn't match its Content-Length",
+                )))
+                .await
+                .unwrap_or(());
+        }
+    });
+
+    receiver
+}
+
+// Exact hostnames first, then wildcard domains from the most specific one
+// (e.g `*.preview.example.com` then `*.example.com` for
+// `a.preview.example.com`), and finally the fallback deployment
+fn find_deployment(deployments: &Deployments, hostname: &str) -> Option<Arc<Deployment>> {
+    if let Some(entry) = deployments.get(hostname) {
+        return Some(Arc::clone(entry.value()));
+    }
+
+    let mut parent = hostname;
+
+    while let Some((_, rest)) = parent.split_once('.') {
+        if let Some(entry) = deployments.get(&format!("*.{}", rest)) {
+            return Some(Arc::clone(entry.value()));
+        }
+
+        parent = rest;
+    }
+
+    FALLBACK_HOSTNAME
+        .as_ref()
+        .and_then(|fallback| deployments.get(fallback))
+        .map(|entry| Arc::clone(entry.value()))
+}
+
+// Production deployments with a canary send a part of their traffic to
+// the canary deployment, which is found using its default domain
+fn pick_deployment(deployment: Arc<Deployment>, deployments: &Deployments) -> Arc<Deployment> {
+    let canary = match &deployment.config.canary {
+        Some(canary) if deployment.is_production && canary.deployment_id != deployment.id => canary,
+        _ => return deployment,
+    };
+
+    if rand::random::<f64>() * 100.0 >= canary.weight {
+        return deployment;
+    }
+
+    let root_domain = env::var("LAGON_ROOT_DOMAIN").expect("LAGON_ROOT_DOMAIN must be set");
+
+    match deployments.get(&format!("{}.{}", canary.deployment_id, root_domain)) {
+        Some(entry) if entry.function_id == deployment.function_id => Arc::clone(entry.value()),
+        _ => deployment,
+    }
+}
+
+// Production deployments with a shadow mirror a part of their traffic to
+// the shadow deployment, found like canaries
+fn pick_shadow(deployment: &Deployment, deployments: &Deployments) -> Option<Arc<Deployment>> {
+    let shadow = match &deployment.config.shadow {
+        Some(shadow) if deployment.is_production && shadow.deployment_id != deployment.id => shadow,
+        _ => return None,
     };
 
-    let hostname = match req.headers().get(HOST) {
-        Some(hostname) => hostname.to_str()?.to_string(),
-        None => {
-            increment_counter!(
-                "lagon_ignored_requests",
-                "reason" => "No hostname",
-                "region" => REGION.clone(),
-            );
-            warn!(req = as_debug!(req), ip = ip, request = request_id; "No Host header found in request");
+    if rand::random::<f64>() * 100.0 >= shadow.weight {
+        return None;
+    }
+
+    let root_domain = env::var("LAGON_ROOT_DOMAIN").expect("LAGON_ROOT_DOMAIN must be set");
+
+    deployments
+        .get(&format!("{}.{}", shadow.deployment_id, root_domain))
+        .filter(|entry| entry.function_id == deployment.function_id)
+        .map(|entry| Arc::clone(entry.value()))
+}
+
+// Clients are pinned to a variant with a cookie. New clients (or clients
+// whose variant was removed) get a random one by weight, with the cookie
+// to set. The deployment itself gets the remaining weight, like canaries
+fn pick_variant(
+    deployment: Arc<Deployment>,
+    headers: &HeaderMap,
+) -> (Arc<Deployment>, Option<HeaderValue>) {
+    if deployment.config.variants.is_empty() {
+        return (deployment, None);
+    }
+
+    let pinned_variant = headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .find_map(|cookie| match cookie.trim().split_once('=') {
+            Some((name, value)) if name == VARIANT_COOKIE => Some(value),
+            _ => None,
+        });
+
+    if let Some(name) = pinned_variant {
+        if name == DEFAULT_VARIANT {
+            return (deployment, None);
+        }
+
+        if let Some(variant) = deployment.with_variant(name) {
+            return (Arc::new(variant), None);
+        }
+    }
+
+    let mut random = rand::random::<f64>() * 100.0;
+    let variant = deployment.config.variants.iter().find(|variant| {
+        if random < variant.weight {
+            return true;
+        }
+
+        random -= variant.weight;
+        false
+    });
+
+    let name = variant.map_or(DEFAULT_VARIANT, |variant| variant.name.as_str());
+    let cookie = HeaderValue::from_str(&format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+        VARIANT_COOKIE, name, VARIANT_COOKIE_MAX_AGE_SECONDS
+    ))
+    .ok();
+
+    match deployment.with_variant(name) {
+        Some(variant) => (Arc::new(variant), cookie),
+        None => (deployment, cookie),
+    }
+}
+
+// Reuse the id of the request if it has been set by a proxy,
+// otherwise generate a new one
+fn get_request_id(req: &Request<Body>) -> String {
+    [X_REQUEST_ID, X_LAGON_ID]
+        .iter()
+        .find_map(|header| {
+            req.headers()
+                .get(*header)
+                .and_then(|value| value.to_str().ok())
+                .filter(|value| !value.is_empty())
+        })
+        .map_or_else(|| Uuid::new_v4().to_string(), String::from)
+}
+
+struct AccessLog {
+    method: Method,
+    path: String,
+    host: String,
+    bytes_in: u64,
+    start: Instant,
+}
+
+impl AccessLog {
+    // Sampled when the request is received, to avoid capturing
+    // anything for requests that won't be logged
+    fn sample(req: &Request<Body>) -> Option<Self> {
+        if !*ACCESS_LOG || rand::random::<f64>() >= *ACCESS_LOG_SAMPLE_RATE {
+            return None;
+        }
+
+        Some(Self {
+            method: req.method().clone(),
+            path: req.uri().path().to_string(),
+            host: req
+                .headers()
+                .get(HOST)
+                .and_then(|value| value.to_str().ok())
+                .map(String::from)
+                .or_else(|| req.uri().authority().map(|authority| authority.to_string()))
+                .unwrap_or_default(),
+            bytes_in: req.body().size_hint().lower(),
+            start: Instant::now(),
+        })
+    }
+
+    fn log(self, response: &Response<Body>, request_id: &str) {
+        let deployment = response
+            .headers()
+            .get(X_LAGON_DEPLOYMENT)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default();
+        let status = response.status().as_u16();
+        let response_time = self.start.elapsed().as_secs_f64() * 1000.0;
+        // Streamed responses don't have a known size
+        let bytes_out = response.body().size_hint().exact().unwrap_or_default();
+
+        info!(
+            method = self.method.as_str(),
+            path = self.path,
+            host = self.host,
+            status = status,
+            response_time = response_time,
+            bytes_in = self.bytes_in,
+            bytes_out = bytes_out,
+            deployment = deployment,
+            request = request_id;
+            "{} {} {} {:.2}ms", self.method, self.path, status, response_time
+        );
+    }
+}
+
+// Answered on /__lagon/ready. The deployments are all loaded before the
+// server starts, so the node is ready until it drains or shuts down, as long
+// as the connections it depends on are live. The storage (e.g S3) isn't
+// checked: the code of the deployments is downloaded once to the disk, so
+// it being unreachable only fails new deployments, retried on the next
+// re-sync, and a download can also fail for a single missing deployment
+pub struct Readiness {
+    draining: AtomicBool,
+    // Deploy messages are missed while disconnected from the pub/sub
+    pubsub_connected: AtomicBool,
+    // Result of the last re-sync of the deployments, which checks out
+    // a pinged connection from the database pool
+    database_connected: AtomicBool,
+}
+
+impl Readiness {
+    fn new() -> Self {
+        Self {
+            draining: AtomicBool::new(false),
+            pubsub_connected: AtomicBool::new(false),
+            database_connected: AtomicBool::new(true),
+        }
+    }
+
+    fn is_ready(&self) -> bool {
+        !self.draining.load(Ordering::Relaxed)
+            && self.pubsub_connected.load(Ordering::Relaxed)
+            && self.database_connected.load(Ordering::Relaxed)
+    }
+
+    // Returns false when the node was already draining
+    fn drain(&self) -> bool {
+        !self.draining.swap(true, Ordering::Relaxed)
+    }
+
+    pub fn set_pubsub_connected(&self, connected: bool) {
+        self.pubsub_connected.store(connected, Ordering::Relaxed);
+    }
+
+    pub fn set_database_connected(&self, connected: bool) {
+        self.database_connected.store(connected, Ordering::Relaxed);
+    }
+}
+
+// Reserved routes for load balancers, answered before looking up
+// the deployment so they never reach an isolate
+fn handle_probe(req: &Request<Body>, readiness: &Readiness) -> Option<Result<Response<Body>>> {
+    let status = match req.uri().path() {
+        HEALTH_PATH => 200,
+        READY_PATH if readiness.is_ready() => 200,
+        READY_PATH => 503,
+        _ => return None,
+    };
+
+    Some(
+        Response::builder()
+            .status(status)
+            .body(Body::empty())
+            .map_err(Into::into),
+    )
+}
+
+// Compare in constant time to not leak the secret through timing
+fn is_admin_secret(value: &[u8], secret: &[u8]) -> bool {
+    value.len() == secret.len()
+        && value
+            .iter()
+            .zip(secret)
+            .fold(0, |result, (a, b)| result | (a ^ b))
+            == 0
+}
+
+fn is_admin_request(headers: &HeaderMap, secret: &str) -> bool {
+    headers.get(X_LAGON_ADMIN_SECRET).map_or(false, |value| {
+        is_admin_secret(value.as_bytes(), secret.as_bytes())
+    })
+}
+
+// Limits of a single request set by a trusted caller authenticated with the
+// admin secret, e.g the control plane running a heavy batch call. The timeout
+// (in ms) overrides both the tick and total timeouts, and the memory is in MB.
+// These headers are always removed so they never reach the Function
+fn take_limits_override(headers: &mut HeaderMap, deployment: &Deployment) -> Option<Deployment> {
+    let parse = |value: Option<HeaderValue>| {
+        value.and_then(|value| value.to_str().ok()?.trim().parse::<usize>().ok())
+    };
+    let timeout = parse(headers.remove(X_LAGON_TIMEOUT));
+    let memory = parse(headers.remove(X_LAGON_MEMORY));
+    let is_trusted = ADMIN_SECRET
+        .as_ref()
+        .map_or(false, |secret| is_admin_request(headers, secret));
+    headers.remove(X_LAGON_ADMIN_SECRET);
+
+    if !is_trusted || (timeout.is_none() && memory.is_none()) {
+        return None;
+    }
+
+    let mut deployment = deployment.clone();
+
+    if let Some(timeout) = timeout {
+        deployment.tick_timeout = timeout;
+        deployment.total_timeout = timeout;
+    }
+
+    if let Some(memory) = memory {
+        deployment.memory = memory;
+    }
+
+    Some(deployment)
+}
+
+// Stop being ready so load balancers stop sending new requests, while
+// still answering them and the ones in flight until the node is shut down
+fn handle_drain(
+    req: &Request<Body>,
+    readiness: &Readiness,
+    in_flight_requests: &AtomicUsize,
+) -> Result<Response<Body>> {
+    if req.method() != Method::POST {
+        return Ok(Response::builder()
+            .status(405)
+            .header(ALLOW, "POST")
+            .body(Body::empty())?);
+    }
+
+    let in_flight_requests = in_flight_requests.load(Ordering::Relaxed);
+
+    if readiness.drain() {
+        info!(in_flight_requests = in_flight_requests; "Draining, {} requests in flight", in_flight_requests);
+    }
+
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(
+            json!({
+                "draining": true,
+                "inFlightRequests": in_flight_requests,
+            })
+            .to_string()
+            .into(),
+        )?)
+}
+
+// Variants have their own isolates, under `<id>#<variant>`
+fn is_isolate_created(workers: &Workers, deployment_id: &str) -> bool {
+    workers.contains_key(deployment_id)
+        || workers.iter().any(|entry| {
+            entry
+                .key()
+                .strip_prefix(deployment_id)
+                .map_or(false, |key| key.starts_with('#'))
+        })
+}
+
+// List the loaded deployments and whether their isolate is currently
+// created, or drain the node. Disabled unless LAGON_ADMIN_SECRET is set
+fn handle_admin(
+    req: &Request<Body>,
+    deployments: &Deployments,
+    workers: &Workers,
+    circuit_breakers: &CircuitBreakers,
+    readiness: &Readiness,
+    in_flight_requests: &AtomicUsize,
+) -> Option<Result<Response<Body>>> {
+    let path = req.uri().path();
+
+    if path != ADMIN_DEPLOYMENTS_PATH && path != ADMIN_DRAIN_PATH {
+        return None;
+    }
+
+    // Reserved routes are never sent to the isolates, even when disabled
+    let secret = match ADMIN_SECRET.as_ref() {
+        Some(secret) => secret,
+        None => {
+            return Some(
+                Response::builder()
+                    .status(404)
+                    .body(Body::empty())
+                    .map_err(Into::into),
+            )
+        }
+    };
+
+    if !is_admin_request(req.headers(), secret) {
+        return Some(
+            Response::builder()
+                .status(401)
+                .body(Body::empty())
+                .map_err(Into::into),
+        );
+    }
+
+    if path == ADMIN_DRAIN_PATH {
+        return Some(handle_drain(req, readiness, in_flight_requests));
+    }
+
+    let mut list = deployments
+        .iter()
+        .map(|entry| {
+            let deployment = entry.value();
+
+            json!({
+                "hostname": entry.key(),
+                "deploymentId": deployment.id,
+                "functionId": deployment.function_id,
+                "functionName": deployment.function_name,
+                "isProduction": deployment.is_production,
+                "memory": deployment.memory,
+                "tickTimeout": deployment.tick_timeout,
+                "totalTimeout": deployment.total_timeout,
+                "cron": deployment.cron,
+                "isolateCreated": is_isolate_created(workers, &deployment.id),
+                "circuitBreaker": circuit_breakers
+                    .get(&deployment.id)
+                    .map_or("closed", |circuit_breaker| circuit_breaker.state()),
+            })
+        })
+        .collect::<Vec<_>>();
+    list.sort_by(|a, b| a["hostname"].as_str().cmp(&b["hostname"].as_str()));
+
+    Some(
+        Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(Value::from(list).to_string().into())
+            .map_err(Into::into),
+    )
+}
+
+// Serve the metrics on the main port when PROMETHEUS_ON_MAIN_PORT and
+// LAGON_ADMIN_SECRET are set. The path is always answered here, so it
+// never reaches a Function even when disabled
+fn handle_metrics(req: &Request<Body>) -> Result<Response<Body>> {
+    let secret = match ADMIN_SECRET.as_ref() {
+        Some(secret) => secret,
+        None => return Ok(Response::builder().status(404).body(Body::empty())?),
+    };
+
+    if !is_admin_request(req.headers(), secret) {
+        return Ok(Response::builder().status(401).body(Body::empty())?);
+    }
+
+    #[cfg(feature = "prometheus")]
+    if let Some(response) = crate::prometheus::main_port_metrics() {
+        return response;
+    }
+
+    Ok(Response::builder().status(404).body(Body::empty())?)
+}
+
+// Run a loaded deployment in a throwaway isolate, e.g to check that a
+// preview deployment compiles and answers before promoting it. Needs the
+// request body, so it isn't handled with the other admin endpoints
+async fn handle_validate(
+    req: Request<Body>,
+    secret: &str,
+    deployments: &Deployments,
+) -> Result<Response<Body>> {
+    if !is_admin_request(req.headers(), secret) {
+        return Ok(Response::builder().status(401).body(Body::empty())?);
+    }
+
+    if req.method() != Method::POST {
+        return Ok(Response::builder()
+            .status(405)
+            .header(ALLOW, "POST")
+            .body(Body::empty())?);
+    }
+
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let deployment_id = match serde_json::from_slice::<Value>(&body)
+        .ok()
+        .and_then(|body| body["deploymentId"].as_str().map(String::from))
+    {
+        Some(deployment_id) => deployment_id,
+        None => {
+            return Ok(Response::builder()
+                .status(400)
+                .header(CONTENT_TYPE, "application/json")
+                .body(
+                    json!({ "error": "Missing deploymentId" })
+                        .to_string()
+                        .into(),
+                )?)
+        }
+    };
+
+    let deployment = deployments
+        .iter()
+        .find(|entry| entry.id == deployment_id)
+        .map(|entry| Arc::clone(entry.value()));
+
+    let deployment = match deployment {
+        Some(deployment) => deployment,
+        None => {
+            return Ok(Response::builder()
+                .status(404)
+                .header(CONTENT_TYPE, "application/json")
+                .body(
+                    json!({ "error": "Deployment not found" })
+                        .to_string()
+                        .into(),
+                )?)
+        }
+    };
+
+    info!(deployment = deployment.id, function = deployment.function_id; "Validating deployment");
+
+    let validation = validate_deployment(deployment).await;
+
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(validation.to_string().into())?)
+}
+
+// Terminate an isolate created for a single request once dropped,
+// i.e when the response (or the whole stream) has been sent
+struct SingleRequestIsolateGuard(flume::Sender<IsolateEvent>);
+
+impl Drop for SingleRequestIsolateGuard {
+    fn drop(&mut self) {
+        self.0
+            .send(IsolateEvent::Terminate(String::from("single request")))
+            .unwrap_or(());
+    }
+}
+
+// Decrement the number of requests handled by an isolate once dropped,
+// i.e when the response (or the whole stream) has been sent
+struct IsolateRequestGuard {
+    deployment_id: String,
+    isolate_requests: IsolateRequests,
+}
+
+impl IsolateRequestGuard {
+    fn new(deployment_id: String, isolate_requests: IsolateRequests) -> Self {
+        *isolate_requests.entry(deployment_id.clone()).or_default() += 1;
+
+        Self {
+            deployment_id,
+            isolate_requests,
+        }
+    }
+}
+
+impl Drop for IsolateRequestGuard {
+    fn drop(&mut self) {
+        self.isolate_requests
+            .remove_if_mut(&self.deployment_id, |_, requests| {
+                *requests -= 1;
+                *requests == 0
+            });
+    }
+}
+
+// Dropped with the request future while the isolate is still running it,
+// e.g when hyper drops it because the client disconnected. Dropping the
+// response receiver at the same time aborts the run in the isolate
+struct CancellationGuard {
+    function_id: String,
+    deployment_id: String,
+    request_id: String,
+    labels: Labels,
+    is_armed: bool,
+}
+
+impl CancellationGuard {
+    fn new(function_id: String, deployment_id: String, request_id: String, labels: Labels) -> Self {
+        Self {
+            function_id,
+            deployment_id,
+            request_id,
+            labels,
+            is_armed: true,
+        }
+    }
+
+    // The isolate answered, or the request timed out
+    fn disarm(mut self) {
+        self.is_armed = false;
+    }
+}
+
+impl Drop for CancellationGuard {
+    fn drop(&mut self) {
+        if self.is_armed {
+            increment_counter!("lagon_requests_client_cancelled", &*self.labels);
+            warn!(deployment = self.deployment_id, function = self.function_id, request = self.request_id; "Client disconnected before the response");
+        }
+    }
+}
+
+// Release the slot of a queued request once dropped, which also happens
+// when hyper drops the request future because the client disconnected
+struct QueueSlotGuard<'a> {
+    queued: &'a AtomicUsize,
+    // Number of requests already queued before this one
+    position: usize,
+}
+
+impl<'a> QueueSlotGuard<'a> {
+    fn new(queued: &'a AtomicUsize) -> Self {
+        let position = queued.fetch_add(1, Ordering::Relaxed);
+
+        Self { queued, position }
+    }
+}
+
+impl Drop for QueueSlotGuard<'_> {
+    fn drop(&mut self) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Once all the slots are used, requests wait in a small queue for one to be
+// released, or are rejected when the queue is full or they waited too long
+struct InFlightLimit {
+    semaphore: Arc<Semaphore>,
+    queued: AtomicUsize,
+    queue_size: usize,
+}
+
+impl InFlightLimit {
+    fn new(max_requests: usize, queue_size: usize) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(max_requests)),
+            queued: AtomicUsize::new(0),
+            queue_size,
+        }
+    }
+
+    // Returns the reason of the rejection on error
+    async fn acquire(&self) -> Result<OwnedSemaphorePermit, &'static str> {
+        if let Ok(permit) = Arc::clone(&self.semaphore).try_acquire_owned() {
+            return Ok(permit);
+        }
+
+        let queue_slot = QueueSlotGuard::new(&self.queued);
+
+        if queue_slot.position >= self.queue_size {
+            return Err("queue_full");
+        }
+
+        let permit = tokio::time::timeout(
+            IN_FLIGHT_QUEUE_TIMEOUT,
+            Arc::clone(&self.semaphore).acquire_owned(),
+        )
+        .await;
+
+        match permit {
+            Ok(Ok(permit)) => Ok(permit),
+            _ => Err("queue_timeout"),
+        }
+    }
+}
+
+fn record_in_flight_requests(in_flight_requests: usize) {
+    gauge!(
+        "lagon_in_flight_requests",
+        in_flight_requests as f64,
+        "region" => REGION.clone(),
+    );
+}
+
+// Count a request as in flight until it's dropped, which also happens
+// when hyper drops the request future because the client disconnected
+struct InFlightRequestGuard {
+    in_flight_requests: Arc<AtomicUsize>,
+}
+
+impl InFlightRequestGuard {
+    fn new(in_flight_requests: Arc<AtomicUsize>) -> Self {
+        record_in_flight_requests(in_flight_requests.fetch_add(1, Ordering::Relaxed) + 1);
+
+        Self { in_flight_requests }
+    }
+}
+
+impl Drop for InFlightRequestGuard {
+    fn drop(&mut self) {
+        record_in_flight_requests(self.in_flight_requests.fetch_sub(1, Ordering::Relaxed) - 1);
+    }
+}
+
+// Terminate the least recently used idle isolate to make room for a new one.
+// Returns false when all the isolates are handling requests
+fn evict_isolate(
+    workers: &Workers,
+    last_requests: &DashMap<String, Instant>,
+    isolate_requests: &IsolateRequests,
+) -> bool {
+    let deployment_id = workers
+        .iter()
+        .filter(|entry| !isolate_requests.contains_key(entry.key()))
+        .map(|entry| {
+            let last_request = last_requests.get(entry.key()).map(|entry| *entry.value());
+
+            (entry.key().clone(), last_request)
+        })
+        .min_by_key(|(_, last_request)| *last_request)
+        .map(|(deployment_id, _)| deployment_id);
+
+    let (deployment_id, pool) =
+        match deployment_id.and_then(|deployment_id| workers.remove(&deployment_id)) {
+            Some(pool) => pool,
+            None => return false,
+        };
+
+    last_requests.remove(&deployment_id);
+
+    increment_counter!(
+        "lagon_isolates_evicted",
+        "reason" => "capacity",
+        "region" => REGION.clone(),
+    );
+
+    pool.terminate("capacity");
+
+    true
+}
+
+// Most requests target an isolate that already exists, so only take a
+// read lock first and fallback to a write lock to create the isolate.
+// The sender is cloned to avoid holding a lock while sending the request.
+// Returns None when the node already has the maximum number of isolates
+fn get_isolate_sender(
+    deployment: Arc<Deployment>,
+    workers: &Workers,
+    last_requests: &DashMap<String, Instant>,
+    isolate_requests: &IsolateRequests,
+    log_sender: flume::Sender<LogMessage>,
+    request_id: String,
+) -> Option<(flume::Sender<IsolateEvent>, bool)> {
+    let worker_key = deployment.worker_key();
+
+    if let Some(pool) = workers.get(&worker_key) {
+        if !pool.is_stopped() {
+            if pool.size() > 1 {
+                let requests = isolate_requests
+                    .get(&worker_key)
+                    .map_or(0, |requests| *requests);
+
+                histogram!(
+                    "lagon_isolate_pool_utilization",
+                    requests as f64 / pool.size() as f64,
+                    "deployment" => deployment.id.clone(),
+                    "function" => deployment.function_id.clone(),
+                    "region" => REGION.clone(),
+                );
+            }
+
+            return Some((pool.sender(), false));
+        }
+    }
+
+    // All the isolates of the pool stopped, but it wasn't removed yet
+    workers.remove_if(&worker_key, |_, pool| pool.is_stopped());
+
+    // Checked before taking the write lock, which would deadlock when
+    // iterating over the isolates to evict one
+    if let Some(max_isolates) = *MAX_ISOLATES {
+        if isolates_count(workers) >= max_isolates
+            && !evict_isolate(workers, last_requests, isolate_requests)
+        {
+            return None;
+        }
+    }
+
+    let mut cold_start = false;
+    let isolate_workers = Arc::clone(workers);
+    let isolate_sender = workers
+        .entry(worker_key)
+        .or_insert_with(|| {
+            cold_start = true;
+
+            IsolatePool::new(deployment, &isolate_workers, log_sender, request_id)
+        })
+        .sender();
+
+    record_isolates_count(workers);
+
+    Some((isolate_sender, cold_start))
+}
+
+// Everything needed to send a request once more to a new isolate
+struct RetryContext {
+    deployment: Arc<Deployment>,
+    // The pool created for the request, whose isolate failed
+    pool_id: Option<usize>,
+    workers: Workers,
+    last_requests: Arc<DashMap<String, Instant>>,
+    isolate_requests: IsolateRequests,
+    inserters: Arc<Mutex<(Inserter<RequestRow>, Inserter<LogRow>)>>,
+    log_sender: flume::Sender<LogMessage>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+    request_id: String,
+    labels: Labels,
+    // Past the request timeout, the client already got a 504
+    deadline: Option<Instant>,
+}
+
+impl RetryContext {
+    async fn retry(self) -> Result<flume::Receiver<RunResult>> {
+        let worker_key = self.deployment.worker_key();
+        let requests = self
+            .isolate_requests
+            .get(&worker_key)
+            .map_or(0, |requests| *requests);
+
+        // Only the pool created for this request is recreated, and only when
+        // it has no other requests in flight, which terminating it would
+        // kill. Otherwise, the retry goes to another isolate of the pool
+        if requests <= 1 {
+            if let Some((_, pool)) = self
+                .workers
+                .remove_if(&worker_key, |_, pool| Some(pool.id) == self.pool_id)
+            {
+                record_isolates_count(&self.workers);
+
+                pool.terminate("retry");
+            }
+        }
+
+        let (isolate_sender, _) = get_isolate_sender(
+            Arc::clone(&self.deployment),
+            &self.workers,
+            &self.last_requests,
+            &self.isolate_requests,
+            self.log_sender.clone(),
+            self.request_id.clone(),
+        )
+        .ok_or_else(|| anyhow!("Maximum number of isolates reached"))?;
+
+        let mut request = Request::builder()
+            .method(self.method.clone())
+            .uri(self.uri.clone())
+            .body(())?;
+        *request.headers_mut() = self.headers.clone();
+
+        let (parts, _) = request.into_parts();
+        let (sender, receiver) = flume::unbounded();
+
+        isolate_sender
+            .send_async(IsolateEvent::Request(IsolateRequest {
+                request: (parts, self.body.clone()),
+                body_stream: None,
+                sender,
+                request_id: Some(self.request_id.clone()),
+            }))
+            .await
+            .unwrap_or(());
+
+        Ok(receiver)
+    }
+}
+
+// Everything needed to mirror a request to a shadow deployment
+struct ShadowContext {
+    deployment: Arc<Deployment>,
+    workers: Workers,
+    last_requests: Arc<DashMap<String, Instant>>,
+    isolate_requests: IsolateRequests,
+    inserters: Arc<Mutex<(Inserter<RequestRow>, Inserter<LogRow>)>>,
+    log_sender: flume::Sender<LogMessage>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+    request_id: String,
+}
+
+impl ShadowContext {
+    // Spawned once the primary deployment responded, so the client never
+    // waits for the shadow. Its response is discarded, but its errors are
+    // logged and its metrics have a `shadow` label
+    async fn run(self) {
+        let deployment = Arc::clone(&self.deployment);
+        let worker_key = deployment.worker_key();
+        let labels: Labels = Arc::new([
+            ("deployment", deployment.id.clone()),
+            ("function", deployment.function_id.clone()),
+            ("region", REGION.clone()),
+            ("shadow", String::from("true")),
+        ]);
+        let log_filter = get_log_filter(&deployment);
+
+        let (isolate_sender, is_cold_start) = match get_isolate_sender(
+            Arc::clone(&deployment),
+            &self.workers,
+            &self.last_requests,
+            &self.isolate_requests,
+            self.log_sender.clone(),
+            self.request_id.clone(),
+        ) {
+            Some(isolate_sender) => isolate_sender,
+            None => {
+                increment_counter!("lagon_requests_isolates_exhausted", &*labels);
+                return;
+            }
+        };
+
+        if is_cold_start {
+            increment_counter!("lagon_isolate_cold_starts", &*labels);
+        }
+
+        increment_counter!("lagon_shadow_requests", &*labels);
+        self.last_requests
+            .insert(worker_key.clone(), Instant::now());
+
+        let _isolate_request = IsolateRequestGuard::new(worker_key, self.isolate_requests);
+        let mut request = match Request::builder()
+            .method(self.method)
+            .uri(self.uri)
+            .body(())
+        {
+            Ok(request) => request,
+            Err(error) => {
+                error!(deployment = deployment.id, function = deployment.function_id, request = self.request_id; "Error while building shadow request: {}", error);
+                return;
+            }
+        };
+        *request.headers_mut() = self.headers;
+
+        let (parts, _) = request.into_parts();
+        let (sender, receiver) = flume::unbounded();
+        let start = Instant::now();
+
+        isolate_sender
+            .send_async(IsolateEvent::Request(IsolateRequest {
+                request: (parts, self.body),
+                body_stream: None,
+                sender,
+                request_id: Some(self.request_id.clone()),
+            }))
+            .await
+            .unwrap_or(());
+
+        let function_id = deployment.function_id.clone();
+        let deployment_id = deployment.id.clone();
+        let request_id = self.request_id.clone();
+        let error_labels = Arc::clone(&labels);
+        let inserters = Arc::clone(&self.inserters);
+
+        // The body is read entirely to let streams complete
+        let response = async move {
+            let response = handle_response(receiver, None, move |event| {
+                let function_id = function_id.clone();
+                let deployment_id = deployment_id.clone();
+                let request_id = request_id.clone();
+                let labels = Arc::clone(&error_labels);
+                let inserters = Arc::clone(&inserters);
+
+                async move {
+                    match event {
+                        ResponseEvent::StreamDoneNoDataError => {
+                            handle_error(
+                                RunResult::Error(
+                                    "The stream was done before sending a response/data".into(),
+                                ),
+                                function_id,
+                                deployment_id,
+                                &request_id,
+                                &labels,
+                                log_filter,
+                                inserters,
+                            )
+                            .await;
+                        }
+                        ResponseEvent::UnexpectedStreamResult(result)
+                        | ResponseEvent::LimitsReached(result)
+                        | ResponseEvent::Error(result) => {
+                            handle_error(
+                                result,
+                                function_id,
+                                deployment_id,
+                                &request_id,
+                                &labels,
+                                log_filter,
+                                inserters,
+                            )
+                            .await;
+                        }
+                        _ => {}
+                    }
+
+                    Ok(())
+                }
+            })
+            .await?;
+
+            let status = response.status();
+            hyper::body::to_bytes(response.into_body()).await?;
+
+            Ok::<_, anyhow::Error>(status)
+        };
+
+        let request_timeout = deployment.config.request_timeout.map(Duration::from_millis);
+        let status = match request_timeout {
+            Some(request_timeout) => match tokio::time::timeout(request_timeout, response).await {
+                Ok(status) => status,
+                Err(_) => {
+                    increment_counter!("lagon_request_timeouts", &*labels);
+                    handle_error(
+                        RunResult::Timeout,
+                        deployment.function_id.clone(),
+                        deployment.id.clone(),
+                        &self.request_id,
+                        &labels,
+                        log_filter,
+                        Arc::clone(&self.inserters),
+                    )
+                    .await;
+                    return;
+                }
+            },
+            None => response.await,
+        };
+
+        histogram!(
+            "lagon_shadow_duration",
+            start.elapsed().as_secs_f64(),
+            &*labels
+        );
+
+        match status {
+            Ok(status) => {
+                let [deployment, function, region, shadow] = (*labels).clone();
+
+                increment_counter!(
+                    "lagon_shadow_responses",
+                    &[
+                        deployment,
+                        function,
+                        region,
+                        shadow,
+                        ("status", status.as_u16().to_string()),
+                    ]
+                );
+            }
+            Err(error) => {
+                error!(deployment = deployment.id, function = deployment.function_id, request = self.request_id; "Shadow isolate stopped before responding: {}", error);
+            }
+        }
+    }
+}
+
+// Errors while evaluating the code of an isolate created for the request,
+// e.g a failed fetch() at the top level of the code, can be transient.
+// The request is sent once more to a new isolate, and the original error
+// is logged. Compile errors (e.g syntax errors or imports) and errors thrown
+// by the handler aren't retried, since they would likely be thrown again.
+// The results are forwarded to the returned receiver
+fn retry_transient_error(
+    receiver: flume::Receiver<RunResult>,
+    context: RetryContext,
+) -> flume::Receiver<RunResult> {
+    let (sender, retry_receiver) = flume::unbounded();
+
+    tokio::spawn(async move {
+        let receiver = match receiver.recv_async().await {
+            Ok(RunResult::EvaluationError(error))
+                if context
+                    .deadline
+                    .map_or(true, |deadline| Instant::now() < deadline) =>
+            {
+                let deployment = Arc::clone(&context.deployment);
+                let request_id = context.request_id.clone();
+                let labels = Arc::clone(&context.labels);
+                let inserters = Arc::clone(&context.inserters);
+                let log_filter = get_log_filter(&deployment);
+
+                increment_counter!("lagon_isolate_retries", &*labels);
+                handle_error(
+                    RunResult::EvaluationError(error.clone()),
+                    deployment.function_id.clone(),
+                    deployment.id.clone(),
+                    &request_id,
+                    &labels,
+                    log_filter,
+                    inserters,
+                )
+                .await;
+                warn!(deployment = deployment.id, function = deployment.function_id, request = request_id; "Retrying request in a new isolate");
+
+                match context.retry().await {
+                    Ok(receiver) => receiver,
+                    Err(retry_error) => {
+                        error!(deployment = deployment.id, function = deployment.function_id, request = request_id; "Error while retrying request: {}", retry_error);
+
+                        sender
+                            .send_async(RunResult::EvaluationError(error))
+                            .await
+                            .unwrap_or(());
+                        return;
+                    }
+                }
+            }
+            Ok(result) => {
+                if sender.send_async(result).await.is_err() {
+                    return;
+                }
+
+                receiver
+            }
+            Err(_) => return,
+        };
+
+        // Stops when the response is sent, or when the client went away
+        while let Ok(result) = receiver.recv_async().await {
+            if sender.send_async(result).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    retry_receiver
+}
+
+// Everything needed to forward the events of a WebSocket connection
+// to the isolate, each event being sent as a new request
+struct WebSocketContext {
+    deployment: Arc<Deployment>,
+    workers: Workers,
+    last_requests: Arc<DashMap<String, Instant>>,
+    isolate_requests: IsolateRequests,
+    inserters: Arc<Mutex<(Inserter<RequestRow>, Inserter<LogRow>)>>,
+    log_sender: flume::Sender<LogMessage>,
+    uri: Uri,
+    headers: HeaderMap,
+    request_id: String,
+    labels: Labels,
+    request_timeout: Option<Duration>,
+}
+
+impl WebSocketContext {
+    // Send an event to the isolate and return the message to reply with, from
+    // the body of the response. Errors when the isolate doesn't reply with a 2xx
+    async fn send_event(
+        &self,
+        event: &'static str,
+        content_type: Option<&'static str>,
+        body: Bytes,
+    ) -> Result<Option<Message>> {
+        let deployment_id = self.deployment.id.clone();
+        let worker_key = self.deployment.worker_key();
+        let bytes_in = body.len() as u32;
+
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(self.uri.clone())
+            .body(())?;
+        *request.headers_mut() = self.headers.clone();
+        request
+            .headers_mut()
+            .insert(X_LAGON_WEBSOCKET_EVENT, HeaderValue::from_static(event));
+        request
+            .headers_mut()
+            .insert(X_LAGON_WEBSOCKET_ID, self.request_id.parse()?);
+
+        if let Some(content_type) = content_type {
+            request
+                .headers_mut()
+                .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+        }
+
+        self.last_requests.insert(worker_key, Instant::now());
+
+        let (isolate_sender, _) = get_isolate_sender(
+            Arc::clone(&self.deployment),
+            &self.workers,
+            &self.last_requests,
+            &self.isolate_requests,
+            self.log_sender.clone(),
+            self.request_id.clone(),
+        )
+        .ok_or_else(|| anyhow!("Maximum number of isolates reached"))?;
+        let (sender, receiver) = flume::unbounded();
+        let (parts, _) = request.into_parts();
+
+        isolate_sender
+            .send_async(IsolateEvent::Request(IsolateRequest {
+                request: (parts, body),
+                body_stream: None,
+                sender,
+                request_id: Some(self.request_id.clone()),
+            }))
+            .await
+            .unwrap_or(());
+
+        let function_id = self.deployment.function_id.clone();
+        let request_id = self.request_id.clone();
+        let labels = Arc::clone(&self.labels);
+        let inserters = Arc::clone(&self.inserters);
+        let log_filter = get_log_filter(&self.deployment);
+
+        let max_response_size = self
+            .deployment
+            .config
+            .max_response_size
+            .or(*MAX_RESPONSE_SIZE);
+
+        let response = handle_response(receiver, max_response_size, move |event| {
+            let function_id = function_id.clone();
+            let deployment_id = deployment_id.clone();
+            let request_id = request_id.clone();
+            let labels = Arc::clone(&labels);
+            let inserters = Arc::clone(&inserters);
+
+            async move {
+                match event {
+                    ResponseEvent::Data(_) => {}
+                    ResponseEvent::Bytes(bytes, cpu_time_micros) => {
+                        inserters
+                            .lock()
+                            .await
+                            .0
+                            .write(&RequestRow {
+                                function_id,
+                                deployment_id,
+                                region: REGION.clone(),
+                                bytes_in,
+                                bytes_out: bytes as u32,
+                                cpu_time_micros,
+                                timestamp: UNIX_EPOCH.elapsed().unwrap().as_secs() as u32,
+                            })
+                            .await
+                            .unwrap_or(());
+                    }
+                    ResponseEvent::StreamDoneNoDataError => {
+                        handle_error(
+                            RunResult::Error(
+                                "The stream was done before sending a response/data".into(),
+                            ),
+                            function_id,
+                            deployment_id,
+                            &request_id,
+                            &labels,
+                            log_filter,
+                            inserters,
+                        )
+                        .await;
+                    }
+                    ResponseEvent::UnexpectedStreamResult(result)
+                    | ResponseEvent::LimitsReached(result)
+                    | ResponseEvent::Error(result) => {
+                        handle_error(
+                            result,
+                            function_id,
+                            deployment_id,
+                            &request_id,
+                            &labels,
+                            log_filter,
+                            inserters,
+                        )
+                        .await;
+                    }
+                    ResponseEvent::SizeLimitReached(max_size) => {
+                        handle_size_limit(
+                            max_size,
+                            function_id,
+                            deployment_id,
+                            &request_id,
+                            &labels,
+                            log_filter,
+                            inserters,
+                        )
+                        .await;
+                    }
+                }
+
+                Ok(())
+            }
+        });
+
+        let response = match self.request_timeout {
+            Some(request_timeout) => match tokio::time::timeout(request_timeout, response).await {
+                Ok(response) => response?,
+                Err(_) => {
+                    increment_counter!("lagon_request_timeouts", &*self.labels);
+                    handle_error(
+                        RunResult::Timeout,
+                        self.deployment.function_id.clone(),
+                        self.deployment.id.clone(),
+                        &self.request_id,
+                        &self.labels,
+                        log_filter,
+                        Arc::clone(&self.inserters),
+                    )
+                    .await;
+
+                    return Err(anyhow!("WebSocket event timed out"));
+                }
+            },
+            None => response.await?,
+        };
+
+        if !response.status().is_success() {
+            return Err(anyhow!(
+                "WebSocket event failed with status {}",
+                response.status()
+            ));
+        }
+
+        let is_binary = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .map_or(false, |value| value == "application/octet-stream");
+        let body = hyper::body::to_bytes(response.into_body()).await?.to_vec();
+
+        if body.is_empty() {
+            return Ok(None);
+        }
+
+        if is_binary {
+            return Ok(Some(Message::Binary(body)));
+        }
+
+        Ok(Some(match String::from_utf8(body) {
+            Ok(text) => Message::Text(text),
+            Err(error) => Message::Binary(error.into_bytes()),
+        }))
+    }
+}
+
+// Forward the messages of an upgraded connection to the isolate, and
+// its replies back to the client, until one of them closes it
+async fn handle_websocket(
+    on_upgrade: OnUpgrade,
+    context: WebSocketContext,
+    max_message_size: usize,
+    _permit: Option<OwnedSemaphorePermit>,
+) {
+    let deployment_id = &context.deployment.id;
+    let request_id = &context.request_id;
+
+    let upgraded = match on_upgrade.await {
+        Ok(upgraded) => upgraded,
+        Err(error) => {
+            warn!(deployment = deployment_id, request = request_id; "Failed to upgrade WebSocket connection: {}", error);
+            return;
+        }
+    };
+
+    let mut websocket = WebSocket::new(upgraded, max_message_size);
+    increment_gauge!("lagon_websockets", 1.0, &*context.labels);
+
+    loop {
+        let (content_type, body) = match websocket.read_message().await {
+            Ok(Some(Message::Text(text))) => ("text/plain;charset=UTF-8", Bytes::from(text)),
+            Ok(Some(Message::Binary(bytes))) => ("application/octet-stream", Bytes::from(bytes)),
+            Ok(None) => break,
+            Err(error) => {
+                warn!(deployment = deployment_id, request = request_id; "Closing WebSocket connection: {}", error);
+                break;
+            }
+        };
+
+        increment_counter!("lagon_websocket_messages", &*context.labels);
+
+        match context
+            .send_event("message", Some(content_type), body)
+            .await
+        {
+            Ok(Some(reply)) => {
+                if websocket.send(reply).await.is_err() {
+                    break;
+                }
+            }
+            Ok(None) => {}
+            Err(error) => {
+                warn!(deployment = deployment_id, request = request_id; "Closing WebSocket connection: {}", error);
+                websocket.close(CLOSE_INTERNAL_ERROR).await.unwrap_or(());
+                break;
+            }
+        }
+    }
+
+    // The connection is closed, so any reply is ignored
+    context
+        .send_event("close", None, Bytes::new())
+        .await
+        .unwrap_or(None);
+
+    decrement_gauge!("lagon_websockets", 1.0, &*context.labels);
+}
+
+// Closed circuit breakers behave like new ones, so they are removed on success
+fn on_circuit_breaker_result(
+    circuit_breakers: &CircuitBreakers,
+    deployment_id: &String,
+    labels: &[(&'static str, String); 4],
+    success: bool,
+) {
+    let options = match *CIRCUIT_BREAKER {
+        Some(options) => options,
+        None => return,
+    };
+
+    if success {
+        if circuit_breakers.remove(deployment_id).is_some() {
+            gauge!("lagon_circuit_breaker_open", 0.0, labels);
+        }
+
+        return;
+    }
+
+    let opened = circuit_breakers
+        .entry(deployment_id.clone())
+        .or_insert_with(|| CircuitBreaker::new(options))
+        .on_failure(Instant::now());
+
+    if opened {
+        increment_counter!("lagon_circuit_breaker_opened", labels);
+        gauge!("lagon_circuit_breaker_open", 1.0, labels);
+        warn!(deployment = deployment_id; "Too many consecutive failures, opening circuit breaker");
+    }
+}
+
+// Only the first `debugBodies` requests of a deployment are logged,
+// logging is then disabled until the next deployment
+fn should_debug_bodies(debug_bodies: &DebugBodies, deployment: &Deployment) -> bool {
+    let limit = match deployment.config.debug_bodies {
+        Some(limit) => limit,
+        None => return false,
+    };
+
+    let mut count = debug_bodies.entry(deployment.id.clone()).or_insert(0);
+
+    if *count >= limit {
+        return false;
+    }
+
+    *count += 1;
+    true
+}
+
+// Durations are in milliseconds, as expected by browsers
+fn get_server_timing(total: Duration, cpu_time_micros: Option<u128>, cold_start: bool) -> String {
+    let mut metrics = vec![format!("total;dur={:.3}", total.as_secs_f64() * 1000.0)];
+
+    if let Some(cpu_time_micros) = cpu_time_micros {
+        metrics.push(format!(
+            "cpu;dur={:.3};desc=\"CPU time\"",
+            cpu_time_micros as f64 / 1000.0
+        ));
+    }
+
+    if cold_start {
+        metrics.push("cold-start;desc=\"Isolate created\"".into());
+    }
+
+    metrics.join(", ")
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn handle_request(
+    mut req: Request<Body>,
+    remote_ip: IpAddr,
+    is_tls: bool,
+    deployments: Deployments,
+    last_requests: Arc<DashMap<String, Instant>>,
+    workers: Workers,
+    concurrency_limits: ConcurrencyLimits,
+    isolate_requests: IsolateRequests,
+    circuit_breakers: CircuitBreakers,
+    rate_limits: RateLimits,
+    debug_bodies: DebugBodies,
+    response_cache: Option<Arc<ResponseCache>>,
+    inserters: Arc<Mutex<(Inserter<RequestRow>, Inserter<LogRow>)>>,
+    log_sender: flume::Sender<LogMessage>,
+    request_id: String,
+) -> Result<Response<Body>> {
+    let trace_context = req
+        .headers()
+        .get(TRACEPARENT)
+        .and_then(|value| value.to_str().ok())
+        .and_then(TraceContext::from_traceparent);
+
+    let client_ip = get_client_ip(req.headers(), remote_ip, &TRUSTED_PROXIES);
+    let ip = client_ip.to_string();
+
+    if req.uri().path() == ADMIN_VALIDATE_PATH {
+        return match ADMIN_SECRET.as_ref() {
+            Some(secret) => handle_validate(req, secret, &deployments).await,
+            None => Ok(Response::builder().status(404).body(Body::empty())?),
+        };
+    }
+
+    if req.uri().path() == ADMIN_METRICS_PATH {
+        return handle_metrics(&req);
+    }
+
+    let start = Instant::now();
+    let mut span = Span::root("handle_request", trace_context);
+    span.set_attribute("lagon.request_id", &request_id);
+    span.set_attribute("client.address", &ip);
+
+    if req.headers().len() > *MAX_HEADERS {
+        increment_counter!(
+            "lagon_ignored_requests",
+            "reason" => "Too many headers",
+            "region" => REGION.clone(),
+        );
+        warn!(ip = ip, request = request_id, headers = req.headers().len(); "Too many headers in request");
+
+        return Ok(Response::builder().status(431).body(PAGE_431.into())?);
+    }
+
+    let lookup_span = span.child("deployment_lookup");
+
+    // HTTP/2 requests don't have a Host header but an :authority pseudo-header,
+    // and HTTP/1.0 requests might have none of them
+    let hostname = match req
+        .headers()
+        .get(HOST)
+        .and_then(|hostname| hostname.to_str().ok())
+        .map(String::from)
+        .or_else(|| req.uri().authority().map(|authority| authority.to_string()))
+    {
+        Some(hostname) => hostname,
+        None => {
+            increment_counter!(
+                "lagon_ignored_requests",
+                "reason" => "No hostname",
+                "region" => REGION.clone(),
+            );
+            warn!(req = as_debug!(req), ip = ip, request = request_id; "No valid Host header found in request");
+
+            return Ok(Builder::new().status(400).body(PAGE_400.into())?);
+        }
+    };
+
+    let deployment = match find_deployment(&deployments, &hostname) {
+        Some(deployment) => deployment,
+        None => {
+            increment_counter!(
+                "lagon_ignored_requests",
+                "reason" => "No deployment",
+                "hostname" => hostname.clone(),
+                "region" => REGION.clone(),
+            );
+            warn!(req = as_debug!(req), ip = ip, hostname = hostname, request = request_id; "No deployment found for hostname");
+
+            return Ok(Response::builder()
+                .status(404)
+                .body(NOT_FOUND_PAGE.as_deref().unwrap_or(PAGE_404).into())?);
+        }
+    };
+
+    if deployment.cron.is_some() {
+        increment_counter!(
+            "lagon_ignored_requests",
+            "reason" => "Cron",
+            "hostname" => hostname.clone(),
+            "region" => REGION.clone(),
+        );
+        warn!(req = as_debug!(req), ip = ip, hostname = hostname, request = request_id; "Cron deployment cannot be called directly");
+
+        return Ok(Response::builder().status(403).body(PAGE_403.into())?);
+    }
+
+    if deployment.config.maintenance {
+        increment_counter!(
+            "lagon_ignored_requests",
+            "reason" => "Maintenance",
+            "hostname" => hostname.clone(),
+            "region" => REGION.clone(),
+        );
+        warn!(deployment = deployment.id, function = deployment.function_id, request = request_id; "Deployment is in maintenance");
+
+        let response = Response::builder()
+            .status(503)
+            .header(LAGON_RUN_RESULT, "maintenance")
+            .body(PAGE_MAINTENANCE.into())?;
+
+        return deployment.config.error_pages.apply(response);
+    }
+
+    if let Err(error) = check_deployment_limits(&deployment) {
+        increment_counter!(
+            "lagon_ignored_requests",
+            "reason" => "Misconfigured",
+            "hostname" => hostname.clone(),
+            "region" => REGION.clone(),
+        );
+        warn!(deployment = deployment.id, function = deployment.function_id, request = request_id; "Deployment is misconfigured: {}", error);
+
+        return Ok(Response::builder()
+            .status(503)
+            .header(CONTENT_TYPE, "text/plain;charset=UTF-8")
+            .body(format!("Deployment is misconfigured: {}", error).into())?);
+    }
+
+    if let Some(region) = &deployment.config.region {
+        if region != REGION.as_str() {
+            increment_counter!(
+                "lagon_ignored_requests",
+                "reason" => "Misdirected",
+                "hostname" => hostname.clone(),
+                "region" => REGION.clone(),
+            );
+            warn!(req = as_debug!(req), ip = ip, hostname = hostname, request = request_id, deployment = deployment.id, deployment_region = region; "Deployment is pinned to another region");
+
+            return Ok(Response::builder().status(421).body(PAGE_421.into())?);
+        }
+    }
+
+    if let Some(allowed_methods) = deployment.config.get_allowed_methods() {
+        if !allowed_methods
+            .iter()
+            .any(|method| method == req.method().as_str())
+        {
+            increment_counter!(
+                "lagon_ignored_requests",
+                "reason" => "Method not allowed",
+                "hostname" => hostname.clone(),
+                "region" => REGION.clone(),
+            );
+            warn!(ip = ip, hostname = hostname, request = request_id, deployment = deployment.id, method = req.method().as_str(); "Method not allowed for deployment");
+
+            return Ok(Response::builder()
+                .status(405)
+                .header(ALLOW, allowed_methods.join(", "))
+                .body(PAGE_405.into())?);
+        }
+    }
+
+    // Requests without a body, e.g GET ones, don't need a content type
+    let content_type = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+
+    if (content_type.is_some() || !req.body().is_end_stream())
+        && !deployment.config.is_content_type_allowed(content_type)
+    {
+        increment_counter!(
+            "lagon_ignored_requests",
+            "reason" => "Unsupported media type",
+            "hostname" => hostname.clone(),
+            "region" => REGION.clone(),
+        );
+        warn!(ip = ip, hostname = hostname, request = request_id, deployment = deployment.id, content_type = content_type.unwrap_or_default(); "Content type not allowed for deployment");
+
+        return Ok(Response::builder().status(415).body(PAGE_415.into())?);
+    }
+
+    if let Some(auth) = &deployment.config.auth {
+        if !auth.is_authorized(req.headers()) {
+            increment_counter!(
+                "lagon_ignored_requests",
+                "reason" => "Unauthorized",
+                "hostname" => hostname.clone(),
+                "region" => REGION.clone(),
+            );
+            warn!(ip = ip, hostname = hostname, request = request_id, deployment = deployment.id; "Missing or invalid credentials for deployment");
+
+            return Ok(Response::builder()
+                .status(401)
+                .header(WWW_AUTHENTICATE, auth.www_authenticate())
+                .body(PAGE_401.into())?);
+        }
+    }
+
+    let shadow_deployment = pick_shadow(&deployment, &deployments);
+    let deployment = pick_deployment(deployment, &deployments);
+    let (deployment, variant_cookie) = pick_variant(deployment, req.headers());
+
+    drop(lookup_span);
+    span.set_attribute("lagon.deployment", &deployment.id);
+    span.set_attribute("lagon.function", &deployment.function_id);
+
+    let function_id = deployment.function_id.clone();
+    let deployment_id = deployment.id.clone();
+    let worker_key = deployment.worker_key();
+    let deployment_header = HeaderValue::from_str(&deployment_id)?;
+    let request_id_handle = request_id.clone();
+    let (sender, mut receiver) = flume::unbounded();
+    let bytes_in = Arc::new(AtomicUsize::new(0));
+    let mut permit = None;
+    let mut isolate_request = None;
+    let mut cancellation = None;
+    let mut single_request_isolate = None;
+    let mut cold_start = false;
+    let mut run_span = None;
+    let mut upgrade = None;
+    let mut websocket = None;
+    let mut debug_body = false;
+    let mut run_start = None;
+    let mut timed_out = false;
+    let mut shadow = None;
+
+    let labels: Labels = Arc::new([
+        ("deployment", deployment.id.clone()),
+        ("function", deployment.function_id.clone()),
+        ("region", REGION.clone()),
+        (
+            "variant",
+            deployment
+                .variant
+                .clone()
+                .unwrap_or_else(|| DEFAULT_VARIANT.into()),
+        ),
+    ]);
+    let request_timeout = deployment.config.request_timeout.map(Duration::from_millis);
+    let error_pages = deployment.config.error_pages.clone();
+    let strip_headers = deployment.config.strip_response_headers.clone();
+    let early_hints = deployment.config.early_hints.clone();
+    let server_timing = deployment.config.server_timing;
+    let log_filter = get_log_filter(&deployment);
+    let max_response_size = deployment.config.max_response_size.or(*MAX_RESPONSE_SIZE);
+
+    let accept_encoding = req
+        .headers()
+        .get(ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+
+    let is_head = req.method() == Method::HEAD;
+    let slow_request_path = SLOW_REQUEST_THRESHOLD.map(|_| req.uri().path().to_string());
+
+    // The request headers are kept to store the response, since
+    // the Vary header can refer to any of them
+    let cache_request = response_cache
+        .as_ref()
+        .and_then(|_| ResponseCache::get_key(&worker_key, &req))
+        .map(|key| (key, req.headers().clone()));
+    let cached_response = cache_request.as_ref().and_then(|(key, request_headers)| {
+        response_cache
+            .as_ref()
+            .and_then(|response_cache| response_cache.get(key, request_headers, Instant::now()))
+    });
+    let url = req.uri().path();
+    let is_favicon = url == FAVICON_URL;
+
+    if let Some(asset) = find_asset(url, &deployment.assets) {
+        let root = Path::new(env::current_dir().unwrap().as_path())
+            .join(DEPLOYMENTS_DIR)
+            .join(&deployment.id);
+
+        let run_result = match handle_asset(
+            root,
+            asset,
+            req.headers(),
+            &deployment.config.content_types,
+        ) {
+            Ok(response) => RunResult::Response(response, None),
+            Err(error) => {
+                error!(deployment = &deployment.id, function = &deployment.function_id, asset = asset, request = request_id_handle; "Error while handing asset: {}", error);
+
+                RunResult::Error("Could not retrieve asset.".into())
+            }
+        };
+
+        sender.send_async(run_result).await.unwrap_or(());
+    } else if is_favicon {
+        sender
+            .send_async(RunResult::Response(
+                Response::builder().status(404).body(Body::empty())?,
+                None,
+            ))
+            .await
+            .unwrap_or(());
+    } else if let Some(cached_response) = cached_response {
+        // Cached responses don't use the isolate, so the limits
+        // protecting it don't apply
+        increment_counter!("lagon_response_cache_hits", &*labels);
+
+        sender
+            .send_async(RunResult::Response(cached_response, None))
+            .await
+            .unwrap_or(());
+    } else {
+        if cache_request.is_some() {
+            increment_counter!("lagon_response_cache_misses", &*labels);
+        }
+
+        if let Some(rate_limit) = deployment.config.rate_limit.or(*RATE_LIMIT) {
+            let now = Instant::now();
+            let result = rate_limits
+                .entry((deployment_id.clone(), ip.clone()))
+                .or_insert_with(|| TokenBucket::new(rate_limit, now))
+                .try_acquire(rate_limit, now);
+
+            // The local rate limit is checked first, so requests
+            // already over it don't need to reach Redis
+            let result = match result {
+                Ok(()) => distributed_rate_limit::try_acquire(&deployment_id, &ip, rate_limit)
+                    .await
+                    .unwrap_or(Ok(())),
+                Err(retry_after) => Err(retry_after),
+            };
+
+            if let Err(retry_after) = result {
+                increment_counter!("lagon_requests_rate_limited", &*labels);
+                warn!(deployment = deployment_id, function = function_id, request = request_id, ip = ip; "Rate limit exceeded");
+
+                // Retry-After is in seconds, rounded up
+                let retry_after = retry_after
+                    .as_secs()
+                    .saturating_add(u64::from(retry_after.subsec_nanos() > 0));
+
+                return Ok(Response::builder()
+                    .status(429)
+                    .header(RETRY_AFTER, retry_after)
+                    .body(PAGE_429.into())?);
+            }
+        }
+
+        if let Some(max_concurrency) = deployment.config.max_concurrency {
+            let semaphore = concurrency_limits
+                .entry(deployment_id.clone())
+                .or_insert_with(|| Arc::new(Semaphore::new(max_concurrency)))
+                .clone();
+
+            match semaphore.try_acquire_owned() {
+                Ok(acquired_permit) => permit = Some(acquired_permit),
+                Err(_) => {
+                    increment_counter!("lagon_requests_throttled", &*labels);
+                    warn!(deployment = deployment_id, function = function_id, request = request_id; "Too many concurrent requests");
+
+                    return Ok(Response::builder().status(429).body(PAGE_429.into())?);
+                }
+            }
+        }
+
+        // Upgrade requests are first sent to the isolate as an open event,
+        // and the connection is only upgraded if it returns a 2xx response
+        let on_upgrade = if deployment.config.websocket && is_upgrade_request(&req) {
+            upgrade = Some(upgrade_response(&req)?);
+            Some(hyper::upgrade::on(&mut req))
+        } else {
+            None
+        };
+
+        let (mut parts, body) = req.into_parts();
+        let limits_override = take_limits_override(&mut parts.headers, &deployment);
+        let max_body_size = deployment.config.max_body_size.unwrap_or(*MAX_BODY_SIZE);
+
+        if !meets_expectation(&parts.headers, &body, max_body_size) {
+            increment_counter!("lagon_requests_expectation_failed", &*labels);
+            warn!(deployment = deployment_id, function = function_id, request = request_id; "Request expectation can't be met");
+
+            return Ok(Response::builder().status(417).body(PAGE_417.into())?);
+        }
+
+        parts.headers.remove(EXPECT);
+
+        let content_length = get_content_length(&parts.headers);
+
+        // Streamed bodies with a Content-Length can be refused
+        // early, others are stopped once they reach the limit
+        let body = if deployment.config.stream_request_body && on_upgrade.is_none() {
+            if body.size_hint().lower() <= max_body_size as u64 {
+                let body_stream = stream_body(
+                    body,
+                    max_body_size,
+                    content_length,
+                    Arc::clone(&bytes_in),
+                    Arc::clone(&labels),
+                );
+
+                Ok((Bytes::new(), Some(body_stream)))
+            } else {
+                Err(BodyError::TooLarge)
+            }
+        } else {
+            let body = read_body(body, max_body_size, content_length);
+
+            match *BODY_READ_TIMEOUT {
+                Some(body_read_timeout) => tokio::time::timeout(body_read_timeout, body)
+                    .await
+                    .unwrap_or(Err(BodyError::Timeout)),
+                None => body.await,
+            }
+            .map(|body| (body, None))
+        };
+
+        let (body, body_stream) = match body {
+            Ok(body) => body,
+            Err(BodyError::TooLarge) => {
+                increment_counter!("lagon_requests_body_too_large", &*labels);
+                warn!(deployment = deployment_id, function = function_id, request = request_id; "Request body is larger than {} bytes", max_body_size);
+
+                return Ok(Response::builder().status(413).body(PAGE_413.into())?);
+            }
+            Err(BodyError::LengthMismatch) => {
+                increment_counter!("lagon_requests_length_mismatch", &*labels);
+                warn!(deployment = deployment_id, function = function_id, request = request_id; "Request body doesn't match its Content-Length");
+
+                return Ok(Response::builder().status(400).body(PAGE_400.into())?);
+            }
+            Err(BodyError::Timeout) => {
+                increment_counter!("lagon_requests_body_timeouts", &*labels);
+                warn!(deployment = deployment_id, function = function_id, request = request_id; "Request body wasn't received in time");
+
+                return Ok(Response::builder().status(408).body(PAGE_408.into())?);
+            }
+            Err(BodyError::Read(error)) => return Err(error.into()),
+        };
+
+        last_requests.insert(worker_key.clone(), Instant::now());
+
+        if body_stream.is_none() {
+            bytes_in.store(body.len(), Ordering::Relaxed);
+        }
+
+        if should_debug_bodies(&debug_bodies, &deployment) {
+            debug_body = true;
+
+            write_log(
+                "debug",
+                format!(
+                    "Request {} {} (headers: {}): {}",
+                    parts.method,
+                    parts.uri,
+                    format_headers(&parts.headers),
+                    match body_stream {
+                        Some(_) => String::from("(streamed)"),
+                        None => format_body(&body, DEBUG_BODY_MAX_SIZE),
+                    },
+                ),
+                function_id.clone(),
+                deployment_id.clone(),
+                &request_id,
+                Arc::clone(&inserters),
+            )
+            .await;
+        }
+
+        // The open event of WebSocket connections keeps the Upgrade
+        // and Connection headers, e.g to check the upgrade protocol
+        if on_upgrade.is_none() {
+            strip_hop_by_hop_headers(&mut parts.headers);
+        }
 
-            return Ok(Builder::new().status(404).body(PAGE_404.into())?);
+        parts.headers.insert(X_FORWARDED_FOR, ip.parse()?);
+        parts.headers.insert(X_LAGON_REGION, REGION.parse()?);
+
+        if *PROXY_HEADERS {
+            apply_proxy_headers(
+                &mut parts.headers,
+                &hostname,
+                client_ip,
+                is_tls,
+                remote_ip,
+                &TRUSTED_PROXIES,
+            )?;
         }
-    };
 
-    let deployment = match deployments.get(&hostname) {
-        Some(entry) => Arc::clone(entry.value()),
-        None => {
-            increment_counter!(
-                "lagon_ignored_requests",
-                "reason" => "No deployment",
-                "hostname" => hostname.clone(),
-                "region" => REGION.clone(),
-            );
-            warn!(req = as_debug!(req), ip = ip, hostname = hostname, request = request_id; "No deployment found for hostname");
+        if let Some(on_upgrade) = on_upgrade {
+            let mut headers = parts.headers.clone();
 
-            return Ok(Response::builder().status(404).body(PAGE_404.into())?);
+            for header in [
+                CONNECTION,
+                UPGRADE,
+                SEC_WEBSOCKET_KEY,
+                SEC_WEBSOCKET_VERSION,
+                SEC_WEBSOCKET_EXTENSIONS,
+                SEC_WEBSOCKET_PROTOCOL,
+            ] {
+                headers.remove(header);
+            }
+
+            parts
+                .headers
+                .insert(X_LAGON_WEBSOCKET_EVENT, HeaderValue::from_static("open"));
+            parts
+                .headers
+                .insert(X_LAGON_WEBSOCKET_ID, request_id.parse()?);
+
+            websocket = Some((
+                on_upgrade,
+                WebSocketContext {
+                    deployment: Arc::clone(&deployment),
+                    workers: Arc::clone(&workers),
+                    last_requests: Arc::clone(&last_requests),
+                    isolate_requests: Arc::clone(&isolate_requests),
+                    inserters: Arc::clone(&inserters),
+                    log_sender: log_sender.clone(),
+                    uri: parts.uri.clone(),
+                    headers,
+                    request_id: request_id.clone(),
+                    labels: Arc::clone(&labels),
+                    request_timeout,
+                },
+                max_body_size,
+            ));
         }
-    };
 
-    if deployment.cron.is_some() {
-        increment_counter!(
-            "lagon_ignored_requests",
-            "reason" => "Cron",
-            "hostname" => hostname.clone(),
-            "region" => REGION.clone(),
-        );
-        warn!(req = as_debug!(req), ip = ip, hostname = hostname, request = request_id; "Cron deployment cannot be called directly");
+        if let Some(options) = *CIRCUIT_BREAKER {
+            let result = circuit_breakers
+                .entry(deployment_id.clone())
+                .or_insert_with(|| CircuitBreaker::new(options))
+                .try_acquire(Instant::now());
 
-        return Ok(Response::builder().status(403).body(PAGE_403.into())?);
-    }
+            if let Err(retry_after) = result {
+                increment_counter!("lagon_requests_circuit_open", &*labels);
+                warn!(deployment = deployment_id, function = function_id, request = request_id; "Circuit breaker is open");
 
-    let function_id = deployment.function_id.clone();
-    let deployment_id = deployment.id.clone();
-    let request_id_handle = request_id.clone();
-    let (sender, receiver) = flume::unbounded();
-    let mut bytes_in = 0;
+                return Ok(Response::builder()
+                    .status(503)
+                    .header(RETRY_AFTER, retry_after.as_secs().max(1))
+                    .body(PAGE_503.into())?);
+            }
+        }
 
-    let labels = [
-        ("deployment", deployment.id.clone()),
-        ("function", deployment.function_id.clone()),
-        ("region", REGION.clone()),
-    ];
+        let mut acquire_span = span.child("isolate_acquire");
 
-    let url = req.uri().path();
-    let is_favicon = url == FAVICON_URL;
+        // Overridden limits need their own isolate, which isn't shared with
+        // other requests. WebSocket messages are sent to the shared isolate,
+        // so upgrade requests always use the limits of the deployment
+        let isolate_sender = match limits_override.filter(|_| websocket.is_none()) {
+            Some(limits_override) => {
+                increment_counter!("lagon_limits_overrides", &*labels);
+                info!(deployment = deployment_id, function = function_id, request = request_id, memory = limits_override.memory, tick_timeout = limits_override.tick_timeout, total_timeout = limits_override.total_timeout; "Running request with overridden limits");
 
-    if let Some(asset) = find_asset(url, &deployment.assets) {
-        let root = Path::new(env::current_dir().unwrap().as_path())
-            .join(DEPLOYMENTS_DIR)
-            .join(&deployment.id);
+                let isolate_sender = create_isolate_worker(
+                    Arc::new(limits_override),
+                    None,
+                    0,
+                    log_sender.clone(),
+                    request_id_handle,
+                );
+                single_request_isolate = Some(SingleRequestIsolateGuard(isolate_sender.clone()));
 
-        let run_result = match handle_asset(root, asset) {
-            Ok(response) => RunResult::Response(response, None),
-            Err(error) => {
-                error!(deployment = &deployment.id, asset = asset, request = request_id_handle; "Error while handing asset: {}", error);
+                Some((isolate_sender, true))
+            }
+            None => get_isolate_sender(
+                Arc::clone(&deployment),
+                &workers,
+                &last_requests,
+                &isolate_requests,
+                log_sender.clone(),
+                request_id_handle,
+            ),
+        };
 
-                RunResult::Error("Could not retrieve asset.".into())
+        let (isolate_sender, is_cold_start) = match isolate_sender {
+            Some(isolate_sender) => isolate_sender,
+            None => {
+                increment_counter!("lagon_requests_isolates_exhausted", &*labels);
+                warn!(deployment = deployment_id, function = function_id, request = request_id; "Maximum number of isolates reached");
+
+                return Ok(Response::builder()
+                    .status(503)
+                    .header(RETRY_AFTER, ISOLATES_EXHAUSTED_RETRY_AFTER_SECONDS)
+                    .body(PAGE_503.into())?);
             }
         };
 
-        sender.send_async(run_result).await.unwrap_or(());
-    } else if is_favicon {
-        sender
-            .send_async(RunResult::Response(
-                Response::builder().status(404).body(Body::empty())?,
-                None,
-            ))
-            .await
-            .unwrap_or(());
-    } else {
-        last_requests.insert(deployment_id.clone(), Instant::now());
+        if is_cold_start {
+            cold_start = true;
+            increment_counter!("lagon_isolate_cold_starts", &*labels);
+            acquire_span.set_attribute("lagon.cold_start", true);
+        }
 
-        // Try to Extract the X-Real-Ip header or fallback to remote addr IP
-        let ip = req.headers().get(X_REAL_IP).map_or(ip.clone(), |header| {
-            header.clone().to_str().map_or(ip, |ip| ip.to_string())
-        });
+        drop(acquire_span);
+        let isolate_run_span = span.child("isolate_run");
 
-        let (mut parts, body) = req.into_parts();
-        let body = hyper::body::to_bytes(body).await?;
+        // Continue the trace from the isolate, e.g for fetch() calls
+        if let Some(trace_context) = isolate_run_span.context() {
+            parts
+                .headers
+                .insert(TRACEPARENT, trace_context.to_traceparent().parse()?);
+        }
 
-        bytes_in = body.len() as u32;
+        run_span = Some(isolate_run_span);
+        run_start = Some(Instant::now());
 
-        parts.headers.insert(X_FORWARDED_FOR, ip.parse()?);
-        parts.headers.insert(X_LAGON_REGION, REGION.parse()?);
+        // Streamed bodies can only be read once, by the primary deployment
+        if let Some(shadow_deployment) =
+            shadow_deployment.filter(|_| websocket.is_none() && body_stream.is_none())
+        {
+            shadow = Some(ShadowContext {
+                deployment: shadow_deployment,
+                workers: Arc::clone(&workers),
+                last_requests: Arc::clone(&last_requests),
+                isolate_requests: Arc::clone(&isolate_requests),
+                inserters: Arc::clone(&inserters),
+                log_sender: log_sender.clone(),
+                method: parts.method.clone(),
+                uri: parts.uri.clone(),
+                headers: parts.headers.clone(),
+                body: body.clone(),
+                request_id: request_id.clone(),
+            });
+        }
 
+        // Requests with side effects could run twice, so only idempotent ones are retried
+        let retry = (deployment.config.retry_transient_errors
+            && is_cold_start
+            && single_request_isolate.is_none()
+            && websocket.is_none()
+            && body_stream.is_none()
+            && (parts.method == Method::GET || parts.method == Method::HEAD))
+            .then(|| RetryContext {
+                deployment: Arc::clone(&deployment),
+                pool_id: workers.get(&worker_key).map(|pool| pool.id),
+                workers: Arc::clone(&workers),
+                last_requests: Arc::clone(&last_requests),
+                isolate_requests: Arc::clone(&isolate_requests),
+                inserters: Arc::clone(&inserters),
+                log_sender,
+                method: parts.method.clone(),
+                uri: parts.uri.clone(),
+                headers: parts.headers.clone(),
+                body: body.clone(),
+                request_id: request_id.clone(),
+                labels: Arc::clone(&labels),
+                deadline: request_timeout.map(|request_timeout| Instant::now() + request_timeout),
+            });
         let request = (parts, body);
 
-        let isolate_workers = Arc::clone(&workers);
-        let isolate_sender = workers.entry(deployment_id.clone()).or_insert_with(|| {
-            let handle = Handle::current();
-            let (sender, receiver) = flume::unbounded();
-            let labels = labels.clone();
-
-            std::thread::Builder::new().name(String::from("isolate-") + deployment.id.as_str()).spawn(move || {
-                handle.block_on(async move {
-                    increment_gauge!("lagon_isolates", 1.0, &labels);
-                    info!(deployment = deployment.id, function = deployment.function_id, request = request_id_handle; "Creating new isolate");
-
-                    let code = deployment.get_code().unwrap_or_else(|error| {
-                        error!(deployment = deployment.id, request = request_id_handle; "Error while getting deployment code: {}", error);
-
-                        "".into()
-                    });
-                    let options = IsolateOptions::new(code)
-                        .environment_variables(deployment.environment_variables.clone())
-                        .memory(deployment.memory)
-                        .tick_timeout(Duration::from_millis(deployment.tick_timeout as u64))
-                        .total_timeout(Duration::from_millis(
-                            deployment.total_timeout as u64,
-                        ))
-                        .metadata(Some((
-                            deployment.id.clone(),
-                            deployment.function_id.clone(),
-                        )))
-                        .on_drop_callback(Box::new(|metadata| {
-                            if let Some(metadata) = metadata.as_ref().as_ref() {
-                                let labels = [
-                                    ("deployment", metadata.0.clone()),
-                                    ("function", metadata.1.clone()),
-                                    ("region", REGION.clone()),
-                                ];
-
-                                decrement_gauge!("lagon_isolates", 1.0, &labels);
-                                info!(deployment = metadata.0, function = metadata.1; "Dropping isolate");
-                            }
-                        }))
-                        .on_statistics_callback(Box::new(|metadata, statistics| {
-                            if let Some(metadata) = metadata.as_ref().as_ref() {
-                                let labels = [
-                                    ("deployment", metadata.0.clone()),
-                                    ("function", metadata.1.clone()),
-                                    ("region", REGION.clone()),
-                                ];
-
-                                histogram!(
-                                    "lagon_isolate_memory_usage",
-                                    statistics as f64,
-                                    &labels
-                                );
-                            }
-                        }))
-                        .log_sender(log_sender)
-                        .snapshot_blob(SNAPSHOT_BLOB);
-
-                    let mut isolate = Isolate::new(options, receiver);
-                    isolate.evaluate();
-                    isolate.run_event_loop().await;
-
-                    // When the event loop is completed, that means a) the isolate was terminate due to limits
-                    // or b) the isolate was dropped because of cache expiration. In the first case, the isolate
-                    // isn't removed from the workers map
-                    isolate_workers.remove(&deployment.id);
-                });
-            }).unwrap();
-
-            sender
-        });
+        // Events the isolate hasn't picked up yet, rising when the
+        // isolate can't keep up with the requests of its deployment
+        histogram!(
+            "lagon_isolate_queue_depth",
+            isolate_sender.len() as f64,
+            &*labels
+        );
 
         isolate_sender
-            .send_async(IsolateEvent::Request(IsolateRequest { request, sender }))
+            .send_async(IsolateEvent::Request(IsolateRequest {
+                request,
+                body_stream,
+                sender,
+                request_id: Some(request_id.clone()),
+            }))
             .await
             .unwrap_or(());
+
+        if let Some(retry) = retry {
+            receiver = retry_transient_error(receiver, retry);
+        }
+
+        isolate_request = Some(IsolateRequestGuard::new(
+            worker_key,
+            Arc::clone(&isolate_requests),
+        ));
+        cancellation = Some(CancellationGuard::new(
+            function_id.clone(),
+            deployment_id.clone(),
+            request_id.clone(),
+            Arc::clone(&labels),
+        ));
     }
 
-    handle_response(receiver, move |event| {
+    // WebSocket connections count as a concurrent request until closed
+    let websocket_permit = match websocket {
+        Some(_) => permit.take(),
+        None => None,
+    };
+
+    // Cloned before being moved to the response callback,
+    // to report the request timeout
+    let timeout_context = (
+        function_id.clone(),
+        deployment_id.clone(),
+        request_id.clone(),
+        Arc::clone(&labels),
+        Arc::clone(&inserters),
+    );
+
+    // Only known before the response is sent for non-stream responses
+    let cpu_time = Arc::new(OnceCell::new());
+    let cpu_time_handle = Arc::clone(&cpu_time);
+
+    // The circuit breaker only counts the results of the isolate
+    let is_isolate_request = isolate_request.is_some();
+    let timeout_circuit_breakers = Arc::clone(&circuit_breakers);
+
+    let response = handle_response(receiver, max_response_size, move |event| {
+        // The permit and the isolate guards are owned by this callback,
+        // which lives until the response (or the whole stream) has been sent
+        let _permit = &permit;
+        let _isolate_request = &isolate_request;
+        let _single_request_isolate = &single_request_isolate;
+        let circuit_breakers = Arc::clone(&circuit_breakers);
+        let cpu_time = Arc::clone(&cpu_time);
         let inserters = Arc::clone(&inserters);
         let function_id = function_id.clone();
         let deployment_id = deployment_id.clone();
         let request_id = request_id.clone();
-        let labels = labels.clone();
+        let labels = Arc::clone(&labels);
+        let bytes_in = bytes_in.load(Ordering::Relaxed) as u32;
 
         async move {
             match event {
+                ResponseEvent::Data(bytes) => {
+                    counter!("lagon_bytes_out", bytes as u64, &*labels);
+                }
                 ResponseEvent::Bytes(bytes, cpu_time_micros) => {
                     let timestamp = UNIX_EPOCH.elapsed().unwrap().as_secs() as u32;
 
+                    if let Some(cpu_time_micros) = cpu_time_micros {
+                        cpu_time.set(cpu_time_micros).unwrap_or(());
+                    }
+
+                    if is_isolate_request {
+                        on_circuit_breaker_result(&circuit_breakers, &deployment_id, &labels, true);
+                    }
+
                     inserters
                         .lock()
                         .await
@@ -314,6 +3277,15 @@ async fn handle_request(
                         .unwrap_or(());
                 }
                 ResponseEvent::StreamDoneNoDataError => {
+                    if is_isolate_request {
+                        on_circuit_breaker_result(
+                            &circuit_breakers,
+                            &deployment_id,
+                            &labels,
+                            false,
+                        );
+                    }
+
                     handle_error(
                         RunResult::Error(
                             "The stream was done before sending a response/data".into(),
@@ -322,28 +3294,61 @@ async fn handle_request(
                         deployment_id,
                         &request_id,
                         &labels,
+                        log_filter,
                         inserters,
                     )
                     .await;
                 }
                 ResponseEvent::UnexpectedStreamResult(result) => {
+                    if is_isolate_request {
+                        on_circuit_breaker_result(
+                            &circuit_breakers,
+                            &deployment_id,
+                            &labels,
+                            false,
+                        );
+                    }
+
                     handle_error(
                         result,
                         function_id,
                         deployment_id,
                         &request_id,
                         &labels,
+                        log_filter,
                         inserters,
                     )
                     .await;
                 }
                 ResponseEvent::LimitsReached(result) | ResponseEvent::Error(result) => {
+                    if is_isolate_request {
+                        on_circuit_breaker_result(
+                            &circuit_breakers,
+                            &deployment_id,
+                            &labels,
+                            false,
+                        );
+                    }
+
                     handle_error(
                         result,
                         function_id,
                         deployment_id,
                         &request_id,
                         &labels,
+                        log_filter,
+                        inserters,
+                    )
+                    .await;
+                }
+                ResponseEvent::SizeLimitReached(max_size) => {
+                    handle_size_limit(
+                        max_size,
+                        function_id,
+                        deployment_id,
+                        &request_id,
+                        &labels,
+                        log_filter,
                         inserters,
                     )
                     .await;
@@ -352,8 +3357,309 @@ async fn handle_request(
 
             Ok(())
         }
-    })
-    .await
+    });
+
+    let response = match request_timeout {
+        Some(request_timeout) => match tokio::time::timeout(request_timeout, response).await {
+            Ok(response) => response,
+            Err(_) => {
+                // Dropping the response receiver aborts the run in the isolate
+                let (function_id, deployment_id, request_id, labels, inserters) =
+                    timeout_context.clone();
+
+                timed_out = true;
+                increment_counter!("lagon_request_timeouts", &*labels);
+                on_circuit_breaker_result(
+                    &timeout_circuit_breakers,
+                    &deployment_id,
+                    &labels,
+                    false,
+                );
+                handle_error(
+                    RunResult::Timeout,
+                    function_id,
+                    deployment_id,
+                    &request_id,
+                    &labels,
+                    log_filter,
+                    inserters,
+                )
+                .await;
+
+                Ok(Response::builder()
+                    .status(504)
+                    .header(LAGON_RUN_RESULT, "timeout")
+                    .body(PAGE_504.into())?)
+            }
+        },
+        None => response.await,
+    };
+
+    // The isolate stopped without sending a response, e.g because it panicked
+    let response = match response {
+        Ok(response) => response,
+        Err(error) => {
+            let (function_id, deployment_id, request_id, labels, _) = timeout_context.clone();
+
+            if is_isolate_request {
+                on_circuit_breaker_result(
+                    &timeout_circuit_breakers,
+                    &deployment_id,
+                    &labels,
+                    false,
+                );
+            }
+
+            error!(deployment = deployment_id, function = function_id, request = request_id; "Isolate stopped before responding: {}", error);
+
+            Response::builder()
+                .status(500)
+                .header(LAGON_RUN_RESULT, "error")
+                .body(PAGE_500.into())?
+        }
+    };
+
+    if let Some(cancellation) = cancellation {
+        cancellation.disarm();
+    }
+
+    if let Some(shadow) = shadow {
+        tokio::spawn(shadow.run());
+    }
+
+    drop(run_span);
+
+    // Timeouts are already logged as errors
+    if let (Some(threshold), Some(path)) = (*SLOW_REQUEST_THRESHOLD, slow_request_path) {
+        let elapsed = start.elapsed();
+
+        if elapsed > threshold && !timed_out {
+            // Init is the time until the request is sent to the isolate (e.g
+            // reading the body), and run until it responded. The isolate is
+            // created during the run on cold starts
+            let (init, run) = match run_start {
+                Some(run_start) => (run_start - start, run_start.elapsed()),
+                None => (elapsed, Duration::ZERO),
+            };
+            let (function_id, deployment_id, request_id, _, _) = &timeout_context;
+
+            warn!(
+                deployment = deployment_id,
+                function = function_id,
+                request = request_id,
+                path = path,
+                status = response.status().as_u16(),
+                total_ms = elapsed.as_millis() as u64,
+                init_ms = init.as_millis() as u64,
+                run_ms = run.as_millis() as u64,
+                cpu_ms = cpu_time_handle.get().map_or(0, |micros| *micros / 1000) as u64,
+                cold_start = cold_start;
+                "Slow request took {:?}", elapsed
+            );
+        }
+    }
+
+    // Stream responses are not buffered, only their headers are logged
+    let response = if debug_body {
+        let (parts, body) = response.into_parts();
+        let (body, formatted_body) = match body.size_hint().exact() {
+            Some(_) => {
+                let bytes = hyper::body::to_bytes(body).await?;
+                let formatted_body = format_body(&bytes, DEBUG_BODY_MAX_SIZE);
+
+                (Body::from(bytes), formatted_body)
+            }
+            None => (body, String::from("[stream]")),
+        };
+
+        let (function_id, deployment_id, request_id, _, inserters) = timeout_context;
+
+        write_log(
+            "debug",
+            format!(
+                "Response {} (headers: {}): {}",
+                parts.status,
+                format_headers(&parts.headers),
+                formatted_body,
+            ),
+            function_id,
+            deployment_id,
+            &request_id,
+            inserters,
+        )
+        .await;
+
+        Response::from_parts(parts, body)
+    } else {
+        response
+    };
+
+    // HEAD responses have no body, only GET ones are stored
+    let response = match (cache_request, &response_cache) {
+        (Some((key, request_headers)), Some(response_cache)) if is_isolate_request && !is_head => {
+            response_cache
+                .store(key, &request_headers, response, Instant::now())
+                .await?
+        }
+        _ => response,
+    };
+
+    if let (Some(upgrade), Some((on_upgrade, context, max_message_size))) = (upgrade, websocket) {
+        if response.status().is_success() {
+            tokio::spawn(handle_websocket(
+                on_upgrade,
+                context,
+                max_message_size,
+                websocket_permit,
+            ));
+
+            return Ok(upgrade);
+        }
+    }
+
+    let mut response = error_pages.apply(response)?;
+
+    strip_response_headers(&mut response, &strip_headers);
+    apply_early_hints(&mut response, &early_hints);
+    apply_default_headers(&mut response, &DEFAULT_RESPONSE_HEADERS, is_tls);
+
+    // Allows comparing the responses of each deployment when using a canary
+    response
+        .headers_mut()
+        .insert(X_LAGON_DEPLOYMENT, deployment_header);
+
+    if let Some(variant_cookie) = variant_cookie {
+        response.headers_mut().append(SET_COOKIE, variant_cookie);
+    }
+
+    if server_timing {
+        response.headers_mut().insert(
+            SERVER_TIMING,
+            get_server_timing(start.elapsed(), cpu_time_handle.get().copied(), cold_start)
+                .parse()?,
+        );
+    }
+
+    // HEAD responses carry the same encoding headers as a GET would,
+    // without compressing the body that isn't sent
+    if is_head {
+        return compress_head_response(
+            into_head_response(response),
+            accept_encoding.as_deref(),
+            &COMPRESSION_OPTIONS,
+        );
+    }
+
+    compress_response(response, accept_encoding.as_deref(), &COMPRESSION_OPTIONS).await
+}
+
+pub fn parse_env<T: FromStr>(key: &str) -> Option<T> {
+    env::var(key)
+        .ok()
+        .filter(|value| !value.is_empty())
+        .map(|value| {
+            value
+                .parse()
+                .unwrap_or_else(|_| panic!("{} is not a valid value", key))
+        })
+}
+
+// The native stack of the threads running isolates also holds the frames
+// of the runtime, so it's larger than the call stack allowed by V8
+pub fn isolate_thread(name: String) -> std::thread::Builder {
+    let builder = std::thread::Builder::new().name(name);
+
+    match *ISOLATE_STACK_SIZE {
+        Some(stack_size) => {
+            builder.stack_size((stack_size + ISOLATE_THREAD_STACK_MARGIN_KB) * 1024)
+        }
+        None => builder,
+    }
+}
+
+// Timeouts in seconds, where 0 disables the timeout
+fn parse_timeout(key: &str, default: u64) -> Option<Duration> {
+    Some(parse_env::<u64>(key).unwrap_or(default))
+        .filter(|seconds| *seconds > 0)
+        .map(Duration::from_secs)
+}
+
+// HTTP/1.1 is always supported, and HTTP/2 with prior knowledge can be
+// enabled. TLS is optional since it can also be terminated by a proxy
+// before the requests reach the serverless
+fn server_builder(addr: &SocketAddr) -> Result<ServerBuilder<TimeoutIncoming>> {
+    let http2_enabled = parse_env::<bool>("LAGON_HTTP2_ENABLED").unwrap_or(false);
+    let keepalive_timeout =
+        parse_env::<u64>("LAGON_KEEPALIVE_TIMEOUT_SECONDS").map(Duration::from_secs);
+
+    let incoming = match env::var("LAGON_UNIX_SOCKET") {
+        // Listen on a Unix socket instead of TCP, e.g when running
+        // behind a proxy on the same host
+        Ok(path) if !path.is_empty() => {
+            // The socket of a previous run would make the bind fail
+            if fs::symlink_metadata(&path)
+                .map_or(false, |metadata| metadata.file_type().is_socket())
+            {
+                fs::remove_file(&path)?;
+            }
+
+            let listener = UnixListener::bind(&path)?;
+            info!("Listening on unix:{}", path);
+
+            Incoming::unix(listener)
+        }
+        _ => {
+            let mut incoming = AddrIncoming::bind(addr)?;
+            incoming.set_keepalive(keepalive_timeout);
+            info!("Listening on {}", incoming.local_addr());
+
+            let tls = TlsConfig::from_env()?;
+
+            if tls.is_some() {
+                info!("TLS is enabled");
+            }
+
+            Incoming::new(incoming, tls)
+        }
+    };
+
+    // hyper answers with a 431 when the headers don't fit in its read
+    // buffer, which can't be smaller than 8KB
+    let max_headers_size =
+        parse_env::<usize>("LAGON_MAX_HEADERS_SIZE").unwrap_or(DEFAULT_MAX_HEADERS_SIZE);
+
+    // Slow clients (e.g slowloris attacks) can't hold connections
+    // open forever by sending their requests a few bytes at a time
+    let timeouts = ConnectionTimeouts {
+        idle: parse_timeout(
+            "LAGON_CONNECTION_IDLE_TIMEOUT_SECONDS",
+            DEFAULT_CONNECTION_IDLE_TIMEOUT_SECONDS,
+        ),
+        lifetime: parse_timeout(
+            "LAGON_CONNECTION_LIFETIME_SECONDS",
+            DEFAULT_CONNECTION_LIFETIME_SECONDS,
+        ),
+    };
+
+    let mut builder = Server::builder(TimeoutIncoming::new(incoming, timeouts))
+        .http1_only(!http2_enabled)
+        .http1_max_buf_size(max_headers_size.max(8192));
+
+    if let Some(header_read_timeout) = parse_timeout(
+        "LAGON_HEADER_READ_TIMEOUT_SECONDS",
+        DEFAULT_HEADER_READ_TIMEOUT_SECONDS,
+    ) {
+        builder = builder.http1_header_read_timeout(header_read_timeout);
+    }
+
+    if http2_enabled {
+        builder = builder
+            .http2_max_concurrent_streams(parse_env::<u32>("LAGON_HTTP2_MAX_CONCURRENT_STREAMS"))
+            .http2_max_header_list_size(max_headers_size as u32)
+            .http2_keep_alive_interval(keepalive_timeout);
+    }
+
+    Ok(builder)
 }
 
 pub async fn start<D, P>(
@@ -364,7 +3670,7 @@ pub async fn start<D, P>(
     client: Client,
 ) -> Result<impl Future<Output = ()> + Send>
 where
-    D: Downloader + Send + Sync + 'static,
+    D: Downloader + ?Sized + Send + Sync + 'static,
     P: PubSubListener + Unpin + 'static,
 {
     let last_requests = Arc::new(DashMap::new());
@@ -381,24 +3687,28 @@ where
             .with_period(Some(insertion_interval)),
     )));
 
-    let (log_sender, log_receiver) = flume::unbounded::<(String, String, Metadata)>();
+    let (log_sender, log_receiver) = flume::unbounded::<LogMessage>();
     let cronjob = Arc::new(TokioMutex::new(
         Cronjob::new(log_sender.clone(), Arc::clone(&inserters)).await,
     ));
 
+    init_warm_pool();
+
     let mut cron_deployments = HashSet::new();
 
     for deployment in deployments.iter() {
         let deployment = deployment.value();
 
-        // Make sure we only register the cron once, since each
-        // deployment can have multiple domains
+        // Make sure we only register the cron and warmup the isolate
+        // once, since each deployment can have multiple domains
         if cron_deployments.contains(&deployment.id) {
             continue;
         }
 
         cron_deployments.insert(deployment.id.clone());
 
+        warmup_deployment(Arc::clone(deployment), &workers, log_sender.clone());
+
         if deployment.should_run_cron() {
             let mut cronjob = cronjob.lock().await;
 
@@ -409,6 +3719,9 @@ where
     }
 
     drop(cron_deployments);
+    record_deployments_count(&deployments);
+
+    let readiness = Arc::new(Readiness::new());
 
     listen_pub_sub(
         Arc::clone(&downloader),
@@ -416,8 +3729,17 @@ where
         Arc::clone(&workers),
         Arc::clone(&cronjob),
         pubsub,
+        log_sender.clone(),
+        Arc::clone(&readiness),
+    );
+    run_resync_task(
+        Arc::clone(&downloader),
+        Arc::clone(&deployments),
+        Arc::clone(&workers),
+        Arc::clone(&cronjob),
+        log_sender.clone(),
+        Arc::clone(&readiness),
     );
-    run_cache_clear_task(Arc::clone(&last_requests), Arc::clone(&workers));
 
     let inserters_handle = Arc::clone(&inserters);
     tokio::spawn(async move {
@@ -455,6 +3777,7 @@ where
                     message: log.1,
                     region: REGION.clone(),
                     timestamp: UNIX_EPOCH.elapsed().unwrap().as_secs() as u32,
+                    request_id: log.3.unwrap_or_default(),
                 })
                 .await
             {
@@ -463,34 +3786,253 @@ where
         }
     });
 
-    let server = Server::bind(&addr).serve(make_service_fn(move |conn: &AddrStream| {
+    let concurrency_limits: ConcurrencyLimits = Arc::new(DashMap::new());
+    let isolate_requests: IsolateRequests = Arc::new(DashMap::new());
+    run_cache_clear_task(
+        Arc::clone(&last_requests),
+        Arc::clone(&workers),
+        Arc::clone(&isolate_requests),
+    );
+    let circuit_breakers: CircuitBreakers = Arc::new(DashMap::new());
+    let rate_limits: RateLimits = Arc::new(DashMap::new());
+    let debug_bodies: DebugBodies = Arc::new(DashMap::new());
+    let response_cache =
+        RESPONSE_CACHE_MAX_SIZE.map(|max_size| Arc::new(ResponseCache::new(max_size)));
+
+    // Remove the buckets of clients that haven't sent requests for
+    // long enough to get a full bucket, to avoid growing forever
+    let rate_limits_handle = Arc::clone(&rate_limits);
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(RATE_LIMITS_CLEANUP_INTERVAL).await;
+
+            let now = Instant::now();
+            rate_limits_handle.retain(|_, bucket| !bucket.is_full(now));
+        }
+    });
+    let in_flight_requests = Arc::new(AtomicUsize::new(0));
+    let in_flight_requests_handle = Arc::clone(&in_flight_requests);
+    let in_flight_limit = MAX_IN_FLIGHT_REQUESTS
+        .map(|max_requests| Arc::new(InFlightLimit::new(max_requests, *IN_FLIGHT_QUEUE_SIZE)));
+    let workers_handle = Arc::clone(&workers);
+
+    let readiness_handle = Arc::clone(&readiness);
+
+    let server = server_builder(&addr)?.serve(make_service_fn(move |conn: &TimeoutConnection| {
         let deployments = Arc::clone(&deployments);
         let last_requests = Arc::clone(&last_requests);
         let workers = Arc::clone(&workers);
+        let concurrency_limits = Arc::clone(&concurrency_limits);
+        let isolate_requests = Arc::clone(&isolate_requests);
+        let circuit_breakers = Arc::clone(&circuit_breakers);
+        let rate_limits = Arc::clone(&rate_limits);
+        let debug_bodies = Arc::clone(&debug_bodies);
+        let response_cache = response_cache.clone();
         let inserters = Arc::clone(&inserters);
         let log_sender = log_sender.clone();
+        let in_flight_requests = Arc::clone(&in_flight_requests);
+        let in_flight_limit = in_flight_limit.clone();
+        let readiness = Arc::clone(&readiness);
 
-        let addr = conn.remote_addr();
-        let ip = addr.ip().to_string();
+        let ip = conn.remote_addr().ip();
+        let is_tls = conn.is_tls();
 
         async move {
             Ok::<_, Infallible>(service_fn(move |req| {
-                handle_request(
+                let in_flight_requests = Arc::clone(&in_flight_requests);
+                let in_flight_limit = in_flight_limit.clone();
+                let probe_response = handle_probe(&req, &readiness).or_else(|| {
+                    handle_admin(
+                        &req,
+                        &deployments,
+                        &workers,
+                        &circuit_breakers,
+                        &readiness,
+                        &in_flight_requests,
+                    )
+                });
+                let request_id = get_request_id(&req);
+                let request_id_header = HeaderValue::from_str(&request_id).ok();
+                let access_log =
+                    AccessLog::sample(&req).map(|access_log| (access_log, request_id.clone()));
+
+                let response = handle_request(
                     req,
-                    ip.clone(),
+                    ip,
+                    is_tls,
                     Arc::clone(&deployments),
                     Arc::clone(&last_requests),
                     Arc::clone(&workers),
+                    Arc::clone(&concurrency_limits),
+                    Arc::clone(&isolate_requests),
+                    Arc::clone(&circuit_breakers),
+                    Arc::clone(&rate_limits),
+                    Arc::clone(&debug_bodies),
+                    response_cache.clone(),
                     Arc::clone(&inserters),
                     log_sender.clone(),
-                )
+                    request_id,
+                );
+
+                async move {
+                    if let Some(probe_response) = probe_response {
+                        return probe_response;
+                    }
+
+                    let permit = match &in_flight_limit {
+                        Some(in_flight_limit) => Some(in_flight_limit.acquire().await),
+                        None => None,
+                    };
+
+                    let response = match permit {
+                        Some(Err(reason)) => {
+                            increment_counter!(
+                                "lagon_requests_shed",
+                                "reason" => reason,
+                                "region" => REGION.clone(),
+                            );
+                            warn!(reason = reason; "Too many requests in flight, rejecting request");
+
+                            Response::builder()
+                                .status(503)
+                                .header(RETRY_AFTER, OVERLOADED_RETRY_AFTER_SECONDS)
+                                .body(PAGE_503.into())
+                                .map_err(Into::into)
+                        }
+                        _ => {
+                            let _in_flight_request =
+                                InFlightRequestGuard::new(in_flight_requests);
+
+                            response.await
+                        }
+                    };
+
+                    response.map(|mut response| {
+                        if let Some(request_id_header) = request_id_header {
+                            response
+                                .headers_mut()
+                                .insert(X_REQUEST_ID, request_id_header);
+                        }
+
+                        if let Some((access_log, request_id)) = access_log {
+                            access_log.log(&response, &request_id);
+                        }
+
+                        response
+                    })
+                }
             }))
         }
     }));
 
+    let grace_period = Duration::from_secs(
+        env::var("LAGON_SHUTDOWN_GRACE_PERIOD_SECONDS")
+            .ok()
+            .filter(|value| !value.is_empty())
+            .map_or(DEFAULT_SHUTDOWN_GRACE_PERIOD_SECONDS, |value| {
+                value
+                    .parse()
+                    .expect("LAGON_SHUTDOWN_GRACE_PERIOD_SECONDS is not a valid number")
+            }),
+    );
+
+    let draining_requests = Arc::new(AtomicUsize::new(0));
+    let draining_requests_handle = Arc::clone(&draining_requests);
+    let in_flight_requests = Arc::clone(&in_flight_requests_handle);
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+
+    let server = server.with_graceful_shutdown(async move {
+        shutdown_signal().await;
+        readiness_handle.drain();
+
+        let in_flight_requests = in_flight_requests.load(Ordering::Relaxed);
+        draining_requests_handle.store(in_flight_requests, Ordering::Relaxed);
+
+        info!(
+            "Shutting down, draining {} in-flight request(s)",
+            in_flight_requests
+        );
+
+        shutdown_tx.send(()).unwrap_or(());
+    });
+
     Ok(async move {
-        if let Err(error) = server.await {
-            error!("Server error: {}", error);
+        // The grace period only starts once the shutdown signal has been received
+        let grace_period_elapsed = async {
+            match shutdown_rx.await {
+                Ok(_) => tokio::time::sleep(grace_period).await,
+                Err(_) => futures::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            result = server => {
+                if let Err(error) = result {
+                    error!("Server error: {}", error);
+                }
+            }
+            _ = grace_period_elapsed => {
+                warn!(
+                    "Grace period of {:?} elapsed with {} request(s) still in flight",
+                    grace_period,
+                    in_flight_requests_handle.load(Ordering::Relaxed),
+                );
+            }
+        }
+
+        let drained_requests = draining_requests
+            .load(Ordering::Relaxed)
+            .saturating_sub(in_flight_requests_handle.load(Ordering::Relaxed));
+
+        info!("Drained {} request(s)", drained_requests);
+
+        let deployments_ids = workers_handle
+            .iter()
+            .map(|worker| worker.key().clone())
+            .collect::<Vec<_>>();
+
+        for deployment_id in deployments_ids {
+            clear_deployment_cache(
+                deployment_id,
+                Arc::clone(&workers_handle),
+                String::from("shutdown"),
+            )
+            .await;
         }
     })
 }
+
+async fn shutdown_signal() {
+    let mut sigterm = signal(SignalKind::terminate()).expect("Failed to listen to SIGTERM");
+
+    tokio::select! {
+        _ = sigterm.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn in_flight_limit_dropped_queued_request() {
+        let limit = InFlightLimit::new(1, 1);
+        let permit = limit.acquire().await.unwrap();
+
+        // Dropped while waiting in the queue, as hyper does when the client
+        // disconnects. A leaked slot would reject the next one with
+        // queue_full instead of letting it wait
+        for _ in 0..2 {
+            assert!(
+                tokio::time::timeout(Duration::from_millis(10), limit.acquire())
+                    .await
+                    .is_err()
+            );
+            assert_eq!(limit.queued.load(Ordering::Relaxed), 0);
+        }
+
+        drop(permit);
+        assert!(limit.acquire().await.is_ok());
+    }
+}
@@ -0,0 +1,152 @@
+use anyhow::{anyhow, Result};
+use log::info;
+use metrics::{
+    Counter, CounterFn, Gauge, GaugeFn, Histogram, HistogramFn, Key, KeyName, Recorder,
+    SharedString, Unit,
+};
+use std::{
+    fmt::Display,
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use crate::serverless::parse_env;
+
+const DEFAULT_ADDR: &str = "127.0.0.1:8125";
+
+// A metric with its labels, formatted once when registered. Labels
+// are sent as DogStatsD tags, which most StatsD servers support
+struct Metric {
+    socket: Arc<UdpSocket>,
+    name: String,
+    tags: String,
+    // Counters are sent as increments, so absolute values are
+    // compared to the last one
+    last_absolute: AtomicU64,
+}
+
+impl Metric {
+    fn send(&self, value: impl Display, kind: &str) {
+        let line = format!("{}:{}|{}{}", self.name, value, kind, self.tags);
+
+        // Like any UDP packet, metrics are lost when the server can't keep up
+        self.socket.send(line.as_bytes()).ok();
+    }
+}
+
+impl CounterFn for Metric {
+    fn increment(&self, value: u64) {
+        self.send(value, "c");
+    }
+
+    fn absolute(&self, value: u64) {
+        let last = self.last_absolute.fetch_max(value, Ordering::Relaxed);
+
+        if value > last {
+            self.send(value - last, "c");
+        }
+    }
+}
+
+impl GaugeFn for Metric {
+    fn increment(&self, value: f64) {
+        self.send(format!("+{}", value), "g");
+    }
+
+    fn decrement(&self, value: f64) {
+        self.send(format!("-{}", value), "g");
+    }
+
+    // Signed values are increments, so negative
+    // values are set by resetting the gauge first
+    fn set(&self, value: f64) {
+        if value < 0.0 {
+            self.send(0, "g");
+        }
+
+        self.send(value, "g");
+    }
+}
+
+impl HistogramFn for Metric {
+    fn record(&self, value: f64) {
+        self.send(value, "h");
+    }
+}
+
+struct StatsdRecorder {
+    socket: Arc<UdpSocket>,
+    prefix: Option<String>,
+}
+
+impl StatsdRecorder {
+    fn metric(&self, key: &Key) -> Arc<Metric> {
+        let name = match &self.prefix {
+            Some(prefix) => format!("{}.{}", prefix, key.name()),
+            None => key.name().to_string(),
+        };
+        let tags = key
+            .labels()
+            .map(|label| format!("{}:{}", label.key(), label.value()))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        Arc::new(Metric {
+            socket: Arc::clone(&self.socket),
+            name,
+            tags: match tags.is_empty() {
+                true => String::new(),
+                false => format!("|#{}", tags),
+            },
+            last_absolute: AtomicU64::new(0),
+        })
+    }
+}
+
+// StatsD doesn't store descriptions nor units
+impl Recorder for StatsdRecorder {
+    fn describe_counter(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn describe_gauge(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn describe_histogram(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn register_counter(&self, key: &Key) -> Counter {
+        Counter::from_arc(self.metric(key))
+    }
+
+    fn register_gauge(&self, key: &Key) -> Gauge {
+        Gauge::from_arc(self.metric(key))
+    }
+
+    fn register_histogram(&self, key: &Key) -> Histogram {
+        Histogram::from_arc(self.metric(key))
+    }
+}
+
+// Metrics are sent over UDP to STATSD_ADDR, prefixed with STATSD_PREFIX
+// when set. The socket is non-blocking so sending a metric never
+// slows down a request
+pub fn init_metrics() -> Result<()> {
+    let addr =
+        parse_env::<SocketAddr>("STATSD_ADDR").unwrap_or_else(|| DEFAULT_ADDR.parse().unwrap());
+    let socket = UdpSocket::bind(match addr {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    })?;
+    socket.connect(addr)?;
+    socket.set_nonblocking(true)?;
+
+    metrics::set_boxed_recorder(Box::new(StatsdRecorder {
+        socket: Arc::new(socket),
+        prefix: parse_env("STATSD_PREFIX"),
+    }))
+    .map_err(|error| anyhow!("Could not install StatsD recorder: {}", error))?;
+
+    info!("Sending metrics to StatsD at {}", addr);
+
+    Ok(())
+}
@@ -0,0 +1,204 @@
+use hyper::{client::HttpConnector, header::CONTENT_TYPE, Body, Client, Method, Request};
+use hyper_tls::HttpsConnector;
+use lagon_runtime_utils::trace_context::TraceContext;
+use log::error;
+use once_cell::sync::OnceCell;
+use serde_json::{json, Value};
+use std::{
+    env,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use uuid::Uuid;
+
+use crate::REGION;
+
+const SERVICE_NAME: &str = "lagon-serverless";
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+const SPAN_KIND_INTERNAL: u8 = 1;
+const SPAN_KIND_SERVER: u8 = 2;
+
+static EXPORTER: OnceCell<flume::Sender<SpanData>> = OnceCell::new();
+
+#[derive(Debug)]
+struct SpanData {
+    name: &'static str,
+    kind: u8,
+    trace_id: String,
+    span_id: String,
+    parent_span_id: Option<String>,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, String)>,
+}
+
+// A span that is exported when dropped. Spans are no-ops
+// when tracing isn't enabled
+pub struct Span(Option<SpanData>);
+
+impl Span {
+    pub fn root(name: &'static str, parent: Option<TraceContext>) -> Self {
+        if EXPORTER.get().is_none() {
+            return Self(None);
+        }
+
+        let (trace_id, parent_span_id) = match parent {
+            Some(parent) => (parent.trace_id, Some(parent.span_id)),
+            None => (Uuid::new_v4().simple().to_string(), None),
+        };
+
+        Self::new(name, SPAN_KIND_SERVER, trace_id, parent_span_id)
+    }
+
+    pub fn child(&self, name: &'static str) -> Self {
+        match &self.0 {
+            Some(parent) => Self::new(
+                name,
+                SPAN_KIND_INTERNAL,
+                parent.trace_id.clone(),
+                Some(parent.span_id.clone()),
+            ),
+            None => Self(None),
+        }
+    }
+
+    fn new(name: &'static str, kind: u8, trace_id: String, parent_span_id: Option<String>) -> Self {
+        let mut span_id = Uuid::new_v4().simple().to_string();
+        span_id.truncate(16);
+
+        let now = SystemTime::now();
+
+        Self(Some(SpanData {
+            name,
+            kind,
+            trace_id,
+            span_id,
+            parent_span_id,
+            start: now,
+            end: now,
+            attributes: Vec::new(),
+        }))
+    }
+
+    pub fn context(&self) -> Option<TraceContext> {
+        self.0.as_ref().map(|span| TraceContext {
+            trace_id: span.trace_id.clone(),
+            span_id: span.span_id.clone(),
+        })
+    }
+
+    pub fn set_attribute(&mut self, key: &'static str, value: impl ToString) {
+        if let Some(span) = &mut self.0 {
+            span.attributes.push((key, value.to_string()));
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let (Some(mut span), Some(exporter)) = (self.0.take(), EXPORTER.get()) {
+            span.end = SystemTime::now();
+            exporter.send(span).unwrap_or(());
+        }
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+fn attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+// Encode spans using the OTLP/HTTP JSON format
+// https://opentelemetry.io/docs/specs/otlp/#json-protobuf-encoding
+fn encode_spans(spans: &[SpanData]) -> Value {
+    let spans = spans
+        .iter()
+        .map(|span| {
+            json!({
+                "traceId": span.trace_id,
+                "spanId": span.span_id,
+                "parentSpanId": span.parent_span_id.clone().unwrap_or_default(),
+                "name": span.name,
+                "kind": span.kind,
+                "startTimeUnixNano": unix_nanos(span.start),
+                "endTimeUnixNano": unix_nanos(span.end),
+                "attributes": span
+                    .attributes
+                    .iter()
+                    .map(|(key, value)| attribute(key, value))
+                    .collect::<Vec<_>>(),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [
+                    attribute("service.name", SERVICE_NAME),
+                    attribute("cloud.region", &REGION),
+                ],
+            },
+            "scopeSpans": [{
+                "scope": { "name": SERVICE_NAME },
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+// Tracing is optional and only enabled when an OTLP endpoint is set
+pub fn init_tracing() {
+    let endpoint = match env::var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+        Ok(endpoint) if !endpoint.is_empty() => endpoint,
+        _ => return,
+    };
+
+    let url = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+    let (tx, rx) = flume::unbounded::<SpanData>();
+
+    if EXPORTER.set(tx).is_err() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let client = Client::builder().build::<_, Body>(HttpsConnector::<HttpConnector>::new());
+
+        loop {
+            tokio::time::sleep(EXPORT_INTERVAL).await;
+
+            let spans = rx.drain().collect::<Vec<_>>();
+
+            if spans.is_empty() {
+                continue;
+            }
+
+            let request = match Request::builder()
+                .method(Method::POST)
+                .uri(&url)
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(encode_spans(&spans).to_string()))
+            {
+                Ok(request) => request,
+                Err(error) => {
+                    error!("Error while building traces request: {}", error);
+                    continue;
+                }
+            };
+
+            match client.request(request).await {
+                Ok(response) if !response.status().is_success() => {
+                    error!("Error while exporting traces: {}", response.status());
+                }
+                Err(error) => error!("Error while exporting traces: {}", error),
+                _ => {}
+            }
+        }
+    });
+}
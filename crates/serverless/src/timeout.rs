@@ -0,0 +1,188 @@
+use hyper::server::accept::Accept;
+use log::warn;
+use metrics::increment_counter;
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    ops::Deref,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{sleep, Instant, Sleep},
+};
+
+use crate::{
+    tls::{Connection, Incoming},
+    REGION,
+};
+
+// Limits of a connection, independently of the requests sent on it
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnectionTimeouts {
+    // Close the connection when nothing was read or written for this
+    // long, e.g a client sending the body of a request and then
+    // stalling, or a kept-alive connection not used anymore
+    pub idle: Option<Duration>,
+    // Stop reading from the connection once it's open for this long,
+    // so a client can't keep it forever by trickling bytes. The
+    // response being written can still be finished
+    pub lifetime: Option<Duration>,
+}
+
+pub struct TimeoutConnection {
+    inner: Connection,
+    remote_addr: SocketAddr,
+    idle_timeout: Option<Duration>,
+    idle: Option<Pin<Box<Sleep>>>,
+    lifetime: Option<Pin<Box<Sleep>>>,
+    timed_out: bool,
+}
+
+impl TimeoutConnection {
+    fn new(inner: Connection, timeouts: ConnectionTimeouts) -> Self {
+        Self {
+            remote_addr: inner.remote_addr(),
+            inner,
+            idle_timeout: timeouts.idle,
+            idle: timeouts.idle.map(|idle| Box::pin(sleep(idle))),
+            lifetime: timeouts.lifetime.map(|lifetime| Box::pin(sleep(lifetime))),
+            timed_out: false,
+        }
+    }
+
+    fn reset_idle(&mut self) {
+        if let (Some(idle), Some(idle_timeout)) = (self.idle.as_mut(), self.idle_timeout) {
+            idle.as_mut().reset(Instant::now() + idle_timeout);
+        }
+    }
+
+    // Polling the timers registers them to wake up the connection
+    // once they elapse, even when no bytes are received
+    fn poll_timeouts(&mut self, cx: &mut Context<'_>, reading: bool) -> Option<io::Error> {
+        let reason = if self
+            .idle
+            .as_mut()
+            .map_or(false, |idle| idle.as_mut().poll(cx).is_ready())
+        {
+            "idle"
+        } else if reading
+            && self
+                .lifetime
+                .as_mut()
+                .map_or(false, |lifetime| lifetime.as_mut().poll(cx).is_ready())
+        {
+            "lifetime"
+        } else {
+            return None;
+        };
+
+        // hyper can poll the connection again before dropping it
+        if !self.timed_out {
+            self.timed_out = true;
+
+            increment_counter!("lagon_connection_timeouts", "reason" => reason, "region" => REGION.clone());
+            warn!(reason = reason; "Closing connection from {} after reaching its {} timeout", self.remote_addr.ip(), reason);
+        }
+
+        Some(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("Connection reached its {} timeout", reason),
+        ))
+    }
+}
+
+impl Deref for TimeoutConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl AsyncRead for TimeoutConnection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if let Some(error) = this.poll_timeouts(cx, true) {
+            return Poll::Ready(Err(error));
+        }
+
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+
+        if matches!(result, Poll::Ready(Ok(()))) && buf.filled().len() > filled {
+            this.reset_idle();
+        }
+
+        result
+    }
+}
+
+impl AsyncWrite for TimeoutConnection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        if let Some(error) = this.poll_timeouts(cx, false) {
+            return Poll::Ready(Err(error));
+        }
+
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+
+        if matches!(result, Poll::Ready(Ok(written)) if written > 0) {
+            this.reset_idle();
+        }
+
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+// Apply the same timeouts to all the connections accepted
+pub struct TimeoutIncoming {
+    inner: Incoming,
+    timeouts: ConnectionTimeouts,
+}
+
+impl TimeoutIncoming {
+    pub fn new(inner: Incoming, timeouts: ConnectionTimeouts) -> Self {
+        Self { inner, timeouts }
+    }
+}
+
+impl Accept for TimeoutIncoming {
+    type Conn = TimeoutConnection;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let this = self.get_mut();
+        let timeouts = this.timeouts;
+
+        Pin::new(&mut this.inner).poll_accept(cx).map(|connection| {
+            connection.map(|connection| {
+                connection.map(|connection| TimeoutConnection::new(connection, timeouts))
+            })
+        })
+    }
+}
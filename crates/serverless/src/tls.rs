@@ -0,0 +1,395 @@
+use anyhow::{anyhow, Result};
+use futures::{stream::FuturesUnordered, Future, StreamExt};
+use hyper::server::{
+    accept::Accept,
+    conn::{AddrIncoming, AddrStream},
+};
+use log::warn;
+use std::{
+    collections::HashMap,
+    env, fs, io,
+    net::SocketAddr,
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpStream, UnixListener, UnixStream},
+};
+use tokio_native_tls::{native_tls, TlsAcceptor, TlsStream};
+
+// Maximum size of a TLS record, plus the record header
+const MAX_CLIENT_HELLO_SIZE: usize = 16384 + 5;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn load_acceptor(cert_path: &Path, key_path: &Path) -> Result<TlsAcceptor> {
+    let cert = fs::read(cert_path)
+        .map_err(|error| anyhow!("Could not read {}: {}", cert_path.display(), error))?;
+    let key = fs::read(key_path)
+        .map_err(|error| anyhow!("Could not read {}: {}", key_path.display(), error))?;
+
+    let identity = native_tls::Identity::from_pkcs8(&cert, &key)?;
+
+    Ok(native_tls::TlsAcceptor::new(identity)?.into())
+}
+
+// TLS uses native-tls (OpenSSL on Linux), the stack already used by every
+// TLS client of the serverless: fetch() through hyper-tls, the Redis pub/sub
+// and the logger. A single TLS library is built, patched and audited that way
+pub struct TlsConfig {
+    default: TlsAcceptor,
+    domains: HashMap<String, TlsAcceptor>,
+}
+
+impl TlsConfig {
+    // TLS is only enabled when both LAGON_TLS_CERT_PATH and LAGON_TLS_KEY_PATH
+    // are set. Certificates for custom domains can be added to LAGON_TLS_CERTS_DIR
+    // as `<domain>.crt` and `<domain>.key` pairs, and are selected using SNI
+    pub fn from_env() -> Result<Option<Self>> {
+        let cert_path = env::var("LAGON_TLS_CERT_PATH").unwrap_or_default();
+        let key_path = env::var("LAGON_TLS_KEY_PATH").unwrap_or_default();
+
+        if cert_path.is_empty() || key_path.is_empty() {
+            return Ok(None);
+        }
+
+        let default = load_acceptor(Path::new(&cert_path), Path::new(&key_path))?;
+        let mut domains = HashMap::new();
+
+        if let Ok(certs_dir) = env::var("LAGON_TLS_CERTS_DIR") {
+            if !certs_dir.is_empty() {
+                for entry in fs::read_dir(&certs_dir)? {
+                    let cert_path = entry?.path();
+
+                    if cert_path
+                        .extension()
+                        .map_or(true, |extension| extension != "crt")
+                    {
+                        continue;
+                    }
+
+                    let domain = match cert_path.file_stem().and_then(|stem| stem.to_str()) {
+                        Some(domain) => domain.to_lowercase(),
+                        None => continue,
+                    };
+
+                    let acceptor = load_acceptor(&cert_path, &cert_path.with_extension("key"))?;
+                    domains.insert(domain, acceptor);
+                }
+            }
+        }
+
+        Ok(Some(Self { default, domains }))
+    }
+
+    // Find the certificate for the given server name, trying an exact match
+    // first and then a wildcard certificate (`_.domain.com.crt` for `*.domain.com`),
+    // falling back to the default certificate
+    fn acceptor(&self, server_name: Option<&str>) -> &TlsAcceptor {
+        server_name
+            .map(|server_name| server_name.to_lowercase())
+            .and_then(|server_name| {
+                self.domains.get(&server_name).or_else(|| {
+                    server_name
+                        .split_once('.')
+                        .and_then(|(_, parent)| self.domains.get(&format!("_.{}", parent)))
+                })
+            })
+            .unwrap_or(&self.default)
+    }
+}
+
+enum ClientHello {
+    Incomplete,
+    Parsed(Option<String>),
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+
+        let (value, rest) = self.0.split_at(len);
+        self.0 = rest;
+
+        Some(value)
+    }
+
+    fn u8(&mut self) -> Option<usize> {
+        self.take(1).map(|value| value[0] as usize)
+    }
+
+    fn u16(&mut self) -> Option<usize> {
+        self.take(2)
+            .map(|value| (value[0] as usize) << 8 | value[1] as usize)
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|value| (value[0] as usize) << 16 | (value[1] as usize) << 8 | value[2] as usize)
+    }
+}
+
+// Extract the server name from the SNI extension of a ClientHello message
+// https://datatracker.ietf.org/doc/html/rfc8446#section-4.1.2
+fn parse_client_hello(buf: &[u8]) -> ClientHello {
+    let mut record = Reader(buf);
+
+    let (content_type, record_len) = match (record.u8(), record.take(2), record.u16()) {
+        (Some(content_type), Some(_), Some(len)) => (content_type, len),
+        _ => return ClientHello::Incomplete,
+    };
+
+    // Not a handshake record, let the TLS acceptor fail
+    if content_type != 0x16 {
+        return ClientHello::Parsed(None);
+    }
+
+    let record = match record.take(record_len) {
+        Some(record) => record,
+        None if buf.len() < MAX_CLIENT_HELLO_SIZE => return ClientHello::Incomplete,
+        None => return ClientHello::Parsed(None),
+    };
+
+    ClientHello::Parsed(parse_server_name(Reader(record)))
+}
+
+fn parse_server_name(mut handshake: Reader) -> Option<String> {
+    // ClientHello type
+    if handshake.u8()? != 0x01 {
+        return None;
+    }
+
+    let len = handshake.u24()?;
+    let mut hello = Reader(handshake.take(len)?);
+
+    // Version and random
+    hello.take(2 + 32)?;
+
+    let session_id_len = hello.u8()?;
+    hello.take(session_id_len)?;
+    let cipher_suites_len = hello.u16()?;
+    hello.take(cipher_suites_len)?;
+    let compression_methods_len = hello.u8()?;
+    hello.take(compression_methods_len)?;
+
+    let extensions_len = hello.u16()?;
+    let mut extensions = Reader(hello.take(extensions_len)?);
+
+    while let (Some(extension_type), Some(extension_len)) = (extensions.u16(), extensions.u16()) {
+        let mut extension = Reader(extensions.take(extension_len)?);
+
+        // server_name extension
+        if extension_type != 0x00 {
+            continue;
+        }
+
+        let list_len = extension.u16()?;
+        let mut list = Reader(extension.take(list_len)?);
+
+        while let Some(name_type) = list.u8() {
+            let name_len = list.u16()?;
+            let name = list.take(name_len)?;
+
+            // host_name type
+            if name_type == 0x00 {
+                return String::from_utf8(name.to_vec()).ok();
+            }
+        }
+    }
+
+    None
+}
+
+// native-tls has no server-side SNI callback to pick the certificate, so
+// the ClientHello is peeked without reading it. The handshake itself is
+// still done entirely by OpenSSL, which sees the same bytes
+async fn read_server_name(stream: &TcpStream) -> io::Result<Option<String>> {
+    let mut buf = vec![0; MAX_CLIENT_HELLO_SIZE];
+
+    loop {
+        let len = stream.peek(&mut buf).await?;
+
+        if len == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        match parse_client_hello(&buf[..len]) {
+            ClientHello::Parsed(server_name) => return Ok(server_name),
+            // Wait for the rest of the ClientHello to arrive
+            ClientHello::Incomplete => tokio::time::sleep(Duration::from_millis(5)).await,
+        }
+    }
+}
+
+async fn handshake(config: Arc<TlsConfig>, stream: AddrStream) -> Result<Connection> {
+    let remote_addr = stream.remote_addr();
+    let stream = stream.into_inner();
+
+    let server_name = read_server_name(&stream).await?;
+    let stream = config
+        .acceptor(server_name.as_deref())
+        .accept(stream)
+        .await?;
+
+    Ok(Connection::Tls(Box::new(stream), remote_addr))
+}
+
+type Handshake = Pin<Box<dyn Future<Output = Result<Connection>> + Send>>;
+
+pub enum Connection {
+    Plain(AddrStream),
+    Tls(Box<TlsStream<TcpStream>>, SocketAddr),
+    Unix(UnixStream),
+}
+
+impl Connection {
+    pub fn remote_addr(&self) -> SocketAddr {
+        match self {
+            Connection::Plain(stream) => stream.remote_addr(),
+            Connection::Tls(_, remote_addr) => *remote_addr,
+            // Peers of a Unix socket are local, e.g a proxy on the same host
+            Connection::Unix(_) => SocketAddr::from(([127, 0, 0, 1], 0)),
+        }
+    }
+
+    pub fn is_tls(&self) -> bool {
+        matches!(self, Connection::Tls(..))
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Connection::Tls(stream, _) => Pin::new(stream).poll_read(cx, buf),
+            Connection::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Connection::Tls(stream, _) => Pin::new(stream).poll_write(cx, buf),
+            Connection::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Connection::Tls(stream, _) => Pin::new(stream).poll_flush(cx),
+            Connection::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Connection::Tls(stream, _) => Pin::new(stream).poll_shutdown(cx),
+            Connection::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+enum Listener {
+    Tcp(AddrIncoming),
+    Unix(UnixListener),
+}
+
+// Accept plaintext connections, or TLS connections when a TlsConfig is given.
+// Handshakes are driven concurrently so a slow client can't block others
+pub struct Incoming {
+    listener: Listener,
+    tls: Option<Arc<TlsConfig>>,
+    handshakes: FuturesUnordered<Handshake>,
+}
+
+impl Incoming {
+    pub fn new(incoming: AddrIncoming, tls: Option<TlsConfig>) -> Self {
+        Self {
+            listener: Listener::Tcp(incoming),
+            tls: tls.map(Arc::new),
+            handshakes: FuturesUnordered::new(),
+        }
+    }
+
+    // Only plaintext connections, TLS being terminated by the proxy
+    pub fn unix(listener: UnixListener) -> Self {
+        Self {
+            listener: Listener::Unix(listener),
+            tls: None,
+            handshakes: FuturesUnordered::new(),
+        }
+    }
+}
+
+impl Accept for Incoming {
+    type Conn = Connection;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let this = self.get_mut();
+
+        let incoming = match &mut this.listener {
+            Listener::Tcp(incoming) => incoming,
+            Listener::Unix(listener) => {
+                return listener
+                    .poll_accept(cx)
+                    .map(|result| Some(result.map(|(stream, _)| Connection::Unix(stream))))
+            }
+        };
+
+        let tls = match &this.tls {
+            Some(tls) => tls,
+            None => return Pin::new(incoming).poll_accept(cx).map_ok(Connection::Plain),
+        };
+
+        loop {
+            match Pin::new(&mut *incoming).poll_accept(cx) {
+                Poll::Ready(Some(Ok(stream))) => {
+                    let handshake =
+                        tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake(Arc::clone(tls), stream));
+
+                    this.handshakes.push(Box::pin(async move {
+                        handshake
+                            .await
+                            .map_err(|_| anyhow!("TLS handshake timed out"))?
+                    }));
+                }
+                Poll::Ready(Some(Err(error))) => return Poll::Ready(Some(Err(error))),
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => break,
+            }
+        }
+
+        loop {
+            match this.handshakes.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(connection))) => return Poll::Ready(Some(Ok(connection))),
+                Poll::Ready(Some(Err(error))) => {
+                    warn!("Failed TLS handshake: {}", error);
+                }
+                Poll::Ready(None) | Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
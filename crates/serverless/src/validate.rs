@@ -0,0 +1,164 @@
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use hyper::Request;
+use lagon_runtime_http::{RunResult, StreamResult};
+use lagon_runtime_isolate::{options::IsolateOptions, Isolate, IsolateEvent, IsolateRequest};
+use lagon_runtime_utils::Deployment;
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::runtime::Handle;
+
+use crate::{
+    deployments::get_environment_variables,
+    serverless::{
+        check_deployment_limits, get_fetch_policy, isolate_thread, with_deployment_limits,
+    },
+    SNAPSHOT_BLOB,
+};
+
+fn get_code(deployment: &Deployment) -> Result<(String, HashMap<String, String>)> {
+    check_deployment_limits(deployment)
+        .map_err(|error| anyhow!("Deployment is misconfigured: {}", error))?;
+
+    let code = deployment.get_code()?;
+    deployment.verify_code(code.as_bytes())?;
+
+    Ok((code, get_environment_variables(deployment)?))
+}
+
+fn as_millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+// Compile the code of a deployment and send it a request in a new isolate,
+// using the limits and fetch policy of the deployment. The isolate isn't
+// part of the workers: it doesn't serve traffic, and is terminated once
+// it answered. Logs and metrics aren't sent for the deployment
+pub async fn validate_deployment(deployment: Arc<Deployment>) -> Value {
+    let (code, environment_variables) = match get_code(&deployment) {
+        Ok(code) => code,
+        Err(error) => {
+            return json!({
+                "deploymentId": deployment.id,
+                "result": "error",
+                "error": error.to_string(),
+            })
+        }
+    };
+
+    let handle = Handle::current();
+    let (isolate_sender, isolate_receiver) = flume::unbounded();
+    let (evaluated_sender, evaluated_receiver) = flume::bounded(1);
+    let isolate_deployment = Arc::clone(&deployment);
+
+    let spawned =
+        isolate_thread(String::from("validate-") + deployment.id.as_str()).spawn(move || {
+            handle.block_on(async move {
+                let options =
+                    IsolateOptions::new(code).environment_variables(environment_variables);
+                let options = with_deployment_limits(options, &isolate_deployment)
+                    .fetch_policy(get_fetch_policy(&isolate_deployment))
+                    .snapshot_blob(SNAPSHOT_BLOB);
+
+                let start = Instant::now();
+                let mut isolate = Isolate::new(options, isolate_receiver);
+                isolate.evaluate();
+
+                evaluated_sender
+                    .send((
+                        isolate.get_compilation_error().map(String::from),
+                        start.elapsed(),
+                    ))
+                    .unwrap_or(());
+
+                isolate.run_event_loop().await;
+            });
+        });
+
+    if let Err(error) = spawned {
+        return json!({
+            "deploymentId": deployment.id,
+            "result": "error",
+            "error": error.to_string(),
+        });
+    }
+
+    let (compilation_error, compile_time) = match evaluated_receiver.recv_async().await {
+        Ok(evaluated) => evaluated,
+        Err(_) => {
+            return json!({
+                "deploymentId": deployment.id,
+                "result": "error",
+                "error": "Isolate stopped before evaluating the code",
+            })
+        }
+    };
+
+    if let Some(error) = compilation_error {
+        return json!({
+            "deploymentId": deployment.id,
+            "result": "compilationError",
+            "error": error,
+            "compileTime": as_millis(compile_time),
+        });
+    }
+
+    let (sender, receiver) = flume::unbounded();
+    let start = Instant::now();
+
+    isolate_sender
+        .send_async(IsolateEvent::Request(IsolateRequest {
+            sender,
+            request: Request::new(Bytes::new()).into_parts(),
+            body_stream: None,
+            request_id: None,
+        }))
+        .await
+        .unwrap_or(());
+
+    let run_result = receiver
+        .recv_async()
+        .await
+        .unwrap_or_else(|_| RunResult::Error("Isolate didn't send a response".into()));
+    let run_time = start.elapsed();
+
+    isolate_sender
+        .send_async(IsolateEvent::Terminate(String::from(
+            "Validation completed",
+        )))
+        .await
+        .unwrap_or(());
+
+    let (result, status, error) = match run_result {
+        RunResult::Response(response, _) => ("success", Some(response.status().as_u16()), None),
+        RunResult::Stream(StreamResult::Start(response)) => (
+            "success",
+            response
+                .body(())
+                .ok()
+                .map(|response| response.status().as_u16()),
+            None,
+        ),
+        RunResult::Stream(_) => ("success", None, None),
+        RunResult::Timeout => ("timeout", None, None),
+        RunResult::MemoryLimit => ("memoryLimit", None, None),
+        RunResult::StackOverflow(error) => ("stackOverflow", None, Some(error)),
+        RunResult::Error(error) => ("runtimeError", None, Some(error)),
+        RunResult::CompileError(error) | RunResult::EvaluationError(error) => {
+            ("compilationError", None, Some(error))
+        }
+    };
+
+    json!({
+        "deploymentId": deployment.id,
+        "result": result,
+        "status": status,
+        "error": error,
+        "compileTime": as_millis(compile_time),
+        "runTime": as_millis(run_time),
+    })
+}
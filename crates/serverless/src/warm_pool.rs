@@ -0,0 +1,92 @@
+use lagon_runtime_isolate::WarmIsolate;
+use log::{error, info};
+use metrics::{decrement_gauge, increment_gauge};
+use once_cell::sync::Lazy;
+
+use crate::{
+    serverless::{isolate_thread, parse_env},
+    REGION, SNAPSHOT_BLOB,
+};
+
+const DEFAULT_MEMORY: usize = 128;
+
+// Run on the thread of the isolate, with the warm isolate of the thread
+// when it came from the pool
+pub type IsolateJob = Box<dyn FnOnce(Option<WarmIsolate>) + Send>;
+
+struct WarmPool {
+    sender: flume::Sender<IsolateJob>,
+    receiver: flume::Receiver<IsolateJob>,
+    memory: usize,
+}
+
+// Threads with a blank isolate, waiting for the code of any deployment.
+// A new thread replaces each one taken, so the pool stays full. Only
+// deployments with the same memory limit as the pool can use it. Disabled
+// when LAGON_WARM_POOL_SIZE isn't set
+static WARM_POOL: Lazy<Option<WarmPool>> = Lazy::new(|| {
+    let size = parse_env::<usize>("LAGON_WARM_POOL_SIZE").filter(|size| *size > 0)?;
+    let memory = parse_env("LAGON_WARM_POOL_MEMORY").unwrap_or(DEFAULT_MEMORY);
+
+    // Jobs are only sent to threads already waiting for one
+    let (sender, receiver) = flume::bounded(0);
+    let pool = WarmPool {
+        sender,
+        receiver,
+        memory,
+    };
+
+    info!("Keeping {} warm isolate(s) of {}MB", size, memory);
+
+    for _ in 0..size {
+        pool.spawn_thread();
+    }
+
+    Some(pool)
+});
+
+impl WarmPool {
+    fn spawn_thread(&self) {
+        let receiver = self.receiver.clone();
+        let memory = self.memory;
+
+        let spawned = isolate_thread(String::from("isolate-warm")).spawn(move || {
+            let warm = WarmIsolate::new(memory, Some(SNAPSHOT_BLOB));
+            increment_gauge!("lagon_warm_isolates", 1.0, "region" => REGION.clone());
+
+            // The pool is never dropped, so this only fails
+            // when the process is exiting
+            if let Ok(job) = receiver.recv() {
+                decrement_gauge!("lagon_warm_isolates", 1.0, "region" => REGION.clone());
+                job(Some(warm));
+            }
+        });
+
+        if let Err(error) = spawned {
+            error!("Error while spawning warm isolate thread: {}", error);
+        }
+    }
+}
+
+// Create the warm isolates in advance, instead of on the first request
+pub fn init_warm_pool() {
+    Lazy::force(&WARM_POOL);
+}
+
+// Run the job on a thread of the warm pool when one is ready and the
+// isolate has the same memory limit (in MB), or on a new thread named
+// `name` otherwise, so the warm isolates aren't wasted on other deployments
+pub fn spawn_isolate(name: String, memory: usize, job: IsolateJob) -> std::io::Result<()> {
+    let job = match WARM_POOL.as_ref().filter(|pool| pool.memory == memory) {
+        Some(pool) => match pool.sender.try_send(job) {
+            Ok(()) => {
+                pool.spawn_thread();
+                return Ok(());
+            }
+            Err(error) => error.into_inner(),
+        },
+        None => job,
+    };
+
+    isolate_thread(name).spawn(move || job(None)).map(|_| ())
+}
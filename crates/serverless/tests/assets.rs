@@ -1,6 +1,6 @@
 use anyhow::Result;
 use dashmap::DashMap;
-use lagon_runtime_utils::Deployment;
+use lagon_runtime_utils::{Deployment, DeploymentConfig};
 use lagon_serverless::serverless::start;
 use lagon_serverless_downloader::FakeDownloader;
 use lagon_serverless_pubsub::FakePubSub;
@@ -31,6 +31,9 @@ async fn html_assets() -> Result<()> {
             total_timeout: 1000,
             is_production: true,
             cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig::default(),
         }),
     );
     let serverless = start(
@@ -86,6 +89,9 @@ async fn assets_nested() -> Result<()> {
             total_timeout: 1000,
             is_production: true,
             cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig::default(),
         }),
     );
     let serverless = start(
@@ -138,6 +144,9 @@ async fn set_content_type() -> Result<()> {
             total_timeout: 1000,
             is_production: true,
             cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig::default(),
         }),
     );
     let serverless = start(
@@ -171,3 +180,188 @@ async fn set_content_type() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+#[serial]
+async fn conditional_assets() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "assets".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::from(["hello.html".into()]),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig::default(),
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000/hello").await?;
+    assert_eq!(response.status(), 200);
+    assert!(response.headers().contains_key("last-modified"));
+    let etag = response.headers().get("etag").unwrap().clone();
+
+    let client = reqwest::Client::new();
+    let response = client
+        .get("http://127.0.0.1:4000/hello")
+        .header("if-none-match", etag)
+        .send()
+        .await?;
+    assert_eq!(response.status(), 304);
+    assert_eq!(response.text().await?, "");
+
+    let response = client
+        .get("http://127.0.0.1:4000/hello")
+        .header("if-none-match", "\"outdated\"")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "hello asset!\n");
+
+    // Responses from the Function don't have an ETag
+    let response = reqwest::get("http://127.0.0.1:4000/other").await?;
+    assert_eq!(response.status(), 200);
+    assert!(!response.headers().contains_key("etag"));
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn head_assets() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "assets".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::from(["hello.html".into()]),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig::default(),
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let client = reqwest::Client::new();
+    let response = client.head("http://127.0.0.1:4000/hello").send().await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers().get("content-length").unwrap(), "13");
+    assert_eq!(response.headers().get("content-type").unwrap(), "text/html");
+    assert!(response.headers().contains_key("etag"));
+    assert_eq!(response.text().await?, "");
+
+    // Responses from the Function are handled the same
+    let response = client.head("http://127.0.0.1:4000/other").send().await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers().get("content-length").unwrap(), "21");
+    assert_eq!(response.text().await?, "");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn assets_range() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "assets".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::from(["hello.html".into()]),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig::default(),
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let client = reqwest::Client::new();
+
+    let response = client.get("http://127.0.0.1:4000/hello").send().await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["accept-ranges"], "bytes");
+
+    let response = client
+        .get("http://127.0.0.1:4000/hello")
+        .header("range", "bytes=0-4")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 206);
+    assert_eq!(response.headers()["content-range"], "bytes 0-4/13");
+    assert_eq!(response.text().await?, "hello");
+
+    let response = client
+        .get("http://127.0.0.1:4000/hello")
+        .header("range", "bytes=100-")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 416);
+    assert_eq!(response.headers()["content-range"], "bytes */13");
+
+    // The asset changed since the first range was downloaded
+    let response = client
+        .get("http://127.0.0.1:4000/hello")
+        .header("range", "bytes=0-4")
+        .header("if-range", "\"outdated\"")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "hello asset!\n");
+
+    Ok(())
+}
@@ -0,0 +1,75 @@
+use anyhow::Result;
+use dashmap::DashMap;
+use lagon_runtime_utils::{response::PAGE_503, Deployment};
+use lagon_serverless::serverless::start;
+use lagon_serverless_downloader::FakeDownloader;
+use lagon_serverless_pubsub::FakePubSub;
+use serial_test::serial;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+
+mod utils;
+
+#[tokio::test]
+#[serial]
+async fn open_circuit_breaker() -> Result<()> {
+    // Each test file is a separate process, so this doesn't affect other tests
+    std::env::set_var("LAGON_CIRCUIT_BREAKER_THRESHOLD", "2");
+    std::env::set_var("LAGON_CIRCUIT_BREAKER_COOLDOWN_SECONDS", "1");
+
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "throw-error".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: Default::default(),
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    for _ in 0..2 {
+        let response = reqwest::get("http://127.0.0.1:4000").await?;
+        assert_eq!(response.status(), 500);
+    }
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 503);
+    assert_eq!(response.headers().get("retry-after").unwrap(), "1");
+    assert_eq!(response.text().await?, PAGE_503);
+
+    // A trial request is allowed after the cooldown, and opens
+    // the circuit again since it also fails
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 500);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 503);
+
+    Ok(())
+}
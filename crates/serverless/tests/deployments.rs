@@ -1,13 +1,16 @@
 use anyhow::Result;
 use dashmap::DashMap;
-use lagon_runtime_utils::Deployment;
+use futures::future::join_all;
+use lagon_runtime_utils::{CanaryConfig, Deployment, DeploymentConfig, ShadowConfig};
 use lagon_serverless::serverless::start;
 use lagon_serverless_downloader::FakeDownloader;
 use lagon_serverless_pubsub::FakePubSub;
 use serial_test::serial;
 use std::{
     collections::{HashMap, HashSet},
+    env,
     sync::Arc,
+    time::Duration,
 };
 
 mod utils;
@@ -31,6 +34,9 @@ async fn simple() -> Result<()> {
             total_timeout: 1000,
             is_production: true,
             cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig::default(),
         }),
     );
     let serverless = start(
@@ -67,6 +73,9 @@ async fn custom_domains() -> Result<()> {
         total_timeout: 1000,
         is_production: true,
         cron: None,
+        code_hash: None,
+        variant: None,
+        config: DeploymentConfig::default(),
     });
     deployments.insert("127.0.0.1:4000".into(), Arc::clone(&deployment));
     deployments.insert("custom.domain".into(), Arc::clone(&deployment));
@@ -115,6 +124,9 @@ async fn reuse_isolate() -> Result<()> {
             total_timeout: 1000,
             is_production: true,
             cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig::default(),
         }),
     );
     let serverless = start(
@@ -155,6 +167,9 @@ async fn reuse_isolate_across_domains() -> Result<()> {
         total_timeout: 1000,
         is_production: true,
         cron: None,
+        code_hash: None,
+        variant: None,
+        config: DeploymentConfig::default(),
     });
     deployments.insert("127.0.0.1:4000".into(), Arc::clone(&deployment));
     deployments.insert("another.domain".into(), deployment);
@@ -183,3 +198,267 @@ async fn reuse_isolate_across_domains() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+#[serial]
+async fn reuse_isolate_concurrent_requests() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "counter".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::from(["127.0.0.1:4000".into()]),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig::default(),
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let responses = join_all((0..50).map(|_| async {
+        let response = reqwest::get("http://127.0.0.1:4000").await?;
+        assert_eq!(response.status(), 200);
+
+        response.text().await
+    }))
+    .await;
+
+    // All requests should have been handled by the same isolate
+    let mut counts = responses
+        .into_iter()
+        .map(|response| response.unwrap().parse::<usize>().unwrap())
+        .collect::<Vec<_>>();
+    counts.sort_unstable();
+
+    assert_eq!(counts, (1..=50).collect::<Vec<_>>());
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn warm_deployment() -> Result<()> {
+    std::env::set_var("LAGON_ADMIN_SECRET", "secret");
+
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::from(["127.0.0.1:4000".into()]),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig {
+                warm: true,
+                ..Default::default()
+            },
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    // The isolate is created when the deployment is loaded, before any request
+    let list = reqwest::Client::new()
+        .get("http://127.0.0.1:4000/__lagon/deployments")
+        .header("x-lagon-admin-secret", "secret")
+        .send()
+        .await?
+        .text()
+        .await?;
+    let list = serde_json::from_str::<serde_json::Value>(&list)?;
+    assert_eq!(list[0]["isolateCreated"], true);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn canary_deployment() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::from(["127.0.0.1:4000".into()]),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig {
+                canary: Some(CanaryConfig {
+                    deployment_id: "counter".into(),
+                    weight: 100.0,
+                }),
+                ..Default::default()
+            },
+        }),
+    );
+    deployments.insert(
+        format!("counter.{}", env::var("LAGON_ROOT_DOMAIN")?),
+        Arc::new(Deployment {
+            id: "counter".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: false,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig::default(),
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response.headers().get("x-lagon-deployment").unwrap(),
+        "counter"
+    );
+    assert_eq!(response.text().await?, "1");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn shadow_deployment() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+    let shadow_hostname = format!("counter.{}", env::var("LAGON_ROOT_DOMAIN")?);
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::from(["127.0.0.1:4000".into()]),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig {
+                shadow: Some(ShadowConfig {
+                    deployment_id: "counter".into(),
+                    weight: 100.0,
+                }),
+                ..Default::default()
+            },
+        }),
+    );
+    deployments.insert(
+        shadow_hostname.clone(),
+        Arc::new(Deployment {
+            id: "counter".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: false,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig::default(),
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response.headers().get("x-lagon-deployment").unwrap(),
+        "simple"
+    );
+    assert_eq!(response.text().await?, "Hello world");
+
+    // The shadow runs after the primary deployment responded
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let client = reqwest::Client::new();
+    let response = client
+        .get("http://127.0.0.1:4000")
+        .header("host", shadow_hostname)
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "2");
+
+    Ok(())
+}
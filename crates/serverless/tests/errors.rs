@@ -1,8 +1,14 @@
 use anyhow::Result;
 use dashmap::DashMap;
+use lagon_runtime_http::LAGON_RUN_RESULT;
 use lagon_runtime_utils::{
-    response::{PAGE_403, PAGE_404, PAGE_500, PAGE_502},
-    Deployment,
+    auth::Auth,
+    rate_limit::RateLimit,
+    response::{
+        PAGE_400, PAGE_401, PAGE_403, PAGE_404, PAGE_405, PAGE_413, PAGE_415, PAGE_417, PAGE_421,
+        PAGE_500, PAGE_504, PAGE_MAINTENANCE,
+    },
+    Deployment, DeploymentConfig,
 };
 use lagon_serverless::serverless::start;
 use lagon_serverless_downloader::FakeDownloader;
@@ -11,6 +17,11 @@ use serial_test::serial;
 use std::{
     collections::{HashMap, HashSet},
     sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
 };
 
 mod utils;
@@ -36,6 +47,32 @@ async fn return_404_no_deployment_found() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+#[serial]
+async fn return_400_no_hostname() -> Result<()> {
+    let client = utils::setup();
+    let serverless = start(
+        Arc::new(DashMap::new()),
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    // HTTP/1.0 requests can omit the Host header
+    let mut stream = TcpStream::connect("127.0.0.1:4000").await?;
+    stream.write_all(b"GET / HTTP/1.0\r\n\r\n").await?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    assert!(response.starts_with("HTTP/1.0 400 Bad Request"));
+    assert!(response.ends_with(PAGE_400));
+
+    Ok(())
+}
+
 #[tokio::test]
 #[serial]
 async fn return_403_cron_deployment() -> Result<()> {
@@ -55,6 +92,9 @@ async fn return_403_cron_deployment() -> Result<()> {
             total_timeout: 1000,
             is_production: true,
             cron: Some("".into()),
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig::default(),
         }),
     );
     let serverless = start(
@@ -74,6 +114,50 @@ async fn return_403_cron_deployment() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+#[serial]
+async fn return_421_other_region() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig {
+                region: Some("eu-west-1".into()),
+                ..Default::default()
+            },
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 421);
+    assert_eq!(response.text().await?, PAGE_421);
+
+    Ok(())
+}
+
 #[tokio::test]
 #[serial]
 async fn return_500_unknown_code() -> Result<()> {
@@ -93,6 +177,9 @@ async fn return_500_unknown_code() -> Result<()> {
             total_timeout: 1000,
             is_production: true,
             cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig::default(),
         }),
     );
     let serverless = start(
@@ -105,6 +192,12 @@ async fn return_500_unknown_code() -> Result<()> {
     .await?;
     tokio::spawn(serverless);
 
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 500);
+    assert_eq!(response.headers().get(LAGON_RUN_RESULT).unwrap(), "error");
+    assert_eq!(response.text().await?, PAGE_500);
+
+    // The broken isolate isn't cached, so the code is fetched again
     let response = reqwest::get("http://127.0.0.1:4000").await?;
     assert_eq!(response.status(), 500);
     assert_eq!(response.text().await?, PAGE_500);
@@ -114,7 +207,7 @@ async fn return_500_unknown_code() -> Result<()> {
 
 #[tokio::test]
 #[serial]
-async fn return_502_timeout_execution() -> Result<()> {
+async fn return_504_timeout_execution() -> Result<()> {
     let client = utils::setup();
     let deployments = Arc::new(DashMap::new());
     deployments.insert(
@@ -131,6 +224,9 @@ async fn return_502_timeout_execution() -> Result<()> {
             total_timeout: 1000,
             is_production: true,
             cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig::default(),
         }),
     );
     let serverless = start(
@@ -144,15 +240,16 @@ async fn return_502_timeout_execution() -> Result<()> {
     tokio::spawn(serverless);
 
     let response = reqwest::get("http://127.0.0.1:4000").await?;
-    assert_eq!(response.status(), 502);
-    assert_eq!(response.text().await?, PAGE_502);
+    assert_eq!(response.status(), 504);
+    assert_eq!(response.headers().get(LAGON_RUN_RESULT).unwrap(), "timeout");
+    assert_eq!(response.text().await?, PAGE_504);
 
     Ok(())
 }
 
 #[tokio::test]
 #[serial]
-async fn return_502_timeout_init() -> Result<()> {
+async fn return_504_timeout_init() -> Result<()> {
     let client = utils::setup();
     let deployments = Arc::new(DashMap::new());
     deployments.insert(
@@ -169,6 +266,9 @@ async fn return_502_timeout_init() -> Result<()> {
             total_timeout: 1000,
             is_production: true,
             cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig::default(),
         }),
     );
     let serverless = start(
@@ -182,8 +282,9 @@ async fn return_502_timeout_init() -> Result<()> {
     tokio::spawn(serverless);
 
     let response = reqwest::get("http://127.0.0.1:4000").await?;
-    assert_eq!(response.status(), 502);
-    assert_eq!(response.text().await?, PAGE_502);
+    assert_eq!(response.status(), 504);
+    assert_eq!(response.headers().get(LAGON_RUN_RESULT).unwrap(), "timeout");
+    assert_eq!(response.text().await?, PAGE_504);
 
     Ok(())
 }
@@ -207,6 +308,9 @@ async fn return_500_code_invalid() -> Result<()> {
             total_timeout: 1000,
             is_production: true,
             cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig::default(),
         }),
     );
     let serverless = start(
@@ -245,6 +349,53 @@ async fn return_500_throw_error() -> Result<()> {
             total_timeout: 1000,
             is_production: true,
             cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig::default(),
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 500);
+    assert_eq!(response.text().await?, PAGE_500);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn return_500_throw_error_not_retried() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "throw-error".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig {
+                retry_transient_errors: true,
+                ..Default::default()
+            },
         }),
     );
     let serverless = start(
@@ -257,9 +408,611 @@ async fn return_500_throw_error() -> Result<()> {
     .await?;
     tokio::spawn(serverless);
 
+    // Errors thrown by the handler are returned without being retried
     let response = reqwest::get("http://127.0.0.1:4000").await?;
     assert_eq!(response.status(), 500);
     assert_eq!(response.text().await?, PAGE_500);
 
+    let response = reqwest::Client::new()
+        .post("http://127.0.0.1:4000")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 500);
+    assert_eq!(response.text().await?, PAGE_500);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn return_429_concurrency_limit() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "timeout-execution".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig {
+                max_concurrency: Some(1),
+                ..Default::default()
+            },
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let (first, second) = tokio::join!(
+        reqwest::get("http://127.0.0.1:4000"),
+        reqwest::get("http://127.0.0.1:4000"),
+    );
+    let mut statuses = [first?.status(), second?.status()];
+    statuses.sort();
+
+    assert_eq!(statuses, [429, 504]);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn return_429_rate_limit() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig {
+                rate_limit: Some(RateLimit {
+                    requests_per_second: 0.5,
+                    burst: Some(1.0),
+                }),
+                ..Default::default()
+            },
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 429);
+    assert_eq!(response.headers().get("retry-after").unwrap(), "2");
+
+    // Clients are rate limited independently
+    let client = reqwest::Client::new();
+    let response = client
+        .get("http://127.0.0.1:4000")
+        .header("x-forwarded-for", "1.2.3.4")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn return_413_body_too_large() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig {
+                max_body_size: Some(10),
+                ..Default::default()
+            },
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let client = reqwest::Client::new();
+    let response = client
+        .post("http://127.0.0.1:4000")
+        .body("a".repeat(11))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 413);
+    assert_eq!(response.text().await?, PAGE_413);
+
+    let response = client
+        .post("http://127.0.0.1:4000")
+        .body("a".repeat(10))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn return_417_expectation_failed() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig {
+                max_body_size: Some(10),
+                ..Default::default()
+            },
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let client = reqwest::Client::new();
+    let response = client
+        .post("http://127.0.0.1:4000")
+        .header("expect", "100-continue")
+        .body("a".repeat(11))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 417);
+    assert_eq!(response.text().await?, PAGE_417);
+
+    let response = client
+        .post("http://127.0.0.1:4000")
+        .header("expect", "something-else")
+        .body("a".repeat(10))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 417);
+
+    let response = client
+        .post("http://127.0.0.1:4000")
+        .header("expect", "100-continue")
+        .body("a".repeat(10))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn return_504_request_timeout() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "slow-response".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 10000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig {
+                request_timeout: Some(100),
+                ..Default::default()
+            },
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let now = Instant::now();
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 504);
+    assert_eq!(response.headers().get(LAGON_RUN_RESULT).unwrap(), "timeout");
+    assert_eq!(response.text().await?, PAGE_504);
+    assert!(now.elapsed() < Duration::from_secs(1));
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn return_405_method_not_allowed() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig {
+                allowed_methods: Some(vec!["post".into()]),
+                ..Default::default()
+            },
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 405);
+    assert_eq!(response.headers()["allow"], "POST");
+    assert_eq!(response.text().await?, PAGE_405);
+
+    let response = reqwest::Client::new()
+        .post("http://127.0.0.1:4000")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn return_415_unsupported_media_type() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig {
+                allowed_content_types: Some(vec!["application/json".into()]),
+                ..Default::default()
+            },
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let client = reqwest::Client::new();
+
+    let response = client
+        .post("http://127.0.0.1:4000")
+        .header("content-type", "text/plain")
+        .body("Hello")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 415);
+    assert_eq!(response.text().await?, PAGE_415);
+
+    let response = client
+        .post("http://127.0.0.1:4000")
+        .header("content-type", "application/json; charset=utf-8")
+        .body("{}")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn return_401_unauthorized() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig {
+                auth: Some(Auth::Basic {
+                    username: "user".into(),
+                    password: "pass".into(),
+                }),
+                ..Default::default()
+            },
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 401);
+    assert_eq!(
+        response.headers()["www-authenticate"],
+        r#"Basic realm="Lagon", charset="UTF-8""#
+    );
+    assert_eq!(response.text().await?, PAGE_401);
+
+    let response = reqwest::Client::new()
+        .get("http://127.0.0.1:4000")
+        .basic_auth("user", Some("wrong"))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 401);
+
+    let response = reqwest::Client::new()
+        .get("http://127.0.0.1:4000")
+        .basic_auth("user", Some("pass"))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn return_431_too_many_headers() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig::default(),
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let mut request = reqwest::Client::new().get("http://127.0.0.1:4000");
+
+    for index in 0..120 {
+        request = request.header(format!("x-header-{index}"), "value");
+    }
+
+    let response = request.send().await?;
+    assert_eq!(response.status(), 431);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn return_503_maintenance() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig {
+                maintenance: true,
+                ..Default::default()
+            },
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 503);
+    assert_eq!(response.headers()[LAGON_RUN_RESULT], "maintenance");
+    assert_eq!(response.text().await?, PAGE_MAINTENANCE);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn return_503_misconfigured() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 0,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig::default(),
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 503);
+    assert_eq!(
+        response.text().await?,
+        "Deployment is misconfigured: memory of 0 MB is below the minimum of 8 MB"
+    );
+
     Ok(())
 }
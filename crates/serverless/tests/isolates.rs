@@ -0,0 +1,74 @@
+use anyhow::Result;
+use dashmap::DashMap;
+use lagon_runtime_utils::{response::PAGE_503, Deployment};
+use lagon_serverless::serverless::start;
+use lagon_serverless_downloader::FakeDownloader;
+use lagon_serverless_pubsub::FakePubSub;
+use serial_test::serial;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+
+mod utils;
+
+fn deployment(id: &str) -> Arc<Deployment> {
+    Arc::new(Deployment {
+        id: id.into(),
+        function_id: "function_id".into(),
+        function_name: "function_name".into(),
+        domains: HashSet::new(),
+        assets: HashSet::new(),
+        environment_variables: HashMap::new(),
+        memory: 128,
+        tick_timeout: 1000,
+        total_timeout: 10000,
+        is_production: true,
+        cron: None,
+        code_hash: None,
+        variant: None,
+        config: Default::default(),
+    })
+}
+
+#[tokio::test]
+#[serial]
+async fn max_isolates() -> Result<()> {
+    // Each test file is a separate process, so this doesn't affect other tests
+    std::env::set_var("LAGON_MAX_ISOLATES", "1");
+
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+    deployments.insert("127.0.0.1:4000".into(), deployment("simple"));
+    deployments.insert("localhost:4000".into(), deployment("slow-response"));
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+
+    // The idle isolate is evicted to create the new one
+    let slow_response = tokio::spawn(reqwest::get("http://localhost:4000"));
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    // The only isolate is busy and can't be evicted
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 503);
+    assert_eq!(response.headers().get("retry-after").unwrap(), "1");
+    assert_eq!(response.text().await?, PAGE_503);
+
+    assert_eq!(slow_response.await??.status(), 200);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+
+    Ok(())
+}
@@ -0,0 +1,109 @@
+use anyhow::Result;
+use lagon_runtime_utils::DEPLOYMENTS_DIR;
+use lagon_serverless::{
+    deployments::local::{get_local_deployments, LocalPubSub},
+    serverless::start,
+};
+use lagon_serverless_downloader::FilesystemDownloader;
+use serial_test::serial;
+use std::{fs, path::Path, sync::Arc, time::Duration};
+
+mod utils;
+
+#[tokio::test]
+#[serial]
+async fn local_deployments() -> Result<()> {
+    let client = utils::setup();
+    let dir = std::env::temp_dir().join("lagon-local-deployments");
+    fs::create_dir_all(&dir)?;
+    fs::write(
+        dir.join("local.json"),
+        r#"{ "domains": ["127.0.0.1:4000"] }"#,
+    )?;
+    fs::write(
+        dir.join("local.js"),
+        "export function handler() {
+  return new Response('Hello world');
+}",
+    )?;
+
+    let downloader = Arc::new(FilesystemDownloader::new(dir.clone()));
+    let deployments = get_local_deployments(&dir, Arc::clone(&downloader)).await?;
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        downloader,
+        LocalPubSub::new(dir.clone()),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    fs::write(
+        dir.join("local.js"),
+        "export function handler() {
+  return new Response('Hello reloaded');
+}",
+    )?;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello reloaded");
+
+    fs::remove_file(dir.join("local.json"))?;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 404);
+
+    fs::remove_dir_all(&dir)?;
+    fs::remove_file(Path::new(DEPLOYMENTS_DIR).join("local.js"))?;
+    fs::remove_file(Path::new(DEPLOYMENTS_DIR).join("local.cache")).unwrap_or(());
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn local_deployment_invalid_config() -> Result<()> {
+    let client = utils::setup();
+    let dir = std::env::temp_dir().join("lagon-local-deployments-invalid");
+    fs::create_dir_all(&dir)?;
+    fs::write(
+        dir.join("invalid.json"),
+        r#"{ "domains": ["127.0.0.1:4000"], "config": { "auth": { "username": "user" } } }"#,
+    )?;
+    fs::write(
+        dir.join("invalid.js"),
+        "export function handler() {
+  return new Response('Hello world');
+}",
+    )?;
+
+    let downloader = Arc::new(FilesystemDownloader::new(dir.clone()));
+    let deployments = get_local_deployments(&dir, Arc::clone(&downloader)).await?;
+    assert!(deployments.is_empty());
+
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        downloader,
+        LocalPubSub::new(dir.clone()),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    // Not served without its credentials
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 404);
+
+    fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
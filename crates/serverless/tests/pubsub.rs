@@ -143,6 +143,118 @@ async fn assign_correct_domains_prod() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+#[serial]
+async fn remove_stale_aliases() -> Result<()> {
+    let client = utils::setup();
+    let pubsub = FakePubSub::default();
+    let tx = pubsub.get_tx();
+    let serverless = start(
+        Arc::new(DashMap::new()),
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        pubsub,
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::Deploy,
+        r#"{
+    "functionId": "function_id",
+    "functionName": "function_name",
+    "deploymentId": "simple",
+    "domains": ["127.0.0.1:4000", "my.domain"],
+    "memory": 128,
+    "tickTimeout": 1000,
+    "totalTimeout": 1000,
+    "cron": null,
+    "cronRegion": "local",
+    "env": {},
+    "isProduction": true,
+    "assets": []
+}"#
+        .into(),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let client = reqwest::Client::new();
+    let response = client
+        .get("http://127.0.0.1:4000")
+        .header("host", "my.domain")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+
+    // my.domain is removed from the domains of the deployment
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::Deploy,
+        r#"{
+    "functionId": "function_id",
+    "functionName": "function_name",
+    "deploymentId": "simple",
+    "domains": ["127.0.0.1:4000"],
+    "memory": 128,
+    "tickTimeout": 1000,
+    "totalTimeout": 1000,
+    "cron": null,
+    "cronRegion": "local",
+    "env": {},
+    "isProduction": true,
+    "assets": []
+}"#
+        .into(),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let response = client
+        .get("http://127.0.0.1:4000")
+        .header("host", "my.domain")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 404);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+
+    // All the hostnames are removed, even if they are missing from the message
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::Undeploy,
+        r#"{
+    "functionId": "function_id",
+    "functionName": "function_name",
+    "deploymentId": "simple",
+    "domains": [],
+    "memory": 128,
+    "tickTimeout": 1000,
+    "totalTimeout": 1000,
+    "cron": null,
+    "cronRegion": "local",
+    "env": {},
+    "isProduction": true,
+    "assets": []
+}"#
+        .into(),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 404);
+
+    let response = client
+        .get("http://127.0.0.1:4000")
+        .header("host", "simple.lagon.dev")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 404);
+
+    Ok(())
+}
+
 #[tokio::test]
 #[serial]
 async fn assign_correct_domains_dev() -> Result<()> {
@@ -291,3 +403,188 @@ async fn warn_cron_direct_access() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+#[serial]
+async fn redeploy_recreate_isolate_on_env_change() -> Result<()> {
+    let client = utils::setup();
+    let pubsub = FakePubSub::default();
+    let tx = pubsub.get_tx();
+    let serverless = start(
+        Arc::new(DashMap::new()),
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        pubsub,
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let deploy = |env: &str| {
+        PubSubMessage::new(
+            PubSubMessageKind::Deploy,
+            format!(
+                r#"{{
+    "functionId": "function_id",
+    "functionName": "function_name",
+    "deploymentId": "counter",
+    "domains": ["127.0.0.1:4000"],
+    "memory": 128,
+    "tickTimeout": 1000,
+    "totalTimeout": 1000,
+    "cron": null,
+    "cronRegion": "local",
+    "env": {},
+    "isProduction": true,
+    "assets": []
+}}"#,
+                env
+            ),
+        )
+    };
+
+    tx.send_async(deploy("{}")).await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.text().await?, "1");
+
+    // The isolate is kept when the environment variables don't change
+    tx.send_async(deploy("{}")).await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.text().await?, "2");
+
+    tx.send_async(deploy(r#"{ "KEY": "value" }"#)).await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.text().await?, "1");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn ignore_stale_messages() -> Result<()> {
+    let client = utils::setup();
+    let pubsub = FakePubSub::default();
+    let tx = pubsub.get_tx();
+    let serverless = start(
+        Arc::new(DashMap::new()),
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        pubsub,
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::Deploy,
+        r#"{
+    "functionId": "function_id",
+    "functionName": "function_name",
+    "deploymentId": "simple",
+    "domains": ["127.0.0.1:4000"],
+    "memory": 128,
+    "tickTimeout": 1000,
+    "totalTimeout": 1000,
+    "cron": null,
+    "cronRegion": "local",
+    "env": {},
+    "isProduction": true,
+    "assets": [],
+    "sequence": 2
+}"#
+        .into(),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    // Delivered late, after the deploy
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::Undeploy,
+        r#"{
+    "functionId": "function_id",
+    "functionName": "function_name",
+    "deploymentId": "simple",
+    "domains": ["127.0.0.1:4000"],
+    "memory": 128,
+    "tickTimeout": 1000,
+    "totalTimeout": 1000,
+    "cron": null,
+    "cronRegion": "local",
+    "env": {},
+    "isProduction": true,
+    "assets": [],
+    "sequence": 1
+}"#
+        .into(),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    // Same sequence number as the deploy
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::Undeploy,
+        r#"{
+    "functionId": "function_id",
+    "functionName": "function_name",
+    "deploymentId": "simple",
+    "domains": ["127.0.0.1:4000"],
+    "memory": 128,
+    "tickTimeout": 1000,
+    "totalTimeout": 1000,
+    "cron": null,
+    "cronRegion": "local",
+    "env": {},
+    "isProduction": true,
+    "assets": [],
+    "sequence": 2
+}"#
+        .into(),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+
+    tx.send_async(PubSubMessage::new(
+        PubSubMessageKind::Undeploy,
+        r#"{
+    "functionId": "function_id",
+    "functionName": "function_name",
+    "deploymentId": "simple",
+    "domains": ["127.0.0.1:4000"],
+    "memory": 128,
+    "tickTimeout": 1000,
+    "totalTimeout": 1000,
+    "cron": null,
+    "cronRegion": "local",
+    "env": {},
+    "isProduction": true,
+    "assets": [],
+    "sequence": 3
+}"#
+        .into(),
+    ))
+    .await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 404);
+    assert_eq!(response.text().await?, PAGE_404);
+
+    Ok(())
+}
@@ -1,11 +1,12 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
 use dashmap::DashMap;
-use futures::StreamExt;
+use futures::{stream, Stream, StreamExt};
 use hyper::body::Bytes;
-use lagon_runtime_utils::Deployment;
-use lagon_serverless::serverless::start;
+use lagon_runtime_utils::{response::PAGE_502, Deployment, DeploymentConfig, VariantConfig};
+use lagon_serverless::{exporter::init_metrics, serverless::start};
 use lagon_serverless_downloader::FakeDownloader;
-use lagon_serverless_pubsub::FakePubSub;
+use lagon_serverless_pubsub::{FakePubSub, PubSubListener, PubSubMessage};
 use serial_test::serial;
 use std::{
     collections::{HashMap, HashSet},
@@ -33,6 +34,9 @@ async fn returns_correct_http() -> Result<()> {
             total_timeout: 1000,
             is_production: true,
             cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig::default(),
         }),
     );
     let serverless = start(
@@ -74,6 +78,9 @@ async fn returns_correct_path() -> Result<()> {
             total_timeout: 1000,
             is_production: true,
             cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig::default(),
         }),
     );
     let serverless = start(
@@ -123,6 +130,9 @@ async fn forwards_headers() -> Result<()> {
             total_timeout: 1000,
             is_production: true,
             cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig::default(),
         }),
     );
     let serverless = start(
@@ -144,6 +154,73 @@ async fn forwards_headers() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+#[serial]
+async fn limits_override_headers() -> Result<()> {
+    std::env::set_var("LAGON_ADMIN_SECRET", "secret");
+
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "forwards-headers".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig::default(),
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let client = reqwest::Client::new();
+
+    // Untrusted requests get the headers stripped
+    let response = client
+        .get("http://127.0.0.1:4000")
+        .header("x-lagon-timeout", "5000")
+        .header("x-lagon-memory", "256")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    assert!(!response.headers().contains_key("x-lagon-timeout"));
+    assert!(!response.headers().contains_key("x-lagon-memory"));
+
+    // Trusted requests run in their own isolate, without
+    // the Function seeing the headers or the secret
+    let response = client
+        .get("http://127.0.0.1:4000")
+        .header("x-lagon-admin-secret", "secret")
+        .header("x-lagon-timeout", "5000")
+        .header("x-lagon-memory", "256")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    assert!(!response.headers().contains_key("x-lagon-timeout"));
+    assert!(!response.headers().contains_key("x-lagon-memory"));
+    assert!(!response.headers().contains_key("x-lagon-admin-secret"));
+    assert_eq!(response.headers()["x-lagon-region"], "local");
+
+    Ok(())
+}
+
 #[tokio::test]
 #[serial]
 async fn stream_sequentially() -> Result<()> {
@@ -163,6 +240,9 @@ async fn stream_sequentially() -> Result<()> {
             total_timeout: 1000,
             is_production: true,
             cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig::default(),
         }),
     );
     let serverless = start(
@@ -186,3 +266,841 @@ async fn stream_sequentially() -> Result<()> {
 
     Ok(())
 }
+
+#[tokio::test]
+#[serial]
+async fn probes_bypass_deployments() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::from(["127.0.0.1:4000".into()]),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig::default(),
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000/__lagon/health").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "");
+
+    let response = reqwest::get("http://127.0.0.1:4000/__lagon/ready").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "");
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    Ok(())
+}
+
+// Never connects, as when Redis is down
+struct DisconnectedPubSub;
+
+#[async_trait]
+impl PubSubListener for DisconnectedPubSub {
+    async fn connect(&mut self) -> Result<()> {
+        Err(anyhow!("Connection refused"))
+    }
+
+    fn get_stream(&mut self) -> Box<dyn Stream<Item = PubSubMessage> + Unpin + Send + '_> {
+        Box::new(stream::empty())
+    }
+}
+
+#[tokio::test]
+#[serial]
+async fn not_ready_without_pubsub() -> Result<()> {
+    let client = utils::setup();
+    let serverless = start(
+        Arc::new(DashMap::new()),
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        DisconnectedPubSub,
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000/__lagon/health").await?;
+    assert_eq!(response.status(), 200);
+
+    let response = reqwest::get("http://127.0.0.1:4000/__lagon/ready").await?;
+    assert_eq!(response.status(), 503);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn returns_request_id() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::from(["127.0.0.1:4000".into()]),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig::default(),
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert!(!response.headers().get("x-request-id").unwrap().is_empty());
+
+    let client = reqwest::Client::new();
+    let response = client
+        .get("http://127.0.0.1:4000")
+        .header("x-request-id", "custom-id")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers().get("x-request-id").unwrap(), "custom-id");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn http2_prior_knowledge() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::from(["127.0.0.1:4000".into()]),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig::default(),
+        }),
+    );
+
+    std::env::set_var("LAGON_HTTP2_ENABLED", "true");
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await;
+    std::env::remove_var("LAGON_HTTP2_ENABLED");
+    tokio::spawn(serverless?);
+
+    let client = reqwest::Client::builder().http2_prior_knowledge().build()?;
+    let response = client.get("http://127.0.0.1:4000").send().await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.version(), reqwest::Version::HTTP_2);
+    assert_eq!(response.text().await?, "Hello world");
+
+    // HTTP/1.1 clients are still supported
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.version(), reqwest::Version::HTTP_11);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn admin_deployments() -> Result<()> {
+    std::env::set_var("LAGON_ADMIN_SECRET", "secret");
+
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::from(["127.0.0.1:4000".into()]),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig::default(),
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000/__lagon/deployments").await?;
+    assert_eq!(response.status(), 401);
+
+    let client = reqwest::Client::new();
+    let list_deployments = || {
+        client
+            .get("http://127.0.0.1:4000/__lagon/deployments")
+            .header("x-lagon-admin-secret", "secret")
+            .send()
+    };
+
+    let response = list_deployments().await?;
+    assert_eq!(response.status(), 200);
+    let list = serde_json::from_str::<serde_json::Value>(&response.text().await?)?;
+    assert_eq!(list[0]["hostname"], "127.0.0.1:4000");
+    assert_eq!(list[0]["deploymentId"], "simple");
+    assert_eq!(list[0]["memory"], 128);
+    assert_eq!(list[0]["isolateCreated"], false);
+
+    reqwest::get("http://127.0.0.1:4000").await?;
+
+    let list = serde_json::from_str::<serde_json::Value>(&list_deployments().await?.text().await?)?;
+    assert_eq!(list[0]["isolateCreated"], true);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn admin_drain() -> Result<()> {
+    std::env::set_var("LAGON_ADMIN_SECRET", "secret");
+
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::from(["127.0.0.1:4000".into()]),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig::default(),
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let client = reqwest::Client::new();
+    let response = client
+        .post("http://127.0.0.1:4000/__lagon/drain")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 401);
+
+    let response = client
+        .get("http://127.0.0.1:4000/__lagon/drain")
+        .header("x-lagon-admin-secret", "secret")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 405);
+
+    let response = reqwest::get("http://127.0.0.1:4000/__lagon/ready").await?;
+    assert_eq!(response.status(), 200);
+
+    let response = client
+        .post("http://127.0.0.1:4000/__lagon/drain")
+        .header("x-lagon-admin-secret", "secret")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    let body = serde_json::from_str::<serde_json::Value>(&response.text().await?)?;
+    assert_eq!(body["draining"], true);
+    assert_eq!(body["inFlightRequests"], 0);
+
+    let response = reqwest::get("http://127.0.0.1:4000/__lagon/ready").await?;
+    assert_eq!(response.status(), 503);
+
+    // Requests are still served while draining
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn admin_metrics() -> Result<()> {
+    std::env::set_var("LAGON_ADMIN_SECRET", "secret");
+    std::env::set_var("PROMETHEUS_ON_MAIN_PORT", "true");
+    init_metrics()?;
+
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig::default(),
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    reqwest::get("http://127.0.0.1:4000").await?;
+
+    let response = reqwest::get("http://127.0.0.1:4000/__lagon/metrics").await?;
+    assert_eq!(response.status(), 401);
+
+    let response = reqwest::Client::new()
+        .get("http://127.0.0.1:4000/__lagon/metrics")
+        .header("x-lagon-admin-secret", "secret")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    assert!(response.text().await?.contains("lagon_isolate_cold_starts"));
+
+    std::env::remove_var("PROMETHEUS_ON_MAIN_PORT");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn admin_validate() -> Result<()> {
+    std::env::set_var("LAGON_ADMIN_SECRET", "secret");
+
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+
+    for (hostname, id) in [
+        ("127.0.0.1:4000", "simple"),
+        ("throw.lagon.test", "throw-error"),
+    ] {
+        deployments.insert(
+            hostname.into(),
+            Arc::new(Deployment {
+                id: id.into(),
+                function_id: "function_id".into(),
+                function_name: "function_name".into(),
+                domains: HashSet::new(),
+                assets: HashSet::new(),
+                environment_variables: HashMap::new(),
+                memory: 128,
+                tick_timeout: 1000,
+                total_timeout: 1000,
+                is_production: false,
+                cron: None,
+                code_hash: None,
+                variant: None,
+                config: DeploymentConfig::default(),
+            }),
+        );
+    }
+
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let client = reqwest::Client::new();
+    let validate = |deployment_id: &'static str| {
+        client
+            .post("http://127.0.0.1:4000/__lagon/validate")
+            .header("x-lagon-admin-secret", "secret")
+            .body(format!(r#"{{"deploymentId":"{deployment_id}"}}"#))
+            .send()
+    };
+
+    let response = client
+        .post("http://127.0.0.1:4000/__lagon/validate")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 401);
+
+    let response = validate("simple").await?;
+    assert_eq!(response.status(), 200);
+    let validation = serde_json::from_str::<serde_json::Value>(&response.text().await?)?;
+    assert_eq!(validation["result"], "success");
+    assert_eq!(validation["status"], 200);
+
+    let validation =
+        serde_json::from_str::<serde_json::Value>(&validate("throw-error").await?.text().await?)?;
+    assert_eq!(validation["result"], "runtimeError");
+    assert!(validation["error"].as_str().unwrap().contains("hello"));
+
+    let response = validate("unknown").await?;
+    assert_eq!(response.status(), 404);
+
+    // The throwaway isolates are never cached
+    let response = client
+        .get("http://127.0.0.1:4000/__lagon/deployments")
+        .header("x-lagon-admin-secret", "secret")
+        .send()
+        .await?;
+    let list = serde_json::from_str::<serde_json::Value>(&response.text().await?)?;
+    assert_eq!(list[0]["isolateCreated"], false);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn server_timing() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig {
+                server_timing: true,
+                ..Default::default()
+            },
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    let server_timing = response.headers().get("server-timing").unwrap().to_str()?;
+    assert!(server_timing.starts_with("total;dur="));
+    assert!(server_timing.contains(", cpu;dur="));
+    assert!(server_timing.ends_with(", cold-start;desc=\"Isolate created\""));
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    let server_timing = response.headers().get("server-timing").unwrap().to_str()?;
+    assert!(!server_timing.contains("cold-start"));
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn response_size_limit() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "simple".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig {
+                max_response_size: Some(5),
+                ..Default::default()
+            },
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 502);
+    assert_eq!(response.text().await?, PAGE_502);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn debug_bodies_keep_response() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "request".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig {
+                debug_bodies: Some(1),
+                ..Default::default()
+            },
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    // The first response is buffered to be logged, the next ones aren't
+    for _ in 0..2 {
+        let response = reqwest::get("http://127.0.0.1:4000").await?;
+        assert_eq!(response.status(), 201);
+        assert_eq!(response.headers()["x-custom"], "custom");
+        assert_eq!(response.text().await?, "body");
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn wildcard_and_fallback_hostnames() -> Result<()> {
+    std::env::set_var("LAGON_FALLBACK_HOSTNAME", "fallback.lagon.test");
+
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+
+    for (hostname, id) in [
+        ("*.preview.lagon.test", "simple"),
+        ("fallback.lagon.test", "request"),
+    ] {
+        deployments.insert(
+            hostname.into(),
+            Arc::new(Deployment {
+                id: id.into(),
+                function_id: "function_id".into(),
+                function_name: "function_name".into(),
+                domains: HashSet::new(),
+                assets: HashSet::new(),
+                environment_variables: HashMap::new(),
+                memory: 128,
+                tick_timeout: 1000,
+                total_timeout: 1000,
+                is_production: true,
+                cron: None,
+                code_hash: None,
+                variant: None,
+                config: DeploymentConfig::default(),
+            }),
+        );
+    }
+
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let client = reqwest::Client::new();
+    let get = |host: &'static str| {
+        client
+            .get("http://127.0.0.1:4000")
+            .header("host", host)
+            .send()
+    };
+
+    let response = get("a.preview.lagon.test").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello world");
+
+    let response = get("a.b.preview.lagon.test").await?;
+    assert_eq!(response.text().await?, "Hello world");
+
+    let response = get("unknown.lagon.test").await?;
+    assert_eq!(response.status(), 201);
+    assert_eq!(response.text().await?, "body");
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn stream_request_body() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "echo-body".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig {
+                stream_request_body: true,
+                max_body_size: Some(20),
+                ..Default::default()
+            },
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let client = reqwest::Client::new();
+
+    let response = client
+        .post("http://127.0.0.1:4000")
+        .body("Hello, streamed 🌍")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.text().await?, "Hello, streamed 🌍");
+
+    let response = client
+        .post("http://127.0.0.1:4000")
+        .body("This body is too large")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 413);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn isolate_pool() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "request".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig {
+                isolate_pool_size: Some(2),
+                ..Default::default()
+            },
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    // Sent to each isolate of the pool in turn
+    for _ in 0..4 {
+        let response = reqwest::get("http://127.0.0.1:4000").await?;
+        assert_eq!(response.status(), 201);
+        assert_eq!(response.text().await?, "body");
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn config_variants() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "env".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::from([("VARIANT".into(), "default".into())]),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig {
+                variants: vec![VariantConfig {
+                    name: "blue".into(),
+                    weight: 100.0,
+                    environment_variables: HashMap::from([("VARIANT".into(), "blue".into())]),
+                }],
+                ..Default::default()
+            },
+        }),
+    );
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let response = reqwest::get("http://127.0.0.1:4000").await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response.headers().get("set-cookie").unwrap(),
+        "lagon-variant=blue; Path=/; Max-Age=2592000; HttpOnly; SameSite=Lax"
+    );
+    assert_eq!(response.text().await?, "blue");
+
+    // Pinned clients keep their variant, without setting the cookie again
+    let response = reqwest::Client::new()
+        .get("http://127.0.0.1:4000")
+        .header("cookie", "theme=dark; lagon-variant=default")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    assert!(response.headers().get("set-cookie").is_none());
+    assert_eq!(response.text().await?, "default");
+
+    Ok(())
+}
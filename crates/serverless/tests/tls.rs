@@ -0,0 +1,114 @@
+use anyhow::Result;
+use dashmap::DashMap;
+use hyper::{body::to_bytes, client::conn::handshake, Body, Request};
+use lagon_runtime_utils::{Deployment, DeploymentConfig};
+use lagon_serverless::serverless::start;
+use lagon_serverless_downloader::FakeDownloader;
+use lagon_serverless_pubsub::FakePubSub;
+use serial_test::serial;
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    sync::Arc,
+};
+use tokio::net::TcpStream;
+use tokio_native_tls::{native_tls, TlsConnector};
+
+mod utils;
+
+// Send a request over TLS using the given server name, returning
+// the certificate presented by the server and the response body
+async fn tls_request(server_name: &str) -> Result<(Vec<u8>, String)> {
+    let connector: TlsConnector = native_tls::TlsConnector::builder()
+        .danger_accept_invalid_certs(true)
+        .build()?
+        .into();
+
+    let stream = TcpStream::connect("127.0.0.1:4000").await?;
+    let stream = connector.connect(server_name, stream).await?;
+    let certificate = stream
+        .get_ref()
+        .peer_certificate()?
+        .expect("No peer certificate")
+        .to_der()?;
+
+    let (mut sender, connection) = handshake(stream).await?;
+    tokio::spawn(connection);
+
+    let response = sender
+        .send_request(
+            Request::builder()
+                .uri("/")
+                .header("host", server_name)
+                .body(Body::empty())?,
+        )
+        .await?;
+    assert_eq!(response.status(), 200);
+
+    let body = to_bytes(response.into_body()).await?;
+
+    Ok((certificate, String::from_utf8(body.to_vec())?))
+}
+
+fn certificate_der(path: &str) -> Result<Vec<u8>> {
+    let pem = fs::read(path)?;
+
+    Ok(native_tls::Certificate::from_pem(&pem)?.to_der()?)
+}
+
+#[tokio::test]
+#[serial]
+async fn tls_sni() -> Result<()> {
+    let client = utils::setup();
+    let deployments = Arc::new(DashMap::new());
+    let deployment = Arc::new(Deployment {
+        id: "simple".into(),
+        function_id: "function_id".into(),
+        function_name: "function_name".into(),
+        domains: HashSet::from(["localhost".into(), "custom.domain".into()]),
+        assets: HashSet::new(),
+        environment_variables: HashMap::new(),
+        memory: 128,
+        tick_timeout: 1000,
+        total_timeout: 1000,
+        is_production: true,
+        cron: None,
+        code_hash: None,
+        variant: None,
+        config: DeploymentConfig::default(),
+    });
+    deployments.insert("localhost".into(), Arc::clone(&deployment));
+    deployments.insert("custom.domain".into(), deployment);
+
+    std::env::set_var("LAGON_TLS_CERT_PATH", "tests/fixtures/tls/default.crt");
+    std::env::set_var("LAGON_TLS_KEY_PATH", "tests/fixtures/tls/default.key");
+    std::env::set_var("LAGON_TLS_CERTS_DIR", "tests/fixtures/tls/certs");
+    let serverless = start(
+        deployments,
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await;
+    std::env::remove_var("LAGON_TLS_CERT_PATH");
+    std::env::remove_var("LAGON_TLS_KEY_PATH");
+    std::env::remove_var("LAGON_TLS_CERTS_DIR");
+    tokio::spawn(serverless?);
+
+    let (certificate, body) = tls_request("localhost").await?;
+    assert_eq!(
+        certificate,
+        certificate_der("tests/fixtures/tls/default.crt")?
+    );
+    assert_eq!(body, "Hello world");
+
+    let (certificate, body) = tls_request("custom.domain").await?;
+    assert_eq!(
+        certificate,
+        certificate_der("tests/fixtures/tls/certs/custom.domain.crt")?
+    );
+    assert_eq!(body, "Hello world");
+
+    Ok(())
+}
@@ -0,0 +1,137 @@
+use anyhow::Result;
+use dashmap::DashMap;
+use lagon_runtime_utils::{Deployment, DeploymentConfig};
+use lagon_serverless::{deployments::Deployments, serverless::start};
+use lagon_serverless_downloader::FakeDownloader;
+use lagon_serverless_pubsub::FakePubSub;
+use serial_test::serial;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+mod utils;
+
+const MASK: [u8; 4] = [1, 2, 3, 4];
+
+fn masked_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8];
+    frame.extend_from_slice(&MASK);
+    frame.extend(
+        payload
+            .iter()
+            .enumerate()
+            .map(|(index, byte)| byte ^ MASK[index % 4]),
+    );
+    frame
+}
+
+fn websocket_deployment(websocket: bool) -> Deployments {
+    let deployments = Arc::new(DashMap::new());
+    deployments.insert(
+        "127.0.0.1:4000".into(),
+        Arc::new(Deployment {
+            id: "websocket".into(),
+            function_id: "function_id".into(),
+            function_name: "function_name".into(),
+            domains: HashSet::new(),
+            assets: HashSet::new(),
+            environment_variables: HashMap::new(),
+            memory: 128,
+            tick_timeout: 1000,
+            total_timeout: 1000,
+            is_production: true,
+            cron: None,
+            code_hash: None,
+            variant: None,
+            config: DeploymentConfig {
+                websocket,
+                ..Default::default()
+            },
+        }),
+    );
+    deployments
+}
+
+async fn handshake(stream: &mut TcpStream) -> Result<String> {
+    stream
+        .write_all(
+            b"GET / HTTP/1.1\r\n\
+Host: 127.0.0.1:4000\r\n\
+Connection: Upgrade\r\n\
+Upgrade: websocket\r\n\
+Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+Sec-WebSocket-Version: 13\r\n\r\n",
+        )
+        .await?;
+
+    let mut response = Vec::new();
+
+    while !response.ends_with(b"\r\n\r\n") {
+        response.push(stream.read_u8().await?);
+    }
+
+    Ok(String::from_utf8(response)?.to_lowercase())
+}
+
+#[tokio::test]
+#[serial]
+async fn websocket_echo() -> Result<()> {
+    let client = utils::setup();
+    let serverless = start(
+        websocket_deployment(true),
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let mut stream = TcpStream::connect("127.0.0.1:4000").await?;
+    let response = handshake(&mut stream).await?;
+    assert!(response.starts_with("http/1.1 101"));
+    assert!(response.contains("sec-websocket-accept: s3pplmbitxaq9kygzzhzrbk+xoo=\r\n"));
+
+    stream.write_all(&masked_frame(0x1, b"Hello")).await?;
+
+    let mut frame = [0; 13];
+    stream.read_exact(&mut frame).await?;
+    assert_eq!(&frame[..2], [0x81, 11]);
+    assert_eq!(&frame[2..], b"Echo: Hello");
+
+    stream
+        .write_all(&masked_frame(0x8, &1000_u16.to_be_bytes()))
+        .await?;
+
+    let mut frame = [0; 4];
+    stream.read_exact(&mut frame).await?;
+    assert_eq!(frame, [0x88, 2, 0x03, 0xe8]);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial]
+async fn websocket_not_enabled() -> Result<()> {
+    let client = utils::setup();
+    let serverless = start(
+        websocket_deployment(false),
+        "127.0.0.1:4000".parse().unwrap(),
+        Arc::new(FakeDownloader),
+        FakePubSub::default(),
+        client,
+    )
+    .await?;
+    tokio::spawn(serverless);
+
+    let mut stream = TcpStream::connect("127.0.0.1:4000").await?;
+    let response = handshake(&mut stream).await?;
+    assert!(response.starts_with("http/1.1 200"));
+
+    Ok(())
+}
@@ -0,0 +1,25 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use std::{fs, path::PathBuf};
+
+use super::Downloader;
+
+// Read deployments from a local directory, using the same
+// layout as the bucket (`<deployment>.js`, `<deployment>/<asset>`)
+pub struct FilesystemDownloader {
+    root: PathBuf,
+}
+
+impl FilesystemDownloader {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+}
+
+#[async_trait]
+impl Downloader for FilesystemDownloader {
+    async fn download(&self, path: String) -> Result<Vec<u8>> {
+        let bytes = fs::read(self.root.join(path))?;
+        Ok(bytes)
+    }
+}
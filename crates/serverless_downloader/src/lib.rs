@@ -1,12 +1,14 @@
 use anyhow::Result;
 use async_trait::async_trait;
 use s3::{creds::Credentials, Bucket, Region};
-use std::env;
+use std::{env, path::PathBuf, sync::Arc};
 
 mod fake;
+mod filesystem;
 mod s3_bucket;
 
 pub use fake::FakeDownloader;
+pub use filesystem::FilesystemDownloader;
 pub use s3_bucket::S3BucketDownloader;
 
 pub fn get_bucket() -> Result<Bucket> {
@@ -34,6 +36,21 @@ pub fn get_bucket() -> Result<Bucket> {
     Ok(bucket)
 }
 
+// Select the storage backend to download deployments from
+// with LAGON_STORAGE_BACKEND, defaulting to S3
+pub fn get_downloader() -> Result<Arc<dyn Downloader + Send + Sync>> {
+    match env::var("LAGON_STORAGE_BACKEND").as_deref() {
+        Ok("filesystem") => {
+            let root = env::var("LAGON_STORAGE_PATH")
+                .expect("LAGON_STORAGE_PATH must be set when using the filesystem backend");
+
+            Ok(Arc::new(FilesystemDownloader::new(PathBuf::from(root))))
+        }
+        Ok("s3") | Ok("") | Err(_) => Ok(Arc::new(S3BucketDownloader::new(get_bucket()?))),
+        Ok(backend) => panic!("LAGON_STORAGE_BACKEND {} is not supported", backend),
+    }
+}
+
 #[async_trait]
 pub trait Downloader {
     async fn download(&self, path: String) -> Result<Vec<u8>>;
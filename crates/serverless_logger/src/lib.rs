@@ -2,16 +2,36 @@ use axiom_rs::Client;
 use chrono::prelude::Local;
 use flume::Sender;
 use serde_json::{json, Value};
-use std::sync::{Arc, RwLock};
+use std::{
+    env,
+    sync::{Arc, RwLock},
+};
 
 use log::{
     as_debug, kv::source::as_map, set_boxed_logger, set_max_level, Level, LevelFilter, Log,
     Metadata, Record, SetLoggerError,
 };
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LogFormat {
+    Text,
+    // One JSON object per line, easier to ingest for log collectors
+    Json,
+}
+
+impl LogFormat {
+    fn from_env() -> Self {
+        match env::var("LAGON_LOG_FORMAT").as_deref() {
+            Ok("json") => LogFormat::Json,
+            _ => LogFormat::Text,
+        }
+    }
+}
+
 struct SimpleLogger {
     tx: Arc<RwLock<Option<Sender<Value>>>>,
     region: String,
+    format: LogFormat,
 }
 
 impl SimpleLogger {
@@ -36,6 +56,7 @@ impl SimpleLogger {
         Self {
             tx: Arc::new(RwLock::new(Some(tx))),
             region,
+            format: LogFormat::from_env(),
         }
     }
 }
@@ -49,13 +70,35 @@ impl Log for SimpleLogger {
         if self.enabled(record.metadata()) {
             let metadata = as_map(record.key_values());
 
-            println!(
-                "{} - {} - {} - {}",
-                Local::now(),
-                record.level(),
-                record.args(),
-                as_debug!(metadata),
-            );
+            match self.format {
+                LogFormat::Text => println!(
+                    "{} - {} - {} - {}",
+                    Local::now(),
+                    record.level(),
+                    record.args(),
+                    as_debug!(metadata),
+                ),
+                LogFormat::Json => {
+                    let mut line = json!({
+                        "timestamp": Local::now().to_rfc3339(),
+                        "level": record.level().to_string(),
+                        "message": record.args().to_string(),
+                        "region": self.region,
+                    });
+
+                    // Flatten the structured key/values (deployment, function,
+                    // request...) so they can be queried directly
+                    if let (Some(line), Ok(Value::Object(metadata))) =
+                        (line.as_object_mut(), serde_json::to_value(&metadata))
+                    {
+                        for (key, value) in metadata {
+                            line.entry(key).or_insert(value);
+                        }
+                    }
+
+                    println!("{line}");
+                }
+            }
 
             // Axiom is optional, so tx can have no listeners
             let tx = self.tx.read().expect("Tx lock is poisoned");
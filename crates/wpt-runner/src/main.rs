@@ -163,12 +163,19 @@ export function handler() {{
 
     tx.send_async(IsolateEvent::Request(IsolateRequest {
         request,
+        body_stream: None,
         sender: request_tx,
+        request_id: None,
     }))
     .await
     .unwrap();
 
-    if let Ok(RunResult::Error(error)) = request_rx.recv_async().await {
+    if let Ok(
+        RunResult::Error(error)
+        | RunResult::CompileError(error)
+        | RunResult::EvaluationError(error),
+    ) = request_rx.recv_async().await
+    {
         println!("{error}");
         exit(1);
     }
@@ -1,5 +1,5 @@
 import { DeleteObjectCommand, DeleteObjectsCommand, GetObjectCommand } from '@aws-sdk/client-s3';
-import redis from 'lib/redis';
+import redis, { nextSequence } from 'lib/redis';
 import s3 from 'lib/s3';
 import prisma from 'lib/prisma';
 import { Readable } from 'node:stream';
@@ -7,6 +7,7 @@ import { TRPCError } from '@trpc/server';
 import { envStringToObject } from 'lib/utils';
 import { checkCanQueryFunction } from './functions';
 import { Plan } from 'lib/plans';
+import { Prisma } from '@prisma/client';
 
 export async function createDeployment(
   func: {
@@ -14,6 +15,7 @@ export async function createDeployment(
   },
   assets: string[],
   triggerer: string,
+  codeHash?: string,
 ): Promise<{
   id: string;
   createdAt: Date;
@@ -27,6 +29,7 @@ export async function createDeployment(
       assets,
       functionId: func.id,
       triggerer,
+      codeHash,
     },
     select: {
       id: true,
@@ -107,6 +110,7 @@ export async function removeDeployment(
       env: envStringToObject(func.env),
       isProduction: deployment.isProduction,
       assets: deployment.assets,
+      sequence: await nextSequence(),
     }),
   );
 }
@@ -158,6 +162,7 @@ export async function promoteProductionDeployment(functionId: string, newDeploym
       totalTimeout: true,
       cron: true,
       cronRegion: true,
+      config: true,
       env: {
         select: {
           key: true,
@@ -188,6 +193,7 @@ export async function promoteProductionDeployment(functionId: string, newDeploym
       updatedAt: true,
       isProduction: true,
       assets: true,
+      codeHash: true,
     },
   });
 
@@ -204,9 +210,12 @@ export async function promoteProductionDeployment(functionId: string, newDeploym
       totalTimeout: func.totalTimeout,
       cron: func.cron,
       cronRegion: func.cronRegion,
+      config: func.config,
       env: envStringToObject(func.env),
       isProduction: true,
       assets: deployment.assets,
+      codeHash: deployment.codeHash,
+      sequence: await nextSequence(),
     }),
   );
 }
@@ -221,28 +230,35 @@ export async function redeploy(
     totalTimeout: number;
     cron: string | null;
     cronRegion: string;
+    config: Prisma.JsonValue;
     env: { key: string; value: string }[];
   },
-  deployment: { id: string; isProduction: boolean; assets: string[] },
+  deployment: { id: string; isProduction: boolean; assets: string[]; codeHash: string | null },
   oldDomains: string[],
+  cronUpdated: boolean,
 ) {
-  await redis.publish(
-    'undeploy',
-    JSON.stringify({
-      functionId: func.id,
-      functionName: func.name,
-      deploymentId: deployment.id,
-      domains: oldDomains,
-      memory: func.memory,
-      tickTimeout: func.tickTimeout,
-      totalTimeout: func.totalTimeout,
-      cron: func.cron,
-      cronRegion: func.cronRegion,
-      env: envStringToObject(func.env),
-      isProduction: deployment.isProduction,
-      assets: deployment.assets,
-    }),
-  );
+  // Serverless nodes update already loaded deployments when receiving a deploy message. An undeploy
+  // is only needed when the cron changes, since nodes in other regions ignore cron deploy messages
+  if (cronUpdated) {
+    await redis.publish(
+      'undeploy',
+      JSON.stringify({
+        functionId: func.id,
+        functionName: func.name,
+        deploymentId: deployment.id,
+        domains: oldDomains,
+        memory: func.memory,
+        tickTimeout: func.tickTimeout,
+        totalTimeout: func.totalTimeout,
+        cron: func.cron,
+        cronRegion: func.cronRegion,
+        env: envStringToObject(func.env),
+        isProduction: deployment.isProduction,
+        assets: deployment.assets,
+        sequence: await nextSequence(),
+      }),
+    );
+  }
 
   await redis.publish(
     'deploy',
@@ -256,9 +272,12 @@ export async function redeploy(
       totalTimeout: func.totalTimeout,
       cron: func.cron,
       cronRegion: func.cronRegion,
+      config: func.config,
       env: envStringToObject(func.env),
       isProduction: deployment.isProduction,
       assets: deployment.assets,
+      codeHash: deployment.codeHash,
+      sequence: await nextSequence(),
     }),
   );
 }
@@ -22,3 +22,7 @@ if (!redis) {
 if (process.env.NODE_ENV === 'development') global.redis = redis;
 
 export default redis;
+
+// Sent with deployment messages so serverless nodes can
+// ignore duplicate and out-of-order messages
+export const nextSequence = () => redis.incr('deployments:sequence');
@@ -11,7 +11,7 @@ import prisma from 'lib/prisma';
 import { T } from 'pages/api/trpc/[trpc]';
 import { z } from 'zod';
 import { getSignedUrl } from '@aws-sdk/s3-request-presigner';
-import redis from 'lib/redis';
+import redis, { nextSequence } from 'lib/redis';
 import { envStringToObject, getFullCurrentDomain } from 'lib/utils';
 import s3 from 'lib/s3';
 import { PRESIGNED_URL_EXPIRES_SECONDS } from 'lib/constants';
@@ -25,6 +25,8 @@ export const deploymentsRouter = (t: T) =>
         z.object({
           functionId: z.string(),
           functionSize: z.number(),
+          // SHA-256 of the code, checked by serverless nodes
+          functionHash: z.string().optional(),
           assets: z
             .object({
               name: z.string(),
@@ -52,6 +54,7 @@ export const deploymentsRouter = (t: T) =>
           },
           input.assets.map(({ name }) => name),
           ctx.session.user.email,
+          input.functionHash,
         );
 
         const getPresignedUrl = async (key: string, size: number) => {
@@ -121,6 +124,7 @@ export const deploymentsRouter = (t: T) =>
               totalTimeout: true,
               cron: true,
               cronRegion: true,
+              config: true,
               env: true,
             },
           }),
@@ -135,6 +139,7 @@ export const deploymentsRouter = (t: T) =>
               id: true,
               isProduction: true,
               assets: true,
+              codeHash: true,
             },
           }),
         ]);
@@ -157,9 +162,12 @@ export const deploymentsRouter = (t: T) =>
             totalTimeout: func.totalTimeout,
             cron: func.cron,
             cronRegion: func.cronRegion,
+            config: func.config,
             env: envStringToObject(func.env),
             isProduction: deployment.isProduction,
             assets: deployment.assets,
+            codeHash: deployment.codeHash,
+            sequence: await nextSequence(),
           }),
         );
 
@@ -307,6 +307,7 @@ LIMIT 100`,
             totalTimeout: true,
             cron: true,
             cronRegion: true,
+            config: true,
             env: {
               select: {
                 key: true,
@@ -320,6 +321,7 @@ LIMIT 100`,
                 commit: true,
                 isProduction: true,
                 assets: true,
+                codeHash: true,
                 createdAt: true,
                 updatedAt: true,
               },
@@ -440,6 +442,8 @@ LIMIT 100`,
               assets: deployment.assets as string[],
             },
             oldDomains,
+            (input.cron !== undefined && input.cron !== func.cron) ||
+              (!!input.cronRegion && input.cronRegion !== func.cronRegion),
           );
         }
 
@@ -0,0 +1,5 @@
+-- AlterTable
+ALTER TABLE `Function` ADD COLUMN `config` JSON NOT NULL;
+
+-- Set an empty config for existing Functions
+UPDATE `Function` SET `config` = JSON_OBJECT();
@@ -0,0 +1,2 @@
+-- AlterTable
+ALTER TABLE `Deployment` ADD COLUMN `codeHash` VARCHAR(64) NULL;
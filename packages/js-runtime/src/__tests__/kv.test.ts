@@ -0,0 +1,49 @@
+import { describe, it, expect, vi, beforeEach, afterEach } from 'vitest';
+import '../';
+
+describe('kv', () => {
+  beforeEach(() => {
+    globalThis.LagonAsync = {
+      ...globalThis.LagonAsync,
+      // @ts-expect-error the mock doesn't match the overloads
+      kv: vi.fn(),
+    };
+  });
+
+  afterEach(() => {
+    vi.resetAllMocks();
+  });
+
+  it('should serialize values', async () => {
+    await kv.set('key', { hello: 'world' }, { ttl: 60 });
+
+    expect(globalThis.LagonAsync.kv).toHaveBeenCalledWith('set', 'key', '{"hello":"world"}', 60);
+  });
+
+  it('should deserialize values', async () => {
+    // @ts-expect-error LagonAsync is not defined
+    globalThis.LagonAsync.kv.mockReturnValueOnce(Promise.resolve('{"hello":"world"}'));
+
+    expect(await kv.get('key')).toEqual({ hello: 'world' });
+    expect(globalThis.LagonAsync.kv).toHaveBeenCalledWith('get', 'key');
+  });
+
+  it('should return null for missing keys', async () => {
+    // @ts-expect-error LagonAsync is not defined
+    globalThis.LagonAsync.kv.mockReturnValueOnce(Promise.resolve(undefined));
+
+    expect(await kv.get('key')).toBeNull();
+  });
+
+  it('should throw with undefined values', async () => {
+    await expect(kv.set('key', undefined)).rejects.toThrow('KV values must be serializable to JSON');
+    expect(globalThis.LagonAsync.kv).not.toHaveBeenCalled();
+  });
+
+  it('should throw errors', async () => {
+    // @ts-expect-error LagonAsync is not defined
+    globalThis.LagonAsync.kv.mockReturnValueOnce(Promise.reject('KV storage is not available'));
+
+    await expect(kv.get('key')).rejects.toThrow(new Error('KV storage is not available'));
+  });
+});
@@ -14,6 +14,7 @@ import './runtime/global/process';
 import './runtime/global/crypto';
 import './runtime/global/navigator';
 import './runtime/global/timers';
+import './runtime/global/kv';
 import './runtime/http/URLSearchParams';
 import './runtime/http/URL';
 import './runtime/http/URLPattern';
@@ -105,6 +106,17 @@ declare global {
       algorithm: RsaHashedKeyGenParams | EcKeyGenParams | HmacKeyGenParams | AesKeyGenParams,
     ): Promise<ArrayBuffer>;
     sleep: (ms: number) => Promise<void>;
+    readBody: (id: number) => Promise<Uint8Array | undefined>;
+    kv: {
+      (operation: 'get', key: string): Promise<string | undefined>;
+      (operation: 'set', key: string, value: string, ttl?: number): Promise<void>;
+      (operation: 'delete', key: string): Promise<boolean>;
+    };
+  };
+  var kv: {
+    get<T = unknown>(key: string): Promise<T | null>;
+    set(key: string, value: unknown, options?: { ttl?: number }): Promise<void>;
+    delete(key: string): Promise<boolean>;
   };
   var __lagon__: {
     isIterable: (value: unknown) => value is ArrayBuffer;
@@ -121,6 +133,7 @@ declare global {
       m: RequestInit['method'];
       h: RequestInit['headers'];
       b: RequestInit['body'];
+      s?: boolean;
     },
   ) => Promise<{
     b?: string;
@@ -141,6 +154,21 @@ declare global {
   }
 }
 
+// Streamed request bodies are read from the serverless as the
+// Function consumes them, instead of being received entirely
+const requestBodyStream = (id: number) =>
+  new ReadableStream<Uint8Array>({
+    async pull(controller) {
+      const chunk = await LagonAsync.readBody(id);
+
+      if (chunk === undefined) {
+        controller.close();
+      } else {
+        controller.enqueue(chunk);
+      }
+    },
+  });
+
 globalThis.masterHandler = async (id, handler, request) => {
   if (typeof handler !== 'function') {
     throw new Error('Handler function is not defined or is not a function');
@@ -149,7 +177,7 @@ globalThis.masterHandler = async (id, handler, request) => {
   const handlerRequest = new Request(request.i, {
     method: request.m,
     headers: request.h,
-    body: request.b,
+    body: request.s ? requestBodyStream(id) : request.b,
   });
 
   const response = await handler(handlerRequest);
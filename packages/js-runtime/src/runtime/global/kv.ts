@@ -0,0 +1,32 @@
+(globalThis => {
+  // Errors from the Rust side are strings
+  const call = async <T>(promise: () => Promise<T>): Promise<T> => {
+    try {
+      return await promise();
+    } catch (error) {
+      if (typeof error === 'string') {
+        throw new Error(error);
+      }
+
+      throw error;
+    }
+  };
+
+  globalThis.kv = {
+    async get(key) {
+      const value = await call(() => LagonAsync.kv('get', key));
+
+      return value === undefined ? null : JSON.parse(value);
+    },
+    async set(key, value, options) {
+      if (value === undefined) {
+        throw new TypeError('KV values must be serializable to JSON');
+      }
+
+      await call(() => LagonAsync.kv('set', key, JSON.stringify(value), options?.ttl));
+    },
+    delete(key) {
+      return call(() => LagonAsync.kv('delete', key));
+    },
+  };
+})(globalThis);
@@ -116,24 +116,27 @@ export class RequestResponseBody {
 
     const reader = (this.theBody as ReadableStream<Uint8Array>).getReader();
 
-    return new Promise(resolve => {
+    return new Promise((resolve, reject) => {
       let result = new Uint8Array();
 
       const pull = () => {
-        reader.read().then(({ done, value }) => {
-          if (done) {
-            this.bodyUsed = true;
-            return resolve(result);
-          }
-
-          const newResult = new Uint8Array(result.length + value.length);
-          newResult.set(result);
-          newResult.set(value, result.length);
-
-          result = newResult;
-
-          pull();
-        });
+        reader
+          .read()
+          .then(({ done, value }) => {
+            if (done) {
+              this.bodyUsed = true;
+              return resolve(result);
+            }
+
+            const newResult = new Uint8Array(result.length + value.length);
+            newResult.set(result);
+            newResult.set(value, result.length);
+
+            result = newResult;
+
+            pull();
+          })
+          .catch(reject);
       };
 
       pull();
@@ -205,24 +208,33 @@ export class RequestResponseBody {
 
     const reader = (this.theBody as ReadableStream<Uint8Array>).getReader();
 
-    return new Promise(resolve => {
-      let result = '';
+    return new Promise((resolve, reject) => {
+      // Chunks are decoded together, since a character
+      // can be split between two chunks
+      let result = new Uint8Array();
 
       const pull = () => {
-        reader.read().then(({ done, value }) => {
-          if (done) {
-            this.bodyUsed = true;
-            return resolve(result);
-          }
-
-          if (globalThis.__lagon__.isIterable(value)) {
-            result += globalThis.__lagon__.TEXT_DECODER.decode(value);
-          } else {
-            result += value;
-          }
-
-          pull();
-        });
+        reader
+          .read()
+          .then(({ done, value }) => {
+            if (done) {
+              this.bodyUsed = true;
+              return resolve(globalThis.__lagon__.TEXT_DECODER.decode(result));
+            }
+
+            const chunk = globalThis.__lagon__.isIterable(value)
+              ? value
+              : globalThis.__lagon__.TEXT_ENCODER.encode(String(value));
+
+            const newResult = new Uint8Array(result.length + chunk.byteLength);
+            newResult.set(result);
+            newResult.set(new Uint8Array(chunk), result.length);
+
+            result = newResult;
+
+            pull();
+          })
+          .catch(reject);
       };
 
       pull();